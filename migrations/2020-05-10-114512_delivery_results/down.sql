@@ -0,0 +1,5 @@
+alter table outbound_queue drop column remote_queue_id;
+alter table outbound_queue drop column remote_mx;
+alter table outbound_queue drop column last_error;
+alter table outbound_queue drop column next_attempt;
+alter table outbound_queue drop column attempts;
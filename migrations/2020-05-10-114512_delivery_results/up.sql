@@ -0,0 +1,5 @@
+alter table outbound_queue add column attempts integer not null default 0;
+alter table outbound_queue add column next_attempt timestamp with time zone not null default now();
+alter table outbound_queue add column last_error text;
+alter table outbound_queue add column remote_mx text;
+alter table outbound_queue add column remote_queue_id text;
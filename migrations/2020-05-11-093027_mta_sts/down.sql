@@ -0,0 +1,2 @@
+drop table mta_sts_policy;
+drop type mta_sts_mode;
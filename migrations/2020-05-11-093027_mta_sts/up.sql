@@ -0,0 +1,10 @@
+create type mta_sts_mode as enum ('enforce', 'testing', 'none');
+
+create table mta_sts_policy (
+    domain text primary key,
+    policy_id text not null,
+    mode mta_sts_mode not null,
+    mx text[] not null,
+    max_age bigint not null,
+    fetched_at timestamp with time zone not null
+);
@@ -0,0 +1 @@
+drop table tls_report_result;
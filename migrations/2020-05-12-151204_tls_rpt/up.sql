@@ -0,0 +1,15 @@
+create table tls_report_result (
+    id uuid primary key,
+    policy_domain text not null,
+    policy_type text not null,
+    policy_string text[] not null,
+    mx_host text[] not null,
+    sending_ip text,
+    receiving_mx_hostname text not null,
+    receiving_ip text not null,
+    result_type text,
+    failure_reason text,
+    recorded_at timestamp with time zone not null
+);
+
+create index tls_report_result_recorded_at on tls_report_result (recorded_at);
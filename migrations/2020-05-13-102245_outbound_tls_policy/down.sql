@@ -0,0 +1,2 @@
+drop table outbound_tls_policy;
+drop type outbound_tls_mode;
@@ -0,0 +1,8 @@
+create type outbound_tls_mode as enum ('opportunistic', 'opportunistic_fallback_plain', 'verify_required', 'pinned');
+
+create table outbound_tls_policy (
+    domain text primary key,
+    mode outbound_tls_mode not null,
+    fingerprint text,
+    constraint pinned_fingerprint check (mode != 'pinned' or fingerprint is not null)
+);
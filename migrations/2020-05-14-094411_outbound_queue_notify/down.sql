@@ -0,0 +1,2 @@
+drop trigger outbound_queue_notify on outbound_queue;
+drop function notify_outbound_queue();
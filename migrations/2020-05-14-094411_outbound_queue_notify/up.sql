@@ -0,0 +1,9 @@
+create function notify_outbound_queue() returns trigger as $$
+begin
+    perform pg_notify('outbound_queue', '');
+    return null;
+end;
+$$ language plpgsql;
+
+create trigger outbound_queue_notify after insert on outbound_queue
+    for each statement execute procedure notify_outbound_queue();
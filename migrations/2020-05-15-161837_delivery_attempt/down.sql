@@ -0,0 +1,2 @@
+drop table delivery_attempt;
+drop type delivery_stage;
@@ -0,0 +1,18 @@
+create type delivery_stage as enum ('connect', 'banner', 'ehlo', 'starttls', 'mail', 'rcpt', 'data');
+
+create table delivery_attempt (
+    id uuid primary key,
+    queue_item_id uuid not null references outbound_queue(id) on delete cascade,
+    mx_host text not null,
+    mx_ip text not null,
+    tls text,
+    failed_stage delivery_stage,
+    response_code integer,
+    response_lines text[],
+    error text,
+    started_at timestamp with time zone not null,
+    duration_ms integer not null,
+    transcript text
+);
+
+create index delivery_attempt_queue_item_id on delivery_attempt (queue_item_id);
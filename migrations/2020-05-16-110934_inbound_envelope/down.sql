@@ -0,0 +1,6 @@
+alter table inbound_queue drop column envelope_from;
+alter table inbound_queue drop column helo;
+alter table inbound_queue drop column peer_ip;
+alter table inbound_queue drop column reverse_dns;
+alter table inbound_queue drop column protocol;
+alter table inbound_queue drop column received_at;
@@ -0,0 +1,6 @@
+alter table inbound_queue add column envelope_from text;
+alter table inbound_queue add column helo text;
+alter table inbound_queue add column peer_ip text;
+alter table inbound_queue add column reverse_dns text;
+alter table inbound_queue add column protocol text;
+alter table inbound_queue add column received_at timestamp with time zone not null default now();
@@ -0,0 +1,9 @@
+alter table mail_subpart drop column raw_message;
+alter table mail_subpart drop column header_offset;
+alter table mail_subpart drop column body_offset;
+alter table mail_subpart drop column end_offset;
+
+alter table inbound_queue drop column raw_message;
+alter table inbound_queue drop column trace_headers;
+
+drop table raw_message;
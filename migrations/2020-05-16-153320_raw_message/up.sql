@@ -0,0 +1,12 @@
+create table raw_message (
+    id uuid primary key,
+    data bytea not null
+);
+
+alter table inbound_queue add column raw_message uuid references raw_message(id);
+alter table inbound_queue add column trace_headers text;
+
+alter table mail_subpart add column raw_message uuid references raw_message(id);
+alter table mail_subpart add column header_offset integer;
+alter table mail_subpart add column body_offset integer;
+alter table mail_subpart add column end_offset integer;
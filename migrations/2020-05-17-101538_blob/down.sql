@@ -0,0 +1,23 @@
+-- compressed blobs can't be decompressed in sql, so refuse rather than lose their contents
+do $$
+begin
+    if exists (select 1 from blob where compressed) then
+        raise exception 'cannot revert blob storage while compressed blobs exist';
+    end if;
+end;
+$$;
+
+drop trigger raw_message_release_blob on raw_message;
+drop function release_raw_message_blob();
+drop trigger mail_subpart_release_blob on mail_subpart;
+drop function release_mail_subpart_blob();
+
+alter table raw_message add column data bytea;
+update raw_message set data = blob.data from blob where blob.hash = raw_message.data_hash;
+alter table raw_message drop column data_hash;
+
+alter table mail_subpart add column body bytea;
+update mail_subpart set body = blob.data from blob where blob.hash = mail_subpart.body_hash;
+alter table mail_subpart drop column body_hash;
+
+drop table blob;
@@ -0,0 +1,41 @@
+create table blob (
+    hash bytea primary key,
+    data bytea not null,
+    compressed boolean not null,
+    ref_count bigint not null
+);
+
+insert into blob (hash, data, compressed, ref_count)
+    select sha256(body), (array_agg(body))[1], false, count(*) from mail_subpart group by sha256(body);
+alter table mail_subpart add column body_hash bytea references blob(hash);
+update mail_subpart set body_hash = sha256(body);
+alter table mail_subpart alter column body_hash set not null;
+alter table mail_subpart drop column body;
+
+insert into blob (hash, data, compressed, ref_count)
+    select sha256(data), (array_agg(data))[1], false, count(*) from raw_message group by sha256(data)
+    on conflict (hash) do update set ref_count = blob.ref_count + excluded.ref_count;
+alter table raw_message add column data_hash bytea references blob(hash);
+update raw_message set data_hash = sha256(data);
+alter table raw_message alter column data_hash set not null;
+alter table raw_message drop column data;
+
+create function release_mail_subpart_blob() returns trigger as $$
+begin
+    update blob set ref_count = ref_count - 1 where hash = old.body_hash;
+    return old;
+end;
+$$ language plpgsql;
+
+create trigger mail_subpart_release_blob after delete on mail_subpart
+    for each row execute procedure release_mail_subpart_blob();
+
+create function release_raw_message_blob() returns trigger as $$
+begin
+    update blob set ref_count = ref_count - 1 where hash = old.data_hash;
+    return old;
+end;
+$$ language plpgsql;
+
+create trigger raw_message_release_blob after delete on raw_message
+    for each row execute procedure release_raw_message_blob();
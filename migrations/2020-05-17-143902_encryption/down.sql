@@ -0,0 +1,23 @@
+-- encrypted data can't be decrypted in sql, so refuse rather than leave ciphertext behind
+do $$
+begin
+    if exists (select 1 from blob where data_key is not null)
+        or exists (select 1 from mail_subpart where data_key is not null)
+        or exists (select 1 from inbound_queue where data_key is not null)
+        or exists (select 1 from outbound_message where data_key is not null) then
+        raise exception 'cannot revert encryption while encrypted data exists';
+    end if;
+end;
+$$;
+
+alter table outbound_message drop column data_key;
+
+alter table inbound_queue drop column data_key;
+alter table inbound_queue drop column encrypted_subject;
+
+alter table mail_subpart drop column data_key;
+alter table mail_subpart drop column encrypted_headers;
+
+alter table blob drop column data_key;
+
+drop table data_key;
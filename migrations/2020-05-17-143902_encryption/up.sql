@@ -0,0 +1,16 @@
+create table data_key (
+    id uuid primary key,
+    master_key_id text not null,
+    wrapped_key bytea not null,
+    created_at timestamp with time zone not null default now()
+);
+
+alter table blob add column data_key uuid references data_key(id);
+
+alter table mail_subpart add column data_key uuid references data_key(id);
+alter table mail_subpart add column encrypted_headers bytea;
+
+alter table inbound_queue add column data_key uuid references data_key(id);
+alter table inbound_queue add column encrypted_subject bytea;
+
+alter table outbound_message add column data_key uuid references data_key(id);
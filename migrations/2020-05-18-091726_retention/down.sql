@@ -0,0 +1,10 @@
+drop index mail_subpart_data_key;
+drop index mail_subpart_raw_message;
+drop index inbound_queue_data_key;
+drop index inbound_queue_raw_message;
+drop index outbound_queue_message_id;
+drop index inbound_queue_contents;
+drop index inbound_queue_released_at;
+drop index inbound_queue_received_at;
+
+alter table inbound_queue drop column released_at;
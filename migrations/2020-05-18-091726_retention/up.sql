@@ -0,0 +1,10 @@
+alter table inbound_queue add column released_at timestamp with time zone;
+
+create index inbound_queue_received_at on inbound_queue (received_at);
+create index inbound_queue_released_at on inbound_queue (released_at);
+create index inbound_queue_contents on inbound_queue (contents);
+create index outbound_queue_message_id on outbound_queue (message_id);
+create index inbound_queue_raw_message on inbound_queue (raw_message);
+create index inbound_queue_data_key on inbound_queue (data_key);
+create index mail_subpart_raw_message on mail_subpart (raw_message);
+create index mail_subpart_data_key on mail_subpart (data_key);
@@ -0,0 +1,6 @@
+alter table inbound_queue drop column spf_result;
+alter table inbound_queue drop column spf_domain;
+alter table inbound_queue drop column dkim_result;
+alter table inbound_queue drop column dkim_domains;
+alter table inbound_queue drop column digested_at;
+alter table inbound_queue drop column action_token_hash;
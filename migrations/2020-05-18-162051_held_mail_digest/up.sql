@@ -0,0 +1,6 @@
+alter table inbound_queue add column spf_result text;
+alter table inbound_queue add column spf_domain text;
+alter table inbound_queue add column dkim_result text;
+alter table inbound_queue add column dkim_domains text[];
+alter table inbound_queue add column digested_at timestamp with time zone;
+alter table inbound_queue add column action_token_hash bytea;
@@ -0,0 +1,6 @@
+drop index registered_addresses_forward_email;
+drop table portal_session;
+drop table portal_login_token;
+drop table sender_rule;
+alter table registered_addresses drop column policy;
+drop type alias_policy;
@@ -0,0 +1,28 @@
+create type alias_policy as enum ('challenge', 'forward', 'reject');
+
+alter table registered_addresses add column policy alias_policy not null default 'challenge';
+
+create table sender_rule (
+    id uuid primary key,
+    registered_address text not null references registered_addresses(id) on delete cascade,
+    sender text not null,
+    allow boolean not null,
+    created_at timestamp with time zone not null default now(),
+    unique (registered_address, sender)
+);
+
+create table portal_login_token (
+    token_hash bytea primary key,
+    forward_email text not null,
+    created_at timestamp with time zone not null default now(),
+    expires_at timestamp with time zone not null
+);
+
+create table portal_session (
+    token_hash bytea primary key,
+    forward_email text not null,
+    expires_at timestamp with time zone not null
+);
+
+create index registered_addresses_forward_email on registered_addresses (forward_email);
+create index portal_login_token_forward_email on portal_login_token (forward_email);
@@ -0,0 +1,17 @@
+alter table inbound_queue drop column dmarc_domain;
+alter table inbound_queue drop column dmarc_result;
+
+alter type alias_policy rename to alias_policy_new;
+create type alias_policy as enum ('challenge', 'forward', 'reject');
+
+alter table registered_addresses alter column policy drop default;
+alter table registered_addresses alter column policy type alias_policy using (
+    case policy
+        when 'forward_directly' then 'forward'
+        when 'forward_if_authenticated' then 'challenge'
+        when 'hold_for_review' then 'challenge'
+        else policy::text
+    end
+)::alias_policy;
+alter table registered_addresses alter column policy set default 'challenge';
+drop type alias_policy_new;
@@ -0,0 +1,15 @@
+alter type alias_policy rename to alias_policy_old;
+create type alias_policy as enum ('challenge', 'forward_directly', 'forward_if_authenticated', 'hold_for_review', 'reject');
+
+alter table registered_addresses alter column policy drop default;
+alter table registered_addresses alter column policy type alias_policy using (
+    case policy
+        when 'forward' then 'forward_directly'
+        else policy::text
+    end
+)::alias_policy;
+alter table registered_addresses alter column policy set default 'challenge';
+drop type alias_policy_old;
+
+alter table inbound_queue add column dmarc_result text;
+alter table inbound_queue add column dmarc_domain text;
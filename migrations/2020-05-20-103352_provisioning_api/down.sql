@@ -0,0 +1,3 @@
+drop table alias_audit;
+drop table api_idempotency_key;
+drop table api_key;
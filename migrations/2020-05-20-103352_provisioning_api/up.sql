@@ -0,0 +1,30 @@
+create table api_key (
+    id uuid primary key,
+    name text not null,
+    key_hash bytea not null unique,
+    created_at timestamp with time zone not null default now(),
+    revoked_at timestamp with time zone
+);
+
+create table api_idempotency_key (
+    api_key uuid not null references api_key(id),
+    idempotency_key text not null,
+    request_hash bytea not null,
+    response_status int4 not null,
+    response_body text,
+    created_at timestamp with time zone not null default now(),
+    primary key (api_key, idempotency_key)
+);
+
+create table alias_audit (
+    id uuid primary key,
+    actor text not null,
+    action text not null,
+    address text not null,
+    forward_email text,
+    policy alias_policy,
+    created_at timestamp with time zone not null default now()
+);
+
+create index alias_audit_address on alias_audit (address);
+create index api_idempotency_key_created_at on api_idempotency_key (created_at);
@@ -0,0 +1,2 @@
+drop table domain_contact;
+drop type contact_role;
@@ -0,0 +1,10 @@
+create type contact_role as enum ('registrant', 'admin', 'tech', 'billing');
+
+create table domain_contact (
+    domain text not null,
+    role contact_role not null,
+    registered_address text not null references registered_addresses(id) on delete cascade,
+    primary key (domain, role)
+);
+
+create index domain_contact_registered_address on domain_contact (registered_address);
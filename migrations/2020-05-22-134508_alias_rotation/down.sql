@@ -0,0 +1,5 @@
+drop table domain_contact_history;
+alter table registered_addresses drop column grace_until;
+alter table registered_addresses drop column grace_mode;
+alter table registered_addresses drop column rotated_to;
+drop type grace_mode;
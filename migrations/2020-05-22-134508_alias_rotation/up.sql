@@ -0,0 +1,21 @@
+create type grace_mode as enum ('challenge', 'reject');
+
+alter table registered_addresses add column rotated_to text references registered_addresses(id) on delete set null;
+alter table registered_addresses add column grace_mode grace_mode;
+alter table registered_addresses add column grace_until timestamp with time zone;
+
+create table domain_contact_history (
+    domain text not null,
+    role contact_role not null,
+    registered_address text not null,
+    forward_email text not null,
+    started_at timestamp with time zone not null default now(),
+    ended_at timestamp with time zone,
+    primary key (domain, role, started_at)
+);
+
+create index domain_contact_history_registered_address on domain_contact_history (registered_address);
+
+insert into domain_contact_history (domain, role, registered_address, forward_email)
+    select domain, role, registered_address, forward_email
+    from domain_contact join registered_addresses on registered_addresses.id = domain_contact.registered_address;
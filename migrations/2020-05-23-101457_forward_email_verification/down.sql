@@ -0,0 +1 @@
+drop table forward_email_verification;
@@ -0,0 +1,11 @@
+create table forward_email_verification (
+    forward_email text primary key,
+    token_hash bytea,
+    requests_sent int4 not null default 0,
+    last_sent_at timestamp with time zone,
+    verified_at timestamp with time zone,
+    verified_until timestamp with time zone,
+    hard_bounces int4 not null default 0
+);
+
+create index forward_email_verification_token_hash on forward_email_verification (token_hash);
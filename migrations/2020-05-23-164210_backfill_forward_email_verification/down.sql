@@ -0,0 +1,3 @@
+-- Backfilled addresses are the ones let through without ever having been confirmed
+update forward_email_verification set verified_until = null
+    where verified_at is null and verified_until is not null;
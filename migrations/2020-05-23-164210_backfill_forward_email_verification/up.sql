@@ -0,0 +1,8 @@
+-- Addresses registered before verification existed were forwarded to all along, so rather than
+-- suspending them all at once they're let through for long enough to be asked to confirm. They've
+-- never actually been confirmed, so verified_at is left unset.
+insert into forward_email_verification (forward_email, verified_until)
+    select distinct lower(forward_email), now() + interval '30 days' from registered_addresses
+    on conflict (forward_email) do update
+        set verified_until = excluded.verified_until
+        where forward_email_verification.verified_until is null;
@@ -0,0 +1,2 @@
+delete from registered_addresses where revoked_at is not null;
+alter table registered_addresses drop column revoked_at;
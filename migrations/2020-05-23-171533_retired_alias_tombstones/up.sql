@@ -0,0 +1 @@
+alter table registered_addresses add column revoked_at timestamp with time zone;
@@ -0,0 +1,2 @@
+delete from api_idempotency_key where response_status is null;
+alter table api_idempotency_key alter column response_status set not null;
@@ -0,0 +1 @@
+alter table api_idempotency_key alter column response_status drop not null;
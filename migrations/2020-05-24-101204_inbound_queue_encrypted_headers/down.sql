@@ -0,0 +1,10 @@
+-- encrypted data can't be decrypted in sql, so refuse rather than leave ciphertext behind
+do $$
+begin
+    if exists (select 1 from inbound_queue where encrypted_headers is not null) then
+        raise exception 'cannot revert header encryption while encrypted headers exist';
+    end if;
+end;
+$$;
+
+alter table inbound_queue drop column encrypted_headers;
@@ -0,0 +1 @@
+alter table inbound_queue add column encrypted_headers bytea;
@@ -0,0 +1 @@
+drop index inbound_queue_rcpt_to;
@@ -0,0 +1,2 @@
+update inbound_queue set rcpt_to = lower(rcpt_to) where rcpt_to <> lower(rcpt_to);
+create index inbound_queue_rcpt_to on inbound_queue (rcpt_to);
@@ -0,0 +1,539 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+/// The domain WHOIS addresses are under
+pub const ALIAS_DOMAIN: &str = "whois.as207960.net";
+/// Mailboxes the alias domain is expected to have for itself, RFC 2142, which can't be registered
+/// as WHOIS addresses
+const RESERVED_LOCAL_PARTS: &[&str] = &[
+    "postmaster", "abuse", "hostmaster", "webmaster", "security", "noc", "mailer-daemon",
+];
+/// Characters of generated local parts, each carrying 5 bits of randomness
+const LOCAL_PART_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const LOCAL_PART_LEN: usize = 20;
+
+/// What to do with a message for one of its recipients
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Disposition {
+    /// Hold it and ask the sender to complete a CAPTCHA
+    Challenge,
+    /// Hold it for the registrant to review, without asking anything of the sender
+    Hold,
+    /// Forward it straight on to the registrant
+    Forward,
+    /// Quietly throw it away
+    Drop,
+}
+
+/// Why mail to a WHOIS address is turned away outright
+#[derive(Debug, PartialEq, Clone)]
+pub enum Refusal {
+    /// The registrant has chosen to reject everything sent to it
+    Rejected,
+    /// It's been rotated and its grace period is up, or it's been rotated and replaced by an
+    /// address that's since gone too
+    Retired,
+    /// It's been rotated, and mail should go to the new address instead
+    Moved(String),
+}
+
+/// Looks up a WHOIS address that's still registered
+pub fn lookup(address: &str, conn: &crate::DbConn) -> QueryResult<Option<models::RegisteredAddress>> {
+    schema::registered_addresses::table
+        .find(address.to_lowercase())
+        .filter(schema::registered_addresses::revoked_at.is_null())
+        .get_result::<models::RegisteredAddress>(conn)
+        .optional()
+}
+
+/// Looks up a WHOIS address, including rotated ones that have since been revoked
+pub fn lookup_any(address: &str, conn: &crate::DbConn) -> QueryResult<Option<models::RegisteredAddress>> {
+    schema::registered_addresses::table
+        .find(address.to_lowercase())
+        .get_result::<models::RegisteredAddress>(conn)
+        .optional()
+}
+
+/// Decides whether mail to a WHOIS address should be turned away outright
+pub fn refusal(address: &models::RegisteredAddress) -> Option<Refusal> {
+    if address.revoked_at.is_some() || address.grace_until.map_or(false, |g| g <= chrono::Utc::now()) {
+        return Some(Refusal::Retired);
+    }
+    if address.grace_mode == Some(schema::GraceMode::Reject) {
+        return Some(match &address.rotated_to {
+            Some(new) => Refusal::Moved(new.clone()),
+            None => Refusal::Retired,
+        });
+    }
+    if address.policy == schema::AliasPolicy::Reject {
+        return Some(Refusal::Rejected);
+    }
+    None
+}
+
+/// All the WHOIS addresses forwarding to a registrant's real address
+pub fn for_forward_email(forward_email: &str, conn: &crate::DbConn) -> QueryResult<Vec<models::RegisteredAddress>> {
+    schema::registered_addresses::table
+        .filter(lower(schema::registered_addresses::forward_email).eq(forward_email.to_lowercase()))
+        .filter(schema::registered_addresses::revoked_at.is_null())
+        .order_by(schema::registered_addresses::id.asc())
+        .load::<models::RegisteredAddress>(conn)
+}
+
+pub fn policy_name(policy: schema::AliasPolicy) -> &'static str {
+    match policy {
+        schema::AliasPolicy::Challenge => "challenge",
+        schema::AliasPolicy::ForwardDirectly => "forward_directly",
+        schema::AliasPolicy::ForwardIfAuthenticated => "forward_if_authenticated",
+        schema::AliasPolicy::HoldForReview => "hold_for_review",
+        schema::AliasPolicy::Reject => "reject",
+    }
+}
+
+pub fn parse_policy(policy: &str) -> Option<schema::AliasPolicy> {
+    match policy {
+        "challenge" => Some(schema::AliasPolicy::Challenge),
+        "forward_directly" => Some(schema::AliasPolicy::ForwardDirectly),
+        "forward_if_authenticated" => Some(schema::AliasPolicy::ForwardIfAuthenticated),
+        "hold_for_review" => Some(schema::AliasPolicy::HoldForReview),
+        "reject" => Some(schema::AliasPolicy::Reject),
+        _ => None
+    }
+}
+
+/// Checks an address looks like a plain `local@domain.tld` one, lowercasing its domain
+pub fn normalise_address(address: &str) -> Option<String> {
+    let address = address.trim();
+    if address.is_empty() || address.chars().any(|c| c.is_whitespace() || c.is_control() || "<>()[],;:\\\"".contains(c)) {
+        return None;
+    }
+    let mut parts = address.rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) if !local.is_empty() && !local.contains('@') && domain.contains('.')
+            && !domain.starts_with('.') && !domain.ends_with('.') => Some(format!("{}@{}", local, domain.to_lowercase())),
+        _ => None
+    }
+}
+
+/// Checks an address can be registered as a WHOIS address, being under the alias domain and not
+/// one of its reserved mailboxes
+pub fn is_assignable(address: &str) -> bool {
+    let mut parts = address.rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => domain.eq_ignore_ascii_case(ALIAS_DOMAIN)
+            && !RESERVED_LOCAL_PARTS.iter().any(|r| local.eq_ignore_ascii_case(r)),
+        _ => false
+    }
+}
+
+/// Makes up a new unguessable WHOIS address
+pub fn random_address() -> String {
+    let mut bytes = [0u8; LOCAL_PART_LEN];
+    openssl::rand::rand_bytes(&mut bytes).expect("Unable to generate address");
+    let local = bytes.iter()
+        .map(|b| LOCAL_PART_ALPHABET[(*b as usize) % LOCAL_PART_ALPHABET.len()] as char)
+        .collect::<String>();
+    format!("{}@{}", local, ALIAS_DOMAIN)
+}
+
+fn audit(actor: &str, action: &str, address: &str, state: Option<&models::RegisteredAddress>, conn: &crate::DbConn) -> QueryResult<()> {
+    diesel::insert_into(schema::alias_audit::table)
+        .values(&models::NewAliasAudit {
+            id: &uuid::Uuid::new_v4(),
+            actor,
+            action,
+            address,
+            forward_email: state.map(|s| s.forward_email.as_str()),
+            policy: state.map(|s| &s.policy),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Registers a new WHOIS address, recording who did so, or gives `None` if it's already taken
+pub fn create(
+    address: &str, forward_email: &str, policy: schema::AliasPolicy, actor: &str, conn: &crate::DbConn,
+) -> QueryResult<Option<models::RegisteredAddress>> {
+    conn.transaction(|| {
+        let created = diesel::insert_into(schema::registered_addresses::table)
+            .values(&models::NewRegisteredAddress {
+                id: &address.to_lowercase(),
+                forward_email,
+                policy: &policy,
+            })
+            .on_conflict_do_nothing()
+            .get_result::<models::RegisteredAddress>(conn)
+            .optional()?;
+        if let Some(created) = &created {
+            audit(actor, "create", &created.id, Some(created), conn)?;
+        }
+        Ok(created)
+    })
+}
+
+/// Changes a WHOIS address's policy, recording who did so
+pub fn update(
+    address: &str, policy: Option<schema::AliasPolicy>, actor: &str, conn: &crate::DbConn,
+) -> QueryResult<Option<models::RegisteredAddress>> {
+    conn.transaction(|| {
+        let existing = match lookup(address, conn)? {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        let updated = diesel::update(schema::registered_addresses::table.find(&existing.id))
+            .set(schema::registered_addresses::policy.eq(policy.unwrap_or(existing.policy)))
+            .get_result::<models::RegisteredAddress>(conn)?;
+        audit(actor, "update", &updated.id, Some(&updated), conn)?;
+        Ok(Some(updated))
+    })
+}
+
+/// Changes where a WHOIS address forwards to, and optionally its policy. A different person gets
+/// a new address rather than having the old one repointed at them, so neither mail meant for the
+/// old recipient nor any held for it ever reaches the new one. The new address takes over its
+/// domain contact roles, and the old one is retired as by `retire`. Gives `None` if there's no such
+/// address, or it's already been rotated or retired and so can't be given to someone else.
+pub fn reassign(
+    address: &str, forward_email: &str, policy: Option<schema::AliasPolicy>, grace: chrono::Duration, actor: &str,
+    conn: &crate::DbConn,
+) -> QueryResult<Option<models::RegisteredAddress>> {
+    conn.transaction(|| {
+        let old = match lookup(address, conn)? {
+            Some(a) => a,
+            None => return Ok(None)
+        };
+        // Only the way the address is written has changed
+        if old.forward_email.eq_ignore_ascii_case(forward_email) {
+            let updated = diesel::update(schema::registered_addresses::table.find(&old.id))
+                .set((
+                    schema::registered_addresses::forward_email.eq(forward_email),
+                    schema::registered_addresses::policy.eq(policy.unwrap_or(old.policy)),
+                ))
+                .get_result::<models::RegisteredAddress>(conn)?;
+            audit(actor, "update", &updated.id, Some(&updated), conn)?;
+            return Ok(Some(updated));
+        }
+        if old.grace_until.is_some() {
+            return Ok(None);
+        }
+
+        let new = loop {
+            if let Some(a) = create(&random_address(), forward_email, policy.unwrap_or(old.policy), actor, conn)? {
+                break a;
+            }
+        };
+        crate::domain::move_contacts(&old.id, &new, conn)?;
+        retire(&old.id, schema::GraceMode::Challenge, grace, actor, conn)?;
+        Ok(Some(new))
+    })
+}
+
+/// Removes a WHOIS address, along with its allow and block lists, recording who did so. Mail
+/// already held for it is left to expire. A rotated address is kept as revoked rather than
+/// deleted, so it goes on being refused instead of being treated as never having existed.
+pub fn revoke(address: &str, actor: &str, conn: &crate::DbConn) -> QueryResult<bool> {
+    conn.transaction(|| {
+        let existing = match lookup(address, conn)? {
+            Some(a) => a,
+            None => return Ok(false)
+        };
+        if existing.grace_until.is_some() {
+            diesel::delete(schema::sender_rule::table.filter(schema::sender_rule::registered_address.eq(&existing.id)))
+                .execute(conn)?;
+            diesel::update(schema::registered_addresses::table.find(&existing.id))
+                .set(schema::registered_addresses::revoked_at.eq(chrono::Utc::now()))
+                .execute(conn)?;
+        } else {
+            diesel::delete(schema::registered_addresses::table.find(&existing.id))
+                .execute(conn)?;
+        }
+        audit(actor, "revoke", &existing.id, None, conn)?;
+        Ok(true)
+    })
+}
+
+pub fn grace_mode_name(mode: schema::GraceMode) -> &'static str {
+    match mode {
+        schema::GraceMode::Challenge => "challenge",
+        schema::GraceMode::Reject => "reject",
+    }
+}
+
+pub fn parse_grace_mode(mode: &str) -> Option<schema::GraceMode> {
+    match mode {
+        "challenge" => Some(schema::GraceMode::Challenge),
+        "reject" => Some(schema::GraceMode::Reject),
+        _ => None
+    }
+}
+
+/// Replaces a WHOIS address with a new one forwarding to the same place, with the same policy,
+/// allow and block lists and domain contact roles. The old address is still accepted in the given
+/// degraded way until the grace period is up, after which the janitor revokes it. Gives `None` if
+/// there's no such address, or it's already been rotated.
+pub fn rotate(
+    address: &str, mode: schema::GraceMode, grace: chrono::Duration, actor: &str, conn: &crate::DbConn,
+) -> QueryResult<Option<models::RegisteredAddress>> {
+    conn.transaction(|| {
+        let old = match lookup(address, conn)? {
+            Some(a) if a.grace_until.is_none() => a,
+            _ => return Ok(None)
+        };
+        let new = loop {
+            if let Some(a) = create(&random_address(), &old.forward_email, old.policy, actor, conn)? {
+                break a;
+            }
+        };
+
+        let rules = schema::sender_rule::table
+            .filter(schema::sender_rule::registered_address.eq(&old.id))
+            .select((schema::sender_rule::id, schema::sender_rule::sender, schema::sender_rule::allow))
+            .load::<models::SenderRule>(conn)?;
+        for rule in &rules {
+            diesel::insert_into(schema::sender_rule::table)
+                .values(&models::NewSenderRule {
+                    id: &uuid::Uuid::new_v4(),
+                    registered_address: &new.id,
+                    sender: &rule.sender,
+                    allow: rule.allow,
+                })
+                .execute(conn)?;
+        }
+
+        crate::domain::move_contacts(&old.id, &new, conn)?;
+
+        // Addresses retired earlier in the chain point straight at the newest one
+        diesel::update(schema::registered_addresses::table.filter(schema::registered_addresses::rotated_to.eq(&old.id)))
+            .set(schema::registered_addresses::rotated_to.eq(&new.id))
+            .execute(conn)?;
+        let retired = diesel::update(schema::registered_addresses::table.find(&old.id))
+            .set((
+                schema::registered_addresses::rotated_to.eq(&new.id),
+                schema::registered_addresses::grace_mode.eq(mode),
+                schema::registered_addresses::grace_until.eq(chrono::Utc::now() + grace),
+            ))
+            .get_result::<models::RegisteredAddress>(conn)?;
+        audit(actor, "rotate", &retired.id, Some(&retired), conn)?;
+
+        Ok(Some(new))
+    })
+}
+
+/// Starts a WHOIS address's grace period without a replacement, as when the contact it was
+/// published for changes. It's accepted in the given degraded way until the grace period is up,
+/// after which the janitor revokes it and it's refused as retired. Gives `None` if there's no
+/// such address, or it's already been rotated.
+pub fn retire(
+    address: &str, mode: schema::GraceMode, grace: chrono::Duration, actor: &str, conn: &crate::DbConn,
+) -> QueryResult<Option<models::RegisteredAddress>> {
+    conn.transaction(|| {
+        let old = match lookup(address, conn)? {
+            Some(a) if a.grace_until.is_none() => a,
+            _ => return Ok(None)
+        };
+        let retired = diesel::update(schema::registered_addresses::table.find(&old.id))
+            .set((
+                schema::registered_addresses::grace_mode.eq(mode),
+                schema::registered_addresses::grace_until.eq(chrono::Utc::now() + grace),
+            ))
+            .get_result::<models::RegisteredAddress>(conn)?;
+        audit(actor, "retire", &retired.id, Some(&retired), conn)?;
+        Ok(Some(retired))
+    })
+}
+
+/// Revokes rotated addresses whose grace periods are up, returning how many there were
+pub fn revoke_retired(conn: &crate::DbConn) -> QueryResult<usize> {
+    let retired = schema::registered_addresses::table
+        .filter(schema::registered_addresses::grace_until.lt(chrono::Utc::now()))
+        .filter(schema::registered_addresses::revoked_at.is_null())
+        .select(schema::registered_addresses::id)
+        .load::<String>(conn)?;
+    for address in &retired {
+        revoke(address, "janitor", conn)?;
+    }
+    Ok(retired.len())
+}
+
+/// Normalises an allow or block list entry; either a full address, or a whole domain written as
+/// `@example.com` (or just `example.com`)
+pub fn normalise_sender_rule(sender: &str) -> Option<String> {
+    let sender = sender.trim().to_lowercase();
+    let sender = if sender.contains('@') {
+        sender
+    } else {
+        format!("@{}", sender)
+    };
+    if sender.contains(char::is_whitespace) || sender.matches('@').count() != 1 {
+        return None;
+    }
+    match sender.rsplitn(2, '@').next() {
+        Some(domain) if domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.') => Some(sender),
+        _ => None
+    }
+}
+
+/// Finds whether any of the senders of a message are on an address's allow or block list. An
+/// entry for a full address wins over one for a whole domain, and blocks win over allows. Anyone
+/// can put an address in the From field, so an allow entry only counts when the message
+/// authenticates as coming from its domain.
+fn sender_verdict(rules: &[models::SenderRule], senders: &[String], auth: &crate::auth::AuthResults) -> Option<bool> {
+    let senders = senders.iter().map(|s| s.to_lowercase()).collect::<Vec<_>>();
+    let domains = senders.iter()
+        .filter_map(|s| s.rsplitn(2, '@').next().map(|d| format!("@{}", d)))
+        .collect::<Vec<_>>();
+
+    for candidates in &[&senders, &domains] {
+        let matched = rules.iter().filter(|r| candidates.contains(&r.sender)).collect::<Vec<_>>();
+        if matched.iter().any(|r| !r.allow) {
+            return Some(false);
+        }
+        if matched.iter().any(|r| r.sender.rsplitn(2, '@').next().map_or(false, |d| auth.authenticates(d))) {
+            return Some(true);
+        }
+    }
+    None
+}
+
+/// Decides what to do with a message to a WHOIS address, based on its policy and allow and block
+/// lists
+pub fn disposition(
+    address: &models::RegisteredAddress, senders: &[String], auth: &crate::auth::AuthResults, conn: &crate::DbConn,
+) -> QueryResult<Disposition> {
+    if refusal(address).is_some() {
+        return Ok(Disposition::Drop);
+    }
+    // A rotated address only ever gets mail through by way of the sender proving they're human
+    if address.grace_mode.is_some() {
+        return Ok(Disposition::Challenge);
+    }
+    let rules = schema::sender_rule::table
+        .filter(schema::sender_rule::registered_address.eq(&address.id))
+        .select((schema::sender_rule::id, schema::sender_rule::sender, schema::sender_rule::allow))
+        .load::<models::SenderRule>(conn)?;
+    let disposition = match sender_verdict(&rules, senders, auth) {
+        Some(true) => Disposition::Forward,
+        Some(false) => Disposition::Drop,
+        None => match address.policy {
+            schema::AliasPolicy::Challenge => Disposition::Challenge,
+            schema::AliasPolicy::ForwardDirectly => Disposition::Forward,
+            schema::AliasPolicy::ForwardIfAuthenticated => match auth.dmarc {
+                crate::auth::DmarcResult::Pass => Disposition::Forward,
+                _ => Disposition::Challenge
+            },
+            schema::AliasPolicy::HoldForReview => Disposition::Hold,
+            schema::AliasPolicy::Reject => Disposition::Drop,
+        }
+    };
+    // Nothing is forwarded to an address its owner hasn't confirmed, in case it's mistyped
+    if disposition == Disposition::Forward && !crate::verification::is_verified(&address.forward_email, conn)? {
+        return Ok(Disposition::Hold);
+    }
+    Ok(disposition)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn assignable_addresses() {
+        assert!(super::is_assignable("registrant-1@whois.as207960.net"));
+        assert!(super::is_assignable("Registrant@WHOIS.as207960.net"));
+        assert!(super::is_assignable(&super::random_address()));
+        assert!(!super::is_assignable("postmaster@whois.as207960.net"));
+        assert!(!super::is_assignable("Abuse@whois.as207960.net"));
+        assert!(!super::is_assignable("someone@example.com"));
+        assert!(!super::is_assignable("someone@sub.whois.as207960.net"));
+        assert!(!super::is_assignable("someone@as207960.net"));
+        assert!(!super::is_assignable("whois.as207960.net"));
+    }
+
+    fn rule(sender: &str, allow: bool) -> crate::models::SenderRule {
+        crate::models::SenderRule {
+            id: uuid::Uuid::new_v4(),
+            sender: sender.to_string(),
+            allow,
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn reassigning_gives_a_new_address() {
+        use diesel::prelude::*;
+        use crate::schema;
+        let conn = crate::testing::connection();
+        let grace = chrono::Duration::days(30);
+        let old = super::create(&super::random_address(), "old@example.com", schema::AliasPolicy::ForwardDirectly, "test", &conn)
+            .unwrap().unwrap();
+
+        let same = super::reassign(&old.id, "Old@Example.com", None, grace, "test", &conn).unwrap().unwrap();
+        assert_eq!(same.id, old.id);
+        assert_eq!(same.forward_email, "Old@Example.com");
+
+        let new = super::reassign(&old.id, "new@example.com", None, grace, "test", &conn).unwrap().unwrap();
+        assert_ne!(new.id, old.id);
+        assert_eq!(new.forward_email, "new@example.com");
+        assert_eq!(new.policy, schema::AliasPolicy::ForwardDirectly);
+        let old = super::lookup(&old.id, &conn).unwrap().unwrap();
+        assert_eq!(old.forward_email, "Old@Example.com");
+        assert!(old.grace_until.is_some());
+        assert_eq!(old.rotated_to, None);
+
+        // A retired address can't be handed on again
+        assert!(super::reassign(&old.id, "other@example.com", None, grace, "test", &conn).unwrap().is_none());
+        let registered = schema::registered_addresses::table
+            .filter(schema::registered_addresses::forward_email.eq("other@example.com"))
+            .count()
+            .get_result::<i64>(&conn)
+            .unwrap();
+        assert_eq!(registered, 0);
+    }
+
+    fn auth(domain: &str, dmarc: crate::auth::DmarcResult) -> crate::auth::AuthResults {
+        crate::auth::AuthResults {
+            spf: crate::auth::SpfResult::None,
+            spf_domain: domain.to_string(),
+            dkim: crate::auth::DkimResult::None,
+            dkim_domains: vec![],
+            dmarc,
+            dmarc_domain: Some(domain.to_string()),
+        }
+    }
+
+    #[test]
+    fn sender_verdicts() {
+        use crate::auth::DmarcResult;
+        let senders = vec!["Friend@Example.com".to_string()];
+        let passed = auth("example.com", DmarcResult::Pass);
+        let failed = auth("example.com", DmarcResult::Fail);
+
+        assert_eq!(super::sender_verdict(&[], &senders, &passed), None);
+        assert_eq!(super::sender_verdict(&[rule("friend@example.com", true)], &senders, &passed), Some(true));
+        assert_eq!(super::sender_verdict(&[rule("@example.com", true)], &senders, &passed), Some(true));
+        assert_eq!(super::sender_verdict(&[rule("@example.org", true)], &senders, &passed), None);
+
+        // Forged allowed senders fall through to the address's policy
+        assert_eq!(super::sender_verdict(&[rule("friend@example.com", true)], &senders, &failed), None);
+        assert_eq!(super::sender_verdict(&[rule("@example.com", true)], &senders, &failed), None);
+        assert_eq!(
+            super::sender_verdict(&[rule("friend@example.com", true)], &senders, &auth("example.org", DmarcResult::Pass)),
+            None
+        );
+
+        // Blocks don't need authenticating, and win over allows
+        assert_eq!(super::sender_verdict(&[rule("@example.com", false)], &senders, &failed), Some(false));
+        assert_eq!(
+            super::sender_verdict(&[rule("friend@example.com", true), rule("friend@example.com", false)], &senders, &passed),
+            Some(false)
+        );
+        assert_eq!(
+            super::sender_verdict(&[rule("friend@example.com", false), rule("@example.com", true)], &senders, &passed),
+            Some(false)
+        );
+        assert_eq!(
+            super::sender_verdict(&[rule("friend@example.com", true), rule("@example.com", false)], &senders, &failed),
+            Some(false)
+        );
+    }
+}
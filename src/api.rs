@@ -0,0 +1,502 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Most operations a single bulk request may carry
+const MAX_BULK_OPERATIONS: usize = 1000;
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+#[derive(Serialize)]
+struct Alias {
+    address: String,
+    forward_email: String,
+    policy: &'static str,
+    /// Set once the address has been rotated, for where mail should go instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotated_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_mode: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_until: Option<String>,
+}
+
+impl From<models::RegisteredAddress> for Alias {
+    fn from(address: models::RegisteredAddress) -> Self {
+        Self {
+            policy: crate::alias::policy_name(address.policy),
+            address: address.id,
+            forward_email: address.forward_email,
+            rotated_to: address.rotated_to,
+            grace_mode: address.grace_mode.map(crate::alias::grace_mode_name),
+            grace_until: address.grace_until.map(|g| g.to_rfc3339()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateAlias {
+    /// Left out to have an unguessable address generated
+    address: Option<String>,
+    forward_email: String,
+    policy: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateAlias {
+    forward_email: Option<String>,
+    policy: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RotateAlias {
+    /// How the old address is treated during its grace period, challenging senders by default
+    grace_mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BulkOperation {
+    Create(CreateAlias),
+    Update {
+        address: String,
+        #[serde(flatten)]
+        changes: UpdateAlias,
+    },
+    Delete {
+        address: String,
+    },
+}
+
+#[derive(Serialize)]
+struct DomainContact {
+    role: &'static str,
+    #[serde(flatten)]
+    alias: Alias,
+}
+
+#[derive(Serialize)]
+struct Domain {
+    domain: String,
+    contacts: Vec<DomainContact>,
+}
+
+#[derive(Serialize)]
+struct DomainContactHistory {
+    role: &'static str,
+    address: String,
+    forward_email: String,
+    started_at: String,
+    ended_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SetDomainContacts {
+    /// The real address of each contact role's holder, by role
+    contacts: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    operations: Vec<BulkOperation>,
+}
+
+struct ApiResponse {
+    status: hyper::StatusCode,
+    body: Option<String>,
+}
+
+impl ApiResponse {
+    fn json<T: serde::Serialize>(status: hyper::StatusCode, body: &T) -> Self {
+        Self {
+            status,
+            body: Some(serde_json::to_string(body).unwrap()),
+        }
+    }
+}
+
+enum ApiError {
+    Db(diesel::result::Error),
+    Client(hyper::StatusCode, String),
+}
+
+impl From<diesel::result::Error> for ApiError {
+    fn from(e: diesel::result::Error) -> Self {
+        ApiError::Db(e)
+    }
+}
+
+fn bad_request(message: &str) -> ApiError {
+    ApiError::Client(hyper::StatusCode::BAD_REQUEST, message.to_string())
+}
+
+fn key_in_use() -> ApiError {
+    ApiError::Client(
+        hyper::StatusCode::CONFLICT,
+        "Another request with this idempotency key was made at the same time".to_string()
+    )
+}
+
+fn not_found(address: &str) -> ApiError {
+    ApiError::Client(hyper::StatusCode::NOT_FOUND, format!("{} isn't registered", address))
+}
+
+fn parse_body<'a, T: serde::Deserialize<'a>>(body: &'a [u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| bad_request(&format!("Invalid request: {}", e)))
+}
+
+fn parse_policy(policy: Option<&str>) -> Result<Option<schema::AliasPolicy>, ApiError> {
+    match policy {
+        Some(p) => match crate::alias::parse_policy(p) {
+            Some(p) => Ok(Some(p)),
+            None => Err(bad_request(&format!("Unknown policy {}", p)))
+        },
+        None => Ok(None)
+    }
+}
+
+fn parse_forward_email(forward_email: &str) -> Result<String, ApiError> {
+    crate::alias::normalise_address(forward_email)
+        .ok_or_else(|| bad_request(&format!("Invalid forwarding address {}", forward_email)))
+}
+
+/// Looks up an API key from a bearer token, as long as it hasn't been revoked
+fn authenticate(authorization: Option<&str>, conn: &crate::DbConn) -> QueryResult<Option<models::ApiKey>> {
+    let token = match authorization.and_then(|a| a.strip_prefix("Bearer ")) {
+        Some(t) => t.trim(),
+        None => return Ok(None)
+    };
+    schema::api_key::table
+        .filter(schema::api_key::key_hash.eq(crate::crypto::token_hash(token)))
+        .filter(schema::api_key::revoked_at.is_null())
+        .select((schema::api_key::id, schema::api_key::name))
+        .get_result::<models::ApiKey>(conn)
+        .optional()
+}
+
+/// Makes a new API key, returning the key itself; only its hash is stored
+pub fn create_key(name: &str, conn: &crate::DbConn) -> QueryResult<String> {
+    let key = crate::crypto::random_token();
+    diesel::insert_into(schema::api_key::table)
+        .values(&models::NewApiKey {
+            id: &uuid::Uuid::new_v4(),
+            name,
+            key_hash: &crate::crypto::token_hash(&key),
+        })
+        .execute(conn)?;
+    Ok(key)
+}
+
+fn create_alias(request: CreateAlias, actor: &str, conn: &crate::DbConn) -> Result<Alias, ApiError> {
+    let forward_email = parse_forward_email(&request.forward_email)?;
+    let policy = parse_policy(request.policy.as_deref())?.unwrap_or(schema::AliasPolicy::Challenge);
+
+    match request.address {
+        Some(address) => {
+            let address = crate::alias::normalise_address(&address)
+                .ok_or_else(|| bad_request(&format!("Invalid address {}", address)))?;
+            if !crate::alias::is_assignable(&address) {
+                return Err(bad_request(&format!(
+                    "{} can't be registered, addresses must be under {}", address, crate::alias::ALIAS_DOMAIN
+                )));
+            }
+            match crate::alias::create(&address, &forward_email, policy, actor, conn)? {
+                Some(a) => Ok(a.into()),
+                None => Err(ApiError::Client(hyper::StatusCode::CONFLICT, format!("{} is already registered", address)))
+            }
+        }
+        // A clash between random addresses is vanishingly unlikely, but costs nothing to retry
+        None => loop {
+            if let Some(a) = crate::alias::create(&crate::alias::random_address(), &forward_email, policy, actor, conn)? {
+                return Ok(a.into());
+            }
+        }
+    }
+}
+
+/// Changing where an alias forwards to gives a new alias, which is what's returned
+fn update_alias(address: &str, request: UpdateAlias, actor: &str, config: &crate::Config, conn: &crate::DbConn) -> Result<Alias, ApiError> {
+    let forward_email = match &request.forward_email {
+        Some(f) => Some(parse_forward_email(f)?),
+        None => None
+    };
+    let policy = parse_policy(request.policy.as_deref())?;
+
+    let updated = match forward_email {
+        Some(f) => crate::alias::reassign(address, &f, policy, config.alias_grace_period, actor, conn)?,
+        None => crate::alias::update(address, policy, actor, conn)?
+    };
+    match updated {
+        Some(a) => Ok(a.into()),
+        None => match crate::alias::lookup(address, conn)? {
+            Some(_) => Err(ApiError::Client(hyper::StatusCode::CONFLICT, format!("{} has been retired", address))),
+            None => Err(not_found(address))
+        }
+    }
+}
+
+fn delete_alias(address: &str, actor: &str, conn: &crate::DbConn) -> Result<(), ApiError> {
+    if crate::alias::revoke(address, actor, conn)? {
+        Ok(())
+    } else {
+        Err(not_found(address))
+    }
+}
+
+fn rotate_alias(address: &str, request: RotateAlias, actor: &str, config: &crate::Config, conn: &crate::DbConn) -> Result<Alias, ApiError> {
+    let mode = match request.grace_mode {
+        Some(m) => crate::alias::parse_grace_mode(&m).ok_or_else(|| bad_request(&format!("Unknown grace mode {}", m)))?,
+        None => schema::GraceMode::Challenge
+    };
+
+    match crate::alias::rotate(address, mode, config.alias_grace_period, actor, conn)? {
+        Some(a) => Ok(a.into()),
+        None => match crate::alias::lookup(address, conn)? {
+            Some(_) => Err(ApiError::Client(hyper::StatusCode::CONFLICT, format!("{} has already been rotated", address))),
+            None => Err(not_found(address))
+        }
+    }
+}
+
+fn domain_response(domain: String, contacts: Vec<(schema::ContactRole, models::RegisteredAddress)>) -> ApiResponse {
+    ApiResponse::json(hyper::StatusCode::OK, &Domain {
+        domain,
+        contacts: contacts.into_iter().map(|(role, address)| DomainContact {
+            role: crate::domain::role_name(role),
+            alias: address.into(),
+        }).collect(),
+    })
+}
+
+fn parse_domain(domain: &str) -> Result<String, ApiError> {
+    crate::domain::normalise_domain(domain).ok_or_else(|| bad_request(&format!("Invalid domain {}", domain)))
+}
+
+fn set_domain_contacts(domain: &str, request: SetDomainContacts, actor: &str, config: &crate::Config, conn: &crate::DbConn) -> Result<ApiResponse, ApiError> {
+    let domain = parse_domain(domain)?;
+    let contacts = request.contacts.iter().map(|(role, forward_email)| {
+        let role = crate::domain::parse_role(role).ok_or_else(|| bad_request(&format!("Unknown contact role {}", role)))?;
+        Ok((role, parse_forward_email(forward_email)?))
+    }).collect::<Result<Vec<_>, ApiError>>()?;
+
+    let contacts = crate::domain::set_contacts(&domain, &contacts, config.alias_grace_period, actor, conn)?;
+    Ok(domain_response(domain, contacts))
+}
+
+/// Runs a batch of operations all or nothing, giving the results of each in order
+fn bulk(request: BulkRequest, actor: &str, config: &crate::Config, conn: &crate::DbConn) -> Result<ApiResponse, ApiError> {
+    if request.operations.len() > MAX_BULK_OPERATIONS {
+        return Err(bad_request(&format!("At most {} operations can be sent at once", MAX_BULK_OPERATIONS)));
+    }
+
+    let mut results = vec![];
+    for (i, operation) in request.operations.into_iter().enumerate() {
+        let result = match operation {
+            BulkOperation::Create(c) => create_alias(c, actor, conn).map(Some),
+            BulkOperation::Update { address, changes } => update_alias(&address, changes, actor, config, conn).map(Some),
+            BulkOperation::Delete { address } => delete_alias(&address, actor, conn).map(|_| None),
+        };
+        match result {
+            Ok(a) => results.push(a),
+            Err(ApiError::Client(status, message)) => {
+                return Err(ApiError::Client(status, format!("Operation {}: {}", i, message)));
+            }
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(ApiResponse::json(hyper::StatusCode::OK, &serde_json::json!({ "results": results })))
+}
+
+fn route(
+    method: &hyper::Method, path: &[&str], body: &[u8], api_key: &models::ApiKey, config: &crate::Config,
+    conn: &crate::DbConn,
+) -> Result<ApiResponse, ApiError> {
+    let actor = format!("api:{}", api_key.name);
+    match (method, path) {
+        (&hyper::Method::POST, ["aliases"]) => {
+            let alias = create_alias(parse_body(body)?, &actor, conn)?;
+            Ok(ApiResponse::json(hyper::StatusCode::CREATED, &alias))
+        }
+        (&hyper::Method::POST, ["aliases", "bulk"]) => bulk(parse_body(body)?, &actor, config, conn),
+        (&hyper::Method::GET, ["aliases", address]) => match crate::alias::lookup(address, conn)? {
+            Some(a) => Ok(ApiResponse::json(hyper::StatusCode::OK, &Alias::from(a))),
+            None => Err(not_found(address))
+        },
+        (&hyper::Method::PATCH, ["aliases", address]) => {
+            let alias = update_alias(address, parse_body(body)?, &actor, config, conn)?;
+            Ok(ApiResponse::json(hyper::StatusCode::OK, &alias))
+        }
+        (&hyper::Method::DELETE, ["aliases", address]) => {
+            delete_alias(address, &actor, conn)?;
+            Ok(ApiResponse {
+                status: hyper::StatusCode::NO_CONTENT,
+                body: None,
+            })
+        }
+        (&hyper::Method::POST, ["aliases", address, "rotate"]) => {
+            let request = if body.is_empty() {
+                RotateAlias::default()
+            } else {
+                parse_body(body)?
+            };
+            let alias = rotate_alias(address, request, &actor, config, conn)?;
+            Ok(ApiResponse::json(hyper::StatusCode::CREATED, &alias))
+        }
+        (&hyper::Method::POST, ["aliases", address, "verify"]) => {
+            let alias = crate::alias::lookup(address, conn)?.ok_or_else(|| not_found(address))?;
+            let verified = crate::verification::request_again(&alias.forward_email, conn)?;
+            Ok(ApiResponse::json(
+                if verified { hyper::StatusCode::OK } else { hyper::StatusCode::ACCEPTED },
+                &serde_json::json!({ "forward_email": alias.forward_email, "verified": verified }),
+            ))
+        }
+        (&hyper::Method::GET, ["domains", domain]) => {
+            let domain = parse_domain(domain)?;
+            let contacts = crate::domain::contacts(&domain, conn)?;
+            Ok(domain_response(domain, contacts))
+        }
+        (&hyper::Method::GET, ["domains", domain, "history"]) => {
+            let history = crate::domain::history(&parse_domain(domain)?, conn)?;
+            Ok(ApiResponse::json(hyper::StatusCode::OK, &serde_json::json!({
+                "history": history.into_iter().map(|h| DomainContactHistory {
+                    role: crate::domain::role_name(h.role),
+                    address: h.registered_address,
+                    forward_email: h.forward_email,
+                    started_at: h.started_at.to_rfc3339(),
+                    ended_at: h.ended_at.map(|e| e.to_rfc3339()),
+                }).collect::<Vec<_>>()
+            })))
+        }
+        (&hyper::Method::PUT, ["domains", domain]) => set_domain_contacts(domain, parse_body(body)?, &actor, config, conn),
+        (&hyper::Method::DELETE, ["domains", domain]) => {
+            crate::domain::set_contacts(&parse_domain(domain)?, &[], config.alias_grace_period, &actor, conn)?;
+            Ok(ApiResponse {
+                status: hyper::StatusCode::NO_CONTENT,
+                body: None,
+            })
+        }
+        _ => Err(ApiError::Client(hyper::StatusCode::NOT_FOUND, "No such endpoint".to_string()))
+    }
+}
+
+/// Runs a request in a transaction. With an idempotency key, a successful response is stored
+/// alongside its changes, and replayed instead of running the same request again.
+fn handle(
+    method: &hyper::Method, path: &[&str], body: &[u8], api_key: &models::ApiKey, idempotency_key: Option<&str>,
+    config: &crate::Config, conn: &crate::DbConn,
+) -> Result<ApiResponse, ApiError> {
+    let mut request_hash = openssl::sha::Sha256::new();
+    request_hash.update(method.as_str().as_bytes());
+    request_hash.update(b" ");
+    request_hash.update(path.join("/").as_bytes());
+    request_hash.update(b"\n");
+    request_hash.update(body);
+    let request_hash = request_hash.finish();
+
+    conn.transaction(|| {
+        if let Some(key) = idempotency_key {
+            let stored = schema::api_idempotency_key::table
+                .find((&api_key.id, key))
+                .select((
+                    schema::api_idempotency_key::request_hash, schema::api_idempotency_key::response_status,
+                    schema::api_idempotency_key::response_body,
+                ))
+                .get_result::<models::ApiIdempotencyKey>(conn)
+                .optional()?;
+            if let Some(stored) = stored {
+                if !openssl::memcmp::eq(&stored.request_hash, &request_hash) {
+                    return Err(ApiError::Client(
+                        hyper::StatusCode::UNPROCESSABLE_ENTITY,
+                        "This idempotency key was already used for a different request".to_string()
+                    ));
+                }
+                let status = stored.response_status.ok_or_else(key_in_use)?;
+                return Ok(ApiResponse {
+                    status: hyper::StatusCode::from_u16(status as u16).unwrap_or(hyper::StatusCode::OK),
+                    body: stored.response_body,
+                });
+            }
+
+            // The key's claimed before the request is run, so a concurrent request with the same
+            // key waits on this one and then gets a conflict, rather than doing it all again
+            match diesel::insert_into(schema::api_idempotency_key::table)
+                .values(&models::NewApiIdempotencyKey {
+                    api_key: &api_key.id,
+                    idempotency_key: key,
+                    request_hash: &request_hash,
+                })
+                .execute(conn) {
+                Ok(_) => {},
+                Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
+                    return Err(key_in_use());
+                },
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        let response = route(method, path, body, api_key, config, conn)?;
+
+        if let Some(key) = idempotency_key {
+            diesel::update(schema::api_idempotency_key::table.find((&api_key.id, key)))
+                .set((
+                    schema::api_idempotency_key::response_status.eq(response.status.as_u16() as i32),
+                    schema::api_idempotency_key::response_body.eq(response.body.as_deref()),
+                ))
+                .execute(conn)?;
+        }
+        Ok(response)
+    })
+}
+
+fn json_response(status: hyper::StatusCode, body: Option<String>) -> hyper::Response<hyper::Body> {
+    let builder = hyper::Response::builder().status(status);
+    match body {
+        Some(b) => builder
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(b)),
+        None => builder.body(hyper::Body::empty())
+    }.unwrap()
+}
+
+fn error_response(status: hyper::StatusCode, message: &str) -> hyper::Response<hyper::Body> {
+    json_response(status, Some(serde_json::json!({ "error": message }).to_string()))
+}
+
+/// Serves the JSON API registrar platforms use to manage WHOIS addresses, authenticated by an API
+/// key sent as a bearer token
+pub async fn handle_request(path: &[&str], req: hyper::Request<hyper::Body>, config: &crate::Config) -> hyper::Response<hyper::Body> {
+    let method = req.method().to_owned();
+    let header = |name| req.headers().get(name).and_then(|h: &hyper::header::HeaderValue| h.to_str().ok()).map(|h| h.to_string());
+    let authorization = header(hyper::header::AUTHORIZATION.as_str());
+    let idempotency_key = header(IDEMPOTENCY_KEY_HEADER);
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(_) => return error_response(hyper::StatusCode::BAD_REQUEST, "Unable to read request")
+    };
+
+    tokio::task::block_in_place(|| {
+        let conn = match config.connection.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting DB connection: {}", e);
+                return error_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+            }
+        };
+
+        let api_key = match authenticate(authorization.as_deref(), &conn) {
+            Ok(Some(k)) => k,
+            Ok(None) => return error_response(hyper::StatusCode::UNAUTHORIZED, "A valid API key is required"),
+            Err(e) => {
+                error!("Error loading API key: {}", e);
+                return error_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+            }
+        };
+
+        match handle(&method, path, &body, &api_key, idempotency_key.as_deref(), config, &conn) {
+            Ok(r) => json_response(r.status, r.body),
+            Err(ApiError::Client(status, message)) => error_response(status, &message),
+            Err(ApiError::Db(e)) => {
+                error!("Error serving API request: {}", e);
+                error_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+            }
+        }
+    })
+}
@@ -0,0 +1,184 @@
+use crate::proto::{SMTPResponse, SMTPCommand};
+use crate::schema::DeliveryStage;
+
+/// Follows a conversation with an MX, keeping track of how far it got along with a transcript.
+/// Message content is left out of the transcript, and the local parts of any email addresses in
+/// it (the envelope addresses in MAIL and RCPT commands, and wherever the server echoes them) are
+/// blanked out, keeping only their domains. Commands and replies are otherwise kept verbatim.
+pub struct AttemptLog {
+    stage: DeliveryStage,
+    last_response: Option<SMTPResponse>,
+    rcpt_responses: Vec<SMTPResponse>,
+    tls: Option<String>,
+    transcript: Vec<String>,
+    started_at: chrono::DateTime<chrono::Utc>,
+    start: std::time::Instant,
+}
+
+/// What happened to one recipient on one attempt at delivering to an MX
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub mx_host: String,
+    pub mx_ip: std::net::IpAddr,
+    pub tls: Option<String>,
+    pub failed_stage: Option<DeliveryStage>,
+    pub response: Option<SMTPResponse>,
+    pub error: Option<String>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub duration: std::time::Duration,
+    pub transcript: Option<String>,
+}
+
+impl Attempt {
+    /// Whether the MX refused the recipient's address itself at RCPT, with a 5.1.x enhanced status
+    /// code, rather than turning the message away for some other reason
+    pub fn is_address_rejection(&self) -> bool {
+        match (&self.failed_stage, &self.response) {
+            (Some(DeliveryStage::Rcpt), Some(r)) if r.code >= 500 && r.code < 600 => r.lines.first()
+                .and_then(|l| l.split_whitespace().next())
+                .map_or(false, |c| c.starts_with("5.1.")),
+            _ => false
+        }
+    }
+}
+
+impl AttemptLog {
+    pub fn new() -> Self {
+        Self {
+            stage: DeliveryStage::Connect,
+            last_response: None,
+            rcpt_responses: vec![],
+            tls: None,
+            transcript: vec![],
+            started_at: chrono::Utc::now(),
+            start: std::time::Instant::now(),
+        }
+    }
+
+    pub fn enter(&mut self, stage: DeliveryStage) {
+        self.stage = stage;
+    }
+
+    pub fn set_tls(&mut self, tls: &str) {
+        self.tls = Some(tls.to_string());
+    }
+
+    pub fn command(&mut self, command: &SMTPCommand) {
+        match command.verb.as_str() {
+            "EHLO" | "HELO" => self.stage = DeliveryStage::Ehlo,
+            "STARTTLS" => self.stage = DeliveryStage::Starttls,
+            "MAIL" => self.stage = DeliveryStage::Mail,
+            "RCPT" => self.stage = DeliveryStage::Rcpt,
+            "DATA" | "BDAT" => self.stage = DeliveryStage::Data,
+            _ => {}
+        }
+        self.transcript.push(format!("C: {}", redact(command.to_string().trim_end())));
+    }
+
+    pub fn response(&mut self, response: &SMTPResponse) {
+        for line in response.to_string().lines() {
+            self.transcript.push(format!("S: {}", redact(line)));
+        }
+        if self.stage == DeliveryStage::Rcpt {
+            self.rcpt_responses.push(response.clone());
+        }
+        self.last_response = Some(response.clone());
+    }
+
+    pub fn note<N: AsRef<str>>(&mut self, note: N) {
+        self.transcript.push(format!("-- {}", redact(note.as_ref())));
+    }
+
+    /// Works out what happened to each recipient, given the outcome of the attempt for them in
+    /// the order they were sent in RCPT commands. Transcripts are only kept where something failed.
+    pub fn finish(self, mx_host: &str, mx_ip: std::net::IpAddr, errors: &[Option<String>]) -> Vec<Attempt> {
+        let duration = self.start.elapsed();
+        let transcript = if errors.iter().any(|e| e.is_some()) {
+            Some(self.transcript.join("\n"))
+        } else {
+            None
+        };
+
+        errors.iter().enumerate().map(|(i, error)| {
+            let rcpt_response = self.rcpt_responses.get(i).filter(|r| r.code >= 400);
+            let (failed_stage, response) = match (error, rcpt_response) {
+                (None, _) => (None, self.last_response.clone()),
+                (Some(_), Some(r)) => (Some(DeliveryStage::Rcpt), Some(r.clone())),
+                (Some(_), None) => (Some(self.stage), self.last_response.clone()),
+            };
+            Attempt {
+                mx_host: mx_host.to_string(),
+                mx_ip,
+                tls: self.tls.clone(),
+                failed_stage,
+                response,
+                error: error.clone(),
+                started_at: self.started_at,
+                duration,
+                transcript: transcript.clone(),
+            }
+        }).collect()
+    }
+}
+
+/// Characters that can't be part of the local part of an address as it appears in SMTP
+const LOCAL_PART_DELIMITERS: &str = "<>()[]:;,=\"'";
+
+/// Blanks out the local part of every email address in a line of transcript
+fn redact(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut local_part_start = 0;
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() || LOCAL_PART_DELIMITERS.contains(c) {
+            out.push_str(&line[local_part_start..i]);
+            out.push(c);
+            local_part_start = i + c.len_utf8();
+        } else if c == '@' {
+            if i > local_part_start {
+                out.push('*');
+            }
+            out.push(c);
+            local_part_start = i + c.len_utf8();
+        }
+    }
+    out.push_str(&line[local_part_start..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_local_parts() {
+        assert_eq!(redact("MAIL FROM:<alice@example.com> SIZE=1024"), "MAIL FROM:<*@example.com> SIZE=1024");
+        assert_eq!(redact("RCPT TO:<bob.smith+tag@example.org>"), "RCPT TO:<*@example.org>");
+        assert_eq!(redact("MAIL FROM:<>"), "MAIL FROM:<>");
+        assert_eq!(redact("250 2.1.5 Recipient <bob@example.org> ok"), "250 2.1.5 Recipient <*@example.org> ok");
+        assert_eq!(redact("550 5.1.1 bob@example.org: user unknown"), "550 5.1.1 *@example.org: user unknown");
+        assert_eq!(redact("220 mx.example.com ESMTP ready"), "220 mx.example.com ESMTP ready");
+    }
+
+    fn failed_at(stage: DeliveryStage, code: u16, msg: &str) -> Attempt {
+        Attempt {
+            mx_host: "mx.example.com".to_string(),
+            mx_ip: std::net::Ipv4Addr::LOCALHOST.into(),
+            tls: None,
+            failed_stage: Some(stage),
+            response: Some(SMTPResponse::new(code, msg)),
+            error: None,
+            started_at: chrono::Utc::now(),
+            duration: std::time::Duration::from_secs(1),
+            transcript: None,
+        }
+    }
+
+    #[test]
+    fn only_rcpt_address_failures_are_address_rejections() {
+        assert!(failed_at(DeliveryStage::Rcpt, 550, "5.1.1 User unknown").is_address_rejection());
+        assert!(!failed_at(DeliveryStage::Rcpt, 550, "5.7.1 Relaying denied").is_address_rejection());
+        assert!(!failed_at(DeliveryStage::Rcpt, 450, "4.1.1 Try again later").is_address_rejection());
+        assert!(!failed_at(DeliveryStage::Rcpt, 550, "No such user").is_address_rejection());
+        assert!(!failed_at(DeliveryStage::Data, 554, "5.1.1 Message rejected as spam").is_address_rejection());
+    }
+}
@@ -0,0 +1,1315 @@
+use futures::future::{BoxFuture, FutureExt};
+
+/// Most DNS lookups an SPF check may cause, per RFC 7208 § 4.6.4
+const SPF_MAX_LOOKUPS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl SpfResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DkimResult {
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DkimResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DkimResult::None => "none",
+            DkimResult::Pass => "pass",
+            DkimResult::Fail => "fail",
+            DkimResult::TempError => "temperror",
+            DkimResult::PermError => "permerror",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmarcResult {
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DmarcResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DmarcResult::None => "none",
+            DmarcResult::Pass => "pass",
+            DmarcResult::Fail => "fail",
+            DmarcResult::TempError => "temperror",
+            DmarcResult::PermError => "permerror",
+        }
+    }
+}
+
+/// What we could verify about who sent a message
+#[derive(Debug, Clone)]
+pub struct AuthResults {
+    pub spf: SpfResult,
+    /// The domain SPF was checked for, from the reverse path or failing that the HELO identity
+    pub spf_domain: String,
+    pub dkim: DkimResult,
+    /// Domains with a valid DKIM signature on the message
+    pub dkim_domains: Vec<String>,
+    pub dmarc: DmarcResult,
+    /// The author domain from the From field, if there was just the one
+    pub dmarc_domain: Option<String>,
+}
+
+impl AuthResults {
+    /// Checks whether the message can be trusted to come from a domain, because DMARC passed for
+    /// an author aligned with it, or SPF or DKIM passed for a domain aligned with it
+    pub fn authenticates(&self, domain: &str) -> bool {
+        let aligned_with = |d: &str| aligned(domain, d, false);
+        (self.dmarc == DmarcResult::Pass && self.dmarc_domain.as_deref().map_or(false, aligned_with))
+            || (self.spf == SpfResult::Pass && aligned_with(&self.spf_domain))
+            || self.dkim_domains.iter().any(|d| aligned_with(d))
+    }
+}
+
+/// Checks SPF for the envelope, DKIM signatures in the message, and whether either of those line
+/// up with the author's domain under its DMARC policy
+pub async fn check_message(
+    peer_addr: std::net::IpAddr, helo: &str, reverse_path: &str, data: &[u8], config: &crate::Config
+) -> AuthResults {
+    // RFC 7208 § 2.4, with a null reverse path the HELO identity is checked as postmaster
+    let sender = if reverse_path.is_empty() {
+        format!("postmaster@{}", helo)
+    } else {
+        reverse_path.to_string()
+    };
+    let spf_domain = sender.rsplitn(2, '@').next().unwrap().trim_end_matches('.').to_lowercase();
+
+    let dns = Dns::Resolver(&config.resolver);
+    let mut check = SpfCheck {
+        ip: peer_addr,
+        sender: &sender,
+        helo,
+        lookups: 0,
+        dns: &dns,
+    };
+    let spf = check.check_host(spf_domain.clone()).await;
+
+    let (dkim, dkim_domains) = check_dkim(data, &dns).await;
+    let (dmarc, dmarc_domain) = check_dmarc(data, spf, &spf_domain, &dkim_domains, &dns).await;
+
+    AuthResults {
+        spf,
+        spf_domain,
+        dkim,
+        dkim_domains,
+        dmarc,
+        dmarc_domain,
+    }
+}
+
+enum TxtLookup {
+    Records(Vec<String>),
+    NotFound,
+    Error,
+}
+
+/// Where SPF, DKIM, and DMARC checks get their DNS answers from
+enum Dns<'a> {
+    Resolver(&'a trust_dns_resolver::TokioAsyncResolver),
+    /// Fixed answers as (name, record type, data), so the checks can be tested without a network
+    #[cfg(test)]
+    Static(&'a [(&'a str, &'a str, &'a str)]),
+}
+
+impl Dns<'_> {
+    #[cfg(test)]
+    fn find(records: &[(&str, &str, &str)], name: &str, record_type: &str) -> Vec<String> {
+        records.iter()
+            .filter(|r| r.0.eq_ignore_ascii_case(name.trim_end_matches('.')) && r.1 == record_type)
+            .map(|r| r.2.to_string())
+            .collect()
+    }
+
+    async fn txt(&self, name: &str) -> TxtLookup {
+        match self {
+            Dns::Resolver(resolver) => match resolver.txt_lookup(format!("{}.", name.trim_end_matches('.'))).await {
+                Ok(r) => TxtLookup::Records(r.iter().map(|r| {
+                    r.txt_data().iter().map(|d| String::from_utf8_lossy(d).into_owned()).collect::<String>()
+                }).collect()),
+                Err(e) if crate::client::is_no_records(&e) => TxtLookup::NotFound,
+                Err(_) => TxtLookup::Error,
+            },
+            #[cfg(test)]
+            Dns::Static(records) => match Self::find(records, name, "TXT") {
+                r if r.is_empty() => TxtLookup::NotFound,
+                r => TxtLookup::Records(r)
+            }
+        }
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, SpfResult> {
+        match self {
+            Dns::Resolver(resolver) => match resolver.mx_lookup(format!("{}.", name.trim_end_matches('.'))).await {
+                Ok(r) => Ok(r.iter().map(|mx| mx.exchange().to_string()).collect()),
+                Err(e) if crate::client::is_no_records(&e) => Ok(vec![]),
+                Err(_) => Err(SpfResult::TempError)
+            },
+            #[cfg(test)]
+            Dns::Static(records) => Ok(Self::find(records, name, "MX"))
+        }
+    }
+
+    /// Looks up IPv4 and IPv6 addresses, or only IPv4 ones
+    async fn ip(&self, name: &str, v4_only: bool) -> Result<Vec<std::net::IpAddr>, SpfResult> {
+        let name = format!("{}.", name.trim_end_matches('.'));
+        match self {
+            Dns::Resolver(resolver) => {
+                let addrs = if v4_only {
+                    resolver.ipv4_lookup(name).await.map(|r| r.iter().map(|a| std::net::IpAddr::V4(*a)).collect())
+                } else {
+                    resolver.lookup_ip(name).await.map(|r| r.iter().collect())
+                };
+                match addrs {
+                    Ok(a) => Ok(a),
+                    Err(e) if crate::client::is_no_records(&e) => Ok(vec![]),
+                    Err(_) => Err(SpfResult::TempError)
+                }
+            },
+            #[cfg(test)]
+            Dns::Static(records) => {
+                let mut addrs = Self::find(records, &name, "A");
+                if !v4_only {
+                    addrs.extend(Self::find(records, &name, "AAAA"));
+                }
+                Ok(addrs.iter().map(|a| a.parse().unwrap()).collect())
+            }
+        }
+    }
+}
+
+struct SpfCheck<'a> {
+    ip: std::net::IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    lookups: u32,
+    dns: &'a Dns<'a>,
+}
+
+impl SpfCheck<'_> {
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > SPF_MAX_LOOKUPS {
+            Err(SpfResult::PermError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// RFC 7208 § 4
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, SpfResult> {
+        async move {
+            let records = match self.dns.txt(&domain).await {
+                TxtLookup::Records(r) => r,
+                TxtLookup::NotFound => return SpfResult::None,
+                TxtLookup::Error => return SpfResult::TempError,
+            };
+            let records = records.into_iter()
+                .filter(|r| r.eq_ignore_ascii_case("v=spf1") || r.to_ascii_lowercase().starts_with("v=spf1 "))
+                .collect::<Vec<_>>();
+            let record = match &records[..] {
+                [] => return SpfResult::None,
+                [r] => r,
+                _ => return SpfResult::PermError
+            };
+
+            let mut redirect = None;
+            for term in record.split_ascii_whitespace().skip(1) {
+                if let Some(eq) = term.find('=') {
+                    if term[..eq].chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+                        if term[..eq].eq_ignore_ascii_case("redirect") {
+                            redirect = Some(term[eq + 1..].to_string());
+                        }
+                        continue;
+                    }
+                }
+
+                let (qualifier, mechanism) = match term.chars().next() {
+                    Some('+') => (SpfResult::Pass, &term[1..]),
+                    Some('-') => (SpfResult::Fail, &term[1..]),
+                    Some('~') => (SpfResult::SoftFail, &term[1..]),
+                    Some('?') => (SpfResult::Neutral, &term[1..]),
+                    _ => (SpfResult::Pass, term)
+                };
+                match self.check_mechanism(mechanism, &domain).await {
+                    Ok(true) => return qualifier,
+                    Ok(false) => {},
+                    Err(e) => return e
+                }
+            }
+
+            match redirect {
+                Some(target) => {
+                    if let Err(e) = self.count_lookup() {
+                        return e;
+                    }
+                    let target = match self.expand(&target, &domain) {
+                        Some(t) => t,
+                        None => return SpfResult::PermError
+                    };
+                    match self.check_host(target).await {
+                        SpfResult::None => SpfResult::PermError,
+                        r => r
+                    }
+                },
+                None => SpfResult::Neutral
+            }
+        }.boxed()
+    }
+
+    /// Sees if a mechanism matches, RFC 7208 § 5
+    async fn check_mechanism(&mut self, mechanism: &str, domain: &str) -> Result<bool, SpfResult> {
+        let (name, arg) = match mechanism.find(|c| c == ':' || c == '/') {
+            Some(i) => (&mechanism[..i], &mechanism[i..]),
+            None => (mechanism, "")
+        };
+        let name = name.to_ascii_lowercase();
+
+        match name.as_str() {
+            "all" => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = self.target_domain(arg, domain, false)?.0;
+                match self.check_host(target).await {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(SpfResult::TempError),
+                    SpfResult::PermError | SpfResult::None => Err(SpfResult::PermError),
+                }
+            },
+            "a" => {
+                self.count_lookup()?;
+                let (target, v4_len, v6_len) = self.target_domain(arg, domain, true)?;
+                let addrs = self.lookup_addresses(&target).await?;
+                Ok(addrs.iter().any(|a| cidr_match(&self.ip, a, v4_len, v6_len)))
+            },
+            "mx" => {
+                self.count_lookup()?;
+                let (target, v4_len, v6_len) = self.target_domain(arg, domain, true)?;
+                let mxs = self.dns.mx(&target).await?;
+                if mxs.len() > 10 {
+                    return Err(SpfResult::PermError);
+                }
+                for mx in mxs {
+                    let addrs = self.lookup_addresses(&mx).await?;
+                    if addrs.iter().any(|a| cidr_match(&self.ip, a, v4_len, v6_len)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            },
+            "ip4" | "ip6" => {
+                let arg = arg.strip_prefix(':').ok_or(SpfResult::PermError)?;
+                let mut parts = arg.splitn(2, '/');
+                let network = parts.next().unwrap().parse::<std::net::IpAddr>().map_err(|_| SpfResult::PermError)?;
+                let len = parts.next();
+                match (name.as_str(), &network) {
+                    ("ip4", std::net::IpAddr::V4(_)) => {
+                        let len = len.map(|l| cidr_length(l, 32)).transpose()?.unwrap_or(32);
+                        Ok(cidr_match(&self.ip, &network, len, 128))
+                    },
+                    ("ip6", std::net::IpAddr::V6(_)) => {
+                        let len = len.map(|l| cidr_length(l, 128)).transpose()?.unwrap_or(128);
+                        Ok(cidr_match(&self.ip, &network, 32, len))
+                    },
+                    _ => Err(SpfResult::PermError)
+                }
+            },
+            "exists" => {
+                self.count_lookup()?;
+                let target = self.target_domain(arg, domain, false)?.0;
+                Ok(!self.dns.ip(&target, true).await?.is_empty())
+            },
+            // RFC 7208 § 5.5 says ptr shouldn't be used, so it's never taken as matching
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            },
+            _ => Err(SpfResult::PermError)
+        }
+    }
+
+    /// Works out the domain a mechanism applies to, and any CIDR lengths after it
+    fn target_domain(&self, arg: &str, domain: &str, allow_cidr: bool) -> Result<(String, u8, u8), SpfResult> {
+        let (spec, cidr) = match arg.find('/') {
+            Some(i) if allow_cidr => (&arg[..i], &arg[i..]),
+            _ => (arg, "")
+        };
+        let target = match spec.strip_prefix(':') {
+            Some(s) => self.expand(s, domain).ok_or(SpfResult::PermError)?,
+            None if spec.is_empty() => domain.to_string(),
+            None => return Err(SpfResult::PermError)
+        };
+
+        let mut v4_len = 32;
+        let mut v6_len = 128;
+        if !cidr.is_empty() {
+            let (v4, v6) = match cidr.find("//") {
+                Some(i) => (&cidr[..i], Some(&cidr[i + 2..])),
+                None => (cidr, None)
+            };
+            if let Some(v4) = v4.strip_prefix('/') {
+                v4_len = cidr_length(v4, 32)?;
+            }
+            if let Some(v6) = v6 {
+                v6_len = cidr_length(v6, 128)?;
+            }
+        }
+        Ok((target, v4_len, v6_len))
+    }
+
+    async fn lookup_addresses(&self, domain: &str) -> Result<Vec<std::net::IpAddr>, SpfResult> {
+        self.dns.ip(domain, false).await
+    }
+
+    /// Expands the macros in a domain spec, RFC 7208 § 7
+    fn expand(&self, spec: &str, domain: &str) -> Option<String> {
+        let (local, sender_domain) = match self.sender.rfind('@') {
+            Some(i) => (&self.sender[..i], &self.sender[i + 1..]),
+            None => ("postmaster", self.sender)
+        };
+
+        let mut out = String::new();
+        let mut chars = spec.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next()? {
+                '%' => out.push('%'),
+                '_' => out.push(' '),
+                '-' => out.push_str("%20"),
+                '{' => {
+                    let mut body = String::new();
+                    loop {
+                        match chars.next()? {
+                            '}' => break,
+                            c => body.push(c)
+                        }
+                    }
+                    let mut body_chars = body.chars();
+                    let value = match body_chars.next()?.to_ascii_lowercase() {
+                        's' => self.sender.to_string(),
+                        'l' => local.to_string(),
+                        'o' => sender_domain.to_string(),
+                        'd' => domain.to_string(),
+                        'h' => self.helo.to_string(),
+                        'v' => if self.ip.is_ipv4() { "in-addr" } else { "ip6" }.to_string(),
+                        'i' => match self.ip {
+                            std::net::IpAddr::V4(a) => a.to_string(),
+                            std::net::IpAddr::V6(a) => a.octets().iter()
+                                .flat_map(|o| vec![format!("{:x}", o >> 4), format!("{:x}", o & 0xf)])
+                                .collect::<Vec<_>>().join("."),
+                        },
+                        _ => return None
+                    };
+
+                    let rest = body_chars.as_str();
+                    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+                    let rest = &rest[digits.len()..];
+                    let (reverse, delimiters) = match rest.strip_prefix(|c| c == 'r' || c == 'R') {
+                        Some(d) => (true, d),
+                        None => (false, rest)
+                    };
+                    let delimiters = if delimiters.is_empty() { "." } else { delimiters };
+
+                    let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+                    if reverse {
+                        parts.reverse();
+                    }
+                    if !digits.is_empty() {
+                        let keep = digits.parse::<usize>().ok()?;
+                        if keep == 0 {
+                            return None;
+                        }
+                        if parts.len() > keep {
+                            parts = parts.split_off(parts.len() - keep);
+                        }
+                    }
+                    out.push_str(&parts.join("."));
+                },
+                _ => return None
+            }
+        }
+
+        // Long expansions are cut down from the left, RFC 7208 § 7.3
+        while out.len() > 253 {
+            out = out.splitn(2, '.').nth(1)?.to_string();
+        }
+        Some(out)
+    }
+}
+
+/// Parses a CIDR prefix length, which is a syntax error past the size of the address,
+/// RFC 7208 § 5.6
+fn cidr_length(len: &str, max: u8) -> Result<u8, SpfResult> {
+    match len.parse::<u8>() {
+        Ok(l) if l <= max && (len == "0" || !len.starts_with('0')) => Ok(l),
+        _ => Err(SpfResult::PermError)
+    }
+}
+
+fn cidr_match(ip: &std::net::IpAddr, network: &std::net::IpAddr, v4_len: u8, v6_len: u8) -> bool {
+    match (ip, network) {
+        (std::net::IpAddr::V4(ip), std::net::IpAddr::V4(network)) if v4_len <= 32 => {
+            let mask = if v4_len == 0 { 0 } else { u32::MAX << (32 - v4_len) };
+            u32::from(*ip) & mask == u32::from(*network) & mask
+        },
+        (std::net::IpAddr::V6(ip), std::net::IpAddr::V6(network)) if v6_len <= 128 => {
+            let mask = if v6_len == 0 { 0 } else { u128::MAX << (128 - v6_len) };
+            u128::from(*ip) & mask == u128::from(*network) & mask
+        },
+        _ => false
+    }
+}
+
+/// Splits a message into its raw header fields (name and the whole field, folding and all) and
+/// its body
+fn split_message(data: &[u8]) -> (Vec<(String, &[u8])>, &[u8]) {
+    let mut headers: Vec<(String, &[u8])> = vec![];
+    let mut pos = 0;
+    let mut field_start = 0;
+
+    while pos < data.len() {
+        let line_end = match data[pos..].iter().position(|c| *c == b'\n') {
+            Some(i) => pos + i + 1,
+            None => data.len()
+        };
+        let line = &data[pos..line_end];
+        if line == b"\r\n" || line == b"\n" {
+            if field_start < pos {
+                push_field(&mut headers, &data[field_start..pos]);
+            }
+            return (headers, &data[line_end..]);
+        }
+        if pos > field_start && !(line[0] == b' ' || line[0] == b'\t') {
+            push_field(&mut headers, &data[field_start..pos]);
+            field_start = pos;
+        }
+        pos = line_end;
+    }
+
+    if field_start < pos {
+        push_field(&mut headers, &data[field_start..pos]);
+    }
+    (headers, &[])
+}
+
+fn push_field<'a>(headers: &mut Vec<(String, &'a [u8])>, field: &'a [u8]) {
+    let field = strip_crlf(field);
+    if let Some(colon) = field.iter().position(|c| *c == b':') {
+        let name = String::from_utf8_lossy(&field[..colon]).trim().to_ascii_lowercase();
+        headers.push((name, field));
+    }
+}
+
+fn strip_crlf(data: &[u8]) -> &[u8] {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    data.strip_suffix(b"\r").unwrap_or(data)
+}
+
+fn is_wsp(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+/// Splits data into lines without their line endings
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = data.split(|c| *c == b'\n').map(strip_crlf).collect::<Vec<_>>();
+    if data.ends_with(b"\n") {
+        lines.pop();
+    }
+    lines
+}
+
+/// RFC 6376 § 3.4.1 and § 3.4.2
+fn canonicalize_header(field: &[u8], relaxed: bool) -> Vec<u8> {
+    if !relaxed {
+        return field.to_vec();
+    }
+
+    let colon = field.iter().position(|c| *c == b':').unwrap_or(field.len());
+    let name = String::from_utf8_lossy(&field[..colon]).trim().to_ascii_lowercase();
+    let mut value = vec![];
+    let mut in_wsp = false;
+    for c in field.get(colon + 1..).unwrap_or(&[]) {
+        match *c {
+            b'\r' | b'\n' => {},
+            c if is_wsp(c) => in_wsp = true,
+            c => {
+                if in_wsp && !value.is_empty() {
+                    value.push(b' ');
+                }
+                in_wsp = false;
+                value.push(c);
+            }
+        }
+    }
+
+    let mut out = name.into_bytes();
+    out.push(b':');
+    out.extend(value);
+    out
+}
+
+/// RFC 6376 § 3.4.3 and § 3.4.4
+fn canonicalize_body(body: &[u8], relaxed: bool) -> Vec<u8> {
+    let mut lines = lines(body).into_iter().map(|line| {
+        if !relaxed {
+            return line.to_vec();
+        }
+        let mut out = vec![];
+        let mut in_wsp = false;
+        for c in line {
+            if is_wsp(*c) {
+                in_wsp = true;
+            } else {
+                if in_wsp {
+                    out.push(b' ');
+                }
+                in_wsp = false;
+                out.push(*c);
+            }
+        }
+        out
+    }).collect::<Vec<_>>();
+
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return if relaxed { vec![] } else { b"\r\n".to_vec() };
+    }
+
+    let mut out = vec![];
+    for line in lines {
+        out.extend(line);
+        out.extend(b"\r\n");
+    }
+    out
+}
+
+fn parse_tags(value: &str) -> std::collections::HashMap<String, String> {
+    value.split(';').filter_map(|tag| {
+        let mut parts = tag.splitn(2, '=');
+        let name = parts.next()?.trim();
+        let value = parts.next()?;
+        if name.is_empty() {
+            return None;
+        }
+        Some((name.to_string(), value.chars().filter(|c| !c.is_whitespace()).collect()))
+    }).collect()
+}
+
+/// Checks every DKIM signature on a message, giving a pass if any of them verify, along with the
+/// domains that signed it
+async fn check_dkim(data: &[u8], dns: &Dns<'_>) -> (DkimResult, Vec<String>) {
+    let (headers, body) = split_message(data);
+    let signatures = headers.iter().filter(|h| h.0 == "dkim-signature").collect::<Vec<_>>();
+    if signatures.is_empty() {
+        return (DkimResult::None, vec![]);
+    }
+
+    let mut domains = vec![];
+    let mut errors = vec![];
+    for signature in signatures {
+        match verify_signature(signature.1, &headers, body, dns).await {
+            Ok(domain) => {
+                if !domains.contains(&domain) {
+                    domains.push(domain);
+                }
+            },
+            Err(e) => errors.push(e)
+        }
+    }
+
+    let result = if !domains.is_empty() {
+        DkimResult::Pass
+    } else if errors.contains(&DkimResult::TempError) {
+        DkimResult::TempError
+    } else if errors.contains(&DkimResult::Fail) {
+        DkimResult::Fail
+    } else {
+        DkimResult::PermError
+    };
+    (result, domains)
+}
+
+/// The shortest RSA key a DKIM signature is accepted from
+const MIN_RSA_KEY_BITS: u32 = 1024;
+
+/// Verifies one DKIM-Signature field, RFC 6376 § 6.1, giving the signing domain
+async fn verify_signature(
+    field: &[u8], headers: &[(String, &[u8])], body: &[u8], dns: &Dns<'_>
+) -> Result<String, DkimResult> {
+    let field_value = String::from_utf8_lossy(&field[field.iter().position(|c| *c == b':').unwrap() + 1..]).into_owned();
+    let tags = parse_tags(&field_value);
+    let tag = |name: &str| tags.get(name).map(|v| v.as_str());
+
+    if tag("v") != Some("1") {
+        return Err(DkimResult::PermError);
+    }
+    let (domain, selector, signed_headers, body_hash, signature) =
+        match (tag("d"), tag("s"), tag("h"), tag("bh"), tag("b")) {
+            (Some(d), Some(s), Some(h), Some(bh), Some(b)) => (d.to_lowercase(), s, h, bh, b),
+            _ => return Err(DkimResult::PermError)
+        };
+    let signed_headers = signed_headers.split(':').map(|h| h.trim().to_ascii_lowercase()).collect::<Vec<_>>();
+    if !signed_headers.iter().any(|h| h == "from") {
+        return Err(DkimResult::PermError);
+    }
+    if let Some(expires) = tag("x") {
+        if expires.parse::<i64>().map(|x| x < chrono::Utc::now().timestamp()).unwrap_or(true) {
+            return Err(DkimResult::Fail);
+        }
+    }
+
+    let mut canonicalization = tag("c").unwrap_or("simple/simple").splitn(2, '/');
+    let relaxed_headers = match canonicalization.next() {
+        Some("relaxed") => true,
+        Some("simple") => false,
+        _ => return Err(DkimResult::PermError)
+    };
+    let relaxed_body = match canonicalization.next() {
+        Some("relaxed") => true,
+        Some("simple") | None => false,
+        _ => return Err(DkimResult::PermError)
+    };
+
+    // RFC 8301 § 3.1 forbids accepting rsa-sha1
+    let (digest, key_type) = match tag("a") {
+        Some("rsa-sha256") => (openssl::hash::MessageDigest::sha256(), "rsa"),
+        Some("ed25519-sha256") => (openssl::hash::MessageDigest::sha256(), "ed25519"),
+        _ => return Err(DkimResult::PermError)
+    };
+
+    let body = canonicalize_body(body, relaxed_body);
+    if let Some(length) = tag("l") {
+        let length = length.parse::<usize>().map_err(|_| DkimResult::PermError)?;
+        if length > body.len() {
+            return Err(DkimResult::Fail);
+        }
+        // Anything could have been appended after the signed part of the body, so a signature
+        // that doesn't cover all of it can't vouch for the message
+        if length < body.len() {
+            return Err(DkimResult::PermError);
+        }
+    }
+    let computed_body_hash = openssl::hash::hash(digest, &body).map_err(|_| DkimResult::TempError)?;
+    if base64::decode(body_hash).map_err(|_| DkimResult::PermError)? != computed_body_hash.to_vec() {
+        return Err(DkimResult::Fail);
+    }
+
+    // Signed headers are taken from the bottom up when a name is listed more than once
+    let mut signed_data = vec![];
+    let mut used = std::collections::HashMap::new();
+    for name in &signed_headers {
+        let count = used.entry(name.clone()).or_insert(0);
+        if let Some(h) = headers.iter().rev().filter(|h| &h.0 == name).nth(*count) {
+            signed_data.extend(canonicalize_header(h.1, relaxed_headers));
+            signed_data.extend(b"\r\n");
+        }
+        *count += 1;
+    }
+    signed_data.extend(canonicalize_header(&strip_signature(field), relaxed_headers));
+
+    let key_tags = match dns.txt(&format!("{}._domainkey.{}", selector, domain)).await {
+        TxtLookup::Records(r) => match r.into_iter().next() {
+            Some(r) => parse_tags(&r),
+            None => return Err(DkimResult::PermError)
+        },
+        TxtLookup::NotFound => return Err(DkimResult::PermError),
+        TxtLookup::Error => return Err(DkimResult::TempError),
+    };
+    if key_tags.get("k").map(|k| k.as_str()).unwrap_or("rsa") != key_type {
+        return Err(DkimResult::PermError);
+    }
+    let key_data = match key_tags.get("p") {
+        Some(p) if !p.is_empty() => base64::decode(p).map_err(|_| DkimResult::PermError)?,
+        // An empty key has been revoked
+        _ => return Err(DkimResult::Fail)
+    };
+    let signature = base64::decode(signature).map_err(|_| DkimResult::PermError)?;
+
+    let verified = if key_type == "ed25519" {
+        let key = openssl::pkey::PKey::public_key_from_raw_bytes(&key_data, openssl::pkey::Id::ED25519)
+            .map_err(|_| DkimResult::PermError)?;
+        let mut verifier = openssl::sign::Verifier::new_without_digest(&key).map_err(|_| DkimResult::PermError)?;
+        verifier.verify_oneshot(&signature, &openssl::sha::sha256(&signed_data))
+    } else {
+        let key = match openssl::pkey::PKey::public_key_from_der(&key_data) {
+            Ok(k) => k,
+            Err(_) => openssl::rsa::Rsa::public_key_from_der_pkcs1(&key_data)
+                .and_then(openssl::pkey::PKey::from_rsa)
+                .map_err(|_| DkimResult::PermError)?
+        };
+        // RFC 8301 § 3.2 forbids accepting keys shorter than 1024 bits
+        if key.id() != openssl::pkey::Id::RSA || key.bits() < MIN_RSA_KEY_BITS {
+            return Err(DkimResult::PermError);
+        }
+        let mut verifier = openssl::sign::Verifier::new(digest, &key).map_err(|_| DkimResult::PermError)?;
+        verifier.update(&signed_data).and_then(|_| verifier.verify(&signature))
+    };
+
+    match verified {
+        Ok(true) => Ok(domain),
+        _ => Err(DkimResult::Fail)
+    }
+}
+
+/// Empties the b= tag of a DKIM-Signature field, leaving everything else as it was
+fn strip_signature(field: &[u8]) -> Vec<u8> {
+    let colon = field.iter().position(|c| *c == b':').map(|i| i + 1).unwrap_or(0);
+    let tags = field[colon..].split(|c| *c == b';').map(|tag| {
+        match tag.iter().position(|c| *c == b'=') {
+            Some(eq) if String::from_utf8_lossy(&tag[..eq]).trim() == "b" => tag[..=eq].to_vec(),
+            _ => tag.to_vec()
+        }
+    }).collect::<Vec<_>>();
+
+    let mut out = field[..colon].to_vec();
+    out.extend(tags.join(&b';'));
+    out
+}
+
+/// Finds the organizational domain of RFC 7489 § 3.2, the domain registered under a public
+/// suffix. Private suffixes like `github.io` count, so their tenants aren't aligned with each
+/// other.
+fn organizational_domain(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    match psl::domain_str(&domain) {
+        Some(d) => d.to_string(),
+        None => domain
+    }
+}
+
+/// Checks whether a domain authenticated by SPF or DKIM is aligned with the author domain, in
+/// relaxed mode unless strict is asked for, RFC 7489 § 3.1
+fn aligned(author_domain: &str, other: &str, strict: bool) -> bool {
+    let other = other.trim_end_matches('.');
+    if strict {
+        other.eq_ignore_ascii_case(author_domain)
+    } else {
+        organizational_domain(other) == organizational_domain(author_domain)
+    }
+}
+
+/// Finds the domain of the message's author, as long as there's only one, RFC 7489 § 6.6.1
+fn author_domain(headers: &[(String, &[u8])]) -> Option<String> {
+    let mut from = headers.iter().filter(|h| h.0 == "from");
+    let field = match (from.next(), from.next()) {
+        (Some(f), None) => String::from_utf8_lossy(f.1).replace("\r\n", ""),
+        _ => return None
+    };
+    let addrs = mailparse::addrparse(field.splitn(2, ':').nth(1)?).ok()?;
+
+    let mut domains = vec![];
+    for addr in addrs.iter() {
+        let singles = match addr {
+            mailparse::MailAddr::Single(s) => vec![s],
+            mailparse::MailAddr::Group(g) => g.addrs.iter().collect()
+        };
+        for single in singles {
+            let domain = single.addr.rsplitn(2, '@').next()?.trim_end_matches('.').to_lowercase();
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    match domains.as_slice() {
+        [domain] => Some(domain.to_string()),
+        _ => None
+    }
+}
+
+async fn lookup_dmarc(domain: &str, dns: &Dns<'_>) -> Result<Option<std::collections::HashMap<String, String>>, DmarcResult> {
+    match dns.txt(&format!("_dmarc.{}", domain)).await {
+        TxtLookup::Records(records) => {
+            let mut records = records.iter()
+                .map(|r| parse_tags(r))
+                .filter(|r| r.get("v").map(String::as_str) == Some("DMARC1"));
+            // RFC 7489 § 6.6.3, more than one record means there isn't one
+            match (records.next(), records.next()) {
+                (Some(r), None) => Ok(Some(r)),
+                _ => Ok(None)
+            }
+        }
+        TxtLookup::NotFound => Ok(None),
+        TxtLookup::Error => Err(DmarcResult::TempError),
+    }
+}
+
+/// Checks whether a passing SPF or DKIM result is aligned with the author domain, RFC 7489 § 6.6,
+/// for authors whose domain publishes a DMARC policy. The policy's requested disposition is left
+/// to the registrant's choice of policy for their address.
+async fn check_dmarc(
+    data: &[u8], spf: SpfResult, spf_domain: &str, dkim_domains: &[String], dns: &Dns<'_>
+) -> (DmarcResult, Option<String>) {
+    let (headers, _) = split_message(data);
+    let domain = match author_domain(&headers) {
+        Some(d) => d,
+        None => return (DmarcResult::PermError, None)
+    };
+    let org_domain = organizational_domain(&domain);
+
+    let record = match lookup_dmarc(&domain, dns).await {
+        Ok(Some(r)) => r,
+        Ok(None) if org_domain != domain => match lookup_dmarc(&org_domain, dns).await {
+            Ok(Some(r)) => r,
+            Ok(None) => return (DmarcResult::None, Some(domain)),
+            Err(e) => return (e, Some(domain))
+        },
+        Ok(None) => return (DmarcResult::None, Some(domain)),
+        Err(e) => return (e, Some(domain))
+    };
+
+    let strict = |mode: Option<&String>| mode.map(String::as_str) == Some("s");
+    let spf_aligned = spf == SpfResult::Pass && aligned(&domain, spf_domain, strict(record.get("aspf")));
+    let dkim_aligned = dkim_domains.iter().any(|d| aligned(&domain, d, strict(record.get("adkim"))));
+
+    if spf_aligned || dkim_aligned {
+        (DmarcResult::Pass, Some(domain))
+    } else {
+        (DmarcResult::Fail, Some(domain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn organizational_domains() {
+        assert_eq!(organizational_domain("mail.example.com"), "example.com");
+        assert_eq!(organizational_domain("a.b.example.co.uk."), "example.co.uk");
+        assert_eq!(organizational_domain("Example.COM"), "example.com");
+        assert_eq!(organizational_domain("foo.github.io"), "foo.github.io");
+        assert_eq!(organizational_domain("github.io"), "github.io");
+    }
+
+    #[test]
+    fn relaxed_alignment() {
+        assert!(aligned("example.com", "mail.example.com", false));
+        assert!(aligned("news.example.co.uk", "bounces.example.co.uk", false));
+        assert!(!aligned("example.com", "example.net", false));
+        assert!(!aligned("example.co.uk", "other.co.uk", false));
+    }
+
+    #[test]
+    fn shared_suffix_tenants_are_not_aligned() {
+        assert!(!aligned("victim.github.io", "attacker.github.io", false));
+        assert!(!aligned("victim.herokuapp.com", "attacker.herokuapp.com", false));
+        assert!(!aligned("victim.blogspot.com", "attacker.blogspot.com", false));
+        assert!(aligned("www.victim.github.io", "victim.github.io", false));
+    }
+
+    #[test]
+    fn authenticated_domains() {
+        let auth = AuthResults {
+            spf: SpfResult::Pass,
+            spf_domain: "bounces.example.com".to_string(),
+            dkim: DkimResult::Pass,
+            dkim_domains: vec!["mail.example.org".to_string()],
+            dmarc: DmarcResult::None,
+            dmarc_domain: Some("example.net".to_string()),
+        };
+        assert!(auth.authenticates("example.com"));
+        assert!(auth.authenticates("example.org"));
+        assert!(!auth.authenticates("example.net"));
+        assert!(!auth.authenticates("attacker.github.io"));
+
+        let auth = AuthResults {
+            spf: SpfResult::Fail,
+            dkim: DkimResult::Fail,
+            dkim_domains: vec![],
+            dmarc: DmarcResult::Pass,
+            ..auth
+        };
+        assert!(!auth.authenticates("example.com"));
+        assert!(auth.authenticates("news.example.net"));
+    }
+
+    #[test]
+    fn strict_alignment() {
+        assert!(aligned("example.com", "EXAMPLE.com.", true));
+        assert!(!aligned("example.com", "mail.example.com", true));
+    }
+
+    fn spf(records: &[(&str, &str, &str)], ip: &str, sender: &str) -> SpfResult {
+        let dns = Dns::Static(records);
+        let mut check = SpfCheck {
+            ip: ip.parse().unwrap(),
+            sender,
+            helo: "mail.example.org",
+            lookups: 0,
+            dns: &dns,
+        };
+        let domain = sender.rsplitn(2, '@').next().unwrap().to_string();
+        futures::executor::block_on(check.check_host(domain))
+    }
+
+    #[test]
+    fn spf_include() {
+        let records = [
+            ("example.com", "TXT", "v=spf1 include:_spf.example.net -all"),
+            ("_spf.example.net", "TXT", "v=spf1 ip4:192.0.2.0/24 -all"),
+            ("broken.example.com", "TXT", "v=spf1 include:missing.example.net -all"),
+        ];
+        assert_eq!(spf(&records, "192.0.2.10", "a@example.com"), SpfResult::Pass);
+        // A failing include only means it didn't match
+        assert_eq!(spf(&records, "198.51.100.1", "a@example.com"), SpfResult::Fail);
+        assert_eq!(spf(&records, "192.0.2.10", "a@broken.example.com"), SpfResult::PermError);
+    }
+
+    #[test]
+    fn spf_redirect() {
+        let records = [
+            ("example.com", "TXT", "v=spf1 ip4:198.51.100.1 redirect=_spf.example.com"),
+            ("_spf.example.com", "TXT", "v=spf1 ip6:2001:db8::/32 ~all"),
+            ("broken.example.com", "TXT", "v=spf1 redirect=missing.example.com"),
+        ];
+        assert_eq!(spf(&records, "2001:db8::1", "a@example.com"), SpfResult::Pass);
+        assert_eq!(spf(&records, "198.51.100.1", "a@example.com"), SpfResult::Pass);
+        assert_eq!(spf(&records, "192.0.2.1", "a@example.com"), SpfResult::SoftFail);
+        assert_eq!(spf(&records, "192.0.2.1", "a@broken.example.com"), SpfResult::PermError);
+    }
+
+    #[test]
+    fn spf_macro_expansion() {
+        // RFC 7208 § 7.4
+        let dns = Dns::Static(&[]);
+        let mut check = SpfCheck {
+            ip: "192.0.2.3".parse().unwrap(),
+            sender: "strong-bad@email.example.com",
+            helo: "mail.example.org",
+            lookups: 0,
+            dns: &dns,
+        };
+        let expand = |check: &SpfCheck, spec: &str| check.expand(spec, "email.example.com").unwrap();
+        assert_eq!(expand(&check, "%{s}"), "strong-bad@email.example.com");
+        assert_eq!(expand(&check, "%{o}"), "email.example.com");
+        assert_eq!(expand(&check, "%{d}"), "email.example.com");
+        assert_eq!(expand(&check, "%{d4}"), "email.example.com");
+        assert_eq!(expand(&check, "%{d3}"), "email.example.com");
+        assert_eq!(expand(&check, "%{d2}"), "example.com");
+        assert_eq!(expand(&check, "%{d1}"), "com");
+        assert_eq!(expand(&check, "%{dr}"), "com.example.email");
+        assert_eq!(expand(&check, "%{d2r}"), "example.email");
+        assert_eq!(expand(&check, "%{l}"), "strong-bad");
+        assert_eq!(expand(&check, "%{l-}"), "strong.bad");
+        assert_eq!(expand(&check, "%{lr}"), "strong-bad");
+        assert_eq!(expand(&check, "%{lr-}"), "bad.strong");
+        assert_eq!(expand(&check, "%{l1r-}"), "strong");
+        assert_eq!(expand(&check, "%{ir}.%{v}._spf.%{d2}"), "3.2.0.192.in-addr._spf.example.com");
+        assert_eq!(expand(&check, "%{lr-}.lp._spf.%{d2}"), "bad.strong.lp._spf.example.com");
+        assert_eq!(
+            expand(&check, "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}"),
+            "bad.strong.lp.3.2.0.192.in-addr._spf.example.com"
+        );
+        assert_eq!(
+            expand(&check, "%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}"),
+            "3.2.0.192.in-addr.strong.lp._spf.example.com"
+        );
+        assert_eq!(expand(&check, "%{d2}.trusted-domains.example.net"), "example.com.trusted-domains.example.net");
+        assert!(check.expand("%{x}", "email.example.com").is_none());
+        assert!(check.expand("%{d0}", "email.example.com").is_none());
+
+        check.ip = "2001:db8::cb01".parse().unwrap();
+        assert_eq!(
+            expand(&check, "%{ir}.%{v}._spf.%{d2}"),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+    }
+
+    #[test]
+    fn spf_macros_in_mechanisms() {
+        let records = [
+            ("example.com", "TXT", "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all"),
+            ("1.2.0.192.someuser._spf.example.com", "A", "127.0.0.2"),
+        ];
+        assert_eq!(spf(&records, "192.0.2.1", "someuser@example.com"), SpfResult::Pass);
+        assert_eq!(spf(&records, "192.0.2.1", "other@example.com"), SpfResult::Fail);
+        assert_eq!(spf(&records, "192.0.2.2", "someuser@example.com"), SpfResult::Fail);
+    }
+
+    /// A chain of includes, each costing one lookup
+    fn include_chain(includes: usize) -> Vec<(String, &'static str, String)> {
+        let mut records = vec![];
+        for i in 0..includes {
+            let name = if i == 0 { "example.com".to_string() } else { format!("l{}.example.com", i) };
+            records.push((name, "TXT", format!("v=spf1 include:l{}.example.com -all", i + 1)));
+        }
+        records.push((format!("l{}.example.com", includes), "TXT", "v=spf1 ip4:192.0.2.1 -all".to_string()));
+        records
+    }
+
+    #[test]
+    fn spf_lookup_limit() {
+        let chain = include_chain(10);
+        let records = chain.iter().map(|r| (r.0.as_str(), r.1, r.2.as_str())).collect::<Vec<_>>();
+        assert_eq!(spf(&records, "192.0.2.1", "a@example.com"), SpfResult::Pass);
+
+        let chain = include_chain(11);
+        let records = chain.iter().map(|r| (r.0.as_str(), r.1, r.2.as_str())).collect::<Vec<_>>();
+        assert_eq!(spf(&records, "192.0.2.1", "a@example.com"), SpfResult::PermError);
+
+        let a_terms = (0..11).map(|i| format!("a:h{}.example.com", i)).collect::<Vec<_>>().join(" ");
+        let record = format!("v=spf1 {} ip4:192.0.2.1 -all", a_terms);
+        assert_eq!(spf(&[("example.com", "TXT", &record)], "192.0.2.1", "a@example.com"), SpfResult::PermError);
+        let record = format!("v=spf1 ip4:192.0.2.1 {} -all", a_terms);
+        assert_eq!(spf(&[("example.com", "TXT", &record)], "192.0.2.1", "a@example.com"), SpfResult::Pass);
+    }
+
+    #[test]
+    fn spf_cidr_lengths() {
+        let check = |record: &str, ip: &str| spf(&[
+            ("example.com", "TXT", record),
+            ("example.com", "A", "192.0.2.1"),
+            ("example.com", "AAAA", "2001:db8::1"),
+        ], ip, "a@example.com");
+        assert_eq!(check("v=spf1 ip4:192.0.2.0/24 -all", "192.0.2.200"), SpfResult::Pass);
+        assert_eq!(check("v=spf1 ip4:192.0.2.0/32 -all", "192.0.2.200"), SpfResult::Fail);
+        assert_eq!(check("v=spf1 ip4:0.0.0.0/0 -all", "192.0.2.200"), SpfResult::Pass);
+        assert_eq!(check("v=spf1 a/24//64 -all", "2001:db8::ffff"), SpfResult::Pass);
+        assert_eq!(check("v=spf1 ip4:192.0.2.0/33 -all", "192.0.2.200"), SpfResult::PermError);
+        assert_eq!(check("v=spf1 ip4:192.0.2.0/024 -all", "192.0.2.200"), SpfResult::PermError);
+        assert_eq!(check("v=spf1 ip6:2001:db8::/129 -all", "2001:db8::1"), SpfResult::PermError);
+        assert_eq!(check("v=spf1 a/33 -all", "192.0.2.1"), SpfResult::PermError);
+        assert_eq!(check("v=spf1 a//129 -all", "2001:db8::1"), SpfResult::PermError);
+        assert_eq!(check("v=spf1 mx/33 -all", "192.0.2.1"), SpfResult::PermError);
+    }
+
+    #[test]
+    fn header_canonicalization() {
+        // RFC 6376 § 3.4.5
+        assert_eq!(canonicalize_header(b"A: X", false), b"A: X");
+        assert_eq!(canonicalize_header(b"B : Y\t\r\n\tZ  ", false), b"B : Y\t\r\n\tZ  ");
+        assert_eq!(canonicalize_header(b"A: X", true), b"a:X");
+        assert_eq!(canonicalize_header(b"B : Y\t\r\n\tZ  ", true), b"b:Y Z");
+    }
+
+    #[test]
+    fn body_canonicalization() {
+        // RFC 6376 § 3.4.5
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(canonicalize_body(body, false), b" C \r\nD \t E\r\n".to_vec());
+        assert_eq!(canonicalize_body(body, true), b" C\r\nD E\r\n".to_vec());
+        assert_eq!(canonicalize_body(b"", false), b"\r\n".to_vec());
+        assert_eq!(canonicalize_body(b"\r\n\r\n", false), b"\r\n".to_vec());
+        assert_eq!(canonicalize_body(b"", true), b"".to_vec());
+        assert_eq!(canonicalize_body(b"\r\n\r\n", true), b"".to_vec());
+        assert_eq!(canonicalize_body(b"no line ending", true), b"no line ending\r\n".to_vec());
+    }
+
+    fn check_dkim_with_key(message: &[u8], key_record: &str) -> (DkimResult, Vec<String>) {
+        let records = [("sel._domainkey.example.com", "TXT", key_record)];
+        futures::executor::block_on(check_dkim(message, &Dns::Static(&records)))
+    }
+
+    #[test]
+    fn dkim_rfc8463_vector() {
+        // RFC 8463 Appendix A
+        let message = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n \
+            d=football.example.com; i=@football.example.com;\r\n \
+            q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n \
+            subject : date : message-id : from : subject : date;\r\n \
+            bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n \
+            b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n \
+            Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n\
+            From: Joe SixPack <joe@football.example.com>\r\n\
+            To: Suzie Q <suzie@shopping.example.net>\r\n\
+            Subject: Is dinner ready?\r\n\
+            Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+            Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+            \r\n\
+            Hi.\r\n\
+            \r\n\
+            We lost the game.  Are you hungry yet?\r\n\
+            \r\n\
+            Joe.\r\n";
+        let records = [(
+            "brisbane._domainkey.football.example.com", "TXT",
+            "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        )];
+        let result = futures::executor::block_on(check_dkim(message, &Dns::Static(&records)));
+        assert_eq!(result, (DkimResult::Pass, vec!["football.example.com".to_string()]));
+    }
+
+    struct Signer {
+        key: openssl::pkey::PKey<openssl::pkey::Private>,
+        digest: openssl::hash::MessageDigest,
+    }
+
+    impl Signer {
+        fn new() -> Self {
+            Signer {
+                key: openssl::pkey::PKey::generate_ed25519().unwrap(),
+                digest: openssl::hash::MessageDigest::sha256(),
+            }
+        }
+
+        fn rsa(bits: u32, digest: openssl::hash::MessageDigest) -> Self {
+            Signer {
+                key: openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(bits).unwrap()).unwrap(),
+                digest,
+            }
+        }
+
+        fn key_record(&self) -> String {
+            match self.key.id() {
+                openssl::pkey::Id::ED25519 => format!("v=DKIM1; k=ed25519; p={}", base64::encode(self.key.raw_public_key().unwrap())),
+                _ => format!("v=DKIM1; k=rsa; p={}", base64::encode(self.key.public_key_to_der().unwrap())),
+            }
+        }
+
+        /// Signs a message over exactly the canonical header data given, so the tests spell out
+        /// what should be signed rather than relying on the code under test to work it out
+        fn sign(&self, tags: &str, canonical_headers: &str, canonical_body: &str, headers: &str, body: &str) -> Vec<u8> {
+            let tags = tags.replace("{bh}", &base64::encode(openssl::sha::sha256(canonical_body.as_bytes())));
+            let relaxed = tags.contains("c=relaxed");
+            let signed_data = if relaxed {
+                format!("{}dkim-signature:{}b=", canonical_headers, tags)
+            } else {
+                format!("{}DKIM-Signature: {}b=", canonical_headers, tags)
+            };
+            let signature = match self.key.id() {
+                openssl::pkey::Id::ED25519 => {
+                    let mut signer = openssl::sign::Signer::new_without_digest(&self.key).unwrap();
+                    signer.sign_oneshot_to_vec(&openssl::sha::sha256(signed_data.as_bytes())).unwrap()
+                },
+                _ => {
+                    let mut signer = openssl::sign::Signer::new(self.digest, &self.key).unwrap();
+                    signer.sign_oneshot_to_vec(signed_data.as_bytes()).unwrap()
+                }
+            };
+            format!("DKIM-Signature: {}b={}\r\n{}\r\n{}", tags, base64::encode(&signature), headers, body).into_bytes()
+        }
+    }
+
+    #[test]
+    fn dkim_simple_and_relaxed() {
+        let signer = Signer::new();
+        let headers = "From:  Alice  <alice@example.com>\r\nSubject: Hi\r\n";
+        let body = "Hello  there \r\n\r\n";
+
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from:subject; bh={bh}; ",
+            "From:  Alice  <alice@example.com>\r\nSubject: Hi\r\n", "Hello  there \r\n",
+            headers, body,
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Pass);
+
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=sel; h=From:Subject; bh={bh}; ",
+            "from:Alice <alice@example.com>\r\nsubject:Hi\r\n", "Hello there\r\n",
+            headers, body,
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Pass);
+        // Whitespace changes are only tolerated by relaxed canonicalization
+        let rewrapped = String::from_utf8(message).unwrap().replace("Hello  there", "Hello there");
+        assert_eq!(check_dkim_with_key(rewrapped.as_bytes(), &signer.key_record()).0, DkimResult::Pass);
+
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from:subject; bh={bh}; ",
+            "From:  Alice  <alice@example.com>\r\nSubject: Hi\r\n", "Hello  there \r\n",
+            headers, body,
+        );
+        let rewrapped = String::from_utf8(message).unwrap().replace("Hello  there", "Hello there");
+        assert_eq!(check_dkim_with_key(rewrapped.as_bytes(), &signer.key_record()).0, DkimResult::Fail);
+    }
+
+    #[test]
+    fn dkim_body_length() {
+        let signer = Signer::new();
+        let headers = "From: alice@example.com\r\n";
+        let tags = "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from; l=7; bh={bh}; ";
+
+        let message = signer.sign(tags, "From: alice@example.com\r\n", "Hello\r\n", headers, "Hello\r\n");
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Pass);
+        // Only the first l= octets are covered, so anything after them could have been added by
+        // anyone and the signature doesn't count
+        let message = signer.sign(
+            tags, "From: alice@example.com\r\n", "Hello\r\n", headers, "Hello\r\nAppended by someone else\r\n",
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::PermError);
+        // A body shorter than l= can't have been the one signed
+        let message = signer.sign(tags, "From: alice@example.com\r\n", "Hello\r\n", headers, "Hi\r\n");
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Fail);
+    }
+
+    #[test]
+    fn dkim_weak_rsa_is_refused() {
+        let headers = "From: alice@example.com\r\n";
+        let sign = |signer: &Signer, algorithm: &str| signer.sign(
+            &format!("v=1; a={}; c=simple/simple; d=example.com; s=sel; h=from; bh={{bh}}; ", algorithm),
+            "From: alice@example.com\r\n", "Hi\r\n", headers, "Hi\r\n",
+        );
+
+        let signer = Signer::rsa(1024, openssl::hash::MessageDigest::sha256());
+        assert_eq!(check_dkim_with_key(&sign(&signer, "rsa-sha256"), &signer.key_record()).0, DkimResult::Pass);
+        let signer = Signer::rsa(512, openssl::hash::MessageDigest::sha256());
+        assert_eq!(check_dkim_with_key(&sign(&signer, "rsa-sha256"), &signer.key_record()).0, DkimResult::PermError);
+        let signer = Signer::rsa(2048, openssl::hash::MessageDigest::sha1());
+        assert_eq!(check_dkim_with_key(&sign(&signer, "rsa-sha1"), &signer.key_record()).0, DkimResult::PermError);
+    }
+
+    #[test]
+    fn dkim_signed_headers_are_taken_from_the_bottom() {
+        let signer = Signer::new();
+        let headers = "From: alice@example.com\r\nSubject: first\r\nSubject: second\r\n";
+
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from:subject; bh={bh}; ",
+            "From: alice@example.com\r\nSubject: second\r\n", "Hi\r\n", headers, "Hi\r\n",
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Pass);
+
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from:subject; bh={bh}; ",
+            "From: alice@example.com\r\nSubject: first\r\n", "Hi\r\n", headers, "Hi\r\n",
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Fail);
+
+        // Listing a name more than there are fields signs its absence, so one can't be added later
+        let message = signer.sign(
+            "v=1; a=ed25519-sha256; c=simple/simple; d=example.com; s=sel; h=from:subject:subject:subject; bh={bh}; ",
+            "From: alice@example.com\r\nSubject: second\r\nSubject: first\r\n", "Hi\r\n", headers, "Hi\r\n",
+        );
+        assert_eq!(check_dkim_with_key(&message, &signer.key_record()).0, DkimResult::Pass);
+        let added = String::from_utf8(message).unwrap().replace("\r\n\r\nHi", "\r\nSubject: third\r\n\r\nHi");
+        assert_eq!(check_dkim_with_key(added.as_bytes(), &signer.key_record()).0, DkimResult::Fail);
+    }
+}
@@ -0,0 +1,153 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Blobs at least this big are stored compressed, if that makes them any smaller
+const COMPRESSION_THRESHOLD: usize = 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+/// How many blobs are looked at at once when re-addressing old ones
+const READDRESS_BATCH_SIZE: i64 = 100;
+
+/// Stores some data content-addressed by its keyed hash, or takes another reference to it if it's
+/// already stored, and returns the hash. Each blob is encrypted under its own data key, as it may
+/// be shared between messages. References are given up when the rows holding the hash are deleted.
+pub fn store(data: &[u8], keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> QueryResult<Vec<u8>> {
+    let hash = keys.blob_hash(data);
+    insert(&hash, data, 1, keys, conn)?;
+    Ok(hash)
+}
+
+/// Adds references to the blob under a hash, storing it if it's not there yet
+fn insert(hash: &[u8], data: &[u8], refs: i64, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> QueryResult<()> {
+    let existing = diesel::update(schema::blob::table.find(hash))
+        .set(schema::blob::ref_count.eq(schema::blob::ref_count + refs))
+        .execute(conn)?;
+    if existing > 0 {
+        return Ok(());
+    }
+
+    let compressed_data = if data.len() >= COMPRESSION_THRESHOLD {
+        match zstd::encode_all(data, COMPRESSION_LEVEL) {
+            Ok(c) => Some(c).filter(|c| c.len() < data.len()),
+            Err(e) => {
+                warn!("Unable to compress blob: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let data_key = keys.new_data_key(conn)?;
+    let new_blob = models::NewBlob {
+        hash,
+        data: &data_key.encrypt(compressed_data.as_deref().unwrap_or(data)),
+        compressed: compressed_data.is_some(),
+        ref_count: refs,
+        data_key: Some(&data_key.id),
+    };
+    let stored_data_key = diesel::insert_into(schema::blob::table)
+        .values(&new_blob)
+        .on_conflict(schema::blob::hash)
+        .do_update()
+        .set(schema::blob::ref_count.eq(schema::blob::ref_count + refs))
+        .returning(schema::blob::data_key)
+        .get_result::<Option<uuid::Uuid>>(conn)?;
+    // Someone else stored the same blob first, so ours was never used
+    if stored_data_key != Some(data_key.id) {
+        diesel::delete(schema::data_key::table.find(&data_key.id))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Loads the data stored under a hash, decrypting and decompressing it if need be
+pub fn load(hash: &[u8], keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<Vec<u8>, String> {
+    let blob = schema::blob::table
+        .find(hash)
+        .select((schema::blob::hash, schema::blob::data, schema::blob::compressed, schema::blob::data_key))
+        .get_result::<models::Blob>(conn)
+        .map_err(|e| format!("Unable to load blob: {}", e))?;
+    open(blob, keys, conn)
+}
+
+fn open(blob: models::Blob, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<Vec<u8>, String> {
+    let data = keys.decrypt(&blob.data_key, blob.data, conn)?;
+    if blob.compressed {
+        zstd::decode_all(data.as_slice()).map_err(|e| format!("Unable to decompress blob: {}", e))
+    } else {
+        Ok(data)
+    }
+}
+
+/// Moves blobs stored before they were encrypted, or addressed by their plain SHA-256 hash, to
+/// their keyed hash, encrypting them on the way and pointing everything that refers to them at
+/// the new address. Returns how many there were.
+pub fn readdress_legacy(keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<usize, String> {
+    let mut readdressed = 0;
+    let mut after = vec![];
+    loop {
+        let blobs = schema::blob::table
+            .filter(schema::blob::hash.gt(&after))
+            .order_by(schema::blob::hash.asc())
+            .limit(READDRESS_BATCH_SIZE)
+            .select((schema::blob::hash, schema::blob::data, schema::blob::compressed, schema::blob::data_key))
+            .load::<models::Blob>(conn)
+            .map_err(|e| format!("Unable to load blobs: {}", e))?;
+        let last = match blobs.last() {
+            Some(b) => b.hash.clone(),
+            None => return Ok(readdressed)
+        };
+
+        for blob in blobs {
+            let old_hash = blob.hash.clone();
+            let old_data_key = blob.data_key;
+            let data = open(blob, keys, conn)?;
+            // Blobs were encrypted before they were addressed by a keyed hash, so any already
+            // under their keyed hash are encrypted too
+            let hash = keys.blob_hash(&data);
+            if hash == old_hash {
+                continue;
+            }
+
+            conn.transaction(|| {
+                // Taken again under lock, as the janitor may have given up references since
+                let refs = schema::blob::table
+                    .find(&old_hash)
+                    .select(schema::blob::ref_count)
+                    .for_update()
+                    .get_result::<i64>(conn)?;
+                insert(&hash, &data, refs, keys, conn)?;
+                diesel::update(schema::mail_subpart::table.filter(schema::mail_subpart::body_hash.eq(&old_hash)))
+                    .set(schema::mail_subpart::body_hash.eq(&hash))
+                    .execute(conn)?;
+                diesel::update(schema::raw_message::table.filter(schema::raw_message::data_hash.eq(&old_hash)))
+                    .set(schema::raw_message::data_hash.eq(&hash))
+                    .execute(conn)?;
+                diesel::delete(schema::blob::table.find(&old_hash))
+                    .execute(conn)?;
+                if let Some(old_data_key) = &old_data_key {
+                    diesel::delete(schema::data_key::table.find(old_data_key))
+                        .execute(conn)?;
+                }
+                Ok(())
+            }).map_err(|e: diesel::result::Error| format!("Unable to re-address blob: {}", e))?;
+            readdressed += 1;
+        }
+        after = last;
+    }
+}
+
+/// Deletes blobs nothing refers to anymore, along with their data keys, returning how many were
+/// deleted
+pub fn collect_garbage(conn: &crate::DbConn) -> QueryResult<usize> {
+    conn.transaction(|| {
+        let deleted = diesel::delete(schema::blob::table.filter(schema::blob::ref_count.le(0)))
+            .returning(schema::blob::data_key)
+            .get_results::<Option<uuid::Uuid>>(conn)?;
+        let data_keys = deleted.iter().filter_map(|k| k.as_ref()).collect::<Vec<_>>();
+        diesel::delete(schema::data_key::table.filter(schema::data_key::id.eq_any(data_keys)))
+            .execute(conn)?;
+        Ok(deleted.len())
+    })
+}
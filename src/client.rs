@@ -1,6 +1,6 @@
 use tokio::prelude::*;
+use std::borrow::Cow;
 use std::str::FromStr;
-use futures::stream::StreamExt;
 use crate::proto::{SMTPResponse, SMTPCommand};
 
 #[derive(Debug, Clone)]
@@ -8,32 +8,89 @@ pub enum SendingError {
     InvalidAddress,
     InvalidMessage(String),
     ConnectionError(String),
+    TlsError(String),
     TransientError(String),
+    /// The server asked us to slow down
+    Throttled(String),
+    /// Delivery wasn't attempted as the destination is at its sending limits
+    Deferred(String),
     PermanentError(String),
 }
 
+impl std::fmt::Display for SendingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress => write!(f, "Invalid address"),
+            Self::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+            Self::ConnectionError(e) => write!(f, "Connection error: {}", e),
+            Self::TlsError(e) => write!(f, "TLS error: {}", e),
+            Self::TransientError(e) => write!(f, "Transient error: {}", e),
+            Self::Throttled(e) => write!(f, "Throttled: {}", e),
+            Self::Deferred(e) => write!(f, "Deferred: {}", e),
+            Self::PermanentError(e) => write!(f, "Permanent error: {}", e),
+        }
+    }
+}
+
 impl From<std::io::Error> for SendingError {
     fn from(from: std::io::Error) -> Self {
         Self::ConnectionError(from.to_string())
     }
 }
 
-impl From<native_tls::Error> for SendingError {
-    fn from(from: native_tls::Error) -> Self {
-        Self::ConnectionError(from.to_string())
+impl From<openssl::error::ErrorStack> for SendingError {
+    fn from(from: openssl::error::ErrorStack) -> Self {
+        Self::TlsError(from.to_string())
     }
 }
 
+/// Details of a successful delivery to a remote server
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// Hostname of the MX the message was accepted by
+    pub mx_domain: String,
+    /// Queue ID reported by the remote server in its end of data reply
+    pub queue_id: Option<String>,
+}
+
 #[derive(Debug)]
 struct Address {
     local_part: String,
     domain: String,
 }
 
+/// Requirements on the TLS session negotiated with an MX
+#[derive(Debug, Clone, PartialEq)]
+enum TlsRequirement {
+    /// Use STARTTLS when it's offered, whether or not the certificate verifies, otherwise deliver
+    /// in plaintext
+    Opportunistic,
+    /// As with `Opportunistic`, but if the TLS handshake fails reconnect and deliver in plaintext
+    OpportunisticFallbackPlain,
+    /// STARTTLS must be used, with a certificate valid for the MX hostname
+    Verified,
+    /// STARTTLS must be used, with a leaf certificate matching the SHA-256 fingerprint
+    Pinned(Vec<u8>),
+    /// STARTTLS must be used, with a certificate chain matching the MX's DNSSEC signed TLSA records
+    Dane(Vec<trust_dns_resolver::proto::rr::rdata::TLSA>),
+}
+
+impl TlsRequirement {
+    fn requires_tls(&self) -> bool {
+        match self {
+            Self::Opportunistic | Self::OpportunisticFallbackPlain => false,
+            Self::Verified | Self::Pinned(_) | Self::Dane(_) => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ForwardPath {
     address: Address,
-    mx_addresses: Vec<MXAddress>
+    mx_addresses: Vec<MXAddress>,
+    tls_requirement: TlsRequirement,
+    dnssec_mx: bool,
+    sts_policy: Option<crate::mta_sts::Policy>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -45,78 +102,54 @@ struct MXAddress {
 #[derive(Debug)]
 struct ForwardPaths {
     addresses: Vec<(usize, Address)>,
-    mx_addresses: Vec<MXAddress>
+    mx_addresses: Vec<MXAddress>,
+    tls_requirement: TlsRequirement,
+    dnssec_mx: bool,
+    sts_policy: Option<crate::mta_sts::Policy>,
+    /// Recipient domains TLS session results are reported against
+    policy_domains: Vec<String>,
 }
 
-pub async fn send_mail(reverse_path: &str, forward_paths: &[&str], data: &[u8], config: &crate::Config) -> Vec<Result<(), SendingError>> {
+/// Delivers a message to each of the forward paths, returning the result for each along with a
+/// record of every attempt made at delivering to it
+pub async fn send_mail(
+    reverse_path: &str, forward_paths: &[&str], data: &[u8], config: &crate::Config
+) -> Vec<(Result<Delivery, SendingError>, Vec<crate::attempt::Attempt>)> {
     let mut out_list = Vec::new();
+    let mut attempts = Vec::new();
     for _ in 0..forward_paths.len() {
-        out_list.push(Ok(()));
+        out_list.push(Err(SendingError::InvalidAddress));
+        attempts.push(vec![]);
     }
 
-    let forward_paths = futures::stream::iter(forward_paths.clone()).then(|p| async move {
-        let mut parts = p.rsplitn(2,'@');
-        let domain = parts.next().unwrap();
-        let local_part = match parts.next() {
-            Some(l) => l,
-            None => return Err(SendingError::InvalidAddress)
-        };
-        let mut addr = match std::net::IpAddr::from_str(domain) {
-            Ok(a) => vec![MXAddress {
-                address: a,
-                domain: a.to_string()
-            }],
-            Err(_) => match config.resolver.mx_lookup(domain).await {
-                Ok(r) => {
-                    let mut mxs: Vec<_> = r.iter().collect();
-                    mxs.sort_by_key(|mx| mx.preference());
-                    let addrs = futures::stream::iter(mxs).filter_map(|mx| async move {
-                        match config.resolver.lookup_ip(mx.exchange().to_owned()).await {
-                            Ok(r) => Some(futures::stream::iter(r.into_iter().map(move |a| MXAddress {
-                                address: a,
-                                domain: mx.exchange().to_string().trim_end_matches('.').to_string(),
-                            }))),
-                            Err(_) => None
-                        }
-                    }).flatten().collect::<Vec<_>>().await;
-                    if addrs.is_empty() {
-                        return Err(SendingError::InvalidAddress);
-                    }
-                    addrs
-                },
-                Err(_) => return Err(SendingError::InvalidAddress)
-            }
-        };
-        addr.sort_by_key(|a| match a.address {
-            std::net::IpAddr::V6(_) => 0,
-            std::net::IpAddr::V4(_) => 1
-        });
-        Ok(ForwardPath {
-            address: Address {
-                local_part: local_part.to_string(),
-                domain: domain.to_string(),
-            },
-            mx_addresses: addr
-        })
-    }).enumerate().collect::<Vec<_>>().await.into_iter().filter_map(|fp| {
-        match fp.1 {
-            Ok(v) => {
-                Some((fp.0, v))
-            },
-            Err(e) => {
-                out_list[fp.0] = Err(e);
-                None
-            }
+    let mut resolved_forward_paths = vec![];
+    for (i, p) in forward_paths.iter().enumerate() {
+        match resolve_forward_path(p, config).await {
+            Ok(fp) => resolved_forward_paths.push((i, fp)),
+            Err(e) => out_list[i] = Err(e)
         }
-    }).collect::<Vec<_>>();
+    }
+    let forward_paths = resolved_forward_paths;
 
     let mut forward_paths_grouped: Vec<ForwardPaths> = vec![];
     for forward_path in forward_paths {
-        match forward_paths_grouped.iter_mut().find(|p| p.mx_addresses == forward_path.1.mx_addresses) {
-            Some(p) => p.addresses.push((forward_path.0, forward_path.1.address)),
+        match forward_paths_grouped.iter_mut().find(|p| {
+            p.mx_addresses == forward_path.1.mx_addresses && p.tls_requirement == forward_path.1.tls_requirement &&
+                p.dnssec_mx == forward_path.1.dnssec_mx && p.sts_policy == forward_path.1.sts_policy
+        }) {
+            Some(p) => {
+                if !p.policy_domains.contains(&forward_path.1.address.domain) {
+                    p.policy_domains.push(forward_path.1.address.domain.clone());
+                }
+                p.addresses.push((forward_path.0, forward_path.1.address))
+            },
             None => forward_paths_grouped.push(ForwardPaths {
+                policy_domains: vec![forward_path.1.address.domain.clone()],
                 addresses: vec![(forward_path.0, forward_path.1.address)],
-                mx_addresses: forward_path.1.mx_addresses
+                mx_addresses: forward_path.1.mx_addresses,
+                tls_requirement: forward_path.1.tls_requirement,
+                dnssec_mx: forward_path.1.dnssec_mx,
+                sts_policy: forward_path.1.sts_policy,
             })
         }
     }
@@ -126,19 +159,91 @@ pub async fn send_mail(reverse_path: &str, forward_paths: &[&str], data: &[u8],
         let addresses = forward_path.addresses.into_iter().map(|a| a.1).collect::<Vec<_>>();
         let mut last_error = None;
         for mx_address in forward_path.mx_addresses {
-            match try_send_mail(reverse_path, &addresses[..], &data, mx_address).await {
-                Ok(_) => break,
+            // DANE takes priority over any MTA-STS policy, as per RFC 8461 § 2
+            let tls_requirement = if forward_path.dnssec_mx {
+                match crate::dane::lookup_tlsa(&mx_address.domain, config).await {
+                    Ok(Some(records)) => TlsRequirement::Dane(records),
+                    Ok(None) => forward_path.tls_requirement.clone(),
+                    Err(e) => {
+                        warn!("Skipping MX {}: {}", mx_address.domain, e);
+                        last_error = Some(SendingError::TransientError(e));
+                        continue;
+                    }
+                }
+            } else {
+                forward_path.tls_requirement.clone()
+            };
+
+            let recorder = crate::tls_rpt::SessionRecorder {
+                policy_domains: &forward_path.policy_domains,
+                policy: match (&tls_requirement, &forward_path.sts_policy) {
+                    (TlsRequirement::Dane(records), _) => crate::tls_rpt::Policy::tlsa(records),
+                    (_, Some(policy)) => crate::tls_rpt::Policy::sts(policy),
+                    _ => crate::tls_rpt::Policy::none()
+                },
+                config,
+            };
+
+            let permit = match config.throttle.acquire(&forward_path.policy_domains, &mx_address.domain) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("Deferring delivery via {}: {}", mx_address.domain, e);
+                    last_error = Some(SendingError::Deferred(e));
+                    break;
+                }
+            };
+
+            let mut log = crate::attempt::AttemptLog::new();
+            let mut result = try_send_mail(
+                reverse_path, &addresses[..], &data, &mx_address, &tls_requirement, &recorder, true, config, &mut log
+            ).await;
+            if tls_requirement == TlsRequirement::OpportunisticFallbackPlain {
+                if let Err(SendingError::TlsError(e)) = &result {
+                    warn!("TLS with {} failed, retrying in plaintext: {}", mx_address.domain, e);
+                    record_attempt(&mut attempts, &indexes, &mx_address, log, &result);
+                    log = crate::attempt::AttemptLog::new();
+                    log.note("Retrying in plaintext");
+                    result = try_send_mail(
+                        reverse_path, &addresses[..], &data, &mx_address, &tls_requirement, &recorder, false, config, &mut log
+                    ).await;
+                }
+            }
+            record_attempt(&mut attempts, &indexes, &mx_address, log, &result);
+
+            let throttled = match &result {
+                Ok(results) => results.iter().any(|r| match r {
+                    Err(SendingError::Throttled(_)) => true,
+                    _ => false
+                }),
+                Err(SendingError::Throttled(_)) => true,
+                Err(_) => false
+            };
+            if throttled {
+                config.throttle.throttled(&permit);
+            } else if result.is_ok() {
+                config.throttle.succeeded(&permit);
+            }
+
+            match result {
+                Ok(results) => {
+                    for (index, result) in indexes.iter().zip(results.into_iter()) {
+                        out_list[*index] = result;
+                    }
+                    last_error = None;
+                    break;
+                },
                 Err(SendingError::PermanentError(s)) => {
                     error!("Permanent error sending message: {}", s);
                     last_error = Some(SendingError::PermanentError(s));
                     break;
                 },
-                Err(SendingError::TransientError(s)) => {
-                    warn!("Transient error sending message: {}", s);
-                    last_error = Some(SendingError::TransientError(s));
+                Err(e @ SendingError::TransientError(_)) | Err(e @ SendingError::Throttled(_)) => {
+                    warn!("Error sending message: {}", e);
+                    last_error = Some(e);
                     break;
                 },
                 Err(e) => {
+                    warn!("Error sending message, trying next MX: {}", e);
                     last_error = Some(e)
                 }
             }
@@ -150,7 +255,148 @@ pub async fn send_mail(reverse_path: &str, forward_paths: &[&str], data: &[u8],
         }
     }
 
-    out_list
+    out_list.into_iter().zip(attempts.into_iter()).collect()
+}
+
+fn record_attempt(
+    attempts: &mut [Vec<crate::attempt::Attempt>], indexes: &[usize], mx_address: &MXAddress,
+    mut log: crate::attempt::AttemptLog, result: &Result<Vec<Result<Delivery, SendingError>>, SendingError>
+) {
+    let errors = match result {
+        Ok(results) => results.iter().map(|r| r.as_ref().err().map(|e| e.to_string())).collect::<Vec<_>>(),
+        Err(e) => {
+            log.note(format!("Error: {}", e));
+            vec![Some(e.to_string()); indexes.len()]
+        }
+    };
+    for (index, attempt) in indexes.iter().zip(log.finish(&mx_address.domain, mx_address.address, &errors)) {
+        attempts[*index].push(attempt);
+    }
+}
+
+async fn resolve_forward_path(path: &str, config: &crate::Config) -> Result<ForwardPath, SendingError> {
+    let mut parts = path.rsplitn(2,'@');
+    let domain = parts.next().unwrap();
+    let local_part = match parts.next() {
+        Some(l) => l,
+        None => return Err(SendingError::InvalidAddress)
+    };
+    let mut tls_requirement = TlsRequirement::Opportunistic;
+    let mut dnssec_mx = false;
+    let mut sts_policy = None;
+    let mut addr = match std::net::IpAddr::from_str(domain) {
+        Ok(a) => vec![MXAddress {
+            address: a,
+            domain: a.to_string()
+        }],
+        Err(_) => {
+            let mut addr = resolve_mx_addresses(domain, config).await?;
+            // A locally configured policy takes priority over anything the domain publishes
+            if let Some(policy) = crate::tls_policy::get_policy(domain, config).await {
+                tls_requirement = match policy.mode {
+                    crate::schema::OutboundTlsMode::Opportunistic => TlsRequirement::Opportunistic,
+                    crate::schema::OutboundTlsMode::OpportunisticFallbackPlain => TlsRequirement::OpportunisticFallbackPlain,
+                    crate::schema::OutboundTlsMode::VerifyRequired => TlsRequirement::Verified,
+                    crate::schema::OutboundTlsMode::Pinned => match policy.fingerprint.as_deref().and_then(crate::tls_policy::parse_fingerprint) {
+                        Some(f) => TlsRequirement::Pinned(f),
+                        None => return Err(SendingError::TransientError(format!("Invalid pinned fingerprint for {}", policy.domain)))
+                    }
+                };
+                info!("Using local TLS policy {} ({:?}) for {}", policy.domain, policy.mode, domain);
+            } else {
+                dnssec_mx = crate::dane::mx_is_secure(domain, config).await;
+                if let Some(policy) = crate::mta_sts::get_policy(domain, config).await {
+                    match policy.mode {
+                        crate::schema::MtaStsMode::Enforce => {
+                            addr.retain(|a| policy.matches_mx(&a.domain));
+                            if addr.is_empty() {
+                                return Err(SendingError::TransientError(format!("No MX for {} matches its MTA-STS policy", domain)));
+                            }
+                            tls_requirement = TlsRequirement::Verified;
+                        },
+                        crate::schema::MtaStsMode::Testing => {
+                            for a in addr.iter().filter(|a| !policy.matches_mx(&a.domain)) {
+                                warn!("MX {} for {} doesn't match its MTA-STS policy", a.domain, domain);
+                            }
+                        },
+                        crate::schema::MtaStsMode::None => {}
+                    }
+                    if policy.mode != crate::schema::MtaStsMode::None {
+                        sts_policy = Some(policy);
+                    }
+                }
+            }
+            addr
+        }
+    };
+    addr.sort_by_key(|a| match a.address {
+        std::net::IpAddr::V6(_) => 0,
+        std::net::IpAddr::V4(_) => 1
+    });
+    Ok(ForwardPath {
+        address: Address {
+            local_part: local_part.to_string(),
+            domain: domain.to_string(),
+        },
+        mx_addresses: addr,
+        tls_requirement,
+        dnssec_mx,
+        sts_policy,
+    })
+}
+
+pub fn is_no_records(err: &trust_dns_resolver::error::ResolveError) -> bool {
+    match err.kind() {
+        trust_dns_resolver::error::ResolveErrorKind::NoRecordsFound { .. } => true,
+        _ => false
+    }
+}
+
+async fn resolve_mx_addresses(domain: &str, config: &crate::Config) -> Result<Vec<MXAddress>, SendingError> {
+    let mut mxs = match config.resolver.mx_lookup(domain).await {
+        Ok(r) => r.iter().cloned().collect::<Vec<_>>(),
+        // The resolver doesn't distinguish NXDOMAIN from an empty answer, but either way per
+        // RFC 5321 § 5.1 the domain itself is treated as an implicit MX. If the domain doesn't
+        // exist the address lookup will fail too.
+        Err(e) if is_no_records(&e) => {
+            return match config.resolver.lookup_ip(domain).await {
+                Ok(r) => Ok(r.into_iter().map(|a| MXAddress {
+                    address: a,
+                    domain: domain.trim_end_matches('.').to_string(),
+                }).collect()),
+                Err(e) if is_no_records(&e) => Err(SendingError::InvalidAddress),
+                Err(e) => Err(SendingError::TransientError(format!("Address lookup for {} failed: {}", domain, e)))
+            };
+        },
+        Err(e) => return Err(SendingError::TransientError(format!("MX lookup for {} failed: {}", domain, e)))
+    };
+
+    // RFC 7505 null MX
+    if mxs.iter().any(|mx| mx.exchange().is_root()) {
+        return Err(SendingError::PermanentError(format!("{} does not accept mail (null MX)", domain)));
+    }
+
+    mxs.sort_by_key(|mx| mx.preference());
+    let mut addrs = vec![];
+    let mut lookup_error = None;
+    for mx in mxs {
+        match config.resolver.lookup_ip(mx.exchange().to_owned()).await {
+            Ok(r) => addrs.extend(r.into_iter().map(|a| MXAddress {
+                address: a,
+                domain: mx.exchange().to_string().trim_end_matches('.').to_string(),
+            })),
+            Err(e) if is_no_records(&e) => {},
+            Err(e) => lookup_error = Some(format!("Address lookup for {} failed: {}", mx.exchange(), e))
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err(match lookup_error {
+            Some(e) => SendingError::TransientError(e),
+            None => SendingError::InvalidAddress
+        });
+    }
+    Ok(addrs)
 }
 
 struct SessionState {
@@ -160,58 +406,353 @@ struct SessionState {
     starttls_support: bool,
 }
 
-async fn try_send_mail(reverse_path: &str, addresses: &[Address], data: &[u8], mx_address: MXAddress) -> Result<(), SendingError> {
-    let s = tokio::net::TcpStream::connect((mx_address.address, 25)).await?;
-    let mut stream = tokio::io::BufStream::new(s);
+/// How long an idle session is kept open for reuse
+const SESSION_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// How many messages are sent over one session before it's closed
+const MAX_MESSAGES_PER_SESSION: u32 = 100;
+
+/// A connection to an MX, either in plaintext or after STARTTLS
+enum ClientStream {
+    Plain(tokio::net::TcpStream),
+    Tls(tokio_openssl::SslStream<tokio::net::TcpStream>),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut [u8]
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+            Self::Tls(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+            Self::Tls(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => std::pin::Pin::new(s).poll_flush(cx),
+            Self::Tls(s) => std::pin::Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+            Self::Tls(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A session with an MX that's been greeted and had any TLS negotiated, ready to start a transaction
+struct SmtpSession {
+    stream: tokio::io::BufStream<ClientStream>,
+    state: SessionState,
+    tls_summary: String,
+    messages_sent: u32,
+    last_used: std::time::Instant,
+}
+
+impl SmtpSession {
+    fn new(stream: tokio::io::BufStream<ClientStream>, state: SessionState, tls_summary: String) -> Self {
+        Self {
+            stream,
+            state,
+            tls_summary,
+            messages_sent: 0,
+            last_used: std::time::Instant::now(),
+        }
+    }
+
+    /// Clears any state left from the last transaction, which also checks the server is still there
+    async fn reset(&mut self, log: &mut crate::attempt::AttemptLog) -> Result<(), SendingError> {
+        let resp = send_command(&mut self.stream, SMTPCommand::new("RSET", &[]), log).await?;
+        match resp.code {
+            250 => Ok(()),
+            _ => Err(SendingError::ConnectionError(resp.format_resp()))
+        }
+    }
+}
+
+/// Sessions are only reused for deliveries with the same TLS requirement, as that's what the
+/// server was authenticated against when the session was set up
+#[derive(PartialEq)]
+struct SessionKey {
+    address: std::net::IpAddr,
+    domain: String,
+    tls_requirement: TlsRequirement,
+    use_starttls: bool,
+}
+
+/// Idle sessions with MXs, kept open for reuse by later deliveries
+#[derive(Default)]
+pub struct ConnectionPool {
+    sessions: std::sync::Mutex<Vec<(SessionKey, SmtpSession)>>,
+}
+
+impl ConnectionPool {
+    fn take(&self, key: &SessionKey) -> Option<SmtpSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let i = sessions.iter().position(|(k, s)| k == key && s.last_used.elapsed() < SESSION_IDLE_TIMEOUT)?;
+        Some(sessions.swap_remove(i).1)
+    }
+
+    async fn release(&self, key: SessionKey, mut session: SmtpSession) {
+        if session.messages_sent >= MAX_MESSAGES_PER_SESSION {
+            debug!("Closing session with {} after {} messages", key.domain, session.messages_sent);
+            if let Err(e) = handle_quit(&mut session.stream).await {
+                debug!("Error closing session with {}: {}", key.domain, e);
+            }
+            return;
+        }
+        session.last_used = std::time::Instant::now();
+        self.sessions.lock().unwrap().push((key, session));
+    }
+
+    async fn close_idle(&self) {
+        let idle = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let (idle, active): (Vec<_>, Vec<_>) = sessions.drain(..)
+                .partition(|(_, s)| s.last_used.elapsed() >= SESSION_IDLE_TIMEOUT);
+            *sessions = active;
+            idle
+        };
+
+        for (key, mut session) in idle {
+            debug!("Closing idle session with {}", key.domain);
+            if let Err(e) = handle_quit(&mut session.stream).await {
+                debug!("Error closing session with {}: {}", key.domain, e);
+            }
+        }
+    }
+}
+
+pub async fn close_idle_sessions_task(config: crate::Config) {
+    loop {
+        tokio::time::delay_for(std::time::Duration::new(10, 0)).await;
+        config.pool.close_idle().await;
+    }
+}
 
-    let data = match mailparse::parse_mail(data) {
-        Ok(m) => m,
-        Err(e) => return Err(SendingError::InvalidMessage(e.to_string()))
+async fn try_send_mail(
+    reverse_path: &str, addresses: &[Address], data: &[u8], mx_address: &MXAddress, tls_requirement: &TlsRequirement,
+    recorder: &crate::tls_rpt::SessionRecorder<'_>, use_starttls: bool, config: &crate::Config,
+    log: &mut crate::attempt::AttemptLog
+) -> Result<Vec<Result<Delivery, SendingError>>, SendingError> {
+    if let Err(e) = mailparse::parse_mail(data) {
+        return Err(SendingError::InvalidMessage(e.to_string()));
+    }
+
+    let key = SessionKey {
+        address: mx_address.address,
+        domain: mx_address.domain.clone(),
+        tls_requirement: tls_requirement.clone(),
+        use_starttls,
+    };
+    let mut session = match config.pool.take(&key) {
+        Some(mut session) => {
+            log.note(format!("Reusing session ({})", session.tls_summary));
+            match session.reset(log).await {
+                Ok(_) => {
+                    debug!("Reusing session with {}", mx_address.domain);
+                    session
+                },
+                Err(e) => {
+                    debug!("Pooled session with {} no longer usable: {}", mx_address.domain, e);
+                    log.note(format!("Pooled session no longer usable: {}", e));
+                    log.enter(crate::schema::DeliveryStage::Connect);
+                    connect_session(mx_address, tls_requirement, recorder, use_starttls, log).await?
+                }
+            }
+        },
+        None => connect_session(mx_address, tls_requirement, recorder, use_starttls, log).await?
     };
+    log.set_tls(&session.tls_summary);
 
+    let results = handle_send_mail(&mut session.stream, reverse_path, addresses, data, &session.state, log).await?;
+    session.messages_sent += 1;
+    let results = map_delivery_results(addresses, results, mx_address, &session.tls_summary);
+    config.pool.release(key, session).await;
+    Ok(results)
+}
+
+async fn connect_session(
+    mx_address: &MXAddress, tls_requirement: &TlsRequirement, recorder: &crate::tls_rpt::SessionRecorder<'_>,
+    use_starttls: bool, log: &mut crate::attempt::AttemptLog
+) -> Result<SmtpSession, SendingError> {
+    log.note(format!("Connecting to {} ({})", mx_address.domain, mx_address.address));
+    let s = tokio::net::TcpStream::connect((mx_address.address, 25)).await?;
+    let sending_ip = s.local_addr().ok().map(|a| a.ip());
+    let record_session = |failure: Option<&crate::tls_rpt::Failure>| {
+        recorder.record(&mx_address.domain, mx_address.address, sending_ip, failure)
+    };
+    let mut stream = tokio::io::BufStream::new(s);
 
-    let banner = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+    log.enter(crate::schema::DeliveryStage::Banner);
+    let banner = read_response(&mut stream, log).await?;
     match banner.code {
         220 => {},
         554 => return Err(SendingError::PermanentError(banner.format_resp())),
-        421 => return Err(SendingError::TransientError(banner.format_resp())),
+        421 => return Err(transient_error(&banner)),
         _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
     }
     info!("Connected to {}", banner.lines[0]);
 
-    let state = handle_helo(&mut stream).await?;
+    let state = handle_helo(&mut stream, log).await?;
 
-    if state.starttls_support {
-        stream.write(SMTPCommand::new("STARTTLS", &[]).to_string().as_bytes()).await?;
-        stream.flush().await?;
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+    if state.starttls_support && use_starttls {
+        let resp = send_command(&mut stream, SMTPCommand::new("STARTTLS", &[]), log).await?;
         match resp.code {
             220 => {
                 debug!("STARTTLS response: {}", resp.format_resp());
             },
-            500 | 501 => return Err(SendingError::PermanentError(resp.format_resp())),
-            421 | 454 => return Err(SendingError::TransientError(resp.format_resp())),
-            _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
+            c => {
+                record_session(Some(&crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::StarttlsNotSupported, resp.format_resp()
+                )));
+                match c {
+                    500 | 501 => return Err(SendingError::PermanentError(resp.format_resp())),
+                    421 | 454 => return Err(SendingError::TransientError(resp.format_resp())),
+                    _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
+                }
+            }
+        }
+        let connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())?.build();
+        let mut ssl_config = connector.configure()?;
+        // The certificate is checked against the TLS requirement once the handshake is done,
+        // as DANE authentication doesn't follow the usual PKIX rules
+        ssl_config.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        let new_stream = match tokio_openssl::connect(ssl_config, &mx_address.domain, stream.into_inner()).await {
+            Ok(s) => s,
+            Err(e) => {
+                log.note(format!("TLS handshake failed: {}", e));
+                record_session(Some(&crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::ValidationFailure, e.to_string()
+                )));
+                return Err(SendingError::TlsError(format!("TLS handshake with {} failed: {}", mx_address.domain, e)));
+            }
+        };
+        let verification = check_tls_session(new_stream.ssl(), mx_address, tls_requirement);
+        record_session(verification.as_ref().err());
+        let tls_summary = format!(
+            "{} {}, {}",
+            new_stream.ssl().version_str(),
+            new_stream.ssl().current_cipher().map(|c| c.name()).unwrap_or("unknown cipher"),
+            match &verification {
+                Ok(_) => "verified".to_string(),
+                Err(f) => format!("unverified: {}", f.reason)
+            }
+        );
+        log.note(format!("TLS established: {}", tls_summary));
+        log.set_tls(&tls_summary);
+        if let Err(failure) = verification {
+            if tls_requirement.requires_tls() {
+                return Err(SendingError::TlsError(format!("{}: {}", mx_address.domain, failure.reason)));
+            }
         }
-        let connector: tokio_native_tls::TlsConnector = native_tls::TlsConnector::builder()
-            .build()?
-            .into();
-        let new_stream = connector.connect(&mx_address.domain, stream.into_inner()).await?;
-        info!("Connected with STARTTLS");
-        let mut stream = tokio::io::BufStream::new(new_stream);
+        info!("Connected to {} with STARTTLS ({})", mx_address.domain, tls_summary);
+        let mut stream = tokio::io::BufStream::new(ClientStream::Tls(new_stream));
 
-        let state = handle_helo(&mut stream).await?;
-        handle_send_mail(&mut stream, reverse_path, addresses, data, &state).await?;
+        let state = handle_helo(&mut stream, log).await?;
+        Ok(SmtpSession::new(stream, state, tls_summary))
     } else {
-        handle_send_mail(&mut stream, reverse_path, addresses, data, &state).await?;
+        // When falling back to plaintext the TLS failure has already been recorded
+        if use_starttls {
+            record_session(Some(&crate::tls_rpt::Failure::new(
+                crate::tls_rpt::ResultType::StarttlsNotSupported, "STARTTLS not offered"
+            )));
+        }
+        if tls_requirement.requires_tls() {
+            return Err(SendingError::TlsError(format!("{} doesn't offer STARTTLS but policy requires TLS", mx_address.domain)));
+        }
+        let stream = tokio::io::BufStream::new(ClientStream::Plain(stream.into_inner()));
+        Ok(SmtpSession::new(stream, state, "no TLS".to_string()))
     }
+}
 
-    info!("Email successfully delivered to {}", mx_address.domain);
+fn check_tls_session(
+    ssl: &openssl::ssl::SslRef, mx_address: &MXAddress, tls_requirement: &TlsRequirement
+) -> Result<(), crate::tls_rpt::Failure> {
+    match tls_requirement {
+        TlsRequirement::Opportunistic | TlsRequirement::OpportunisticFallbackPlain | TlsRequirement::Verified => {
+            let result = ssl.verify_result();
+            if result != openssl::x509::X509VerifyResult::OK {
+                let result_type = match result.as_raw() {
+                    // X509_V_ERR_CERT_NOT_YET_VALID, X509_V_ERR_CERT_HAS_EXPIRED
+                    9 | 10 => crate::tls_rpt::ResultType::CertificateExpired,
+                    // X509_V_ERR_HOSTNAME_MISMATCH
+                    62 => crate::tls_rpt::ResultType::CertificateHostMismatch,
+                    _ => crate::tls_rpt::ResultType::CertificateNotTrusted
+                };
+                return Err(crate::tls_rpt::Failure::new(
+                    result_type, format!("Certificate failed verification: {}", result.error_string())
+                ));
+            }
+        },
+        TlsRequirement::Pinned(fingerprint) => {
+            let cert = match ssl.peer_certificate() {
+                Some(c) => c,
+                None => return Err(crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::ValidationFailure, "No certificate presented"
+                ))
+            };
+            let digest = cert.digest(openssl::hash::MessageDigest::sha256())
+                .map_err(|e| crate::tls_rpt::Failure::new(crate::tls_rpt::ResultType::ValidationFailure, e.to_string()))?;
+            if &digest[..] != &fingerprint[..] {
+                return Err(crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::ValidationFailure, "Certificate doesn't match pinned fingerprint"
+                ));
+            }
+        },
+        TlsRequirement::Dane(records) => {
+            let chain = match ssl.peer_cert_chain() {
+                Some(c) => c,
+                None => return Err(crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::ValidationFailure, "No certificate presented"
+                ))
+            };
+            crate::dane::verify_chain(records, chain, &mx_address.domain)
+                .map_err(|e| crate::tls_rpt::Failure::new(
+                    crate::tls_rpt::ResultType::ValidationFailure, format!("DANE authentication failed: {}", e)
+                ))?;
+            info!("DANE authenticated {}", mx_address.domain);
+        }
+    }
     Ok(())
 }
 
+fn map_delivery_results(
+    addresses: &[Address], results: Vec<Result<Option<String>, SendingError>>, mx_address: &MXAddress, tls_summary: &str
+) -> Vec<Result<Delivery, SendingError>> {
+    addresses.iter().zip(results.into_iter()).map(|(address, result)| match result {
+        Ok(queue_id) => {
+            info!(
+                "Email successfully delivered to {}@{} via {} (queue ID {}, {})",
+                address.local_part, address.domain, mx_address.domain, queue_id.as_deref().unwrap_or("unknown"), tls_summary
+            );
+            Ok(Delivery {
+                mx_domain: mx_address.domain.clone(),
+                queue_id,
+            })
+        },
+        Err(e) => {
+            warn!("Email to {}@{} rejected by {}: {}", address.local_part, address.domain, mx_address.domain, e);
+            Err(e)
+        }
+    }).collect()
+}
+
 async fn handle_helo<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
-    mut stream: &mut T
+    mut stream: &mut T, log: &mut crate::attempt::AttemptLog
 ) -> Result<SessionState, SendingError> {
     let mut state = SessionState {
         utf8_support: false,
@@ -220,9 +761,7 @@ async fn handle_helo<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
         starttls_support: false,
     };
 
-    stream.write(SMTPCommand::new("EHLO", &["relay-mx.as207960.net"]).to_string().as_bytes()).await?;
-    stream.flush().await?;
-    let greeting = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+    let greeting = send_command(&mut stream, SMTPCommand::new("EHLO", &["relay-mx.as207960.net"]), log).await?;
     match greeting.code {
         250 => {
             let extensions = &greeting.lines[1..];
@@ -238,9 +777,7 @@ async fn handle_helo<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
             state.starttls_support = extensions.contains(&"STARTTLS".to_string());
         },
         502 => {
-            stream.write(SMTPCommand::new("HELO", &["relay-mx.as207960.net"]).to_string().as_bytes()).await?;
-            stream.flush().await?;
-            let greeting = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+            let greeting = send_command(&mut stream, SMTPCommand::new("HELO", &["relay-mx.as207960.net"]), log).await?;
             match greeting.code {
                 250 => {
                     debug!("Greeting: {}", greeting.lines[0]);
@@ -250,7 +787,7 @@ async fn handle_helo<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
             }
         }
         500 | 501 | 550 => return Err(SendingError::PermanentError(greeting.format_resp())),
-        421 => return Err(SendingError::TransientError(greeting.format_resp())),
+        421 => return Err(transient_error(&greeting)),
         _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
     }
 
@@ -258,99 +795,180 @@ async fn handle_helo<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
 }
 
 async fn handle_send_mail<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
-    mut stream: &mut T, reverse_path: &str, addresses: &[Address], mut data: mailparse::ParsedMail<'_>, state: &SessionState
-) -> Result<(), SendingError> {
+    mut stream: &mut T, reverse_path: &str, addresses: &[Address], data: &[u8], state: &SessionState,
+    log: &mut crate::attempt::AttemptLog
+) -> Result<Vec<Result<Option<String>, SendingError>>, SendingError> {
     let mut args = vec![format!("FROM:<{}>", reverse_path)];
     if state.utf8_support {
         args.push("BODY=8BITMIME".to_string());
     }
-    stream.write(SMTPCommand::new("MAIL", &args.iter().map(|x| x.as_ref()).collect::<Vec<_>>()).to_string().as_bytes()).await?;
-    stream.flush().await?;
-    let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+    let resp = send_command(&mut stream, SMTPCommand::new("MAIL", &args.iter().map(|x| x.as_ref()).collect::<Vec<_>>()), log).await?;
     match resp.code {
         250 => {
             debug!("MAIL response: {}", resp.format_resp());
         },
         500 | 501 | 550 | 552 | 553 | 555 => return Err(SendingError::PermanentError(resp.format_resp())),
-        421 | 451 | 452 | 455 => return Err(SendingError::TransientError(resp.format_resp())),
+        421 | 451 | 452 | 455 => return Err(transient_error(&resp)),
         _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
     }
 
+    let mut rcpt_results = vec![];
     for address in addresses {
-        stream.write(SMTPCommand::new("RCPT", &[&format!("TO:<{}@{}>", address.local_part, address.domain)]).to_string().as_bytes()).await?;
-        stream.flush().await?;
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
-        match resp.code {
+        let resp = send_command(&mut stream, SMTPCommand::new("RCPT", &[&format!("TO:<{}@{}>", address.local_part, address.domain)]), log).await?;
+        rcpt_results.push(match resp.code {
             250 | 251 => {
                 debug!("RCPT response: {}", resp.format_resp());
+                Ok(())
             },
-            500 | 501 | 550 | 551 | 552 | 553 | 555 | 503 => return Err(SendingError::PermanentError(resp.format_resp())),
-            421 | 450 | 451 | 452 | 453 | 455 => return Err(SendingError::TransientError(resp.format_resp())),
-            _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
-        }
+            500 | 501 | 550 | 551 | 552 | 553 | 555 | 503 => Err(SendingError::PermanentError(resp.format_resp())),
+            // 421 means the server is closing the channel, so there's no continuing the transaction
+            421 => return Err(transient_error(&resp)),
+            450 | 451 | 452 | 453 | 455 => Err(transient_error(&resp)),
+            _ => Err(SendingError::PermanentError("Bad status code".to_string()))
+        });
     }
 
-    let body_data = encode_body_part(&state, &mut data);
-    if state.chunking_support {
-        let mut headers = vec![];
-        for header in &data.headers {
-            headers.extend(format!("{}: {}\r\n", header.get_key(), encode_header(&header.get_value())).as_bytes());
-        }
-        headers.extend("\r\n".bytes());
-        stream.write(SMTPCommand::new("BDAT", &[&format!("{}", headers.len())]).to_string().as_bytes()).await?;
+    if !rcpt_results.iter().any(|r| r.is_ok()) {
+        debug!("No recipients accepted, not sending data");
+        return Ok(rcpt_results.into_iter().map(|r| r.map(|_| None)).collect());
+    }
+
+    let (headers, body_data) = encode_part(&state, data)
+        .map_err(|e| SendingError::InvalidMessage(e.to_string()))?;
+    let data_result = if state.chunking_support {
+        let command = SMTPCommand::new("BDAT", &[&format!("{}", headers.len())]);
+        log.command(&command);
+        log.note(format!("{} bytes of message headers", headers.len()));
+        stream.write(command.to_string().as_bytes()).await?;
         stream.write(&headers).await?;
         stream.flush().await?;
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+        let resp = read_response(&mut stream, log).await?;
         match resp.code {
             250 => {
                 debug!("BDAT response: {}", resp.format_resp());
             },
-            500 | 501 | 503 | 554 => return Err(SendingError::PermanentError(resp.format_resp())),
-            421 => return Err(SendingError::TransientError(resp.format_resp())),
+            400..=499 => return Err(transient_error(&resp)),
+            500..=599 => return Err(SendingError::PermanentError(resp.format_resp())),
             _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
         }
 
-        stream.write(SMTPCommand::new("BDAT", &[&format!("{}", body_data.len()), "LAST"]).to_string().as_bytes()).await?;
+        let command = SMTPCommand::new("BDAT", &[&format!("{}", body_data.len()), "LAST"]);
+        log.command(&command);
+        log.note(format!("{} bytes of message body", body_data.len()));
+        stream.write(command.to_string().as_bytes()).await?;
         stream.write(&body_data).await?;
         stream.flush().await?;
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
-        match resp.code {
-            250 => {
-                debug!("BDAT response: {}", resp.format_resp());
-            },
-            500 | 501 | 503 | 554 => return Err(SendingError::PermanentError(resp.format_resp())),
-            421 => return Err(SendingError::TransientError(resp.format_resp())),
-            _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
-        }
+        let resp = read_response(&mut stream, log).await?;
+        debug!("BDAT LAST response: {}", resp.format_resp());
+        handle_data_end_response(&resp)
     } else {
-        stream.write(SMTPCommand::new("DATA", &[]).to_string().as_bytes()).await?;
-        stream.flush().await?;
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+        let resp = send_command(&mut stream, SMTPCommand::new("DATA", &[]), log).await?;
         match resp.code {
             354 => {
                 debug!("DATA response: {}", resp.format_resp());
             },
-            500 | 501 | 503 | 554 => return Err(SendingError::PermanentError(resp.format_resp())),
-            421 => return Err(SendingError::TransientError(resp.format_resp())),
+            400..=499 => return Err(transient_error(&resp)),
+            500..=599 => return Err(SendingError::PermanentError(resp.format_resp())),
             _ => return Err(SendingError::PermanentError("Bad status code".to_string()))
         }
-        for header in &data.headers {
-            send_data(&mut stream, format!("{}: {}\r\n", header.get_key(), encode_header(&header.get_value())).as_bytes()).await?;
-        }
-        stream.write(b"\r\n").await?;
-        send_data(&mut stream, &body_data).await?;
+        send_data(&mut stream, &[&headers[..], &body_data[..]].concat()).await?;
         stream.write(b"\r\n.\r\n").await?;
         stream.flush().await?;
+        log.note(format!("Message headers and {} bytes of message body", body_data.len()));
 
-        let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+        let resp = read_response(&mut stream, log).await?;
         debug!("DATA end response: {}", resp.format_resp());
+        handle_data_end_response(&resp)
+    };
+
+    Ok(rcpt_results.into_iter().map(|r| r.and_then(|_| data_result.clone())).collect())
+}
+
+/// Enhanced status codes servers use to say they're rate limiting us, RFC 3463's "mail system
+/// congestion" and Gmail's rate limit code
+const THROTTLING_STATUS_CODES: &[&str] = &["4.4.5", "4.7.28"];
+/// Reply wording servers use to say they're rate limiting us
+const THROTTLING_WORDING: &[&str] = &["rate limit", "rate-limit", "too many", "throttl"];
+
+/// Distinguishes rate limiting from other temporary failures. Servers don't have a standard way
+/// of saying so, so it's only taken as such when the enhanced status code or the reply text says
+/// so. Most 421s are just the server going away, and are plain transient errors.
+fn transient_error(resp: &SMTPResponse) -> SendingError {
+    let text = resp.lines.join(" ").to_lowercase();
+    let status_code = resp.lines.first().and_then(|l| l.split_whitespace().next()).unwrap_or_default();
+    if THROTTLING_STATUS_CODES.contains(&status_code) || THROTTLING_WORDING.iter().any(|m| text.contains(m)) {
+        SendingError::Throttled(resp.format_resp())
+    } else {
+        SendingError::TransientError(resp.format_resp())
+    }
+}
+
+/// Classifies the reply to the end of the message by its reply class, as servers use all sorts of
+/// codes here beyond the ones RFC 5321 lists
+fn handle_data_end_response(resp: &SMTPResponse) -> Result<Option<String>, SendingError> {
+    match resp.code {
+        250 => Ok(parse_queue_id(resp)),
+        400..=499 => Err(transient_error(&resp)),
+        500..=599 => Err(SendingError::PermanentError(resp.format_resp())),
+        _ => Err(SendingError::PermanentError("Bad status code".to_string()))
+    }
+}
+
+/// Extracts the remote queue ID from an end of data reply, for the reply formats of the common
+/// servers. Unrecognised replies give no ID, rather than taking the whole reply as one.
+fn parse_queue_id(resp: &SMTPResponse) -> Option<String> {
+    let line = resp.lines.first()?.trim();
+    // Skip over any enhanced status code
+    let mut parts = line.splitn(2, ' ');
+    let line = match (parts.next(), parts.next()) {
+        (Some(code), Some(rest)) if code.split('.').count() == 3 && code.split('.').all(|p| p.parse::<u16>().is_ok()) => rest.trim(),
+        _ => line
+    };
+
+    // Postfix, Exim, and Exchange
+    let line_lower = line.to_lowercase();
+    for marker in &["queued as ", "id="] {
+        if let Some(pos) = line_lower.find(marker) {
+            let id = line[pos + marker.len()..].split_ascii_whitespace().next().unwrap_or_default()
+                .trim_end_matches(|c: char| c == ')' || c == ',' || c == '.' || c == ']');
+            if !id.is_empty() {
+                return Some(id.to_string());
+            }
+        }
+    }
+
+    let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        // Gmail
+        [.., id, "-", "gsmtp"] => Some(id.to_string()),
+        // Sendmail
+        [id, "Message", "accepted", "for", "delivery"] => Some(id.to_string()),
+        _ => None
     }
+}
+
+async fn send_command<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(
+    stream: &mut T, command: SMTPCommand, log: &mut crate::attempt::AttemptLog
+) -> Result<SMTPResponse, SendingError> {
+    log.command(&command);
+    stream.write(command.to_string().as_bytes()).await?;
+    stream.flush().await?;
+    read_response(stream, log).await
+}
+
+async fn read_response<T: AsyncBufRead + std::marker::Unpin>(
+    stream: &mut T, log: &mut crate::attempt::AttemptLog
+) -> Result<SMTPResponse, SendingError> {
+    let resp = SMTPResponse::parse(stream).await.map_err(|e| SendingError::ConnectionError(e))?;
+    log.response(&resp);
+    Ok(resp)
+}
 
+async fn handle_quit<T: AsyncBufRead + AsyncWrite + std::marker::Unpin>(mut stream: &mut T) -> Result<(), SendingError> {
     stream.write(SMTPCommand::new("QUIT", &[]).to_string().as_bytes()).await?;
     stream.flush().await?;
     let resp = SMTPResponse::parse(&mut stream).await.map_err(|e| SendingError::ConnectionError(e))?;
     debug!("QUIT response: {}", resp.format_resp());
-
     Ok(())
 }
 
@@ -366,61 +984,248 @@ async fn send_data<T: AsyncWrite + std::marker::Unpin>(stream: &mut T, data: &[u
     Ok(())
 }
 
-fn encode_body_part(session_state: &SessionState, part: &mut mailparse::ParsedMail) -> Vec<u8> {
-    let body = part.get_body_encoded();
+/// Splits a message, or a MIME part of one, into its header block and its body. The header block
+/// is kept verbatim, with only a part's Content-Transfer-Encoding replaced when the server can't
+/// take its body as it is and it has to be re-encoded. Multiparts are gone into to find the parts
+/// that need it, and everything else in them is kept as it was.
+fn encode_part<'a>(
+    session_state: &SessionState, data: &'a [u8]
+) -> Result<(Cow<'a, [u8]>, Cow<'a, [u8]>), mailparse::MailParseError> {
+    let mut headers = vec![];
+    let mut body_start = 0;
+    loop {
+        match &data[body_start..] {
+            [] => break,
+            [b'\n', ..] => {
+                body_start += 1;
+                break;
+            }
+            [b'\r', b'\n', ..] => {
+                body_start += 2;
+                break;
+            }
+            rest => {
+                let (header, len) = mailparse::parse_header(rest)?;
+                headers.push((header, &rest[..len]));
+                body_start += len;
+            }
+        }
+    }
+    let (header_block, body) = data.split_at(body_start);
+    let header_value = |name: &str| headers.iter()
+        .find(|h| h.0.get_key().eq_ignore_ascii_case(name))
+        .map(|h| h.0.get_value());
 
-    let body_enc = match (session_state.utf8_support, session_state.binary_support, body) {
-        (_, _, mailparse::body::Body::Base64(e)) => e.get_raw().to_vec(),
-        (_, _, mailparse::body::Body::QuotedPrintable(e)) => e.get_raw().to_vec(),
-        (_, _, mailparse::body::Body::SevenBit(e)) => e.get_raw().to_vec(),
-        (true, _, mailparse::body::Body::EightBit(e)) => e.get_raw().to_vec(),
-        (false, _, mailparse::body::Body::EightBit(e)) => {
-            let enc = quoted_printable::encode(e.get_raw());
-            part.headers.retain(|h| !h.get_key().eq_ignore_ascii_case("Content-Transfer-Encoding"));
-            part.headers.push(mailparse::parse_header(b"Content-Transfer-Encoding: quoted-printable").unwrap().0);
-            enc
-        },
-        (_, true, mailparse::body::Body::Binary(e)) => e.get_raw().to_vec(),
-        (_, false, mailparse::body::Body::Binary(e)) => {
-            let enc = quoted_printable::encode(e.get_raw());
-            part.headers.retain(|h| !h.get_key().eq_ignore_ascii_case("Content-Transfer-Encoding"));
-            part.headers.push(mailparse::parse_header(b"Content-Transfer-Encoding: quoted-printable").unwrap().0);
-            enc
-        },
+    let content_type = header_value("Content-Type").map(|c| mailparse::parse_content_type(&c)).unwrap_or_default();
+    if content_type.mimetype.starts_with("multipart/") {
+        if let Some(boundary) = content_type.params.get("boundary") {
+            let delimiter = format!("--{}", boundary);
+            let mut out = vec![];
+            let mut copied_to = 0;
+            // Parts are found the same way mailparse does, running from the line after one
+            // delimiter up to the next
+            let mut next_delimiter = find_bytes(body, 0, delimiter.as_bytes());
+            while let Some(delimiter_start) = next_delimiter {
+                let delimiter_end = delimiter_start + delimiter.len();
+                if body[delimiter_end..].starts_with(b"--") {
+                    break;
+                }
+                let part_start = match find_bytes(body, delimiter_end, b"\n") {
+                    Some(i) => i + 1,
+                    None => break
+                };
+                next_delimiter = find_bytes(body, part_start, delimiter.as_bytes());
+                let part_end = next_delimiter.unwrap_or_else(|| body.len());
+                if let (part_headers, Cow::Owned(part_body)) = encode_part(session_state, &body[part_start..part_end])? {
+                    out.extend_from_slice(&body[copied_to..part_start]);
+                    out.extend_from_slice(&part_headers);
+                    out.extend_from_slice(&part_body);
+                    copied_to = part_end;
+                }
+            }
+            if copied_to == 0 {
+                return Ok((Cow::Borrowed(header_block), Cow::Borrowed(body)));
+            }
+            out.extend_from_slice(&body[copied_to..]);
+            return Ok((Cow::Borrowed(header_block), Cow::Owned(out)));
+        }
+    }
+
+    let needs_downgrade = match header_value("Content-Transfer-Encoding").map(|e| e.trim().to_lowercase()).as_deref() {
+        Some("8bit") => !session_state.utf8_support,
+        Some("binary") => !session_state.binary_support,
+        _ => false
     };
+    if !needs_downgrade {
+        return Ok((Cow::Borrowed(header_block), Cow::Borrowed(body)));
+    }
+
+    let mut new_header_block = vec![];
+    for (header, raw) in &headers {
+        if !header.get_key().eq_ignore_ascii_case("Content-Transfer-Encoding") {
+            new_header_block.extend_from_slice(raw);
+        }
+    }
+    new_header_block.extend_from_slice(b"Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+    Ok((Cow::Owned(new_header_block), Cow::Owned(quoted_printable::encode(body))))
+}
 
-    body_enc
+fn find_bytes(haystack: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|i| i + from)
 }
 
-fn encode_header(header: &str) -> String {
-    if header.is_ascii() {
-        return header.to_string();
+pub fn encode_header(header: &str) -> String {
+    let start = match header.find(|c: char| !c.is_ascii()) {
+        Some(i) => i,
+        None => return header.to_string()
+    };
+    // Only the words with something in them that isn't ASCII are encoded, so the likes of addresses
+    // around them, which can't go in encoded-words, are left as they are
+    let start = header[..start].rfind(' ').map(|i| i + 1).unwrap_or(0);
+    let end = header.rfind(|c: char| !c.is_ascii()).unwrap();
+    let end = header[end..].find(|c: char| c.is_ascii()).map(|i| i + end).unwrap_or(header.len());
+    let end = header[end..].find(' ').map(|i| i + end).unwrap_or(header.len());
+
+    let mut out = header[..start].to_string();
+    let mut chunk = String::new();
+    for c in header[start..end].chars() {
+        // Chunks are split between characters, as encoded-words can't have half of one
+        if chunk.len() + c.len_utf8() > 48 {
+            out.extend(format!("=?utf-8?B?{}?=\r\n ", base64::encode(&chunk)).chars());
+            chunk.clear();
+        }
+        chunk.push(c);
     }
+    out.extend(format!("=?utf-8?B?{}?=", base64::encode(&chunk)).chars());
+    out.push_str(&header[end..]);
+    out
+}
 
-    let mut first = true;
-    let mut data = header.as_bytes();
-    let mut out = String::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    loop {
-        let len = data.len();
-        if !first {
-            out.extend("\r\n ".chars());
-        }
-        let cur_data = if len > 48 {
-            let d = data.split_at(48);
-            data = d.1;
-            d.0
-        } else {
-            data
+    fn queue_id(line: &str) -> Option<String> {
+        parse_queue_id(&SMTPResponse::new(250, line))
+    }
+
+    #[test]
+    fn parses_queue_ids() {
+        assert_eq!(queue_id("2.0.0 Ok: queued as 49XbHc1xJzz9sWN").as_deref(), Some("49XbHc1xJzz9sWN"));
+        assert_eq!(queue_id("OK id=1jcSbN-0003Xa-Ql").as_deref(), Some("1jcSbN-0003Xa-Ql"));
+        assert_eq!(
+            queue_id("2.6.0 <a@b> [InternalId=5334349516949, Hostname=AM0PR02MB1234.eurprd02.prod.outlook.com] Queued mail for delivery").as_deref(),
+            Some("5334349516949")
+        );
+        assert_eq!(queue_id("2.0.0 OK  1590000000 x12si3456789ejb.123 - gsmtp").as_deref(), Some("x12si3456789ejb.123"));
+        assert_eq!(queue_id("2.0.0 04N1abc123456 Message accepted for delivery").as_deref(), Some("04N1abc123456"));
+    }
+
+    #[test]
+    fn only_rate_limiting_replies_are_throttling() {
+        let throttled = |code, line| match transient_error(&SMTPResponse::new(code, line)) {
+            SendingError::Throttled(_) => true,
+            _ => false
         };
-        out.extend(format!("=?utf-8?B?{}?=", base64::encode(cur_data)).chars());
-        first = false;
-        if len <= 48 {
-            break;
+        assert!(throttled(421, "4.7.0 Try again later, closing connection. (rate limit exceeded)"));
+        assert!(throttled(450, "4.7.28 Our system has detected an unusual rate of unsolicited mail"));
+        assert!(throttled(451, "4.4.5 Server busy, try again later"));
+        assert!(throttled(452, "Too many recipients received this hour"));
+        assert!(!throttled(421, "4.3.2 Service not available, closing transmission channel"));
+        assert!(!throttled(421, "Service not available"));
+        assert!(!throttled(451, "4.7.1 Greylisted, please try again later"));
+    }
+
+    #[test]
+    fn data_end_replies_are_classified_by_reply_class() {
+        let result = |code, line| handle_data_end_response(&SMTPResponse::new(code, line));
+        assert!(matches!(result(250, "2.0.0 Ok: queued as ABC123"), Ok(Some(_))));
+        assert!(matches!(result(451, "4.3.0 Temporary lookup failure"), Err(SendingError::TransientError(_))));
+        assert!(matches!(result(447, "4.7.0 Timeout waiting for client input"), Err(SendingError::TransientError(_))));
+        assert!(matches!(result(421, "4.7.0 Rate limit exceeded"), Err(SendingError::Throttled(_))));
+        assert!(matches!(result(554, "5.7.1 Message rejected as spam"), Err(SendingError::PermanentError(_))));
+        assert!(matches!(result(521, "5.3.2 Not accepting mail"), Err(SendingError::PermanentError(_))));
+    }
+
+    fn session(utf8_support: bool) -> SessionState {
+        SessionState {
+            utf8_support,
+            binary_support: false,
+            chunking_support: false,
+            starttls_support: false,
         }
     }
 
-    println!("{:?} {:?}", header, out);
+    fn encode(utf8_support: bool, data: &[u8]) -> Vec<u8> {
+        let (headers, body) = encode_part(&session(utf8_support), data).unwrap();
+        [&headers[..], &body[..]].concat()
+    }
+
+    const MULTIPART: &[u8] = b"From: =?utf-8?q?J=C3=B6rg?= <jorg@example.com>\r\n\
+        Subject: A long subject that has been\r\n folded onto a second line\r\n\
+        Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+        \r\n\
+        Preamble\r\n\
+        --b\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 8bit\r\n\
+        \r\n\
+        Gr\xc3\xbc\xc3\x9fe\r\n\
+        --b\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        Plain\r\n\
+        --b--\r\n\
+        Epilogue\r\n";
 
-    out
+    #[test]
+    fn only_non_ascii_words_are_encoded() {
+        assert_eq!(encode_header("Alice <alice@example.com>"), "Alice <alice@example.com>");
+        assert_eq!(
+            encode_header("Ren\u{e9}e Dupont <renee@example.com>"),
+            "=?utf-8?B?UmVuw6ll?= Dupont <renee@example.com>"
+        );
+
+        let name = "\u{410}\u{43b}\u{435}\u{43a}\u{441}\u{430}\u{43d}\u{434}\u{440} \u{41f}\u{435}\u{442}\u{440}\u{43e}\u{432} \u{438}\u{437} \u{41c}\u{43e}\u{441}\u{43a}\u{432}\u{44b}";
+        let encoded = encode_header(&format!("{} <alexander@example.com>", name));
+        assert!(encoded.ends_with("?= <alexander@example.com>"));
+        let header = format!("From: {}", encoded);
+        let (parsed, _) = mailparse::parse_header(header.as_bytes()).unwrap();
+        assert_eq!(parsed.get_value(), format!("{} <alexander@example.com>", name));
+    }
+
+    #[test]
+    fn messages_are_sent_verbatim_when_the_server_takes_8bit() {
+        assert_eq!(encode(true, MULTIPART), MULTIPART);
+        let single = b"Subject: =?utf-8?b?R3LDvMOfZQ==?=\r\nContent-Transfer-Encoding: 8bit\r\n\r\nGr\xc3\xbc\xc3\x9fe\r\n";
+        assert_eq!(encode(true, single), &single[..]);
+    }
+
+    #[test]
+    fn only_8bit_parts_are_downgraded() {
+        let expected: &[u8] = b"From: =?utf-8?q?J=C3=B6rg?= <jorg@example.com>\r\n\
+            Subject: A long subject that has been\r\n folded onto a second line\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            Preamble\r\n\
+            --b\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Gr=C3=BC=C3=9Fe\r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Plain\r\n\
+            --b--\r\n\
+            Epilogue\r\n";
+        assert_eq!(encode(false, MULTIPART), expected);
+    }
+
+    #[test]
+    fn unrecognised_replies_have_no_queue_id() {
+        assert_eq!(queue_id("2.0.0 Ok"), None);
+        assert_eq!(queue_id("Message received, thanks!"), None);
+        assert_eq!(queue_id(""), None);
+    }
 }
\ No newline at end of file
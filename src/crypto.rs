@@ -0,0 +1,218 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const TOKEN_LEN: usize = 32;
+/// The ID of the line in the master key file holding the key blobs are addressed with
+const BLOB_HASH_KEY_ID: &str = "blob-hash";
+
+/// Encrypts with AES-256-GCM under a random nonce, giving nonce || ciphertext || tag
+fn seal(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce).expect("Unable to generate nonce");
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = openssl::symm::encrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(), key, Some(&nonce), &[], data, &mut tag
+    ).expect("Unable to encrypt data");
+
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+    out.extend(&tag);
+    out
+}
+
+fn open(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err("Encrypted data is too short".to_string());
+    }
+    let (nonce, rest) = data.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    openssl::symm::decrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(), key, Some(nonce), &[], ciphertext, tag
+    ).map_err(|e| format!("Unable to decrypt data: {}", e))
+}
+
+/// Makes a random URL-safe token, for links sent out by email and the like
+pub fn random_token() -> String {
+    let mut token = [0u8; TOKEN_LEN];
+    openssl::rand::rand_bytes(&mut token).expect("Unable to generate token");
+    base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hashes a token for storage, so a leaked database doesn't leak working links
+pub fn token_hash(token: &str) -> Vec<u8> {
+    openssl::sha::sha256(token.as_bytes()).to_vec()
+}
+
+/// Compares a token against a stored hash of one in constant time
+pub fn verify_token(token: &str, hash: &[u8]) -> bool {
+    let token_hash = token_hash(token);
+    hash.len() == token_hash.len() && openssl::memcmp::eq(hash, &token_hash)
+}
+
+/// A key for encrypting one message (or one stored blob)
+pub struct DataKey {
+    pub id: uuid::Uuid,
+    key: Vec<u8>,
+}
+
+impl DataKey {
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        seal(&self.key, data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        open(&self.key, data)
+    }
+}
+
+/// The master keys that data keys are wrapped with. Only the current master key wraps new data
+/// keys, older ones are kept around to unwrap data keys until they've been rewrapped.
+pub struct KeyRing {
+    current: String,
+    master_keys: std::collections::HashMap<String, Vec<u8>>,
+    /// Kept apart from the master keys, as blobs have to keep the same address when those rotate
+    blob_hash_key: Vec<u8>,
+}
+
+impl KeyRing {
+    /// Loads master keys from a file with a `<key id> <base64 key>` pair on each line, the first
+    /// of which is the current key. The key with the ID `blob-hash` is the one blobs are addressed
+    /// with rather than a master key.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read master key file {}: {}", path, e))?;
+
+        let mut current = None;
+        let mut blob_hash_key = None;
+        let mut master_keys = std::collections::HashMap::new();
+        for line in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut parts = line.split_whitespace();
+            let (id, key) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(id), Some(key), None) => (id, key),
+                _ => return Err(format!("Invalid line in master key file: {}", line))
+            };
+            let key = match base64::decode(key) {
+                Ok(k) if k.len() == KEY_LEN => k,
+                _ => return Err(format!("Master key {} isn't a base64 encoded {} byte key", id, KEY_LEN))
+            };
+            if id == BLOB_HASH_KEY_ID {
+                blob_hash_key = Some(key);
+                continue;
+            }
+            if current.is_none() {
+                current = Some(id.to_string());
+            }
+            master_keys.insert(id.to_string(), key);
+        }
+
+        match (current, blob_hash_key) {
+            (Some(current), Some(blob_hash_key)) => Ok(Self {
+                current,
+                master_keys,
+                blob_hash_key,
+            }),
+            (None, _) => Err(format!("No keys in master key file {}", path)),
+            (_, None) => Err(format!("No {} key in master key file {}", BLOB_HASH_KEY_ID, path))
+        }
+    }
+
+    /// A key ring with a single random master key
+    #[cfg(test)]
+    pub fn ephemeral() -> Self {
+        let mut key = vec![0u8; KEY_LEN];
+        openssl::rand::rand_bytes(&mut key).expect("Unable to generate master key");
+        let mut master_keys = std::collections::HashMap::new();
+        master_keys.insert("test".to_string(), key);
+        let mut blob_hash_key = vec![0u8; KEY_LEN];
+        openssl::rand::rand_bytes(&mut blob_hash_key).expect("Unable to generate blob hash key");
+        Self {
+            current: "test".to_string(),
+            master_keys,
+            blob_hash_key,
+        }
+    }
+
+    /// The address of a blob, keyed so that the database alone doesn't give away whether some
+    /// known content is stored
+    pub fn blob_hash(&self, data: &[u8]) -> Vec<u8> {
+        let key = openssl::pkey::PKey::hmac(&self.blob_hash_key).expect("Unable to load blob hash key");
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)
+            .expect("Unable to create HMAC");
+        signer.sign_oneshot_to_vec(data).expect("Unable to compute HMAC")
+    }
+
+    /// Makes a new data key, storing it wrapped with the current master key
+    pub fn new_data_key(&self, conn: &crate::DbConn) -> QueryResult<DataKey> {
+        let mut key = vec![0u8; KEY_LEN];
+        openssl::rand::rand_bytes(&mut key).expect("Unable to generate data key");
+
+        let data_key = DataKey {
+            id: uuid::Uuid::new_v4(),
+            key,
+        };
+        let new_data_key = models::NewDataKey {
+            id: &data_key.id,
+            master_key_id: &self.current,
+            wrapped_key: &seal(&self.master_keys[&self.current], &data_key.key),
+        };
+        diesel::insert_into(schema::data_key::table)
+            .values(&new_data_key)
+            .execute(conn)?;
+
+        Ok(data_key)
+    }
+
+    pub fn data_key(&self, id: &uuid::Uuid, conn: &crate::DbConn) -> Result<DataKey, String> {
+        let data_key = schema::data_key::table
+            .find(id)
+            .select((schema::data_key::id, schema::data_key::master_key_id, schema::data_key::wrapped_key))
+            .get_result::<models::DataKey>(conn)
+            .map_err(|e| format!("Unable to load data key {}: {}", id, e))?;
+
+        Ok(DataKey {
+            id: data_key.id,
+            key: self.unwrap(&data_key)?,
+        })
+    }
+
+    fn unwrap(&self, data_key: &models::DataKey) -> Result<Vec<u8>, String> {
+        let master_key = self.master_keys.get(&data_key.master_key_id)
+            .ok_or_else(|| format!("Master key {} isn't loaded", data_key.master_key_id))?;
+        open(master_key, &data_key.wrapped_key)
+    }
+
+    /// Decrypts data stored under a data key, or hands it back as is if it was stored before we
+    /// started encrypting
+    pub fn decrypt(&self, data_key: &Option<uuid::Uuid>, data: Vec<u8>, conn: &crate::DbConn) -> Result<Vec<u8>, String> {
+        match data_key {
+            Some(id) => self.data_key(id, conn)?.decrypt(&data),
+            None => Ok(data)
+        }
+    }
+
+    /// Rewraps every data key that isn't wrapped with the current master key, so older master
+    /// keys can be retired. Content encrypted under the data keys is left alone.
+    pub fn rewrap(&self, conn: &crate::DbConn) -> Result<usize, String> {
+        let data_keys = schema::data_key::table
+            .filter(schema::data_key::master_key_id.ne(&self.current))
+            .select((schema::data_key::id, schema::data_key::master_key_id, schema::data_key::wrapped_key))
+            .load::<models::DataKey>(conn)
+            .map_err(|e| format!("Unable to load data keys: {}", e))?;
+
+        for data_key in &data_keys {
+            let key = self.unwrap(data_key)?;
+            diesel::update(schema::data_key::table.find(&data_key.id))
+                .set((
+                    schema::data_key::master_key_id.eq(&self.current),
+                    schema::data_key::wrapped_key.eq(seal(&self.master_keys[&self.current], &key)),
+                ))
+                .execute(conn)
+                .map_err(|e| format!("Unable to update data key {}: {}", data_key.id, e))?;
+        }
+
+        Ok(data_keys.len())
+    }
+}
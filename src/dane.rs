@@ -0,0 +1,222 @@
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::proto::rr::rdata::tlsa::{TLSA, CertUsage, Selector, Matching};
+
+/// Checks if a domain's MX RRset validates under DNSSEC, which RFC 7672 § 2.2.1 requires before
+/// the MX hostnames can be used as TLSA base domains
+pub async fn mx_is_secure(domain: &str, config: &crate::Config) -> bool {
+    config.dnssec_resolver.mx_lookup(domain).await.is_ok()
+}
+
+/// Checks if an MX host's address records validate under DNSSEC, meaning its zone is signed and
+/// any TLSA records for it that don't validate are bogus rather than insecure
+async fn host_is_secure(mx_host: &str, config: &crate::Config) -> bool {
+    config.dnssec_resolver.lookup_ip(format!("{}.", mx_host.trim_end_matches('.')).as_str()).await.is_ok()
+}
+
+/// Looks up the usable TLSA records for an MX host.
+///
+/// `Ok(None)` means DANE doesn't apply to the host, and `Ok(Some(records))` with no records means
+/// TLSA records were published but none of them are usable, in which case RFC 7672 § 2.2 still
+/// requires TLS, just without authentication. An error means delivery to the host has to wait,
+/// either because the lookup failed or because the records in a signed zone are bogus.
+pub async fn lookup_tlsa(mx_host: &str, config: &crate::Config) -> Result<Option<Vec<TLSA>>, String> {
+    let name = format!("_25._tcp.{}.", mx_host.trim_end_matches('.'));
+    match config.dnssec_resolver.lookup(name.as_str(), RecordType::TLSA, Default::default()).await {
+        Ok(r) => {
+            let records = r.iter().filter_map(|r| match r {
+                RData::TLSA(t) => Some(t.clone()),
+                _ => None
+            }).collect::<Vec<_>>();
+            if records.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(records.into_iter().filter(is_usable).collect()))
+        },
+        Err(e) if crate::client::is_no_records(&e) => Ok(None),
+        Err(e) => {
+            // The resolver reports records in insecure zones the same way as ones that fail
+            // validation, so see if there's anything there at all. If there is and the host's zone
+            // is signed the records are bogus, which RFC 7672 § 2.2 treats as a temporary failure
+            // rather than a reason to go without DANE. In an unsigned zone they can't be trusted,
+            // so DANE doesn't apply. If the lookup fails outright delivery has to wait.
+            match config.resolver.lookup(name.as_str(), RecordType::TLSA, Default::default()).await {
+                Ok(_) if host_is_secure(mx_host, config).await => {
+                    Err(format!("TLSA records for {} are bogus: {}", mx_host, e))
+                },
+                Ok(_) => {
+                    warn!("TLSA records for {} are in an unsigned zone: {}", mx_host, e);
+                    Ok(None)
+                },
+                Err(e) if crate::client::is_no_records(&e) => Ok(None),
+                Err(e) => Err(format!("TLSA lookup for {} failed: {}", mx_host, e))
+            }
+        }
+    }
+}
+
+/// Only DANE-TA and DANE-EE are to be used for SMTP, per RFC 7672 § 3.1.3
+fn is_usable(record: &TLSA) -> bool {
+    match record.cert_usage() {
+        CertUsage::TrustAnchor | CertUsage::DomainIssued => {},
+        _ => return false
+    }
+    match record.selector() {
+        Selector::Full | Selector::Spki => {},
+        _ => return false
+    }
+    match record.matching() {
+        Matching::Raw | Matching::Sha256 | Matching::Sha512 => true,
+        _ => false
+    }
+}
+
+fn record_matches(record: &TLSA, cert: &openssl::x509::X509Ref) -> Result<bool, openssl::error::ErrorStack> {
+    let data = match record.selector() {
+        Selector::Full => cert.to_der()?,
+        Selector::Spki => cert.public_key()?.public_key_to_der()?,
+        _ => return Ok(false)
+    };
+    let data = match record.matching() {
+        Matching::Raw => data,
+        Matching::Sha256 => openssl::sha::sha256(&data).to_vec(),
+        Matching::Sha512 => openssl::sha::sha512(&data).to_vec(),
+        _ => return Ok(false)
+    };
+    Ok(data == record.cert_data())
+}
+
+/// Verifies a server's certificate chain up to a DANE-TA trust anchor with OpenSSL's own path
+/// validation, so intermediates have to be CAs allowed to issue certificates, every certificate has
+/// to be in date, and the leaf has to be valid for the MX hostname. The anchor itself needn't be a
+/// self-signed root.
+fn chain_verifies(
+    anchor: &openssl::x509::X509Ref, chain: &openssl::stack::StackRef<openssl::x509::X509>, mx_host: &str,
+) -> Result<Result<(), String>, openssl::error::ErrorStack> {
+    let mut param = openssl::x509::verify::X509VerifyParam::new()?;
+    param.set_flags(openssl::x509::verify::X509VerifyFlags::PARTIAL_CHAIN)?;
+    param.set_host(mx_host.trim_end_matches('.'))?;
+
+    let mut store = openssl::x509::store::X509StoreBuilder::new()?;
+    store.add_cert(anchor.to_owned())?;
+    store.set_param(&param)?;
+    let store = store.build();
+
+    let mut untrusted = openssl::stack::Stack::new()?;
+    for cert in chain.iter().skip(1) {
+        untrusted.push(cert.to_owned())?;
+    }
+
+    let mut context = openssl::x509::X509StoreContext::new()?;
+    context.init(&store, &chain[0], &untrusted, |c| {
+        Ok(match c.verify_cert()? {
+            true => Ok(()),
+            false => Err(c.error().error_string().to_string())
+        })
+    })
+}
+
+/// Authenticates a server's certificate chain against its TLSA records, following RFC 7672 § 3.
+///
+/// DANE-EE records are matched against the leaf certificate alone, with no name or expiry checks.
+/// DANE-TA records must match a certificate in the chain presented by the server, which the chain
+/// must validate up to, with the leaf valid for the MX hostname.
+pub fn verify_chain(records: &[TLSA], chain: &openssl::stack::StackRef<openssl::x509::X509>, mx_host: &str) -> Result<(), String> {
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let leaf = match chain.iter().next() {
+        Some(c) => c,
+        None => return Err("Server didn't present a certificate".to_string())
+    };
+
+    for record in records {
+        match record.cert_usage() {
+            CertUsage::DomainIssued => {
+                if record_matches(record, leaf).map_err(|e| e.to_string())? {
+                    return Ok(());
+                }
+            },
+            CertUsage::TrustAnchor => {
+                for cert in chain.iter().skip(1) {
+                    if !record_matches(record, cert).map_err(|e| e.to_string())? {
+                        continue;
+                    }
+                    if let Err(e) = chain_verifies(cert, chain, mx_host).map_err(|e| e.to_string())? {
+                        return Err(format!("Certificate chain doesn't validate to the DANE-TA trust anchor: {}", e));
+                    }
+                    return Ok(());
+                }
+            },
+            _ => {}
+        }
+    }
+
+    Err("No TLSA record matched the server's certificate chain".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{issue, Issued};
+
+    fn chain(certs: &[&Issued]) -> openssl::stack::Stack<openssl::x509::X509> {
+        let mut chain = openssl::stack::Stack::new().unwrap();
+        for c in certs {
+            chain.push(c.cert.clone()).unwrap();
+        }
+        chain
+    }
+
+    fn record(usage: CertUsage, cert: &Issued) -> TLSA {
+        let spki = cert.cert.public_key().unwrap().public_key_to_der().unwrap();
+        TLSA::new(usage, Selector::Spki, Matching::Sha256, openssl::sha::sha256(&spki).to_vec())
+    }
+
+    #[test]
+    fn dane_ee_matches_leaf() {
+        // DANE-EE ignores names and expiry entirely
+        let leaf = issue("other.example.net", false, None, -20, -10);
+        let records = [record(CertUsage::DomainIssued, &leaf)];
+        assert!(verify_chain(&records, &chain(&[&leaf]), "mx.example.com").is_ok());
+
+        let other = issue("mx.example.com", false, None, -1, 1);
+        assert!(verify_chain(&records, &chain(&[&other]), "mx.example.com").is_err());
+    }
+
+    #[test]
+    fn dane_ta_matches_anchor() {
+        let root = issue("Root", true, None, -1, 10);
+        let intermediate = issue("Intermediate", true, Some(&root), -1, 10);
+        let leaf = issue("mx.example.com", false, Some(&intermediate), -1, 1);
+        let certs = chain(&[&leaf, &intermediate, &root]);
+
+        assert!(verify_chain(&[record(CertUsage::TrustAnchor, &root)], &certs, "mx.example.com").is_ok());
+        assert!(verify_chain(&[record(CertUsage::TrustAnchor, &intermediate)], &certs, "mx.example.com.").is_ok());
+    }
+
+    #[test]
+    fn dane_ta_rejects_wrong_name() {
+        let root = issue("Root", true, None, -1, 10);
+        let leaf = issue("mx.example.com", false, Some(&root), -1, 1);
+        let records = [record(CertUsage::TrustAnchor, &root)];
+        assert!(verify_chain(&records, &chain(&[&leaf, &root]), "mx.example.net").is_err());
+    }
+
+    #[test]
+    fn dane_ta_rejects_non_ca_intermediate() {
+        let root = issue("Root", true, None, -1, 10);
+        let end_entity = issue("attacker.example.org", false, Some(&root), -1, 10);
+        let forged = issue("mx.example.com", false, Some(&end_entity), -1, 1);
+        let records = [record(CertUsage::TrustAnchor, &root)];
+        assert!(verify_chain(&records, &chain(&[&forged, &end_entity, &root]), "mx.example.com").is_err());
+    }
+
+    #[test]
+    fn dane_ta_rejects_expired_leaf() {
+        let root = issue("Root", true, None, -30, 10);
+        let leaf = issue("mx.example.com", false, Some(&root), -20, -10);
+        let records = [record(CertUsage::TrustAnchor, &root)];
+        assert!(verify_chain(&records, &chain(&[&leaf, &root]), "mx.example.com").is_err());
+    }
+}
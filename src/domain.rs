@@ -0,0 +1,201 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+pub fn role_name(role: schema::ContactRole) -> &'static str {
+    match role {
+        schema::ContactRole::Registrant => "registrant",
+        schema::ContactRole::Admin => "admin",
+        schema::ContactRole::Tech => "tech",
+        schema::ContactRole::Billing => "billing",
+    }
+}
+
+pub fn parse_role(role: &str) -> Option<schema::ContactRole> {
+    match role {
+        "registrant" => Some(schema::ContactRole::Registrant),
+        "admin" => Some(schema::ContactRole::Admin),
+        "tech" => Some(schema::ContactRole::Tech),
+        "billing" => Some(schema::ContactRole::Billing),
+        _ => None
+    }
+}
+
+pub fn normalise_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    if domain.contains('.') && !domain.starts_with('.') && domain.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.') {
+        Some(domain)
+    } else {
+        None
+    }
+}
+
+/// The WHOIS addresses published for each contact role of a domain
+pub fn contacts(domain: &str, conn: &crate::DbConn) -> QueryResult<Vec<(schema::ContactRole, models::RegisteredAddress)>> {
+    schema::domain_contact::table
+        .inner_join(schema::registered_addresses::table)
+        .filter(schema::domain_contact::domain.eq(domain))
+        .order_by(schema::domain_contact::role.asc())
+        .select((schema::domain_contact::role, schema::registered_addresses::all_columns))
+        .load::<(schema::ContactRole, models::RegisteredAddress)>(conn)
+}
+
+/// Closes off the history of who held a domain's contact role, when the role is handed to someone
+/// else or dropped
+fn end_history(domain: &str, role: schema::ContactRole, conn: &crate::DbConn) -> QueryResult<()> {
+    diesel::update(schema::domain_contact_history::table
+        .filter(schema::domain_contact_history::domain.eq(domain))
+        .filter(schema::domain_contact_history::role.eq(role))
+        .filter(schema::domain_contact_history::ended_at.is_null())
+    )
+        .set(schema::domain_contact_history::ended_at.eq(chrono::Utc::now()))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn start_history(domain: &str, role: schema::ContactRole, address: &models::RegisteredAddress, conn: &crate::DbConn) -> QueryResult<()> {
+    diesel::insert_into(schema::domain_contact_history::table)
+        .values(&models::NewDomainContactHistory {
+            domain,
+            role: &role,
+            registered_address: &address.id,
+            forward_email: &address.forward_email,
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Sets who each contact role of a domain forwards to, with one WHOIS address per person, shared
+/// by all the roles they hold. A role whose contact changes gets a new address rather than
+/// repointing the old one, so mail meant for the old contact never reaches the new one. Addresses
+/// no role uses anymore, including those for roles left out, are retired, still reaching the old
+/// contact for the grace period and being refused after that.
+pub fn set_contacts(
+    domain: &str, new_contacts: &[(schema::ContactRole, String)], grace: chrono::Duration, actor: &str, conn: &crate::DbConn,
+) -> QueryResult<Vec<(schema::ContactRole, models::RegisteredAddress)>> {
+    conn.transaction(|| {
+        let old_contacts = contacts(domain, conn)?;
+        diesel::delete(schema::domain_contact::table.filter(schema::domain_contact::domain.eq(domain)))
+            .execute(conn)?;
+
+        let mut addresses: Vec<models::RegisteredAddress> = vec![];
+        for (role, forward_email) in new_contacts {
+            let existing = addresses.iter()
+                .chain(old_contacts.iter().map(|c| &c.1))
+                .find(|a| a.forward_email.eq_ignore_ascii_case(forward_email))
+                .cloned();
+            let address = match existing {
+                Some(a) => a,
+                None => loop {
+                    if let Some(a) = crate::alias::create(
+                        &crate::alias::random_address(), forward_email, schema::AliasPolicy::Challenge, actor, conn
+                    )? {
+                        addresses.push(a.clone());
+                        break a;
+                    }
+                }
+            };
+
+            diesel::insert_into(schema::domain_contact::table)
+                .values(&models::NewDomainContact {
+                    domain,
+                    role,
+                    registered_address: &address.id,
+                })
+                .execute(conn)?;
+
+            if !old_contacts.iter().any(|(r, a)| r == role && a.id == address.id) {
+                end_history(domain, *role, conn)?;
+                start_history(domain, *role, &address, conn)?;
+            }
+        }
+
+        for (role, old_address) in &old_contacts {
+            if !new_contacts.iter().any(|(r, _)| r == role) {
+                end_history(domain, *role, conn)?;
+            }
+
+            let still_used = diesel::select(diesel::dsl::exists(schema::domain_contact::table
+                .filter(schema::domain_contact::registered_address.eq(&old_address.id))
+            )).get_result::<bool>(conn)?;
+            if !still_used {
+                crate::alias::retire(&old_address.id, schema::GraceMode::Challenge, grace, actor, conn)?;
+            }
+        }
+
+        contacts(domain, conn)
+    })
+}
+
+/// Hands every contact role a WHOIS address holds over to another address, as when it's rotated
+pub fn move_contacts(from: &str, to: &models::RegisteredAddress, conn: &crate::DbConn) -> QueryResult<()> {
+    let moved = diesel::update(schema::domain_contact::table.filter(schema::domain_contact::registered_address.eq(from)))
+        .set(schema::domain_contact::registered_address.eq(&to.id))
+        .returning((schema::domain_contact::domain, schema::domain_contact::role))
+        .get_results::<(String, schema::ContactRole)>(conn)?;
+    for (domain, role) in moved {
+        end_history(&domain, role, conn)?;
+        start_history(&domain, role, to, conn)?;
+    }
+    Ok(())
+}
+
+/// Everyone who has held a domain's contact roles, most recent first
+pub fn history(domain: &str, conn: &crate::DbConn) -> QueryResult<Vec<models::DomainContactHistory>> {
+    schema::domain_contact_history::table
+        .filter(schema::domain_contact_history::domain.eq(domain))
+        .order_by((schema::domain_contact_history::started_at.desc(), schema::domain_contact_history::role.asc()))
+        .select((
+            schema::domain_contact_history::role, schema::domain_contact_history::registered_address,
+            schema::domain_contact_history::forward_email, schema::domain_contact_history::started_at,
+            schema::domain_contact_history::ended_at,
+        ))
+        .load::<models::DomainContactHistory>(conn)
+}
+
+/// The domains and roles a WHOIS address is published for
+pub fn roles(address: &str, conn: &crate::DbConn) -> QueryResult<Vec<(String, schema::ContactRole)>> {
+    schema::domain_contact::table
+        .filter(schema::domain_contact::registered_address.eq(address.to_lowercase()))
+        .order_by((schema::domain_contact::domain.asc(), schema::domain_contact::role.asc()))
+        .select((schema::domain_contact::domain, schema::domain_contact::role))
+        .load::<(String, schema::ContactRole)>(conn)
+}
+
+/// The domains and roles a WHOIS address used to be published for
+fn former_roles(address: &str, conn: &crate::DbConn) -> QueryResult<Vec<(String, schema::ContactRole)>> {
+    schema::domain_contact_history::table
+        .filter(schema::domain_contact_history::registered_address.eq(address.to_lowercase()))
+        .order_by((schema::domain_contact_history::domain.asc(), schema::domain_contact_history::role.asc()))
+        .select((schema::domain_contact_history::domain, schema::domain_contact_history::role))
+        .distinct()
+        .load::<(String, schema::ContactRole)>(conn)
+}
+
+/// Describes the roles a WHOIS address is published for, like "registrant and admin contact for
+/// example.com", or those it once was, for mail to a retired address
+pub fn describe_roles(address: &str, conn: &crate::DbConn) -> QueryResult<Option<String>> {
+    let (current, prefix) = match roles(address, conn)? {
+        r if r.is_empty() => (former_roles(address, conn)?, "former "),
+        r => (r, ""),
+    };
+
+    let mut domains: Vec<(String, Vec<&'static str>)> = vec![];
+    for (domain, role) in current {
+        match domains.iter_mut().find(|d| d.0 == domain) {
+            Some(d) => d.1.push(role_name(role)),
+            None => domains.push((domain, vec![role_name(role)]))
+        }
+    }
+    if domains.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(domains.into_iter().map(|(domain, roles)| {
+        let roles = match roles.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => unreachable!()
+        };
+        format!("{}{} contact for {}", prefix, roles, domain)
+    }).collect::<Vec<_>>().join("; ")))
+}
@@ -0,0 +1,262 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// The hour of the day (UTC) digests are sent at
+const DIGEST_HOUR: u32 = 8;
+
+/// What registrants are shown about a held message
+#[derive(Serialize, Debug)]
+pub struct HeldSummary {
+    pub id: String,
+    pub from: String,
+    pub rcpt_to: String,
+    /// The domains and contact roles the address is published for
+    pub roles: Option<String>,
+    pub subject: Option<String>,
+    pub received_at: String,
+    pub expires_at: String,
+    pub spf: String,
+    pub dkim: String,
+    pub dmarc: String,
+    pub release_link: Option<String>,
+    pub delete_link: Option<String>,
+}
+
+/// The headers of a queued message that are stored encrypted under its data key
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct QueuedHeaders {
+    pub message_id: Option<String>,
+    pub from: Vec<String>,
+    pub sender: Option<String>,
+    pub reply_to: Option<Vec<String>>,
+}
+
+/// Decrypts a queued message's headers, or takes them from the plaintext columns if it was stored
+/// before they were encrypted
+pub fn headers(item: &models::InboundQueueItem, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<QueuedHeaders, String> {
+    match &item.encrypted_headers {
+        Some(h) => {
+            let h = keys.decrypt(&item.data_key, h.to_vec(), conn)?;
+            serde_json::from_slice(&h).map_err(|e| format!("Unable to parse headers: {}", e))
+        },
+        None => Ok(QueuedHeaders {
+            message_id: item.message_id.clone(),
+            from: item.from.clone(),
+            sender: item.sender.clone(),
+            reply_to: item.reply_to.clone(),
+        })
+    }
+}
+
+pub fn summarise(item: &models::InboundQueueItem, config: &crate::Config, conn: &crate::DbConn) -> HeldSummary {
+    let subject = match &item.encrypted_subject {
+        Some(s) => match config.keys.decrypt(&item.data_key, s.to_vec(), conn) {
+            Ok(s) => Some(String::from_utf8_lossy(&s).into_owned()),
+            Err(e) => {
+                warn!("Unable to decrypt subject of {}: {}", item.id, e);
+                None
+            }
+        },
+        None => item.subject.clone()
+    };
+    let headers = headers(item, &config.keys, conn).unwrap_or_else(|e| {
+        warn!("Unable to decrypt headers of {}: {}", item.id, e);
+        QueuedHeaders::default()
+    });
+
+    let roles = match crate::domain::describe_roles(&item.rcpt_to, conn) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Unable to load contact roles of {}: {}", item.rcpt_to, e);
+            None
+        }
+    };
+
+    HeldSummary {
+        id: item.id.simple().to_string(),
+        from: headers.from.join(", "),
+        rcpt_to: item.rcpt_to.clone(),
+        roles,
+        subject,
+        received_at: item.received_at.to_rfc2822(),
+        expires_at: (item.received_at + config.held_mail_expiry).to_rfc2822(),
+        spf: match (&item.spf_result, &item.spf_domain) {
+            (Some(r), Some(d)) => format!("{} ({})", r, d),
+            (Some(r), None) => r.to_string(),
+            _ => "unknown".to_string()
+        },
+        dkim: match (&item.dkim_result, &item.dkim_domains) {
+            (Some(r), Some(d)) if !d.is_empty() => format!("{} ({})", r, d.join(", ")),
+            (Some(r), _) => r.to_string(),
+            _ => "unknown".to_string()
+        },
+        dmarc: match (&item.dmarc_result, &item.dmarc_domain) {
+            (Some(r), Some(d)) => format!("{} ({})", r, d),
+            (Some(r), None) => r.to_string(),
+            _ => "unknown".to_string()
+        },
+        release_link: None,
+        delete_link: None,
+    }
+}
+
+/// Finds where mail to a WHOIS address should be forwarded to, if it's still registered
+pub fn forward_address(rcpt_to: &str, conn: &crate::DbConn) -> QueryResult<Option<String>> {
+    Ok(crate::alias::lookup(rcpt_to, conn)?.map(|a| a.forward_email))
+}
+
+/// Checks a release or delete link's token against the one we sent out for a message
+pub fn verify_token(item: &models::InboundQueueItem, token: &str) -> bool {
+    match &item.action_token_hash {
+        Some(hash) => crate::crypto::verify_token(token, hash),
+        None => false
+    }
+}
+
+/// Queues a held message for delivery to the registrant, rebuilt with the trace headers it was
+/// received with, unless the address would now turn the message away at RCPT
+pub fn release(item: &models::InboundQueueItem, config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    if item.released_at.is_some() {
+        return Err("This email has already been released".to_string());
+    }
+    let address = crate::alias::lookup(&item.rcpt_to, conn)
+        .map_err(|e| format!("Unable to load registered address: {}", e))?
+        .ok_or_else(|| format!("{} is no longer registered", item.rcpt_to))?;
+    match crate::alias::refusal(&address) {
+        Some(crate::alias::Refusal::Rejected) => return Err(format!("{} no longer accepts mail", item.rcpt_to)),
+        Some(crate::alias::Refusal::Retired) => return Err(format!("{} has been retired", item.rcpt_to)),
+        Some(crate::alias::Refusal::Moved(new)) => return Err(format!("{} has moved to {}", item.rcpt_to, new)),
+        None => {}
+    }
+    let forward_email = address.forward_email;
+    if !crate::verification::is_verified(&forward_email, conn)
+        .map_err(|e| format!("Unable to load forwarding address verification: {}", e))? {
+        return Err(format!("{} hasn't been confirmed by its owner", forward_email));
+    }
+
+    let mut data = item.trace_headers.as_deref().unwrap_or_default()
+        .lines()
+        .filter(|l| !l.to_ascii_lowercase().starts_with("return-path:"))
+        .flat_map(|l| l.bytes().chain(b"\r\n".iter().copied()))
+        .collect::<Vec<_>>();
+    data.extend(crate::reassemble::reassemble_message(&item.contents_id, &config.keys, conn)?);
+
+    // Claimed in the same transaction as it's queued, so two releases racing each other can't
+    // both queue it
+    conn.transaction(|| {
+        let claimed = diesel::update(schema::inbound_queue::table
+            .find(&item.id)
+            .filter(schema::inbound_queue::released_at.is_null())
+        )
+            .set((
+                schema::inbound_queue::released_at.eq(chrono::Utc::now()),
+                schema::inbound_queue::action_token_hash.eq(None::<Vec<u8>>),
+            ))
+            .execute(conn)?;
+        if claimed == 0 {
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+        crate::sender::queue_mail(crate::sender::NOTIFICATION_FROM, &[&forward_email], &data, &config.keys, conn)?;
+        Ok(())
+    }).map_err(|e| match e {
+        diesel::result::Error::RollbackTransaction => "This email has already been released".to_string(),
+        e => format!("Unable to queue released email: {}", e)
+    })
+}
+
+pub fn delete(item: &models::InboundQueueItem, conn: &crate::DbConn) -> Result<(), String> {
+    crate::janitor::delete_inbound(&[item.id], conn)
+        .map(|_| ())
+        .map_err(|e| format!("Unable to delete email: {}", e))
+}
+
+fn send_digest(forward_email: &str, items: &[models::InboundQueueItem], config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    let mut summaries = vec![];
+    let mut token_hashes = vec![];
+    for item in items {
+        let token = crate::crypto::random_token();
+        let mut summary = summarise(item, config, conn);
+        summary.release_link = Some(format!("{}/held/{}/release?token={}", crate::web::BASE_URL, item.id.simple(), token));
+        summary.delete_link = Some(format!("{}/held/{}/delete?token={}", crate::web::BASE_URL, item.id.simple(), token));
+        summaries.push(summary);
+        token_hashes.push(crate::crypto::token_hash(&token));
+    }
+
+    let mut context = tera::Context::new();
+    context.insert("items", &summaries);
+    context.insert("portal_link", &format!("{}/portal/login", crate::web::BASE_URL));
+
+    let data = crate::sender::build_notification(forward_email, "Emails held for your WHOIS addresses", "digest", &context)?;
+
+    conn.transaction(|| {
+        crate::sender::queue_mail(crate::sender::NOTIFICATION_FROM, &[forward_email], &data, &config.keys, conn)?;
+        let now = chrono::Utc::now();
+        for (item, token_hash) in items.iter().zip(token_hashes.iter()) {
+            diesel::update(schema::inbound_queue::table.find(&item.id))
+                .set((
+                    schema::inbound_queue::digested_at.eq(now),
+                    schema::inbound_queue::action_token_hash.eq(token_hash),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    }).map_err(|e: diesel::result::Error| format!("Unable to queue digest: {}", e))
+}
+
+/// Sends each registrant a digest of mail held for their WHOIS addresses since the last one
+fn send_digests(config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    let items = schema::inbound_queue::table
+        .filter(schema::inbound_queue::released_at.is_null())
+        .filter(schema::inbound_queue::digested_at.is_null())
+        .order_by(schema::inbound_queue::received_at.asc())
+        .select(models::INBOUND_QUEUE_ITEM_COLUMNS)
+        .load::<models::InboundQueueItem>(conn)
+        .map_err(|e| format!("Unable to load held mail: {}", e))?;
+
+    let mut registrant_items: std::collections::BTreeMap<String, Vec<models::InboundQueueItem>> = std::collections::BTreeMap::new();
+    for item in items {
+        match forward_address(&item.rcpt_to, conn) {
+            Ok(Some(f)) => registrant_items.entry(f).or_default().push(item),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Unable to load forwarding address: {}", e))
+        }
+    }
+    // Held mail for unconfirmed addresses waits for the first digest after they're confirmed
+    let mut unverified = vec![];
+    for forward_email in registrant_items.keys() {
+        if !crate::verification::is_verified(forward_email, conn)
+            .map_err(|e| format!("Unable to load forwarding address verification: {}", e))? {
+            unverified.push(forward_email.clone());
+        }
+    }
+    for forward_email in unverified {
+        registrant_items.remove(&forward_email);
+    }
+
+    for (forward_email, items) in registrant_items {
+        match send_digest(&forward_email, &items, config, conn) {
+            Ok(_) => info!("Sent digest of {} held emails to {}", items.len(), forward_email),
+            Err(e) => error!("Error sending digest to {}: {}", forward_email, e)
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn digest_task(config: crate::Config) {
+    loop {
+        let now = chrono::Utc::now();
+        let mut next_run = now.date().and_hms(DIGEST_HOUR, 0, 0);
+        if next_run <= now {
+            next_run = next_run + chrono::Duration::days(1);
+        }
+        tokio::time::delay_for((next_run - now).to_std().unwrap_or_default()).await;
+
+        if let Err(e) = tokio::task::block_in_place(|| {
+            let conn = config.connection.get().map_err(|e| e.to_string())?;
+            send_digests(&config, &conn)
+        }) {
+            error!("Error sending held mail digests: {}", e);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use diesel::prelude::*;
+use diesel::dsl::{exists, not};
+use crate::schema;
+
+/// How often the janitor looks for things to delete
+const JANITOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Deletes the subpart trees under the given roots, unless some other inbound message still uses them
+const DELETE_SUBPART_TREES: &str = "
+with recursive tree(id) as (
+    select id from mail_subpart
+        where id = any($1)
+        and not exists (select 1 from inbound_queue where inbound_queue.contents = mail_subpart.id)
+    union all
+    select child from mail_subpart join tree on mail_subpart.id = tree.id, unnest(mail_subpart.subparts) as child
+)
+delete from mail_subpart where id in (select id from tree)";
+
+#[derive(Debug, Default)]
+struct Purged {
+    inbound: usize,
+    subparts: usize,
+    outbound: usize,
+    blobs: usize,
+    aliases: usize,
+}
+
+/// Deletes held mail past its retention period
+fn purge_inbound(purged: &mut Purged, config: &crate::Config, conn: &crate::DbConn) -> QueryResult<()> {
+    let now = chrono::Utc::now();
+
+    let ids = schema::inbound_queue::table
+        .filter(
+            schema::inbound_queue::released_at.is_null()
+                .and(schema::inbound_queue::received_at.lt(now - config.held_mail_expiry))
+                .or(schema::inbound_queue::released_at.lt(now - config.released_mail_retention))
+        )
+        .select(schema::inbound_queue::id)
+        .load::<uuid::Uuid>(conn)?;
+
+    let (inbound, subparts) = delete_inbound(&ids, conn)?;
+    purged.inbound += inbound;
+    purged.subparts += subparts;
+    Ok(())
+}
+
+/// Deletes inbound messages, along with their subpart trees, raw messages and data keys once
+/// nothing else uses them, returning how many messages and subparts were deleted. Their blobs are
+/// left for the janitor's garbage collection.
+pub fn delete_inbound(ids: &[uuid::Uuid], conn: &crate::DbConn) -> QueryResult<(usize, usize)> {
+    conn.transaction(|| {
+        let deleted = diesel::delete(schema::inbound_queue::table.filter(schema::inbound_queue::id.eq_any(ids)))
+            .returning((schema::inbound_queue::contents, schema::inbound_queue::raw_message, schema::inbound_queue::data_key))
+            .get_results::<(uuid::Uuid, Option<uuid::Uuid>, Option<uuid::Uuid>)>(conn)?;
+
+        let contents = deleted.iter().map(|d| d.0).collect::<Vec<_>>();
+        let subparts = diesel::sql_query(DELETE_SUBPART_TREES)
+            .bind::<diesel::sql_types::Array<diesel::sql_types::Uuid>, _>(&contents)
+            .execute(conn)?;
+
+        let raw_messages = deleted.iter().filter_map(|d| d.1).collect::<Vec<_>>();
+        diesel::delete(schema::raw_message::table
+            .filter(schema::raw_message::id.eq_any(&raw_messages))
+            .filter(not(exists(schema::inbound_queue::table.filter(
+                schema::inbound_queue::raw_message.eq(schema::raw_message::id.nullable())
+            ))))
+            .filter(not(exists(schema::mail_subpart::table.filter(
+                schema::mail_subpart::raw_message.eq(schema::raw_message::id.nullable())
+            ))))
+        ).execute(conn)?;
+
+        let data_keys = deleted.iter().filter_map(|d| d.2).collect::<Vec<_>>();
+        diesel::delete(schema::data_key::table
+            .filter(schema::data_key::id.eq_any(&data_keys))
+            .filter(not(exists(schema::inbound_queue::table.filter(
+                schema::inbound_queue::data_key.eq(schema::data_key::id.nullable())
+            ))))
+            .filter(not(exists(schema::mail_subpart::table.filter(
+                schema::mail_subpart::data_key.eq(schema::data_key::id.nullable())
+            ))))
+        ).execute(conn)?;
+
+        Ok((deleted.len(), subparts))
+    })
+}
+
+/// Deletes sent and failed deliveries past their retention periods, and their messages once no
+/// other delivery of them is left
+fn purge_outbound(purged: &mut Purged, config: &crate::Config, conn: &crate::DbConn) -> QueryResult<()> {
+    let now = chrono::Utc::now();
+
+    conn.transaction(|| {
+        let message_ids = diesel::delete(schema::outbound_queue::table.filter(
+            schema::outbound_queue::state.eq(schema::MailState::Sent)
+                .and(schema::outbound_queue::state_since.lt(now - config.sent_mail_retention))
+                .or(schema::outbound_queue::state.eq(schema::MailState::Failed)
+                    .and(schema::outbound_queue::state_since.lt(now - config.failed_mail_retention)))
+        ))
+            .returning(schema::outbound_queue::message_id)
+            .get_results::<uuid::Uuid>(conn)?;
+
+        let data_keys = diesel::delete(schema::outbound_message::table
+            .filter(schema::outbound_message::id.eq_any(&message_ids))
+            .filter(not(exists(schema::outbound_queue::table.filter(
+                schema::outbound_queue::message_id.eq(schema::outbound_message::id)
+            ))))
+        )
+            .returning(schema::outbound_message::data_key)
+            .get_results::<Option<uuid::Uuid>>(conn)?;
+        purged.outbound += data_keys.len();
+
+        let data_keys = data_keys.into_iter().filter_map(|k| k).collect::<Vec<_>>();
+        diesel::delete(schema::data_key::table.filter(schema::data_key::id.eq_any(&data_keys)))
+            .execute(conn)?;
+
+        Ok(())
+    })
+}
+
+/// Deletes expired portal login links and sessions, and API idempotency keys
+fn purge_tokens(config: &crate::Config, conn: &crate::DbConn) -> QueryResult<()> {
+    let now = chrono::Utc::now();
+    diesel::delete(schema::portal_login_token::table.filter(schema::portal_login_token::expires_at.lt(now)))
+        .execute(conn)?;
+    diesel::delete(schema::portal_session::table.filter(schema::portal_session::expires_at.lt(now)))
+        .execute(conn)?;
+    diesel::delete(schema::api_idempotency_key::table.filter(
+        schema::api_idempotency_key::created_at.lt(now - config.idempotency_key_retention)
+    ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn purge(config: &crate::Config, conn: &crate::DbConn) -> QueryResult<Purged> {
+    let mut purged = Purged::default();
+    purge_inbound(&mut purged, config, conn)?;
+    purge_outbound(&mut purged, config, conn)?;
+    purge_tokens(config, conn)?;
+    purged.aliases = crate::alias::revoke_retired(conn)?;
+    purged.blobs = crate::blob::collect_garbage(conn)?;
+    Ok(purged)
+}
+
+pub async fn janitor_task(config: crate::Config) {
+    loop {
+        match tokio::task::block_in_place(|| {
+            let conn = config.connection.get().map_err(|e| e.to_string())?;
+            purge(&config, &conn).map_err(|e| e.to_string())
+        }) {
+            Ok(p) => info!(
+                "Purged {} inbound messages ({} subparts), {} outbound messages, {} blobs and {} rotated addresses",
+                p.inbound, p.subparts, p.outbound, p.blobs, p.aliases
+            ),
+            Err(e) => error!("Error purging old mail: {}", e)
+        }
+
+        tokio::time::delay_for(JANITOR_INTERVAL).await;
+    }
+}
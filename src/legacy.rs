@@ -0,0 +1,279 @@
+//! Brings data stored before encryption at rest up to date, encrypting mail contents and headers
+//! that are still in plaintext and moving blobs to their keyed hash.
+
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// How many rows are looked at at once
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Default)]
+pub struct Encrypted {
+    pub blobs: usize,
+    pub inbound: usize,
+    pub subparts: usize,
+    pub outbound: usize,
+}
+
+/// Checks for rows still holding plaintext. Blobs that are encrypted but under their plain SHA-256
+/// hash can't be told apart in SQL, so aren't looked for.
+pub fn plaintext_remains(conn: &crate::DbConn) -> QueryResult<bool> {
+    diesel::select(
+        diesel::dsl::exists(schema::blob::table.filter(schema::blob::data_key.is_null()))
+            .or(diesel::dsl::exists(schema::inbound_queue::table.filter(schema::inbound_queue::encrypted_headers.is_null())))
+            .or(diesel::dsl::exists(schema::mail_subpart::table.filter(schema::mail_subpart::encrypted_headers.is_null())))
+            .or(diesel::dsl::exists(schema::outbound_message::table.filter(schema::outbound_message::data_key.is_null())))
+    ).get_result(conn)
+}
+
+/// Encrypts everything stored in plaintext, and re-addresses blobs by their keyed hash
+pub fn encrypt(keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<Encrypted, String> {
+    let mut encrypted = Encrypted::default();
+    encrypted.blobs = crate::blob::readdress_legacy(keys, conn)?;
+
+    loop {
+        let items = schema::inbound_queue::table
+            .filter(schema::inbound_queue::encrypted_headers.is_null())
+            .limit(BATCH_SIZE)
+            .select(models::INBOUND_QUEUE_ITEM_COLUMNS)
+            .load::<models::InboundQueueItem>(conn)
+            .map_err(|e| format!("Unable to load inbound messages: {}", e))?;
+        if items.is_empty() {
+            break;
+        }
+        for item in &items {
+            encrypted.subparts += encrypt_inbound(item, keys, conn)?;
+            encrypted.inbound += 1;
+        }
+    }
+
+    // Subparts no inbound message refers to get a data key each
+    loop {
+        let parts = schema::mail_subpart::table
+            .filter(schema::mail_subpart::encrypted_headers.is_null())
+            .limit(BATCH_SIZE)
+            .select(models::MAIL_SUBPART_COLUMNS)
+            .load::<models::MailSubpart>(conn)
+            .map_err(|e| format!("Unable to load subparts: {}", e))?;
+        if parts.is_empty() {
+            break;
+        }
+        for part in &parts {
+            conn.transaction(|| {
+                let data_key = keys.new_data_key(conn)?;
+                encrypt_subpart(part, &data_key, conn)
+            }).map_err(|e| format!("Unable to encrypt subpart {}: {}", part.id, e))?;
+            encrypted.subparts += 1;
+        }
+    }
+
+    loop {
+        let messages = schema::outbound_message::table
+            .filter(schema::outbound_message::data_key.is_null())
+            .limit(BATCH_SIZE)
+            .load::<models::OutboundMessage>(conn)
+            .map_err(|e| format!("Unable to load outbound messages: {}", e))?;
+        if messages.is_empty() {
+            break;
+        }
+        for message in &messages {
+            conn.transaction(|| {
+                let data_key = keys.new_data_key(conn)?;
+                diesel::update(schema::outbound_message::table.find(&message.id))
+                    .set((
+                        schema::outbound_message::data.eq(data_key.encrypt(&message.data)),
+                        schema::outbound_message::data_key.eq(&data_key.id),
+                    ))
+                    .execute(conn)
+            }).map_err(|e| format!("Unable to encrypt outbound message {}: {}", message.id, e))?;
+            encrypted.outbound += 1;
+        }
+    }
+
+    Ok(encrypted)
+}
+
+/// Encrypts the headers of an inbound message and its subpart tree, under the message's data key,
+/// or that of its subpart tree if it's shared with a message that's already been encrypted.
+/// Returns how many subparts were encrypted.
+fn encrypt_inbound(item: &models::InboundQueueItem, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<usize, String> {
+    let root_data_key = schema::mail_subpart::table
+        .find(&item.contents_id)
+        .select(schema::mail_subpart::data_key)
+        .get_result::<Option<uuid::Uuid>>(conn)
+        .map_err(|e| format!("Unable to load subpart {}: {}", item.contents_id, e))?;
+    let existing_data_key = match item.data_key.or(root_data_key) {
+        Some(id) => Some(keys.data_key(&id, conn)?),
+        None => None
+    };
+
+    let headers = serde_json::to_vec(&crate::held::QueuedHeaders {
+        message_id: item.message_id.clone(),
+        from: item.from.clone(),
+        sender: item.sender.clone(),
+        reply_to: item.reply_to.clone(),
+    }).map_err(|e| format!("Unable to serialize headers: {}", e))?;
+
+    conn.transaction(|| {
+        let data_key = match existing_data_key {
+            Some(k) => k,
+            None => keys.new_data_key(conn)?
+        };
+        let encrypted_subject = match &item.subject {
+            Some(s) => Some(data_key.encrypt(s.as_bytes())),
+            None => item.encrypted_subject.clone()
+        };
+        diesel::update(schema::inbound_queue::table.find(&item.id))
+            .set((
+                schema::inbound_queue::message_id.eq(None::<String>),
+                schema::inbound_queue::mail_from.eq(Vec::<String>::new()),
+                schema::inbound_queue::mail_sender.eq(None::<String>),
+                schema::inbound_queue::mail_reply_to.eq(None::<Vec<String>>),
+                schema::inbound_queue::subject.eq(None::<String>),
+                schema::inbound_queue::data_key.eq(&data_key.id),
+                schema::inbound_queue::encrypted_subject.eq(encrypted_subject),
+                schema::inbound_queue::encrypted_headers.eq(data_key.encrypt(&headers)),
+            ))
+            .execute(conn)?;
+
+        let mut subparts = 0;
+        let mut pending = vec![item.contents_id];
+        while let Some(id) = pending.pop() {
+            let part = schema::mail_subpart::table
+                .find(&id)
+                .select(models::MAIL_SUBPART_COLUMNS)
+                .get_result::<models::MailSubpart>(conn)?;
+            pending.extend(&part.subparts);
+            if part.encrypted_headers.is_none() {
+                encrypt_subpart(&part, &data_key, conn)?;
+                subparts += 1;
+            }
+        }
+        Ok(subparts)
+    }).map_err(|e: diesel::result::Error| format!("Unable to encrypt inbound message {}: {}", item.id, e))
+}
+
+/// Moves a subpart's headers into its encrypted headers, in the form they're stored in by the
+/// SMTP server
+fn encrypt_subpart(part: &models::MailSubpart, data_key: &crate::crypto::DataKey, conn: &crate::DbConn) -> QueryResult<usize> {
+    let headers = part.headers.iter().map(|h| (h.0.as_str(), h.1.as_str())).collect::<Vec<_>>();
+    let headers = serde_json::to_vec(&headers).expect("Unable to serialize headers");
+    diesel::update(schema::mail_subpart::table.find(&part.id))
+        .set((
+            schema::mail_subpart::headers.eq(&[] as &[&schema::MailHeader]),
+            schema::mail_subpart::data_key.eq(&data_key.id),
+            schema::mail_subpart::encrypted_headers.eq(data_key.encrypt(&headers)),
+        ))
+        .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use crate::{schema, models};
+
+    #[test]
+    #[ignore]
+    fn plaintext_mail_is_encrypted_and_readdressed() {
+        let conn = crate::testing::connection();
+        let keys = crate::crypto::KeyRing::ephemeral();
+        let body = b"Hello world\r\n";
+        let raw = b"Subject: Hi\r\n\r\nHello world\r\n";
+
+        for data in &[&body[..], &raw[..]] {
+            diesel::insert_into(schema::blob::table)
+                .values(&models::NewBlob {
+                    hash: &openssl::sha::sha256(data),
+                    data,
+                    compressed: false,
+                    ref_count: 1,
+                    data_key: None,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        let raw_id = uuid::Uuid::new_v4();
+        diesel::insert_into(schema::raw_message::table)
+            .values(&models::NewRawMessage {
+                id: &raw_id,
+                data_hash: &openssl::sha::sha256(raw),
+            })
+            .execute(&conn)
+            .unwrap();
+        let contents_id = uuid::Uuid::new_v4();
+        diesel::insert_into(schema::mail_subpart::table)
+            .values(&models::NewMailSubpart {
+                id: &contents_id,
+                headers: &[&schema::MailHeader("Subject", "Hi")],
+                subparts: &[],
+                raw_message: None,
+                header_offset: None,
+                body_offset: None,
+                end_offset: None,
+                body_hash: &openssl::sha::sha256(body),
+                data_key: None,
+                encrypted_headers: None,
+            })
+            .execute(&conn)
+            .unwrap();
+        let item_id = uuid::Uuid::new_v4();
+        diesel::insert_into(schema::inbound_queue::table)
+            .values(&models::NewInboundQueueItem {
+                id: &item_id,
+                rcpt_to: "someone@whois.as207960.net",
+                message_id: Some("<1@example.com>"),
+                mail_from: &["alice@example.com"],
+                mail_sender: None,
+                mail_reply_to: None,
+                subject: Some("Hi"),
+                contents: &contents_id,
+                envelope_from: None,
+                helo: None,
+                peer_ip: None,
+                reverse_dns: None,
+                protocol: None,
+                received_at: &chrono::Utc::now(),
+                raw_message: Some(&raw_id),
+                trace_headers: None,
+                data_key: None,
+                encrypted_subject: None,
+                spf_result: None,
+                spf_domain: None,
+                dkim_result: None,
+                dkim_domains: None,
+                dmarc_result: None,
+                dmarc_domain: None,
+                encrypted_headers: None,
+            })
+            .execute(&conn)
+            .unwrap();
+
+        super::encrypt(&keys, &conn).unwrap();
+        assert!(!super::plaintext_remains(&conn).unwrap());
+
+        let part = schema::mail_subpart::table.find(&contents_id).select(models::MAIL_SUBPART_COLUMNS).get_result::<models::MailSubpart>(&conn).unwrap();
+        assert!(part.headers.is_empty());
+        assert_eq!(part.body_hash, keys.blob_hash(body));
+        assert_eq!(crate::blob::load(&part.body_hash, &keys, &conn).unwrap(), &body[..]);
+        let data_hash = schema::raw_message::table
+            .find(&raw_id)
+            .select(schema::raw_message::data_hash)
+            .get_result::<Vec<u8>>(&conn)
+            .unwrap();
+        assert_eq!(data_hash, keys.blob_hash(raw));
+        let old_blobs = schema::blob::table
+            .filter(schema::blob::hash.eq_any(vec![openssl::sha::sha256(body).to_vec(), openssl::sha::sha256(raw).to_vec()]))
+            .count()
+            .get_result::<i64>(&conn)
+            .unwrap();
+        assert_eq!(old_blobs, 0);
+
+        let item = schema::inbound_queue::table.find(&item_id).select(models::INBOUND_QUEUE_ITEM_COLUMNS).get_result::<models::InboundQueueItem>(&conn).unwrap();
+        assert_eq!(item.subject, None);
+        assert!(item.from.is_empty());
+        assert_eq!(item.data_key, part.data_key);
+        let headers = crate::held::headers(&item, &keys, &conn).unwrap();
+        assert_eq!(headers.from, vec!["alice@example.com".to_string()]);
+        assert_eq!(headers.message_id.as_deref(), Some("<1@example.com>"));
+    }
+}
@@ -6,6 +6,8 @@ extern crate lazy_static;
 extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
+#[macro_use]
+extern crate serde;
 
 mod proto;
 mod server;
@@ -13,6 +15,27 @@ mod client;
 mod models;
 mod schema;
 mod sender;
+mod mta_sts;
+mod dane;
+mod tls_rpt;
+mod tls_policy;
+mod throttle;
+mod attempt;
+mod reassemble;
+mod blob;
+mod crypto;
+mod janitor;
+mod auth;
+mod held;
+mod web;
+mod alias;
+mod portal;
+mod api;
+mod domain;
+mod verification;
+mod legacy;
+#[cfg(test)]
+mod testing;
 
 embed_migrations!("migrations");
 
@@ -21,7 +44,23 @@ type DbConn = diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<die
 #[derive(Clone)]
 pub struct Config {
     resolver: std::sync::Arc<trust_dns_resolver::TokioAsyncResolver>,
+    dnssec_resolver: std::sync::Arc<trust_dns_resolver::TokioAsyncResolver>,
     connection: diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::pg::PgConnection>>,
+    pool: std::sync::Arc<client::ConnectionPool>,
+    throttle: std::sync::Arc<throttle::Throttle>,
+    keys: std::sync::Arc<crypto::KeyRing>,
+    /// How long held mail is kept waiting to be released before it's deleted
+    held_mail_expiry: chrono::Duration,
+    /// How long a rotated WHOIS address is still accepted for before it's revoked
+    alias_grace_period: chrono::Duration,
+    /// How long held mail is kept after it's released
+    released_mail_retention: chrono::Duration,
+    /// How long outbound messages are kept after they're sent
+    sent_mail_retention: chrono::Duration,
+    /// How long outbound messages that couldn't be delivered are kept after they're given up on
+    failed_mail_retention: chrono::Duration,
+    /// How long responses to requests with idempotency keys are kept for replaying
+    idempotency_key_retention: chrono::Duration,
 }
 
 pub fn establish_connection() -> diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::pg::PgConnection>> {
@@ -59,14 +98,141 @@ async fn main() {
         conn
     });
 
+    let master_key_file = std::env::var("MASTER_KEY_FILE")
+        .expect("MASTER_KEY_FILE must be set");
+    let keys = crypto::KeyRing::load(&master_key_file).expect("Unable to load master keys");
+
+    if std::env::args().nth(1).as_deref() == Some("rewrap-keys") {
+        match tokio::task::block_in_place(|| {
+            let conn = connection.get().map_err(|e| e.to_string())?;
+            keys.rewrap(&conn)
+        }) {
+            Ok(n) => info!("Rewrapped {} data keys", n),
+            Err(e) => {
+                error!("Error rewrapping data keys: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("encrypt-legacy-data") {
+        match tokio::task::block_in_place(|| {
+            let conn = connection.get().map_err(|e| e.to_string())?;
+            legacy::encrypt(&keys, &conn)
+        }) {
+            Ok(e) => info!(
+                "Re-addressed {} blobs, and encrypted {} inbound messages, {} subparts and {} outbound messages",
+                e.blobs, e.inbound, e.subparts, e.outbound
+            ),
+            Err(e) => {
+                error!("Error encrypting legacy data: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("create-api-key") {
+        let name = std::env::args().nth(2).expect("Usage: create-api-key <name>");
+        match tokio::task::block_in_place(|| {
+            let conn = connection.get().map_err(|e| e.to_string())?;
+            api::create_key(&name, &conn).map_err(|e| e.to_string())
+        }) {
+            Ok(k) => println!("{}", k),
+            Err(e) => {
+                error!("Error creating API key: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    match tokio::task::block_in_place(|| {
+        let conn = connection.get().map_err(|e| e.to_string())?;
+        legacy::plaintext_remains(&conn).map_err(|e| e.to_string())
+    }) {
+        Ok(false) => {},
+        Ok(true) => {
+            error!("Mail from before encryption at rest is still stored in plaintext, run encrypt-legacy-data first");
+            std::process::exit(1);
+        },
+        Err(e) => {
+            error!("Error checking for plaintext mail: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let held_mail_expiry = match std::env::var("HELD_MAIL_EXPIRY_DAYS") {
+        Ok(d) => chrono::Duration::days(d.parse().expect("HELD_MAIL_EXPIRY_DAYS must be a number of days")),
+        Err(_) => chrono::Duration::days(30)
+    };
+    let alias_grace_period = match std::env::var("ALIAS_GRACE_PERIOD_DAYS") {
+        Ok(d) => chrono::Duration::days(d.parse().expect("ALIAS_GRACE_PERIOD_DAYS must be a number of days")),
+        Err(_) => chrono::Duration::days(30)
+    };
+    let released_mail_retention = match std::env::var("RELEASED_MAIL_RETENTION_DAYS") {
+        Ok(d) => chrono::Duration::days(d.parse().expect("RELEASED_MAIL_RETENTION_DAYS must be a number of days")),
+        Err(_) => chrono::Duration::days(7)
+    };
+    let sent_mail_retention = match std::env::var("SENT_MAIL_RETENTION_DAYS") {
+        Ok(d) => chrono::Duration::days(d.parse().expect("SENT_MAIL_RETENTION_DAYS must be a number of days")),
+        Err(_) => chrono::Duration::days(7)
+    };
+    let failed_mail_retention = match std::env::var("FAILED_MAIL_RETENTION_DAYS") {
+        Ok(d) => chrono::Duration::days(d.parse().expect("FAILED_MAIL_RETENTION_DAYS must be a number of days")),
+        Err(_) => chrono::Duration::days(30)
+    };
+    let idempotency_key_retention = match std::env::var("IDEMPOTENCY_KEY_RETENTION_HOURS") {
+        Ok(h) => chrono::Duration::hours(h.parse().expect("IDEMPOTENCY_KEY_RETENTION_HOURS must be a number of hours")),
+        Err(_) => chrono::Duration::hours(24)
+    };
+
+    let default_limits = throttle::Limits::default();
+    let throttle_limits = throttle::Limits {
+        max_concurrent_per_domain: match std::env::var("THROTTLE_MAX_CONCURRENT_PER_DOMAIN") {
+            Ok(n) => n.parse().expect("THROTTLE_MAX_CONCURRENT_PER_DOMAIN must be a number of connections"),
+            Err(_) => default_limits.max_concurrent_per_domain
+        },
+        max_concurrent_per_mx: match std::env::var("THROTTLE_MAX_CONCURRENT_PER_MX") {
+            Ok(n) => n.parse().expect("THROTTLE_MAX_CONCURRENT_PER_MX must be a number of connections"),
+            Err(_) => default_limits.max_concurrent_per_mx
+        },
+        max_rate_per_minute: match std::env::var("THROTTLE_MAX_RATE_PER_MINUTE") {
+            Ok(r) => r.parse().expect("THROTTLE_MAX_RATE_PER_MINUTE must be a number of transactions per minute"),
+            Err(_) => default_limits.max_rate_per_minute
+        },
+        min_rate_per_minute: match std::env::var("THROTTLE_MIN_RATE_PER_MINUTE") {
+            Ok(r) => r.parse().expect("THROTTLE_MIN_RATE_PER_MINUTE must be a number of transactions per minute"),
+            Err(_) => default_limits.min_rate_per_minute
+        },
+        backoff: match std::env::var("THROTTLE_BACKOFF_SECS") {
+            Ok(s) => std::time::Duration::from_secs(s.parse().expect("THROTTLE_BACKOFF_SECS must be a number of seconds")),
+            Err(_) => default_limits.backoff
+        },
+    };
+
     let (system_conf, mut system_options) = trust_dns_resolver::system_conf::read_system_conf().expect("Unable to read DNS config");
     system_options.ip_strategy = trust_dns_resolver::config::LookupIpStrategy::Ipv4AndIpv6;
-    let resolver = trust_dns_resolver::TokioAsyncResolver::tokio(system_conf, system_options).await.expect("Unable to load DNS config");
+    let mut dnssec_options = system_options.clone();
+    dnssec_options.validate = true;
+    let resolver = trust_dns_resolver::TokioAsyncResolver::tokio(system_conf.clone(), system_options).await.expect("Unable to load DNS config");
+    let dnssec_resolver = trust_dns_resolver::TokioAsyncResolver::tokio(system_conf, dnssec_options).await.expect("Unable to load DNS config");
     let mut listener = tokio::net::TcpListener::bind("[::]:2525").await.expect("Unable to open listener");
 
     let config = Config {
         resolver: std::sync::Arc::new(resolver),
+        dnssec_resolver: std::sync::Arc::new(dnssec_resolver),
         connection,
+        pool: std::sync::Arc::new(client::ConnectionPool::default()),
+        throttle: std::sync::Arc::new(throttle::Throttle::new(throttle_limits)),
+        keys: std::sync::Arc::new(keys),
+        held_mail_expiry,
+        alias_grace_period,
+        released_mail_retention,
+        sent_mail_retention,
+        failed_mail_retention,
+        idempotency_key_retention,
     };
 
 //    tokio::task::block_in_place(|| {
@@ -105,6 +271,41 @@ async fn main() {
         sender::sending_task(conf).await
     });
 
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        client::close_idle_sessions_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        throttle::logging_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        tls_rpt::reporting_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        janitor::janitor_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        held::digest_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        verification::verification_task(conf).await
+    });
+
+    let conf = config.clone();
+    tokio::task::spawn(async {
+        web::web_task(conf).await
+    });
+
     loop {
         let (socket, addr) = listener.accept().await.expect("Unable to accept client");
         println!("new connection from {:?}", addr);
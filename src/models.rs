@@ -1,4 +1,4 @@
-use super::schema::{inbound_queue, mail_subpart, outbound_message, outbound_queue};
+use super::schema::{alias_audit, api_idempotency_key, api_key, blob, data_key, delivery_attempt, domain_contact, domain_contact_history, forward_email_verification, inbound_queue, mail_subpart, mta_sts_policy, outbound_message, outbound_queue, portal_login_token, portal_session, raw_message, registered_addresses, sender_rule, tls_report_result};
 use super::schema;
 
 #[derive(Queryable, Debug)]
@@ -10,9 +10,70 @@ pub struct InboundQueueItem {
     pub sender: Option<String>,
     pub reply_to: Option<Vec<String>>,
     pub subject: Option<String>,
-    pub contents_id: uuid::Uuid
+    pub contents_id: uuid::Uuid,
+    pub received_at: chrono::DateTime<chrono::Utc>,
+    pub trace_headers: Option<String>,
+    pub data_key: Option<uuid::Uuid>,
+    pub encrypted_subject: Option<Vec<u8>>,
+    pub released_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub spf_result: Option<String>,
+    pub spf_domain: Option<String>,
+    pub dkim_result: Option<String>,
+    pub dkim_domains: Option<Vec<String>>,
+    pub action_token_hash: Option<Vec<u8>>,
+    pub dmarc_result: Option<String>,
+    pub dmarc_domain: Option<String>,
+    pub encrypted_headers: Option<Vec<u8>>,
 }
 
+/// The columns of `inbound_queue` loaded into an `InboundQueueItem`
+pub type InboundQueueItemColumns = (
+    inbound_queue::id,
+    inbound_queue::rcpt_to,
+    inbound_queue::message_id,
+    inbound_queue::mail_from,
+    inbound_queue::mail_sender,
+    inbound_queue::mail_reply_to,
+    inbound_queue::subject,
+    inbound_queue::contents,
+    inbound_queue::received_at,
+    inbound_queue::trace_headers,
+    inbound_queue::data_key,
+    inbound_queue::encrypted_subject,
+    inbound_queue::released_at,
+    inbound_queue::spf_result,
+    inbound_queue::spf_domain,
+    inbound_queue::dkim_result,
+    inbound_queue::dkim_domains,
+    inbound_queue::action_token_hash,
+    inbound_queue::dmarc_result,
+    inbound_queue::dmarc_domain,
+    inbound_queue::encrypted_headers,
+);
+pub const INBOUND_QUEUE_ITEM_COLUMNS: InboundQueueItemColumns = (
+    inbound_queue::id,
+    inbound_queue::rcpt_to,
+    inbound_queue::message_id,
+    inbound_queue::mail_from,
+    inbound_queue::mail_sender,
+    inbound_queue::mail_reply_to,
+    inbound_queue::subject,
+    inbound_queue::contents,
+    inbound_queue::received_at,
+    inbound_queue::trace_headers,
+    inbound_queue::data_key,
+    inbound_queue::encrypted_subject,
+    inbound_queue::released_at,
+    inbound_queue::spf_result,
+    inbound_queue::spf_domain,
+    inbound_queue::dkim_result,
+    inbound_queue::dkim_domains,
+    inbound_queue::action_token_hash,
+    inbound_queue::dmarc_result,
+    inbound_queue::dmarc_domain,
+    inbound_queue::encrypted_headers,
+);
+
 #[derive(Insertable)]
 #[table_name="inbound_queue"]
 pub struct NewInboundQueueItem<'a> {
@@ -23,16 +84,116 @@ pub struct NewInboundQueueItem<'a> {
     pub mail_sender: Option<&'a str>,
     pub mail_reply_to: Option<&'a[&'a str]>,
     pub subject: Option<&'a str>,
-    pub contents: &'a uuid::Uuid
+    pub contents: &'a uuid::Uuid,
+    pub envelope_from: Option<&'a str>,
+    pub helo: Option<&'a str>,
+    pub peer_ip: Option<&'a str>,
+    pub reverse_dns: Option<&'a str>,
+    pub protocol: Option<&'a str>,
+    pub received_at: &'a chrono::DateTime<chrono::Utc>,
+    pub raw_message: Option<&'a uuid::Uuid>,
+    pub trace_headers: Option<&'a str>,
+    pub data_key: Option<&'a uuid::Uuid>,
+    pub encrypted_subject: Option<&'a[u8]>,
+    pub spf_result: Option<&'a str>,
+    pub spf_domain: Option<&'a str>,
+    pub dkim_result: Option<&'a str>,
+    pub dkim_domains: Option<&'a[String]>,
+    pub dmarc_result: Option<&'a str>,
+    pub dmarc_domain: Option<&'a str>,
+    pub encrypted_headers: Option<&'a[u8]>,
 }
 
+#[derive(Queryable, Debug)]
+pub struct MailSubpart {
+    pub id: uuid::Uuid,
+    pub headers: Vec<schema::StoredMailHeader>,
+    pub subparts: Vec<uuid::Uuid>,
+    pub raw_message: Option<uuid::Uuid>,
+    pub header_offset: Option<i32>,
+    pub end_offset: Option<i32>,
+    pub body_hash: Vec<u8>,
+    pub data_key: Option<uuid::Uuid>,
+    pub encrypted_headers: Option<Vec<u8>>,
+}
+
+/// The columns of `mail_subpart` loaded into a `MailSubpart`
+pub type MailSubpartColumns = (
+    mail_subpart::id,
+    mail_subpart::headers,
+    mail_subpart::subparts,
+    mail_subpart::raw_message,
+    mail_subpart::header_offset,
+    mail_subpart::end_offset,
+    mail_subpart::body_hash,
+    mail_subpart::data_key,
+    mail_subpart::encrypted_headers,
+);
+pub const MAIL_SUBPART_COLUMNS: MailSubpartColumns = (
+    mail_subpart::id,
+    mail_subpart::headers,
+    mail_subpart::subparts,
+    mail_subpart::raw_message,
+    mail_subpart::header_offset,
+    mail_subpart::end_offset,
+    mail_subpart::body_hash,
+    mail_subpart::data_key,
+    mail_subpart::encrypted_headers,
+);
+
 #[derive(Insertable)]
 #[table_name="mail_subpart"]
 pub struct NewMailSubpart<'a> {
     pub id: &'a uuid::Uuid,
     pub headers: &'a[&'a schema::MailHeader<'a>],
-    pub body: &'a[u8],
-    pub subparts: &'a[&'a uuid::Uuid]
+    pub subparts: &'a[&'a uuid::Uuid],
+    pub raw_message: Option<&'a uuid::Uuid>,
+    pub header_offset: Option<i32>,
+    pub body_offset: Option<i32>,
+    pub end_offset: Option<i32>,
+    pub body_hash: &'a[u8],
+    pub data_key: Option<&'a uuid::Uuid>,
+    pub encrypted_headers: Option<&'a[u8]>,
+}
+
+#[derive(Insertable)]
+#[table_name="raw_message"]
+pub struct NewRawMessage<'a> {
+    pub id: &'a uuid::Uuid,
+    pub data_hash: &'a[u8],
+}
+
+#[derive(Queryable, Debug)]
+pub struct Blob {
+    pub hash: Vec<u8>,
+    pub data: Vec<u8>,
+    pub compressed: bool,
+    pub data_key: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name="blob"]
+pub struct NewBlob<'a> {
+    pub hash: &'a[u8],
+    pub data: &'a[u8],
+    pub compressed: bool,
+    pub ref_count: i64,
+    pub data_key: Option<&'a uuid::Uuid>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct DataKey {
+    pub id: uuid::Uuid,
+    pub master_key_id: String,
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name="data_key"]
+pub struct NewDataKey<'a> {
+    pub id: &'a uuid::Uuid,
+    pub master_key_id: &'a str,
+    pub wrapped_key: &'a[u8],
 }
 
 #[derive(Identifiable, Queryable, Debug)]
@@ -40,7 +201,8 @@ pub struct NewMailSubpart<'a> {
 pub struct OutboundMessage {
     pub id: uuid::Uuid,
     pub return_path: String,
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    pub data_key: Option<uuid::Uuid>,
 }
 
 #[derive(Insertable)]
@@ -49,6 +211,7 @@ pub struct NewOutboundMessage<'a> {
     pub id: &'a uuid::Uuid,
     pub return_path: &'a str,
     pub data: &'a[u8],
+    pub data_key: Option<&'a uuid::Uuid>,
 }
 
 #[derive(Identifiable, Queryable, Associations, Debug)]
@@ -59,7 +222,12 @@ pub struct OutboundQueueItem {
     pub message_id: uuid::Uuid,
     pub forward_path: String,
     pub state: schema::MailState,
-    pub state_since: chrono::DateTime<chrono::Utc>
+    pub state_since: chrono::DateTime<chrono::Utc>,
+    pub attempts: i32,
+    pub next_attempt: chrono::DateTime<chrono::Utc>,
+    pub last_error: Option<String>,
+    pub remote_mx: Option<String>,
+    pub remote_queue_id: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -70,4 +238,210 @@ pub struct NewOutboundQueueItem<'a> {
     pub forward_path: &'a str,
     pub state: &'a schema::MailState,
     pub state_since: &'a chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct MtaStsPolicy {
+    pub policy_id: String,
+    pub mode: schema::MtaStsMode,
+    pub mx: Vec<String>,
+    pub max_age: i64,
+    pub fetched_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Insertable)]
+#[table_name="mta_sts_policy"]
+pub struct NewMtaStsPolicy<'a> {
+    pub domain: &'a str,
+    pub policy_id: &'a str,
+    pub mode: &'a schema::MtaStsMode,
+    pub mx: &'a[&'a str],
+    pub max_age: i64,
+    pub fetched_at: &'a chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct OutboundTlsPolicy {
+    pub domain: String,
+    pub mode: schema::OutboundTlsMode,
+    pub fingerprint: Option<String>
+}
+
+#[derive(Queryable, Debug)]
+pub struct TlsReportResult {
+    pub policy_domain: String,
+    pub policy_type: String,
+    pub policy_string: Vec<String>,
+    pub mx_host: Vec<String>,
+    pub sending_ip: Option<String>,
+    pub receiving_mx_hostname: String,
+    pub receiving_ip: String,
+    pub result_type: Option<String>,
+    pub failure_reason: Option<String>,
+    pub recorded_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Insertable)]
+#[table_name="tls_report_result"]
+pub struct NewTlsReportResult<'a> {
+    pub id: &'a uuid::Uuid,
+    pub policy_domain: &'a str,
+    pub policy_type: &'a str,
+    pub policy_string: &'a[&'a str],
+    pub mx_host: &'a[&'a str],
+    pub sending_ip: Option<&'a str>,
+    pub receiving_mx_hostname: &'a str,
+    pub receiving_ip: &'a str,
+    pub result_type: Option<&'a str>,
+    pub failure_reason: Option<&'a str>,
+    pub recorded_at: &'a chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name="delivery_attempt"]
+pub struct NewDeliveryAttempt<'a> {
+    pub id: &'a uuid::Uuid,
+    pub queue_item_id: &'a uuid::Uuid,
+    pub mx_host: &'a str,
+    pub mx_ip: &'a str,
+    pub tls: Option<&'a str>,
+    pub failed_stage: Option<&'a schema::DeliveryStage>,
+    pub response_code: Option<i32>,
+    pub response_lines: Option<&'a[String]>,
+    pub error: Option<&'a str>,
+    pub started_at: &'a chrono::DateTime<chrono::Utc>,
+    pub duration_ms: i32,
+    pub transcript: Option<&'a str>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct RegisteredAddress {
+    pub id: String,
+    pub forward_email: String,
+    pub policy: schema::AliasPolicy,
+    pub rotated_to: Option<String>,
+    pub grace_mode: Option<schema::GraceMode>,
+    pub grace_until: Option<chrono::DateTime<chrono::Utc>>,
+    /// Set once a rotated address is revoked, which leaves it behind so it keeps being refused
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name="registered_addresses"]
+pub struct NewRegisteredAddress<'a> {
+    pub id: &'a str,
+    pub forward_email: &'a str,
+    pub policy: &'a schema::AliasPolicy,
+}
+
+#[derive(Insertable)]
+#[table_name="domain_contact"]
+pub struct NewDomainContact<'a> {
+    pub domain: &'a str,
+    pub role: &'a schema::ContactRole,
+    pub registered_address: &'a str,
+}
+
+#[derive(Queryable, Debug)]
+pub struct DomainContactHistory {
+    pub role: schema::ContactRole,
+    pub registered_address: String,
+    pub forward_email: String,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub ended_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name="domain_contact_history"]
+pub struct NewDomainContactHistory<'a> {
+    pub domain: &'a str,
+    pub role: &'a schema::ContactRole,
+    pub registered_address: &'a str,
+    pub forward_email: &'a str,
+}
+
+#[derive(Queryable, Debug)]
+pub struct ForwardEmailVerification {
+    pub forward_email: String,
+    pub requests_sent: i32,
+    pub verified_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name="forward_email_verification"]
+pub struct NewForwardEmailVerification<'a> {
+    pub forward_email: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name="alias_audit"]
+pub struct NewAliasAudit<'a> {
+    pub id: &'a uuid::Uuid,
+    pub actor: &'a str,
+    pub action: &'a str,
+    pub address: &'a str,
+    pub forward_email: Option<&'a str>,
+    pub policy: Option<&'a schema::AliasPolicy>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub name: String,
+}
+
+#[derive(Insertable)]
+#[table_name="api_key"]
+pub struct NewApiKey<'a> {
+    pub id: &'a uuid::Uuid,
+    pub name: &'a str,
+    pub key_hash: &'a[u8],
+}
+
+#[derive(Queryable, Debug)]
+pub struct ApiIdempotencyKey {
+    pub request_hash: Vec<u8>,
+    /// Not set until the request that claimed the key has finished
+    pub response_status: Option<i32>,
+    pub response_body: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name="api_idempotency_key"]
+pub struct NewApiIdempotencyKey<'a> {
+    pub api_key: &'a uuid::Uuid,
+    pub idempotency_key: &'a str,
+    pub request_hash: &'a[u8],
+}
+
+#[derive(Queryable, Debug)]
+pub struct SenderRule {
+    pub id: uuid::Uuid,
+    pub sender: String,
+    pub allow: bool,
+}
+
+#[derive(Insertable)]
+#[table_name="sender_rule"]
+pub struct NewSenderRule<'a> {
+    pub id: &'a uuid::Uuid,
+    pub registered_address: &'a str,
+    pub sender: &'a str,
+    pub allow: bool,
+}
+
+#[derive(Insertable)]
+#[table_name="portal_login_token"]
+pub struct NewPortalLoginToken<'a> {
+    pub token_hash: &'a[u8],
+    pub forward_email: &'a str,
+    pub expires_at: &'a chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name="portal_session"]
+pub struct NewPortalSession<'a> {
+    pub token_hash: &'a[u8],
+    pub forward_email: &'a str,
+    pub expires_at: &'a chrono::DateTime<chrono::Utc>,
 }
\ No newline at end of file
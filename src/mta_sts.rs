@@ -0,0 +1,497 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Largest max_age a policy may specify, per RFC 8461 § 3.2
+const MAX_MAX_AGE: i64 = 31_557_600;
+/// Policy files larger than this are rejected, per RFC 8461 § 3.3
+const MAX_POLICY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub id: String,
+    pub mode: schema::MtaStsMode,
+    pub mx: Vec<String>,
+    pub max_age: i64,
+}
+
+impl Policy {
+    /// Checks an MX hostname against the policy's mx patterns, as per RFC 8461 § 4.1
+    pub fn matches_mx(&self, mx_host: &str) -> bool {
+        let mx_host = mx_host.trim_end_matches('.').to_lowercase();
+        self.mx.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('.').to_lowercase();
+            if pattern.starts_with("*.") {
+                match mx_host.splitn(2, '.').nth(1) {
+                    Some(parent) => parent == &pattern[2..],
+                    None => false
+                }
+            } else {
+                mx_host == pattern
+            }
+        })
+    }
+
+    fn parse(data: &str) -> Result<Self, String> {
+        let mut version = None;
+        let mut mode = None;
+        let mut max_age = None;
+        let mut mx = vec![];
+
+        for line in data.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(v) => v.trim(),
+                None => return Err(format!("Invalid policy line: {}", line))
+            };
+            match key {
+                "version" => version = Some(value.to_string()),
+                "mode" => mode = Some(match value {
+                    "enforce" => schema::MtaStsMode::Enforce,
+                    "testing" => schema::MtaStsMode::Testing,
+                    "none" => schema::MtaStsMode::None,
+                    m => return Err(format!("Invalid policy mode: {}", m))
+                }),
+                "max_age" => max_age = Some(match value.parse::<i64>() {
+                    Ok(a) if a >= 0 => std::cmp::min(a, MAX_MAX_AGE),
+                    _ => return Err(format!("Invalid max_age: {}", value))
+                }),
+                "mx" => mx.push(value.to_string()),
+                // Unknown keys must be ignored
+                _ => {}
+            }
+        }
+
+        if version.as_deref() != Some("STSv1") {
+            return Err("Invalid policy version".to_string());
+        }
+        let mode = mode.ok_or_else(|| "Policy missing mode".to_string())?;
+        let max_age = max_age.ok_or_else(|| "Policy missing max_age".to_string())?;
+        if mx.is_empty() && mode != schema::MtaStsMode::None {
+            return Err("Policy missing mx".to_string());
+        }
+
+        Ok(Self {
+            id: String::new(),
+            mode,
+            mx,
+            max_age,
+        })
+    }
+}
+
+impl From<models::MtaStsPolicy> for Policy {
+    fn from(from: models::MtaStsPolicy) -> Self {
+        Self {
+            id: from.policy_id,
+            mode: from.mode,
+            mx: from.mx,
+            max_age: from.max_age,
+        }
+    }
+}
+
+/// Looks up the `_mta-sts` TXT record for a domain, returning the policy ID it advertises
+async fn lookup_policy_id(domain: &str, config: &crate::Config) -> Result<Option<String>, String> {
+    let records = match config.resolver.txt_lookup(format!("_mta-sts.{}.", domain)).await {
+        Ok(r) => r,
+        Err(e) => match e.kind() {
+            trust_dns_resolver::error::ResolveErrorKind::NoRecordsFound { .. } => return Ok(None),
+            _ => return Err(e.to_string())
+        }
+    };
+
+    let records = records.iter().map(|r| {
+        r.txt_data().iter().map(|d| String::from_utf8_lossy(d).into_owned()).collect::<String>()
+    }).filter(|r| r.starts_with("v=STSv1")).collect::<Vec<_>>();
+
+    // More than one record is to be treated as no policy
+    let record = match &records[..] {
+        [r] => r,
+        _ => return Ok(None)
+    };
+
+    let mut version = None;
+    let mut id = None;
+    for field in record.split(';') {
+        let mut parts = field.trim().splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("v"), Some(v)) => version = Some(v),
+            (Some("id"), Some(i)) => id = Some(i),
+            _ => {}
+        }
+    }
+
+    match (version, id) {
+        (Some("STSv1"), Some(id)) if !id.is_empty() && id.len() <= 32 && id.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Ok(Some(id.to_string()))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Where a domain's policy is served from, as per RFC 8461 § 3.3
+fn policy_base_url(domain: &str) -> String {
+    format!("https://mta-sts.{}", domain)
+}
+
+fn policy_client() -> Result<reqwest::Client, String> {
+    policy_client_builder()
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Policies have to be served directly, redirects aren't to be followed
+fn policy_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(60))
+}
+
+async fn fetch_policy(client: &reqwest::Client, base_url: &str) -> Result<Policy, String> {
+    let resp = client.get(&format!("{}/.well-known/mta-sts.txt", base_url))
+        .send().await
+        .map_err(|e| e.to_string())?;
+
+    if resp.status() != reqwest::StatusCode::OK {
+        return Err(format!("Policy fetch returned {}", resp.status()));
+    }
+    match resp.headers().get(reqwest::header::CONTENT_TYPE).and_then(|c| c.to_str().ok()) {
+        Some(c) if c.to_lowercase().starts_with("text/plain") => {},
+        c => return Err(format!("Invalid policy content type: {:?}", c))
+    }
+    if resp.content_length().unwrap_or_default() as usize > MAX_POLICY_SIZE {
+        return Err("Policy too large".to_string());
+    }
+
+    let body = resp.bytes().await.map_err(|e| e.to_string())?;
+    if body.len() > MAX_POLICY_SIZE {
+        return Err("Policy too large".to_string());
+    }
+
+    Policy::parse(&String::from_utf8_lossy(&body))
+}
+
+fn cached_policy(domain: &str, conn: &crate::DbConn) -> Option<Policy> {
+    let policy = match schema::mta_sts_policy::table
+        .filter(schema::mta_sts_policy::domain.eq(domain))
+        .select((
+            schema::mta_sts_policy::policy_id, schema::mta_sts_policy::mode, schema::mta_sts_policy::mx,
+            schema::mta_sts_policy::max_age, schema::mta_sts_policy::fetched_at,
+        ))
+        .first::<models::MtaStsPolicy>(conn)
+        .optional() {
+        Ok(p) => p?,
+        Err(e) => {
+            error!("Error loading cached MTA-STS policy for {}: {}", domain, e);
+            return None;
+        }
+    };
+
+    if policy.fetched_at + chrono::Duration::seconds(policy.max_age) < chrono::Utc::now() {
+        return None;
+    }
+    Some(policy.into())
+}
+
+fn cache_policy(domain: &str, policy: &Policy, conn: &crate::DbConn) {
+    let now = chrono::Utc::now();
+    let mx = policy.mx.iter().map(|m| m.as_str()).collect::<Vec<_>>();
+    let new_policy = models::NewMtaStsPolicy {
+        domain,
+        policy_id: &policy.id,
+        mode: &policy.mode,
+        mx: &mx,
+        max_age: policy.max_age,
+        fetched_at: &now,
+    };
+
+    match diesel::insert_into(schema::mta_sts_policy::table)
+        .values(&new_policy)
+        .on_conflict(schema::mta_sts_policy::domain)
+        .do_update()
+        .set((
+            schema::mta_sts_policy::policy_id.eq(&policy.id),
+            schema::mta_sts_policy::mode.eq(&policy.mode),
+            schema::mta_sts_policy::mx.eq(&mx),
+            schema::mta_sts_policy::max_age.eq(policy.max_age),
+            schema::mta_sts_policy::fetched_at.eq(&now),
+        ))
+        .execute(conn) {
+        Ok(_) => {},
+        Err(e) => error!("Error caching MTA-STS policy for {}: {}", domain, e)
+    }
+}
+
+/// Works out which policy is in force from the policy ID a domain advertises and the cached
+/// policy, if it's still valid, fetching the policy again when the ID has changed
+async fn current_policy(
+    domain: &str, policy_id: Result<Option<String>, String>, cached: Option<Policy>, client: &reqwest::Client,
+    base_url: &str,
+) -> Option<Policy> {
+    let policy_id = match policy_id {
+        Ok(Some(i)) => i,
+        Ok(None) => return cached,
+        Err(e) => {
+            warn!("Unable to lookup MTA-STS record for {}: {}", domain, e);
+            return cached;
+        }
+    };
+
+    if let Some(cached) = &cached {
+        if cached.id == policy_id {
+            return Some(cached.clone());
+        }
+    }
+
+    match fetch_policy(client, base_url).await {
+        Ok(mut policy) => {
+            policy.id = policy_id;
+            info!("Fetched MTA-STS policy for {}: {:?}", domain, policy);
+            Some(policy)
+        },
+        Err(e) => {
+            warn!("Unable to fetch MTA-STS policy for {}: {}", domain, e);
+            cached
+        }
+    }
+}
+
+/// Finds the MTA-STS policy for a recipient domain, using the cached copy where it's still valid
+/// and hasn't been superseded, as per RFC 8461 § 5.1
+pub async fn get_policy(domain: &str, config: &crate::Config) -> Option<Policy> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    let conn = match tokio::task::block_in_place(|| {
+        config.connection.get()
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error getting DB connection: {}", e);
+            return None;
+        }
+    };
+    let cached = tokio::task::block_in_place(|| cached_policy(&domain, &conn));
+
+    let client = match policy_client() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to setup MTA-STS client: {}", e);
+            return cached;
+        }
+    };
+    let policy_id = lookup_policy_id(&domain, config).await;
+    let policy = current_policy(&domain, policy_id, cached.clone(), &client, &policy_base_url(&domain)).await;
+
+    // Anything other than the still valid cached copy was freshly fetched
+    if let Some(policy) = policy.as_ref().filter(|p| Some(*p) != cached.as_ref()) {
+        tokio::task::block_in_place(|| cache_policy(&domain, policy, &conn));
+    }
+    policy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\nmx: *.example.net\r\nmax_age: 86400\r\n";
+
+    /// A policy server on localhost serving over TLS with the given certificate, counting how many
+    /// times the policy's been fetched
+    struct PolicyServer {
+        base_url: String,
+        policy: std::sync::Arc<std::sync::Mutex<(String, &'static str)>>,
+        fetches: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl PolicyServer {
+        fn start(policy: &str, cert: &crate::testing::Issued) -> Self {
+            let policy = std::sync::Arc::new(std::sync::Mutex::new((policy.to_string(), "text/plain")));
+            let fetches = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+            let mut acceptor = openssl::ssl::SslAcceptor::mozilla_intermediate(openssl::ssl::SslMethod::tls()).unwrap();
+            acceptor.set_private_key(&cert.key).unwrap();
+            acceptor.set_certificate(&cert.cert).unwrap();
+            let acceptor = std::sync::Arc::new(acceptor.build());
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+            let mut listener = tokio::net::TcpListener::from_std(listener).unwrap();
+
+            let (server_policy, server_fetches) = (policy.clone(), fetches.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (acceptor, policy, fetches) = (acceptor.clone(), server_policy.clone(), server_fetches.clone());
+                    tokio::spawn(async move {
+                        // Clients refusing the certificate end the handshake
+                        let stream = match tokio_openssl::accept(&acceptor, stream).await {
+                            Ok(s) => s,
+                            Err(_) => return
+                        };
+                        let service = hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                            let (policy, fetches) = (policy.clone(), fetches.clone());
+                            async move {
+                                if req.uri().path() != "/.well-known/mta-sts.txt" {
+                                    return Ok::<_, hyper::Error>(crate::web::status_response(hyper::StatusCode::NOT_FOUND));
+                                }
+                                fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                                let (body, content_type) = policy.lock().unwrap().clone();
+                                Ok(hyper::Response::builder()
+                                    .header(hyper::header::CONTENT_TYPE, content_type)
+                                    .body(hyper::Body::from(body))
+                                    .unwrap())
+                            }
+                        });
+                        let _ = hyper::server::conn::Http::new().serve_connection(stream, service).await;
+                    });
+                }
+            });
+
+            PolicyServer {
+                base_url,
+                policy,
+                fetches,
+            }
+        }
+
+        fn set_policy(&self, policy: &str, content_type: &'static str) {
+            *self.policy.lock().unwrap() = (policy.to_string(), content_type);
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    /// A CA, and a certificate for localhost it's issued
+    fn localhost_cert() -> (crate::testing::Issued, crate::testing::Issued) {
+        let ca = crate::testing::issue("Test CA", true, None, -1, 10);
+        let cert = crate::testing::issue("localhost", false, Some(&ca), -1, 10);
+        (ca, cert)
+    }
+
+    /// A policy client that trusts the given CA
+    fn client_trusting(ca: &crate::testing::Issued) -> reqwest::Client {
+        policy_client_builder()
+            .add_root_certificate(reqwest::Certificate::from_der(&ca.cert.to_der().unwrap()).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_policies() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert_eq!(policy.mode, schema::MtaStsMode::Enforce);
+        assert_eq!(policy.mx, vec!["mail.example.com", "*.example.net"]);
+        assert_eq!(policy.max_age, 86400);
+
+        let policy = Policy::parse("version: STSv1\nmode: testing\nmx: mail.example.com\nmax_age: 999999999\nextension: ignored\n").unwrap();
+        assert_eq!(policy.mode, schema::MtaStsMode::Testing);
+        assert_eq!(policy.max_age, MAX_MAX_AGE);
+        assert_eq!(Policy::parse("version: STSv1\nmode: none\nmax_age: 0\n").unwrap().mode, schema::MtaStsMode::None);
+
+        assert!(Policy::parse("version: STSv2\nmode: enforce\nmx: mail.example.com\nmax_age: 86400\n").is_err());
+        assert!(Policy::parse("version: STSv1\nmode: strict\nmx: mail.example.com\nmax_age: 86400\n").is_err());
+        assert!(Policy::parse("version: STSv1\nmode: enforce\nmax_age: 86400\n").is_err());
+        assert!(Policy::parse("version: STSv1\nmode: enforce\nmx: mail.example.com\n").is_err());
+        assert!(Policy::parse("version: STSv1\nmode: enforce\nmx: mail.example.com\nmax_age: -1\n").is_err());
+    }
+
+    #[test]
+    fn matches_mx_patterns() {
+        let policy = Policy::parse(POLICY).unwrap();
+        assert!(policy.matches_mx("mail.example.com"));
+        assert!(policy.matches_mx("MAIL.example.com."));
+        assert!(!policy.matches_mx("other.example.com"));
+        assert!(policy.matches_mx("mx1.example.net"));
+        assert!(policy.matches_mx("MX2.Example.NET."));
+        // Wildcards only cover one label
+        assert!(!policy.matches_mx("a.mx1.example.net"));
+        assert!(!policy.matches_mx("example.net"));
+        assert!(!policy.matches_mx("mx1.badexample.net"));
+    }
+
+    #[tokio::test]
+    async fn fetches_policies() {
+        let (ca, cert) = localhost_cert();
+        let server = PolicyServer::start(POLICY, &cert);
+        let client = client_trusting(&ca);
+
+        let policy = fetch_policy(&client, &server.base_url).await.unwrap();
+        assert_eq!(policy.mx, vec!["mail.example.com", "*.example.net"]);
+
+        server.set_policy(POLICY, "text/html");
+        assert!(fetch_policy(&client, &server.base_url).await.is_err());
+        server.set_policy(&"x".repeat(MAX_POLICY_SIZE + 1), "text/plain");
+        assert!(fetch_policy(&client, &server.base_url).await.is_err());
+        assert!(fetch_policy(&client, &format!("{}/elsewhere", server.base_url)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn policies_need_a_trusted_certificate_for_the_host() {
+        let (ca, _) = localhost_cert();
+
+        let untrusted = crate::testing::issue("localhost", false, None, -1, 10);
+        let server = PolicyServer::start(POLICY, &untrusted);
+        assert!(fetch_policy(&client_trusting(&ca), &server.base_url).await.is_err());
+
+        let mismatched = crate::testing::issue("mta-sts.example.com", false, Some(&ca), -1, 10);
+        let server = PolicyServer::start(POLICY, &mismatched);
+        assert!(fetch_policy(&client_trusting(&ca), &server.base_url).await.is_err());
+
+        let expired = crate::testing::issue("localhost", false, Some(&ca), -10, -1);
+        let server = PolicyServer::start(POLICY, &expired);
+        assert!(fetch_policy(&client_trusting(&ca), &server.base_url).await.is_err());
+        assert_eq!(server.fetches(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn policy_is_cached_until_it_expires_or_its_id_changes() {
+        let (ca, cert) = localhost_cert();
+        let server = PolicyServer::start(POLICY, &cert);
+        let client = client_trusting(&ca);
+        let conn = crate::testing::connection();
+        let domain = "example.com";
+        let id = || Ok(Some("20200101".to_string()));
+
+        let policy = current_policy(domain, id(), cached_policy(domain, &conn), &client, &server.base_url).await.unwrap();
+        assert_eq!(policy.id, "20200101");
+        assert_eq!(server.fetches(), 1);
+        cache_policy(domain, &policy, &conn);
+
+        // Unchanged and within max_age the cached copy is used, even when no record can be found
+        let cached = cached_policy(domain, &conn);
+        assert_eq!(cached.as_ref(), Some(&policy));
+        assert_eq!(current_policy(domain, id(), cached.clone(), &client, &server.base_url).await, cached);
+        assert_eq!(current_policy(domain, Ok(None), cached.clone(), &client, &server.base_url).await, cached);
+        assert_eq!(current_policy(domain, Err("SERVFAIL".to_string()), cached, &client, &server.base_url).await.unwrap(), policy);
+        assert_eq!(server.fetches(), 1);
+
+        // A new ID means the policy's changed
+        server.set_policy(&POLICY.replace("mode: enforce", "mode: testing"), "text/plain");
+        let policy = current_policy(
+            domain, Ok(Some("20200202".to_string())), cached_policy(domain, &conn), &client, &server.base_url,
+        ).await.unwrap();
+        assert_eq!(policy.id, "20200202");
+        assert_eq!(policy.mode, schema::MtaStsMode::Testing);
+        assert_eq!(server.fetches(), 2);
+        cache_policy(domain, &policy, &conn);
+
+        // Past max_age the cached copy can't be used, even with the same ID
+        diesel::update(schema::mta_sts_policy::table.filter(schema::mta_sts_policy::domain.eq(domain)))
+            .set(schema::mta_sts_policy::fetched_at.eq(chrono::Utc::now() - chrono::Duration::seconds(86401)))
+            .execute(&conn)
+            .unwrap();
+        assert_eq!(cached_policy(domain, &conn), None);
+        let refetched = current_policy(
+            domain, Ok(Some("20200202".to_string())), cached_policy(domain, &conn), &client, &server.base_url,
+        ).await;
+        assert_eq!(refetched, Some(policy));
+        assert_eq!(server.fetches(), 3);
+    }
+}
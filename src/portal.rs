@@ -0,0 +1,553 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+use crate::web::{render, redirect, status_response};
+
+const SESSION_COOKIE: &str = "portal_session";
+/// How long a login link can be used for
+const LOGIN_TOKEN_LIFETIME_MINUTES: i64 = 30;
+/// How long to wait before sending another login link to the same address
+const LOGIN_TOKEN_INTERVAL_MINUTES: i64 = 1;
+const SESSION_LIFETIME_HOURS: i64 = 12;
+
+/// The policies registrants can pick between, and how they're described to them
+const POLICIES: &[(schema::AliasPolicy, &str)] = &[
+    (schema::AliasPolicy::Challenge, "Ask senders to complete a CAPTCHA"),
+    (schema::AliasPolicy::ForwardDirectly, "Forward everything"),
+    (schema::AliasPolicy::ForwardIfAuthenticated, "Forward mail that passes DMARC, and ask other senders to complete a CAPTCHA"),
+    (schema::AliasPolicy::HoldForReview, "Hold everything for me to review"),
+    (schema::AliasPolicy::Reject, "Reject everything"),
+];
+
+struct Session {
+    forward_email: String,
+    csrf_token: String,
+}
+
+#[derive(Serialize)]
+struct PortalRule {
+    id: String,
+    sender: String,
+    allow: bool,
+}
+
+#[derive(Serialize)]
+struct PortalAlias {
+    id: String,
+    roles: Option<String>,
+    policy: &'static str,
+    /// Set once the address has been rotated
+    rotated_to: Option<String>,
+    grace_mode: Option<&'static str>,
+    grace_until: Option<String>,
+    rules: Vec<PortalRule>,
+    held: Vec<crate::held::HeldSummary>,
+}
+
+#[derive(Serialize)]
+struct PortalPolicy {
+    id: &'static str,
+    name: &'static str,
+}
+
+/// The part of a held message we're willing to show in the browser; its text alone, and the names
+/// of its attachments
+#[derive(Serialize, Default)]
+struct MessagePreview {
+    body: String,
+    attachments: Vec<String>,
+}
+
+fn session_cookie(req: &hyper::Request<hyper::Body>) -> Option<String> {
+    req.headers().get_all(hyper::header::COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| {
+            let mut parts = c.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(SESSION_COOKIE), Some(v)) => Some(v.to_string()),
+                _ => None
+            }
+        })
+        .next()
+}
+
+/// The CSRF token for a session is derived from the session token, so it needn't be stored
+fn csrf_token(session_token: &str) -> String {
+    base64::encode_config(&crate::crypto::token_hash(&format!("csrf:{}", session_token)), base64::URL_SAFE_NO_PAD)
+}
+
+fn load_session(token: &str, conn: &crate::DbConn) -> QueryResult<Option<Session>> {
+    let forward_email = schema::portal_session::table
+        .find(crate::crypto::token_hash(token))
+        .filter(schema::portal_session::expires_at.gt(chrono::Utc::now()))
+        .select(schema::portal_session::forward_email)
+        .get_result::<String>(conn)
+        .optional()?;
+
+    Ok(forward_email.map(|forward_email| Session {
+        forward_email,
+        csrf_token: csrf_token(token),
+    }))
+}
+
+/// Emails a login link to a registrant, if they have any WHOIS addresses and we haven't just sent
+/// them one
+fn send_login_link(email: &str, config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    let forward_email = match crate::alias::for_forward_email(email, conn)
+        .map_err(|e| format!("Unable to load registered addresses: {}", e))?
+        .into_iter().next() {
+        Some(a) => a.forward_email,
+        None => return Ok(())
+    };
+
+    let now = chrono::Utc::now();
+    let recently_sent = schema::portal_login_token::table
+        .filter(schema::portal_login_token::forward_email.eq(&forward_email))
+        .filter(schema::portal_login_token::created_at.gt(now - chrono::Duration::minutes(LOGIN_TOKEN_INTERVAL_MINUTES)))
+        .count()
+        .get_result::<i64>(conn)
+        .map_err(|e| format!("Unable to load login tokens: {}", e))?;
+    if recently_sent > 0 {
+        return Ok(());
+    }
+
+    let token = crate::crypto::random_token();
+    let mut context = tera::Context::new();
+    context.insert("login_link", &format!("{}/portal/login/{}", crate::web::BASE_URL, token));
+    context.insert("expires_in", &LOGIN_TOKEN_LIFETIME_MINUTES);
+    let data = crate::sender::build_notification(&forward_email, "Log in to manage your WHOIS addresses", "portal_login", &context)?;
+
+    conn.transaction(|| {
+        diesel::insert_into(schema::portal_login_token::table)
+            .values(&models::NewPortalLoginToken {
+                token_hash: &crate::crypto::token_hash(&token),
+                forward_email: &forward_email,
+                expires_at: &(now + chrono::Duration::minutes(LOGIN_TOKEN_LIFETIME_MINUTES)),
+            })
+            .execute(conn)?;
+        crate::sender::queue_mail(crate::sender::NOTIFICATION_FROM, &[&forward_email], &data, &config.keys, conn)?;
+        Ok(())
+    }).map_err(|e: diesel::result::Error| format!("Unable to queue login link: {}", e))
+}
+
+/// Swaps a login link's token for a new session, returning the session token
+fn log_in(login_token: &str, conn: &crate::DbConn) -> QueryResult<Option<String>> {
+    conn.transaction(|| {
+        let forward_email = diesel::delete(schema::portal_login_token::table
+            .find(crate::crypto::token_hash(login_token))
+            .filter(schema::portal_login_token::expires_at.gt(chrono::Utc::now()))
+        )
+            .returning(schema::portal_login_token::forward_email)
+            .get_result::<String>(conn)
+            .optional()?;
+        let forward_email = match forward_email {
+            Some(f) => f,
+            None => return Ok(None)
+        };
+        // Following the link shows they get mail sent to the address, just as a verification link would
+        crate::verification::mark_verified(&forward_email, conn)?;
+
+        let session_token = crate::crypto::random_token();
+        diesel::insert_into(schema::portal_session::table)
+            .values(&models::NewPortalSession {
+                token_hash: &crate::crypto::token_hash(&session_token),
+                forward_email: &forward_email,
+                expires_at: &(chrono::Utc::now() + chrono::Duration::hours(SESSION_LIFETIME_HOURS)),
+            })
+            .execute(conn)?;
+        Ok(Some(session_token))
+    })
+}
+
+/// Loads a registered address, as long as it belongs to the logged in registrant
+fn owned_alias(address: &str, session: &Session, conn: &crate::DbConn) -> QueryResult<Option<models::RegisteredAddress>> {
+    Ok(crate::alias::lookup(address, conn)?
+        .filter(|a| a.forward_email.eq_ignore_ascii_case(&session.forward_email)))
+}
+
+/// Loads a held message, as long as it was sent to one of the logged in registrant's addresses
+fn owned_item(id: &str, session: &Session, conn: &crate::DbConn) -> QueryResult<Option<models::InboundQueueItem>> {
+    let id = match uuid::Uuid::parse_str(id) {
+        Ok(i) => i,
+        Err(_) => return Ok(None)
+    };
+    let item = match schema::inbound_queue::table
+        .find(&id)
+        .filter(schema::inbound_queue::released_at.is_null())
+        .select(models::INBOUND_QUEUE_ITEM_COLUMNS)
+        .get_result::<models::InboundQueueItem>(conn)
+        .optional()? {
+        Some(i) => i,
+        None => return Ok(None)
+    };
+    Ok(match owned_alias(&item.rcpt_to, session, conn)? {
+        Some(_) => Some(item),
+        None => None
+    })
+}
+
+fn dashboard(session: &Session, config: &crate::Config, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let addresses = crate::alias::for_forward_email(&session.forward_email, conn)
+        .map_err(|e| format!("Unable to load registered addresses: {}", e))?;
+
+    let mut aliases = vec![];
+    for address in addresses {
+        let rules = schema::sender_rule::table
+            .filter(schema::sender_rule::registered_address.eq(&address.id))
+            .order_by(schema::sender_rule::sender.asc())
+            .select((schema::sender_rule::id, schema::sender_rule::sender, schema::sender_rule::allow))
+            .load::<models::SenderRule>(conn)
+            .map_err(|e| format!("Unable to load sender rules: {}", e))?;
+        let held = schema::inbound_queue::table
+            .filter(schema::inbound_queue::rcpt_to.eq(&address.id))
+            .filter(schema::inbound_queue::released_at.is_null())
+            .order_by(schema::inbound_queue::received_at.desc())
+            .select(models::INBOUND_QUEUE_ITEM_COLUMNS)
+            .load::<models::InboundQueueItem>(conn)
+            .map_err(|e| format!("Unable to load held mail: {}", e))?;
+
+        let roles = crate::domain::describe_roles(&address.id, conn)
+            .map_err(|e| format!("Unable to load contact roles: {}", e))?;
+
+        aliases.push(PortalAlias {
+            roles,
+            policy: crate::alias::policy_name(address.policy),
+            rotated_to: address.rotated_to.clone(),
+            grace_mode: address.grace_mode.map(crate::alias::grace_mode_name),
+            grace_until: address.grace_until.map(|g| g.to_rfc2822()),
+            rules: rules.into_iter().map(|r| PortalRule {
+                id: r.id.simple().to_string(),
+                sender: r.sender,
+                allow: r.allow,
+            }).collect(),
+            held: held.iter().map(|i| crate::held::summarise(i, config, conn)).collect(),
+            id: address.id,
+        });
+    }
+
+    let mut context = tera::Context::new();
+    context.insert("forward_email", &session.forward_email);
+    context.insert("csrf_token", &session.csrf_token);
+    context.insert("aliases", &aliases);
+    context.insert("policies", &POLICIES.iter().map(|(p, name)| PortalPolicy {
+        id: crate::alias::policy_name(*p),
+        name,
+    }).collect::<Vec<_>>());
+    Ok(render("web/portal.html", &context, hyper::StatusCode::OK))
+}
+
+/// Crudely turns HTML into plain text, so it can be shown without loading anything remote or
+/// running anything
+fn html_to_text(html: &str) -> String {
+    let mut out = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('>') {
+            Some(e) => e,
+            None => break
+        };
+        let tag = rest[1..end].trim().to_ascii_lowercase();
+        let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default().to_string();
+        rest = &rest[end + 1..];
+
+        match name.as_str() {
+            "script" | "style" | "head" | "title" if !tag.starts_with('/') => {
+                let close = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => &rest[i..],
+                    None => ""
+                };
+            }
+            "br" | "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" => out.push('\n'),
+            _ => {}
+        }
+    }
+
+    let text = out
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let mut lines = vec![];
+    for line in text.lines().map(|l| l.trim()) {
+        if !line.is_empty() || lines.last().map(|l: &&str| !l.is_empty()).unwrap_or(false) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+fn find_text(part: &mailparse::ParsedMail<'_>, preview: &mut MessagePreview, plain: &mut Option<String>, html: &mut Option<String>) {
+    let disposition = part.get_content_disposition();
+    if let Some(filename) = disposition.params.get("filename").or_else(|| part.ctype.params.get("name")) {
+        preview.attachments.push(filename.to_string());
+        return;
+    }
+
+    if part.subparts.is_empty() {
+        let body = match part.get_body() {
+            Ok(b) => b,
+            Err(_) => return
+        };
+        match part.ctype.mimetype.to_ascii_lowercase().as_str() {
+            "text/plain" if plain.is_none() => *plain = Some(body),
+            "text/html" if html.is_none() => *html = Some(body),
+            _ => {}
+        }
+    } else {
+        for subpart in &part.subparts {
+            find_text(subpart, preview, plain, html);
+        }
+    }
+}
+
+fn preview_message(item: &models::InboundQueueItem, config: &crate::Config, conn: &crate::DbConn) -> Result<MessagePreview, String> {
+    let data = crate::reassemble::reassemble_message(&item.contents_id, &config.keys, conn)?;
+    let parsed = mailparse::parse_mail(&data).map_err(|e| format!("Unable to parse message: {}", e))?;
+
+    let mut preview = MessagePreview::default();
+    let mut plain = None;
+    let mut html = None;
+    find_text(&parsed, &mut preview, &mut plain, &mut html);
+    preview.body = match (plain, html) {
+        (Some(p), _) => p,
+        (None, Some(h)) => html_to_text(&h),
+        (None, None) => String::new()
+    };
+    Ok(preview)
+}
+
+fn message(id: &str, session: &Session, config: &crate::Config, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let item = match owned_item(id, session, conn).map_err(|e| format!("Unable to load held mail: {}", e))? {
+        Some(i) => i,
+        None => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("csrf_token", &session.csrf_token);
+    context.insert("item", &crate::held::summarise(&item, config, conn));
+    context.insert("preview", &preview_message(&item, config, conn)?);
+    Ok(render("web/portal_message.html", &context, hyper::StatusCode::OK))
+}
+
+fn message_action(id: &str, action: &str, session: &Session, config: &crate::Config, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let item = match owned_item(id, session, conn).map_err(|e| format!("Unable to load held mail: {}", e))? {
+        Some(i) => i,
+        None => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    };
+    match action {
+        "release" => crate::held::release(&item, config, conn)?,
+        "delete" => crate::held::delete(&item, conn)?,
+        _ => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    }
+    Ok(redirect("/portal"))
+}
+
+fn set_policy(form: &std::collections::HashMap<String, String>, session: &Session, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let alias = match owned_alias(form.get("alias").map(String::as_str).unwrap_or_default(), session, conn)
+        .map_err(|e| format!("Unable to load registered address: {}", e))? {
+        Some(a) => a,
+        None => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    };
+    let policy = match form.get("policy").and_then(|p| crate::alias::parse_policy(p)) {
+        Some(p) => p,
+        None => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+    };
+
+    crate::alias::update(&alias.id, Some(policy), &format!("portal:{}", session.forward_email), conn)
+        .map_err(|e| format!("Unable to update policy: {}", e))?;
+    Ok(redirect("/portal"))
+}
+
+fn rotate_alias(form: &std::collections::HashMap<String, String>, session: &Session, config: &crate::Config, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let alias = match owned_alias(form.get("alias").map(String::as_str).unwrap_or_default(), session, conn)
+        .map_err(|e| format!("Unable to load registered address: {}", e))? {
+        Some(a) => a,
+        None => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    };
+    let mode = match form.get("grace_mode").and_then(|m| crate::alias::parse_grace_mode(m)) {
+        Some(m) => m,
+        None => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+    };
+
+    match crate::alias::rotate(&alias.id, mode, config.alias_grace_period, &format!("portal:{}", session.forward_email), conn)
+        .map_err(|e| format!("Unable to rotate address: {}", e))? {
+        Some(_) => Ok(redirect("/portal")),
+        None => Ok(status_response(hyper::StatusCode::CONFLICT))
+    }
+}
+
+fn add_sender_rule(form: &std::collections::HashMap<String, String>, session: &Session, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let alias = match owned_alias(form.get("alias").map(String::as_str).unwrap_or_default(), session, conn)
+        .map_err(|e| format!("Unable to load registered address: {}", e))? {
+        Some(a) => a,
+        None => return Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    };
+    let sender = match form.get("sender").and_then(|s| crate::alias::normalise_sender_rule(s)) {
+        Some(s) => s,
+        None => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+    };
+    let allow = match form.get("list").map(String::as_str) {
+        Some("allow") => true,
+        Some("block") => false,
+        _ => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+    };
+
+    diesel::insert_into(schema::sender_rule::table)
+        .values(&models::NewSenderRule {
+            id: &uuid::Uuid::new_v4(),
+            registered_address: &alias.id,
+            sender: &sender,
+            allow,
+        })
+        .on_conflict((schema::sender_rule::registered_address, schema::sender_rule::sender))
+        .do_update()
+        .set(schema::sender_rule::allow.eq(allow))
+        .execute(conn)
+        .map_err(|e| format!("Unable to save sender rule: {}", e))?;
+    Ok(redirect("/portal"))
+}
+
+fn delete_sender_rule(form: &std::collections::HashMap<String, String>, session: &Session, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    let id = match form.get("id").and_then(|i| uuid::Uuid::parse_str(i).ok()) {
+        Some(i) => i,
+        None => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+    };
+    let aliases = crate::alias::for_forward_email(&session.forward_email, conn)
+        .map_err(|e| format!("Unable to load registered addresses: {}", e))?
+        .into_iter().map(|a| a.id).collect::<Vec<_>>();
+
+    diesel::delete(schema::sender_rule::table
+        .find(&id)
+        .filter(schema::sender_rule::registered_address.eq_any(&aliases))
+    )
+        .execute(conn)
+        .map_err(|e| format!("Unable to delete sender rule: {}", e))?;
+    Ok(redirect("/portal"))
+}
+
+fn log_out(session_token: &str, conn: &crate::DbConn) -> Result<hyper::Response<hyper::Body>, String> {
+    diesel::delete(schema::portal_session::table.find(crate::crypto::token_hash(session_token)))
+        .execute(conn)
+        .map_err(|e| format!("Unable to delete session: {}", e))?;
+
+    let mut resp = redirect("/portal/login");
+    resp.headers_mut().insert(
+        hyper::header::SET_COOKIE,
+        hyper::header::HeaderValue::from_str(&format!("{}=; Path=/portal; HttpOnly; Secure; SameSite=Lax; Max-Age=0", SESSION_COOKIE)).unwrap()
+    );
+    Ok(resp)
+}
+
+fn route(
+    method: &hyper::Method, path: &[&str], form: &std::collections::HashMap<String, String>,
+    session_token: Option<&str>, config: &crate::Config, conn: &crate::DbConn,
+) -> Result<hyper::Response<hyper::Body>, String> {
+    let mut context = tera::Context::new();
+    match (method, path) {
+        (&hyper::Method::GET, ["login"]) => {
+            return Ok(render("web/portal_login.html", &context, hyper::StatusCode::OK));
+        }
+        (&hyper::Method::POST, ["login"]) => {
+            match form.get("email") {
+                Some(e) => send_login_link(e.trim(), config, conn)?,
+                None => return Ok(status_response(hyper::StatusCode::BAD_REQUEST))
+            }
+            // Said regardless of whether the address has any aliases, so as not to give away which do
+            context.insert("sent", &true);
+            return Ok(render("web/portal_login.html", &context, hyper::StatusCode::OK));
+        }
+        // Logging in takes a button press after following the link, so mail scanners can't use it up
+        (&hyper::Method::GET, ["login", _]) => {
+            context.insert("confirm", &true);
+            return Ok(render("web/portal_login.html", &context, hyper::StatusCode::OK));
+        }
+        (&hyper::Method::POST, ["login", token]) => {
+            return Ok(match log_in(token, conn).map_err(|e| format!("Unable to log in: {}", e))? {
+                Some(session_token) => {
+                    let mut resp = redirect("/portal");
+                    resp.headers_mut().insert(
+                        hyper::header::SET_COOKIE,
+                        hyper::header::HeaderValue::from_str(&format!(
+                            "{}={}; Path=/portal; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+                            SESSION_COOKIE, session_token, SESSION_LIFETIME_HOURS * 3600
+                        )).unwrap()
+                    );
+                    resp
+                }
+                None => {
+                    context.insert("error", "This login link has expired, or has already been used.");
+                    render("web/portal_login.html", &context, hyper::StatusCode::NOT_FOUND)
+                }
+            });
+        }
+        _ => {}
+    }
+
+    let (session_token, session) = match session_token {
+        Some(t) => match load_session(t, conn).map_err(|e| format!("Unable to load session: {}", e))? {
+            Some(s) => (t, s),
+            None => return Ok(redirect("/portal/login"))
+        },
+        None => return Ok(redirect("/portal/login"))
+    };
+    if method == hyper::Method::POST {
+        let csrf_ok = match form.get("csrf_token") {
+            Some(t) => t.len() == session.csrf_token.len() && openssl::memcmp::eq(t.as_bytes(), session.csrf_token.as_bytes()),
+            None => false
+        };
+        if !csrf_ok {
+            return Ok(status_response(hyper::StatusCode::FORBIDDEN));
+        }
+    }
+
+    match (method, path) {
+        (&hyper::Method::GET, []) | (&hyper::Method::GET, [""]) => dashboard(&session, config, conn),
+        (&hyper::Method::GET, ["held", id]) => message(id, &session, config, conn),
+        (&hyper::Method::POST, ["held", id, action]) => message_action(id, action, &session, config, conn),
+        (&hyper::Method::POST, ["policy"]) => set_policy(form, &session, conn),
+        (&hyper::Method::POST, ["rotate"]) => rotate_alias(form, &session, config, conn),
+        (&hyper::Method::POST, ["senders"]) => add_sender_rule(form, &session, conn),
+        (&hyper::Method::POST, ["senders", "delete"]) => delete_sender_rule(form, &session, conn),
+        (&hyper::Method::POST, ["logout"]) => log_out(session_token, conn),
+        _ => Ok(status_response(hyper::StatusCode::NOT_FOUND))
+    }
+}
+
+/// Serves the registrant portal, where registrants log in by a link emailed to their real address
+/// to manage their WHOIS addresses and the mail held for them
+pub async fn handle_request(path: &[&str], req: hyper::Request<hyper::Body>, config: &crate::Config) -> hyper::Response<hyper::Body> {
+    let method = req.method().to_owned();
+    let session_token = session_cookie(&req);
+    let form = match method {
+        hyper::Method::GET => req.uri().query().unwrap_or_default().as_bytes().to_vec(),
+        hyper::Method::POST => match hyper::body::to_bytes(req.into_body()).await {
+            Ok(b) => b.to_vec(),
+            Err(_) => return status_response(hyper::StatusCode::BAD_REQUEST)
+        },
+        _ => return status_response(hyper::StatusCode::METHOD_NOT_ALLOWED)
+    };
+    let form = url::form_urlencoded::parse(&form).into_owned().collect::<std::collections::HashMap<_, _>>();
+
+    tokio::task::block_in_place(|| {
+        let conn = match config.connection.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting DB connection: {}", e);
+                return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        match route(&method, path, &form, session_token.as_deref(), config, &conn) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Error serving portal: {}", e);
+                status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    })
+}
@@ -3,7 +3,7 @@ use chrono::prelude::*;
 use std::ops::Deref;
 use mailparse::MailHeaderMap;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SMTPResponse {
     pub code: u16,
     pub lines: Vec<String>,
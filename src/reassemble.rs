@@ -0,0 +1,344 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Rebuilds a message that can be sent on (as `outbound_message.data`) from the MIME tree stored
+/// for an inbound message. Parts we have the raw message for are copied out of it byte for byte,
+/// anything older is re-encoded from its decoded body. The Return-Path header is left off, as
+/// that's only meant to be added on final delivery.
+pub fn reassemble_message(contents: &uuid::Uuid, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<Vec<u8>, String> {
+    let mut loader = Loader {
+        keys,
+        conn,
+        raw_messages: std::collections::HashMap::new(),
+    };
+    let part = loader.load_part(contents)?;
+    Ok(write_message(&part))
+}
+
+/// A stored part of a message, loaded ready to be written back out
+#[derive(Debug, PartialEq)]
+enum Part {
+    /// Copied out of the raw message it was received in
+    Raw(Vec<u8>),
+    /// Re-encoded from its headers and decoded body, or from its subparts if it's a multipart
+    /// that has any
+    Decoded {
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        subparts: Vec<Part>,
+    },
+}
+
+struct Loader<'a> {
+    keys: &'a crate::crypto::KeyRing,
+    conn: &'a crate::DbConn,
+    raw_messages: std::collections::HashMap<uuid::Uuid, Vec<u8>>,
+}
+
+impl Loader<'_> {
+    fn raw_message(&mut self, id: &uuid::Uuid) -> Result<&[u8], String> {
+        if !self.raw_messages.contains_key(id) {
+            let data_hash = schema::raw_message::table
+                .find(id)
+                .select(schema::raw_message::data_hash)
+                .get_result::<Vec<u8>>(self.conn)
+                .map_err(|e| format!("Unable to load raw message {}: {}", id, e))?;
+            self.raw_messages.insert(*id, crate::blob::load(&data_hash, self.keys, self.conn)?);
+        }
+        Ok(&self.raw_messages[id])
+    }
+
+    fn load_part(&mut self, id: &uuid::Uuid) -> Result<Part, String> {
+        let part = schema::mail_subpart::table
+            .find(id)
+            .select(models::MAIL_SUBPART_COLUMNS)
+            .get_result::<models::MailSubpart>(self.conn)
+            .map_err(|e| format!("Unable to load subpart {}: {}", id, e))?;
+
+        if let (Some(raw_id), Some(start), Some(end)) = (&part.raw_message, part.header_offset, part.end_offset) {
+            let raw = self.raw_message(raw_id)?;
+            return match raw.get(start as usize..end as usize) {
+                Some(data) => Ok(Part::Raw(data.to_vec())),
+                None => Err(format!("Subpart {} lies outside of raw message {}", id, raw_id))
+            };
+        }
+
+        let headers = match (&part.data_key, &part.encrypted_headers) {
+            (Some(_), Some(encrypted_headers)) => {
+                let headers = self.keys.decrypt(&part.data_key, encrypted_headers.to_vec(), self.conn)?;
+                serde_json::from_slice::<Vec<(String, String)>>(&headers)
+                    .map_err(|e| format!("Invalid headers in subpart {}: {}", id, e))?
+            },
+            _ => part.headers.into_iter().map(|h| (h.0, h.1)).collect()
+        };
+        let subparts = part.subparts.iter()
+            .map(|s| self.load_part(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        // A multipart's own body is only its preamble, which isn't written out
+        let body = if subparts.is_empty() {
+            crate::blob::load(&part.body_hash, self.keys, self.conn)?
+        } else {
+            vec![]
+        };
+
+        Ok(Part::Decoded {
+            headers,
+            body,
+            subparts,
+        })
+    }
+}
+
+fn write_message(part: &Part) -> Vec<u8> {
+    let mut out = vec![];
+    write_part(part, true, &mut out);
+    if !out.ends_with(b"\r\n") {
+        out.extend(b"\r\n");
+    }
+    out
+}
+
+fn write_part(part: &Part, top_level: bool, out: &mut Vec<u8>) {
+    let (headers, body, subparts) = match part {
+        Part::Raw(data) => {
+            out.extend(data);
+            return;
+        },
+        Part::Decoded { headers, body, subparts } => (headers, body, subparts)
+    };
+
+    let mut content_type = None;
+    let mut transfer_encoding = None;
+    for header in headers {
+        if top_level && header.0.eq_ignore_ascii_case("Return-Path") {
+            continue;
+        }
+        if header.0.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(mailparse::parse_content_type(&header.1));
+        } else if header.0.eq_ignore_ascii_case("Content-Transfer-Encoding") {
+            transfer_encoding = Some(header.1.trim().to_lowercase());
+        }
+        // Headers stored before values were trimmed still have the CR of their line ending
+        let value = header.1.trim_end_matches(|c| c == '\r' || c == '\n');
+        out.extend(format!("{}: {}\r\n", header.0, crate::client::encode_header(value)).as_bytes());
+    }
+    out.extend(b"\r\n");
+
+    let boundary = content_type.as_ref()
+        .filter(|c| c.mimetype.starts_with("multipart/"))
+        .and_then(|c| c.params.get("boundary"));
+    if let Some(boundary) = boundary {
+        if !subparts.is_empty() {
+            for subpart in subparts {
+                out.extend(format!("--{}\r\n", boundary).as_bytes());
+                write_part(subpart, false, out);
+                if !out.ends_with(b"\r\n") {
+                    out.extend(b"\r\n");
+                }
+            }
+            out.extend(format!("--{}--\r\n", boundary).as_bytes());
+            return;
+        }
+    }
+
+    match transfer_encoding.as_deref() {
+        Some("base64") => {
+            let encoded = base64::encode(body);
+            for line in encoded.as_bytes().chunks(76) {
+                out.extend(line);
+                out.extend(b"\r\n");
+            }
+        },
+        Some("quoted-printable") => {
+            // Decoding drops the last line break, so one ending the body needs another after it
+            out.extend(quoted_printable::encode(body));
+            out.extend(b"\r\n");
+        },
+        _ => out.extend(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use crate::schema;
+
+    const FIXTURES: &[(&str, &[u8])] = &[
+        ("thunderbird_folded_headers", include_bytes!("../tests/fixtures/thunderbird_folded_headers.eml")),
+        ("outlook_related_preamble", include_bytes!("../tests/fixtures/outlook_related_preamble.eml")),
+        ("apple_mail_forwarded_rfc822", include_bytes!("../tests/fixtures/apple_mail_forwarded_rfc822.eml")),
+        ("gmail_alternative_attachment", include_bytes!("../tests/fixtures/gmail_alternative_attachment.eml")),
+    ];
+
+    /// The tree the server stores for a message, as the loader gives it back when there's no raw
+    /// copy of any of it
+    fn decoded(parsed: &mailparse::ParsedMail<'_>) -> super::Part {
+        super::Part::Decoded {
+            headers: parsed.headers.iter().map(|h| (h.get_key(), crate::server::header_value(h))).collect(),
+            body: if parsed.subparts.is_empty() {
+                crate::server::decode_body(parsed).unwrap()
+            } else {
+                vec![]
+            },
+            subparts: parsed.subparts.iter().map(decoded).collect(),
+        }
+    }
+
+    #[test]
+    fn fixtures_reencode_to_the_same_tree() {
+        for (name, data) in FIXTURES {
+            let stored = decoded(&mailparse::parse_mail(data).unwrap());
+            let written = super::write_message(&stored);
+            let reparsed = decoded(&mailparse::parse_mail(&written).unwrap());
+            assert_eq!(reparsed, stored, "{} didn't survive being re-encoded", name);
+        }
+    }
+
+    #[test]
+    fn raw_parts_are_copied_verbatim() {
+        let raw = b"Content-Type: text/plain\r\nX-Folded: one\r\n two\r\n\r\nCaf\xe9\r\n".to_vec();
+        let message = super::Part::Decoded {
+            headers: vec![
+                ("Return-Path".to_string(), "<bounce@example.com>".to_string()),
+                ("Subject".to_string(), "Gr\u{fc}\u{df}e".to_string()),
+                ("Content-Type".to_string(), "multipart/mixed; boundary=\"b\"".to_string()),
+            ],
+            body: vec![],
+            subparts: vec![super::Part::Raw(raw.clone())],
+        };
+        let mut expected = b"Subject: =?utf-8?B?R3LDvMOfZQ==?=\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n".to_vec();
+        expected.extend(&raw);
+        expected.extend(b"--b--\r\n");
+        assert_eq!(super::write_message(&message), expected);
+    }
+
+    /// Stores a message the way the server does, then checks it comes back byte for byte: as it
+    /// is, with the top level part having to be rebuilt around the raw copies of the parts under
+    /// it, and with no raw copy left at all so every part is re-encoded
+    fn assert_round_trips(data: &[u8]) {
+        let conn = crate::testing::connection();
+        let keys = crate::crypto::KeyRing::ephemeral();
+        let parsed = mailparse::parse_mail(data).unwrap();
+        let stored = crate::server::store_message(data, &parsed, &keys, &conn).map_err(|e| e.to_string()).unwrap();
+        let reassemble = || String::from_utf8(super::reassemble_message(&stored.contents_id, &keys, &conn).unwrap()).unwrap();
+        let data = String::from_utf8(data.to_vec()).unwrap();
+        assert_eq!(reassemble(), data);
+
+        let no_raw_copy = (
+            schema::mail_subpart::raw_message.eq(None::<uuid::Uuid>),
+            schema::mail_subpart::header_offset.eq(None::<i32>),
+            schema::mail_subpart::body_offset.eq(None::<i32>),
+            schema::mail_subpart::end_offset.eq(None::<i32>),
+        );
+        diesel::update(schema::mail_subpart::table.find(&stored.contents_id))
+            .set(no_raw_copy)
+            .execute(&conn)
+            .unwrap();
+        assert_eq!(reassemble(), data);
+
+        diesel::update(schema::mail_subpart::table.filter(schema::mail_subpart::raw_message.eq(&stored.raw_id)))
+            .set(no_raw_copy)
+            .execute(&conn)
+            .unwrap();
+        assert_eq!(reassemble(), data);
+    }
+
+    #[test]
+    #[ignore]
+    fn nested_alternative_round_trips() {
+        assert_round_trips(b"From: alice@example.com\r\n\
+            To: bob@example.net\r\n\
+            Subject: Nested\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+            \r\n\
+            --outer\r\n\
+            Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+            \r\n\
+            --inner\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            Hello\r\n\
+            --inner\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            \r\n\
+            <p>Hello</p>\r\n\
+            --inner--\r\n\
+            --outer\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Footer\r\n\
+            --outer--\r\n");
+    }
+
+    #[test]
+    #[ignore]
+    fn base64_attachment_round_trips() {
+        let attachment = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let encoded = base64::encode(&attachment).as_bytes().chunks(76)
+            .map(|l| format!("{}\r\n", String::from_utf8_lossy(l)))
+            .collect::<String>();
+        assert_round_trips(format!("From: alice@example.com\r\n\
+            Subject: Attachment\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            See attached\r\n\
+            --b\r\n\
+            Content-Type: application/octet-stream; name=\"data.bin\"\r\n\
+            Content-Disposition: attachment; filename=\"data.bin\"\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            {}--b--\r\n", encoded).as_bytes());
+    }
+
+    #[test]
+    #[ignore]
+    fn rfc822_part_round_trips() {
+        assert_round_trips(b"From: alice@example.com\r\n\
+            Subject: Fwd: Inner\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Forwarding this\r\n\
+            --b\r\n\
+            Content-Type: message/rfc822\r\n\
+            \r\n\
+            From: carol@example.com\r\n\
+            Subject: Inner\r\n\
+            \r\n\
+            Original body\r\n\
+            --b--\r\n");
+    }
+
+    #[test]
+    #[ignore]
+    fn fixtures_are_copied_byte_for_byte() {
+        let conn = crate::testing::connection();
+        let keys = crate::crypto::KeyRing::ephemeral();
+        for (name, data) in FIXTURES {
+            let parsed = mailparse::parse_mail(data).unwrap();
+            let stored = crate::server::store_message(data, &parsed, &keys, &conn).map_err(|e| e.to_string()).unwrap();
+            let reassembled = super::reassemble_message(&stored.contents_id, &keys, &conn).unwrap();
+            assert!(reassembled == *data, "{} wasn't copied byte for byte", name);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn single_part_round_trips() {
+        assert_round_trips(b"From: alice@example.com\r\n\
+            Subject: Plain\r\n\
+            \r\n\
+            Just text\r\n");
+    }
+}
@@ -16,6 +16,17 @@ impl<'a> diesel::serialize::ToSql<Mail_header, diesel::pg::Pg> for MailHeader<'a
     }
 }
 
+/// A header read back out of the database
+#[derive(Debug, PartialEq, FromSqlRow)]
+pub struct StoredMailHeader(pub String, pub String);
+
+impl diesel::deserialize::FromSql<Mail_header, diesel::pg::Pg> for StoredMailHeader {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        let (key, value) = diesel::deserialize::FromSql::<diesel::sql_types::Record<(diesel::sql_types::Text, diesel::sql_types::Text)>, diesel::pg::Pg>::from_sql(bytes)?;
+        Ok(StoredMailHeader(key, value))
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(SqlType)]
 #[derive(QueryId)]
@@ -55,6 +66,356 @@ impl diesel::deserialize::FromSql<Mail_state, diesel::pg::Pg> for MailState {
     }
 }
 
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "mta_sts_mode")]
+pub struct Mta_sts_mode;
+
+#[derive(Debug, PartialEq, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Mta_sts_mode"]
+pub enum MtaStsMode {
+    Enforce,
+    Testing,
+    None
+}
+
+impl diesel::serialize::ToSql<Mta_sts_mode, diesel::pg::Pg> for MtaStsMode {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            MtaStsMode::Enforce => out.write_all(b"enforce")?,
+            MtaStsMode::Testing => out.write_all(b"testing")?,
+            MtaStsMode::None => out.write_all(b"none")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Mta_sts_mode, diesel::pg::Pg> for MtaStsMode {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"enforce" => Ok(MtaStsMode::Enforce),
+            b"testing" => Ok(MtaStsMode::Testing),
+            b"none" => Ok(MtaStsMode::None),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "outbound_tls_mode")]
+pub struct Outbound_tls_mode;
+
+#[derive(Debug, PartialEq, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Outbound_tls_mode"]
+pub enum OutboundTlsMode {
+    Opportunistic,
+    OpportunisticFallbackPlain,
+    VerifyRequired,
+    Pinned
+}
+
+impl diesel::serialize::ToSql<Outbound_tls_mode, diesel::pg::Pg> for OutboundTlsMode {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            OutboundTlsMode::Opportunistic => out.write_all(b"opportunistic")?,
+            OutboundTlsMode::OpportunisticFallbackPlain => out.write_all(b"opportunistic_fallback_plain")?,
+            OutboundTlsMode::VerifyRequired => out.write_all(b"verify_required")?,
+            OutboundTlsMode::Pinned => out.write_all(b"pinned")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Outbound_tls_mode, diesel::pg::Pg> for OutboundTlsMode {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"opportunistic" => Ok(OutboundTlsMode::Opportunistic),
+            b"opportunistic_fallback_plain" => Ok(OutboundTlsMode::OpportunisticFallbackPlain),
+            b"verify_required" => Ok(OutboundTlsMode::VerifyRequired),
+            b"pinned" => Ok(OutboundTlsMode::Pinned),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "delivery_stage")]
+pub struct Delivery_stage;
+
+#[derive(Debug, PartialEq, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Delivery_stage"]
+pub enum DeliveryStage {
+    Connect,
+    Banner,
+    Ehlo,
+    Starttls,
+    Mail,
+    Rcpt,
+    Data
+}
+
+impl diesel::serialize::ToSql<Delivery_stage, diesel::pg::Pg> for DeliveryStage {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            DeliveryStage::Connect => out.write_all(b"connect")?,
+            DeliveryStage::Banner => out.write_all(b"banner")?,
+            DeliveryStage::Ehlo => out.write_all(b"ehlo")?,
+            DeliveryStage::Starttls => out.write_all(b"starttls")?,
+            DeliveryStage::Mail => out.write_all(b"mail")?,
+            DeliveryStage::Rcpt => out.write_all(b"rcpt")?,
+            DeliveryStage::Data => out.write_all(b"data")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Delivery_stage, diesel::pg::Pg> for DeliveryStage {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"connect" => Ok(DeliveryStage::Connect),
+            b"banner" => Ok(DeliveryStage::Banner),
+            b"ehlo" => Ok(DeliveryStage::Ehlo),
+            b"starttls" => Ok(DeliveryStage::Starttls),
+            b"mail" => Ok(DeliveryStage::Mail),
+            b"rcpt" => Ok(DeliveryStage::Rcpt),
+            b"data" => Ok(DeliveryStage::Data),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "alias_policy")]
+pub struct Alias_policy;
+
+#[derive(Debug, PartialEq, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Alias_policy"]
+pub enum AliasPolicy {
+    Challenge,
+    ForwardDirectly,
+    ForwardIfAuthenticated,
+    HoldForReview,
+    Reject
+}
+
+impl diesel::serialize::ToSql<Alias_policy, diesel::pg::Pg> for AliasPolicy {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            AliasPolicy::Challenge => out.write_all(b"challenge")?,
+            AliasPolicy::ForwardDirectly => out.write_all(b"forward_directly")?,
+            AliasPolicy::ForwardIfAuthenticated => out.write_all(b"forward_if_authenticated")?,
+            AliasPolicy::HoldForReview => out.write_all(b"hold_for_review")?,
+            AliasPolicy::Reject => out.write_all(b"reject")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Alias_policy, diesel::pg::Pg> for AliasPolicy {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"challenge" => Ok(AliasPolicy::Challenge),
+            b"forward_directly" => Ok(AliasPolicy::ForwardDirectly),
+            b"forward_if_authenticated" => Ok(AliasPolicy::ForwardIfAuthenticated),
+            b"hold_for_review" => Ok(AliasPolicy::HoldForReview),
+            b"reject" => Ok(AliasPolicy::Reject),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "contact_role")]
+pub struct Contact_role;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Contact_role"]
+pub enum ContactRole {
+    Registrant,
+    Admin,
+    Tech,
+    Billing
+}
+
+impl diesel::serialize::ToSql<Contact_role, diesel::pg::Pg> for ContactRole {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            ContactRole::Registrant => out.write_all(b"registrant")?,
+            ContactRole::Admin => out.write_all(b"admin")?,
+            ContactRole::Tech => out.write_all(b"tech")?,
+            ContactRole::Billing => out.write_all(b"billing")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Contact_role, diesel::pg::Pg> for ContactRole {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"registrant" => Ok(ContactRole::Registrant),
+            b"admin" => Ok(ContactRole::Admin),
+            b"tech" => Ok(ContactRole::Tech),
+            b"billing" => Ok(ContactRole::Billing),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(SqlType)]
+#[derive(QueryId)]
+#[postgres(type_name = "grace_mode")]
+pub struct Grace_mode;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromSqlRow, AsExpression)]
+#[sql_type = "Grace_mode"]
+pub enum GraceMode {
+    Challenge,
+    Reject
+}
+
+impl diesel::serialize::ToSql<Grace_mode, diesel::pg::Pg> for GraceMode {
+    fn to_sql<W: std::io::Write>(&self, out: &mut diesel::serialize::Output<W, diesel::pg::Pg>) -> diesel::serialize::Result {
+        match *self {
+            GraceMode::Challenge => out.write_all(b"challenge")?,
+            GraceMode::Reject => out.write_all(b"reject")?,
+        }
+        Ok(diesel::serialize::IsNull::No)
+    }
+}
+
+impl diesel::deserialize::FromSql<Grace_mode, diesel::pg::Pg> for GraceMode {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"challenge" => Ok(GraceMode::Challenge),
+            b"reject" => Ok(GraceMode::Reject),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Alias_policy;
+    alias_audit (id) {
+        id -> Uuid,
+        actor -> Text,
+        action -> Text,
+        address -> Text,
+        forward_email -> Nullable<Text>,
+        policy -> Nullable<Alias_policy>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    api_idempotency_key (api_key, idempotency_key) {
+        api_key -> Uuid,
+        idempotency_key -> Text,
+        request_hash -> Bytea,
+        response_status -> Nullable<Int4>,
+        response_body -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    api_key (id) {
+        id -> Uuid,
+        name -> Text,
+        key_hash -> Bytea,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    blob (hash) {
+        hash -> Bytea,
+        data -> Bytea,
+        compressed -> Bool,
+        ref_count -> Int8,
+        data_key -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    data_key (id) {
+        id -> Uuid,
+        master_key_id -> Text,
+        wrapped_key -> Bytea,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Contact_role;
+    domain_contact (domain, role) {
+        domain -> Text,
+        role -> Contact_role,
+        registered_address -> Text,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Contact_role;
+    domain_contact_history (domain, role, started_at) {
+        domain -> Text,
+        role -> Contact_role,
+        registered_address -> Text,
+        forward_email -> Text,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Delivery_stage;
+    delivery_attempt (id) {
+        id -> Uuid,
+        queue_item_id -> Uuid,
+        mx_host -> Text,
+        mx_ip -> Text,
+        tls -> Nullable<Text>,
+        failed_stage -> Nullable<Delivery_stage>,
+        response_code -> Nullable<Int4>,
+        response_lines -> Nullable<Array<Text>>,
+        error -> Nullable<Text>,
+        started_at -> Timestamptz,
+        duration_ms -> Int4,
+        transcript -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    forward_email_verification (forward_email) {
+        forward_email -> Text,
+        token_hash -> Nullable<Bytea>,
+        requests_sent -> Int4,
+        last_sent_at -> Nullable<Timestamptz>,
+        verified_at -> Nullable<Timestamptz>,
+        verified_until -> Nullable<Timestamptz>,
+        hard_bounces -> Int4,
+    }
+}
+
 table! {
     use diesel::sql_types::*;
     inbound_queue (id) {
@@ -66,6 +427,26 @@ table! {
         mail_reply_to -> Nullable<Array<Text>>,
         subject -> Nullable<Text>,
         contents -> Uuid,
+        envelope_from -> Nullable<Text>,
+        helo -> Nullable<Text>,
+        peer_ip -> Nullable<Text>,
+        reverse_dns -> Nullable<Text>,
+        protocol -> Nullable<Text>,
+        received_at -> Timestamptz,
+        raw_message -> Nullable<Uuid>,
+        trace_headers -> Nullable<Text>,
+        data_key -> Nullable<Uuid>,
+        encrypted_subject -> Nullable<Bytea>,
+        released_at -> Nullable<Timestamptz>,
+        spf_result -> Nullable<Text>,
+        spf_domain -> Nullable<Text>,
+        dkim_result -> Nullable<Text>,
+        dkim_domains -> Nullable<Array<Text>>,
+        digested_at -> Nullable<Timestamptz>,
+        action_token_hash -> Nullable<Bytea>,
+        dmarc_result -> Nullable<Text>,
+        dmarc_domain -> Nullable<Text>,
+        encrypted_headers -> Nullable<Bytea>,
     }
 }
 
@@ -75,8 +456,27 @@ table! {
     mail_subpart (id) {
         id -> Uuid,
         headers -> Array<Mail_header>,
-        body -> Bytea,
         subparts -> Array<Uuid>,
+        raw_message -> Nullable<Uuid>,
+        header_offset -> Nullable<Int4>,
+        body_offset -> Nullable<Int4>,
+        end_offset -> Nullable<Int4>,
+        body_hash -> Bytea,
+        data_key -> Nullable<Uuid>,
+        encrypted_headers -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Mta_sts_mode;
+    mta_sts_policy (domain) {
+        domain -> Text,
+        policy_id -> Text,
+        mode -> Mta_sts_mode,
+        mx -> Array<Text>,
+        max_age -> Int8,
+        fetched_at -> Timestamptz,
     }
 }
 
@@ -86,6 +486,7 @@ table! {
         id -> Uuid,
         return_path -> Text,
         data -> Bytea,
+        data_key -> Nullable<Uuid>,
     }
 }
 
@@ -98,23 +499,127 @@ table! {
         forward_path -> Text,
         state -> Mail_state,
         state_since -> Timestamptz,
+        attempts -> Int4,
+        next_attempt -> Timestamptz,
+        last_error -> Nullable<Text>,
+        remote_mx -> Nullable<Text>,
+        remote_queue_id -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::Outbound_tls_mode;
+    outbound_tls_policy (domain) {
+        domain -> Text,
+        mode -> Outbound_tls_mode,
+        fingerprint -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    portal_login_token (token_hash) {
+        token_hash -> Bytea,
+        forward_email -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    portal_session (token_hash) {
+        token_hash -> Bytea,
+        forward_email -> Text,
+        expires_at -> Timestamptz,
     }
 }
 
 table! {
     use diesel::sql_types::*;
+    raw_message (id) {
+        id -> Uuid,
+        data_hash -> Bytea,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use super::{Alias_policy, Grace_mode};
     registered_addresses (id) {
         id -> Text,
         forward_email -> Text,
+        policy -> Alias_policy,
+        rotated_to -> Nullable<Text>,
+        grace_mode -> Nullable<Grace_mode>,
+        grace_until -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    sender_rule (id) {
+        id -> Uuid,
+        registered_address -> Text,
+        sender -> Text,
+        allow -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    tls_report_result (id) {
+        id -> Uuid,
+        policy_domain -> Text,
+        policy_type -> Text,
+        policy_string -> Array<Text>,
+        mx_host -> Array<Text>,
+        sending_ip -> Nullable<Text>,
+        receiving_mx_hostname -> Text,
+        receiving_ip -> Text,
+        result_type -> Nullable<Text>,
+        failure_reason -> Nullable<Text>,
+        recorded_at -> Timestamptz,
     }
 }
 
+joinable!(api_idempotency_key -> api_key (api_key));
+joinable!(blob -> data_key (data_key));
+joinable!(delivery_attempt -> outbound_queue (queue_item_id));
+joinable!(domain_contact -> registered_addresses (registered_address));
+joinable!(inbound_queue -> data_key (data_key));
+joinable!(inbound_queue -> raw_message (raw_message));
+joinable!(mail_subpart -> raw_message (raw_message));
+joinable!(mail_subpart -> blob (body_hash));
+joinable!(mail_subpart -> data_key (data_key));
+joinable!(outbound_message -> data_key (data_key));
+joinable!(raw_message -> blob (data_hash));
 joinable!(outbound_queue -> outbound_message (message_id));
+joinable!(sender_rule -> registered_addresses (registered_address));
 
 allow_tables_to_appear_in_same_query!(
+    alias_audit,
+    api_idempotency_key,
+    api_key,
+    blob,
+    data_key,
+    delivery_attempt,
+    domain_contact,
+    domain_contact_history,
+    forward_email_verification,
     inbound_queue,
     mail_subpart,
+    mta_sts_policy,
     outbound_message,
     outbound_queue,
+    outbound_tls_policy,
+    portal_login_token,
+    portal_session,
+    raw_message,
     registered_addresses,
+    sender_rule,
+    tls_report_result,
 );
@@ -1,11 +1,17 @@
 use std::io::Read;
+use futures::StreamExt;
 use diesel::prelude::*;
 use crate::{schema, models};
 use crate::proto::{SMTPResponse};
 
-pub fn queue_confirmation_mail(rcpt_to: &str, mail: &crate::proto::ParsedIMF<'_>, conn: &crate::DbConn) -> Result<(), SMTPResponse> {
+/// Where mail we send ourselves comes from. Forwarded mail is sent from here too, so bounces of
+/// it don't go back to the original sender.
+pub const NOTIFICATION_FROM: &str = "noreply@relay.as207961.net";
+
+pub fn queue_confirmation_mail(rcpt_to: &str, mail: &crate::proto::ParsedIMF<'_>, keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> Result<(), SMTPResponse> {
     let mut context = tera::Context::new();
     context.insert("rcpt_to", rcpt_to);
+    context.insert("roles", &crate::domain::describe_roles(rcpt_to, conn)?);
     context.insert("subject", &mail.subject);
     context.insert("captcha_link", "https://google.com");
 
@@ -13,7 +19,7 @@ pub fn queue_confirmation_mail(rcpt_to: &str, mail: &crate::proto::ParsedIMF<'_>
     let content_html = crate::TEMPLATES.render("confirm_email.html", &context).unwrap();
 
     let mut email_builder = lettre_email::Email::builder()
-        .from("noreply@relay.as207961.net")
+        .from(NOTIFICATION_FROM)
         .date(&time::now())
         .subject(format!("Re: Your email to {}", rcpt_to))
         .alternative(content_html, content_txt);
@@ -72,55 +78,308 @@ pub fn queue_confirmation_mail(rcpt_to: &str, mail: &crate::proto::ParsedIMF<'_>
     let email_envelope = email.envelope().to_owned();
     let mut email_msg = email.message();
 
-    let message_id = uuid::Uuid::new_v4();
-
     let mut data = vec![];
     email_msg.read_to_end(&mut data);
 
-    let new_message = crate::models::NewOutboundMessage {
-        id: &message_id,
-        return_path: email_envelope.from().map(|f| f.as_ref()).unwrap_or_default(),
-        data: &data,
-    };
-
+    let forward_paths = email_envelope.to().iter().map(|f| f.as_ref()).collect::<Vec<_>>();
     match tokio::task::block_in_place(|| {
-        diesel::insert_into(crate::schema::outbound_message::table)
-            .values(&new_message)
-            .execute(conn)
+        queue_mail(email_envelope.from().map(|f| f.as_ref()).unwrap_or_default(), &forward_paths, &data, keys, conn)
     }) {
-        Ok(_) => {},
+        Ok(_) => Ok(()),
         Err(e) => {
-            error!("Error creating new message: {}", e);
-            return Err(SMTPResponse::new(451, "Internal server error"));
+            error!("Error queueing message: {}", e);
+            Err(SMTPResponse::new(451, "Internal server error"))
         }
     }
+}
+
+/// Builds an email from us to a registrant out of the `.txt` and `.html` versions of a template
+pub fn build_notification(to: &str, subject: &str, template: &str, context: &tera::Context) -> Result<Vec<u8>, String> {
+    let content_txt = crate::TEMPLATES.render(&format!("{}.txt", template), context)
+        .map_err(|e| format!("Unable to render {}: {}", template, e))?;
+    let content_html = crate::TEMPLATES.render(&format!("{}.html", template), context)
+        .map_err(|e| format!("Unable to render {}: {}", template, e))?;
+
+    let email: lettre::SendableEmail = lettre_email::Email::builder()
+        .from(NOTIFICATION_FROM)
+        .to(to)
+        .date(&time::now())
+        .subject(subject)
+        .alternative(content_html, content_txt)
+        .build()
+        .map_err(|e| format!("Unable to build email: {}", e))?
+        .into();
+
+    let mut data = vec![];
+    email.message().read_to_end(&mut data)
+        .map_err(|e| format!("Unable to build email: {}", e))?;
+    Ok(data)
+}
+
+/// Stores a message, encrypted under its own data key, and queues it for delivery to each of the
+/// forward paths
+pub fn queue_mail(return_path: &str, forward_paths: &[&str], data: &[u8], keys: &crate::crypto::KeyRing, conn: &crate::DbConn) -> QueryResult<uuid::Uuid> {
+    let message_id = uuid::Uuid::new_v4();
 
-    for forward_path in email_envelope.to() {
-        let new_item = crate::models::NewOutboundQueueItem {
-            id: &uuid::Uuid::new_v4(),
-            message_id: &message_id,
-            forward_path: forward_path.as_ref(),
-            state: &crate::schema::MailState::Queued,
-            state_since: &chrono::Utc::now(),
+    conn.transaction(|| {
+        let data_key = keys.new_data_key(conn)?;
+        let new_message = models::NewOutboundMessage {
+            id: &message_id,
+            return_path,
+            data: &data_key.encrypt(data),
+            data_key: Some(&data_key.id),
         };
+        diesel::insert_into(schema::outbound_message::table)
+            .values(&new_message)
+            .execute(conn)?;
 
-        match tokio::task::block_in_place(|| {
-            diesel::insert_into(crate::schema::outbound_queue::table)
+        for forward_path in forward_paths {
+            let new_item = models::NewOutboundQueueItem {
+                id: &uuid::Uuid::new_v4(),
+                message_id: &message_id,
+                forward_path,
+                state: &schema::MailState::Queued,
+                state_since: &chrono::Utc::now(),
+            };
+            diesel::insert_into(schema::outbound_queue::table)
                 .values(&new_item)
-                .execute(conn)
+                .execute(conn)?;
+        }
+
+        Ok(message_id)
+    })
+}
+
+/// How long a message may sit in the queue getting transient errors or being deferred before it's
+/// given up on
+const MAX_QUEUE_TIME_HOURS: i64 = 5 * 24;
+
+fn retry_backoff(attempts: i32) -> chrono::Duration {
+    // 1, 2, 4, 8... minutes, capped at 4 hours
+    let minutes = 1i64 << std::cmp::min(std::cmp::max(attempts - 1, 0), 8);
+    chrono::Duration::minutes(std::cmp::min(minutes, 240))
+}
+
+/// Only failures that say the address itself is bad count as hard bounces, not messages refused
+/// for their content or destinations with no working mail server
+fn is_hard_bounce(result: &Result<crate::client::Delivery, crate::client::SendingError>, attempts: &[crate::attempt::Attempt]) -> bool {
+    match result {
+        Err(crate::client::SendingError::InvalidAddress) => true,
+        Err(crate::client::SendingError::PermanentError(_)) => attempts.last().map_or(false, |a| a.is_address_rejection()),
+        _ => false
+    }
+}
+
+fn record_delivery_result(
+    item: &models::OutboundQueueItem, result: Result<crate::client::Delivery, crate::client::SendingError>,
+    attempts: &[crate::attempt::Attempt], conn: &crate::DbConn
+) -> QueryResult<usize> {
+    match &result {
+        Ok(_) => crate::verification::record_delivery(&item.forward_path, false, conn)?,
+        Err(_) if is_hard_bounce(&result, attempts) => crate::verification::record_delivery(&item.forward_path, true, conn)?,
+        Err(_) => {}
+    }
+
+    let now = chrono::Utc::now();
+    let attempts = item.attempts + 1;
+
+    match result {
+        Ok(delivery) => diesel::update(item).set((
+            schema::outbound_queue::state.eq(schema::MailState::Sent),
+            schema::outbound_queue::state_since.eq(now),
+            schema::outbound_queue::attempts.eq(attempts),
+            schema::outbound_queue::last_error.eq(None::<String>),
+            schema::outbound_queue::remote_mx.eq(Some(delivery.mx_domain)),
+            schema::outbound_queue::remote_queue_id.eq(delivery.queue_id),
+        )).execute(conn),
+        // Not an attempt as such, so just wait for the destination to have capacity again, but
+        // not for longer than a message would be retried for
+        Err(e @ crate::client::SendingError::Deferred(_))
+        if now - item.state_since < chrono::Duration::hours(MAX_QUEUE_TIME_HOURS) => {
+            diesel::update(item).set((
+                schema::outbound_queue::state.eq(schema::MailState::Queued),
+                schema::outbound_queue::next_attempt.eq(now + chrono::Duration::minutes(1)),
+                schema::outbound_queue::last_error.eq(Some(e.to_string())),
+            )).execute(conn)
+        },
+        Err(e @ crate::client::SendingError::TransientError(_)) | Err(e @ crate::client::SendingError::ConnectionError(_)) |
+        Err(e @ crate::client::SendingError::TlsError(_)) | Err(e @ crate::client::SendingError::Throttled(_))
+        if now - item.state_since < chrono::Duration::hours(MAX_QUEUE_TIME_HOURS) => {
+            diesel::update(item).set((
+                schema::outbound_queue::state.eq(schema::MailState::Queued),
+                schema::outbound_queue::attempts.eq(attempts),
+                schema::outbound_queue::next_attempt.eq(now + retry_backoff(attempts)),
+                schema::outbound_queue::last_error.eq(Some(e.to_string())),
+            )).execute(conn)
+        },
+        Err(e) => diesel::update(item).set((
+            schema::outbound_queue::state.eq(schema::MailState::Failed),
+            schema::outbound_queue::state_since.eq(now),
+            schema::outbound_queue::attempts.eq(attempts),
+            schema::outbound_queue::last_error.eq(Some(e.to_string())),
+        )).execute(conn)
+    }
+}
+
+fn record_attempts(item: &models::OutboundQueueItem, attempts: &[crate::attempt::Attempt], conn: &crate::DbConn) -> QueryResult<usize> {
+    let new_attempts = attempts.iter().map(|a| (a, uuid::Uuid::new_v4(), a.mx_ip.to_string())).collect::<Vec<_>>();
+    let new_attempts = new_attempts.iter().map(|(a, id, mx_ip)| models::NewDeliveryAttempt {
+        id,
+        queue_item_id: &item.id,
+        mx_host: &a.mx_host,
+        mx_ip,
+        tls: a.tls.as_deref(),
+        failed_stage: a.failed_stage.as_ref(),
+        response_code: a.response.as_ref().map(|r| r.code as i32),
+        response_lines: a.response.as_ref().map(|r| &r.lines[..]),
+        error: a.error.as_deref(),
+        started_at: &a.started_at,
+        duration_ms: a.duration.as_millis() as i32,
+        transcript: a.transcript.as_deref(),
+    }).collect::<Vec<_>>();
+
+    diesel::insert_into(schema::delivery_attempt::table)
+        .values(&new_attempts)
+        .execute(conn)
+}
+
+async fn deliver_message(config: crate::Config, message: models::OutboundMessage, items: Vec<models::OutboundQueueItem>) {
+    let forward_paths = items.iter().map(|i| i.forward_path.as_str()).collect::<Vec<_>>();
+
+    let results = crate::client::send_mail(&message.return_path, &forward_paths, &message.data, &config).await;
+
+    let connection = match tokio::task::block_in_place(|| {
+        config.connection.get()
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error getting DB connection to record results of {}: {}", message.id, e);
+            return;
+        }
+    };
+
+    for (i, (res, attempts)) in items.iter().zip(results.into_iter()) {
+        match tokio::task::block_in_place(|| {
+            record_attempts(i, &attempts, &connection)
         }) {
             Ok(_) => {},
+            Err(e) => error!("Error recording delivery attempts for queue item {}: {}", i.id, e)
+        }
+        match tokio::task::block_in_place(|| {
+            record_delivery_result(i, res, &attempts, &connection)
+        }) {
+            Ok(_) => {},
+            Err(e) => error!("Error updating queue item {}: {}", i.id, e)
+        }
+    }
+}
+
+/// How long the sending task waits between checking the queue when it's not woken by a new
+/// delivery or a retry coming due
+const FALLBACK_POLL_SECS: u64 = 60;
+
+/// Wakes the sending task whenever the `outbound_queue` trigger notifies us of new deliveries
+async fn listen_for_queued(notify: std::sync::Arc<tokio::sync::Notify>) {
+    let database_url = std::env::var("DATABASE_URL")
+        .expect("DATABASE_URL must be set");
+
+    loop {
+        let (client, mut connection) = match tokio_postgres::connect(&database_url, tokio_postgres::NoTls).await {
+            Ok(c) => c,
             Err(e) => {
-                error!("Error inserting message into queue: {}", e);
-                return Err(SMTPResponse::new(451, "Internal server error"));
+                error!("Error connecting to DB to listen for queued mail: {}", e);
+                tokio::time::delay_for(std::time::Duration::new(5, 0)).await;
+                continue
+            }
+        };
+
+        let n = notify.clone();
+        let messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        let connection_task = tokio::task::spawn(messages.for_each(move |m| {
+            match m {
+                Ok(tokio_postgres::AsyncMessage::Notification(_)) => n.notify(),
+                Ok(_) => {},
+                Err(e) => error!("Error listening for queued mail: {}", e)
+            }
+            futures::future::ready(())
+        }));
+
+        match client.batch_execute("LISTEN outbound_queue").await {
+            Ok(_) => info!("Listening for queued mail"),
+            Err(e) => error!("Error listening for queued mail: {}", e)
+        }
+        // Anything queued while we weren't listening would otherwise wait for the fallback poll
+        notify.notify();
+
+        let _ = connection_task.await;
+        drop(client);
+        warn!("Lost DB connection listening for queued mail, reconnecting");
+        tokio::time::delay_for(std::time::Duration::new(5, 0)).await;
+    }
+}
+
+/// Loads and decrypts the message each queue item is a delivery of, grouping the items by
+/// message so each is decrypted and sent once however many recipients it has. Items whose message
+/// couldn't be loaded are handed back with the reason.
+fn load_messages(
+    items: Vec<models::OutboundQueueItem>, keys: &crate::crypto::KeyRing, conn: &crate::DbConn,
+) -> (Vec<(models::OutboundMessage, Vec<models::OutboundQueueItem>)>, Vec<(models::OutboundQueueItem, String)>) {
+    let mut messages: Vec<(models::OutboundMessage, Vec<models::OutboundQueueItem>)> = vec![];
+    let mut errors: std::collections::HashMap<uuid::Uuid, String> = std::collections::HashMap::new();
+    let mut failed = vec![];
+
+    for item in items {
+        if let Some((_, m)) = messages.iter_mut().find(|(m, _)| m.id == item.message_id) {
+            m.push(item);
+            continue;
+        }
+        if let Some(e) = errors.get(&item.message_id) {
+            let e = e.clone();
+            failed.push((item, e));
+            continue;
+        }
+
+        let message = schema::outbound_message::table
+            .find(item.message_id)
+            .get_result::<models::OutboundMessage>(conn)
+            .map_err(|e| format!("Unable to load message: {}", e))
+            .and_then(|mut message| {
+                let data = std::mem::take(&mut message.data);
+                message.data = keys.decrypt(&message.data_key, data, conn)?;
+                Ok(message)
+            });
+        match message {
+            Ok(message) => messages.push((message, vec![item])),
+            Err(e) => {
+                errors.insert(item.message_id, e.clone());
+                failed.push((item, e));
             }
         }
     }
 
-    Ok(())
+    (messages, failed)
 }
 
 pub async fn sending_task(config: crate::Config) {
+    let notify = std::sync::Arc::new(tokio::sync::Notify::new());
+    let n = notify.clone();
+    tokio::task::spawn(async {
+        listen_for_queued(n).await
+    });
+
+    // Anything still marked as sending was interrupted by a restart
+    match tokio::task::block_in_place(|| {
+        let connection = config.connection.get().map_err(|e| e.to_string())?;
+        diesel::update(schema::outbound_queue::table.filter(schema::outbound_queue::state.eq(schema::MailState::Sending)))
+            .set(schema::outbound_queue::state.eq(schema::MailState::Queued))
+            .execute(&connection)
+            .map_err(|e| e.to_string())
+    }) {
+        Ok(n) if n > 0 => info!("Requeued {} interrupted deliveries", n),
+        Ok(_) => {},
+        Err(e) => error!("Error requeueing interrupted deliveries: {}", e)
+    }
+
     loop {
         let connection = match tokio::task::block_in_place(|| {
             config.connection.get()
@@ -133,43 +392,93 @@ pub async fn sending_task(config: crate::Config) {
             }
         };
 
-        let mut messages: std::collections::HashMap<uuid::Uuid, models::OutboundMessage> = std::collections::HashMap::new();
-        let mut message_forwards: std::collections::HashMap<uuid::Uuid, Vec<models::OutboundQueueItem>> = std::collections::HashMap::new();
-
         let items = schema::outbound_queue::table
             .filter(schema::outbound_queue::state.eq(schema::MailState::Queued))
+            .filter(schema::outbound_queue::next_attempt.le(chrono::Utc::now()))
             .load::<models::OutboundQueueItem>(&connection).unwrap();
 
-        for item in items {
-            let message = schema::outbound_message::table
-                .filter(schema::outbound_message::id.eq(item.message_id))
-                .first::<models::OutboundMessage>(&connection).unwrap();
+        let item_ids = items.iter().map(|i| i.id).collect::<Vec<_>>();
+        match tokio::task::block_in_place(|| {
+            diesel::update(schema::outbound_queue::table.filter(schema::outbound_queue::id.eq_any(&item_ids)))
+                .set(schema::outbound_queue::state.eq(schema::MailState::Sending))
+                .execute(&connection)
+        }) {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Error marking queue items as sending: {}", e);
+                tokio::time::delay_for(std::time::Duration::new(5, 0)).await;
+                continue
+            }
+        }
 
-            match message_forwards.get_mut(&message.id) {
-                Some(m) => m.push(item),
-                None => {
-                    message_forwards.insert(message.id, vec![item]);
-                }
+        let (messages, failed) = tokio::task::block_in_place(|| {
+            load_messages(items, &config.keys, &connection)
+        });
+        for (item, e) in failed {
+            error!("Error loading message {}: {}", item.message_id, e);
+            if let Err(e) = tokio::task::block_in_place(|| {
+                record_delivery_result(&item, Err(crate::client::SendingError::TransientError(e)), &[], &connection)
+            }) {
+                error!("Error updating queue item {}: {}", item.id, e);
             }
-            messages.insert(message.id, message);
         }
 
-        for (id, item) in message_forwards.iter() {
-            let data = messages.get(id).unwrap();
-            let forward_paths = item.iter().map(|i| i.forward_path.as_str()).collect::<Vec<_>>();
+        // Each message is delivered in its own task, with the throttle keeping each destination
+        // within its limits
+        for (message, items) in messages {
+            let conf = config.clone();
+            tokio::task::spawn(async move {
+                deliver_message(conf, message, items).await
+            });
+        }
 
-            let results = crate::client::send_mail(&data.return_path, &forward_paths, &data.data, &config).await;
-//
-//            for (i, res) in item.iter().zip(results.iter()) {
-//                println!("{:?} {:?}", i, res);
-//            }
+        // Sleep until the next retry is due, or until new mail is queued
+        let now = chrono::Utc::now();
+        let fallback_poll = std::time::Duration::from_secs(FALLBACK_POLL_SECS);
+        let wait = match tokio::task::block_in_place(|| {
+            schema::outbound_queue::table
+                .filter(schema::outbound_queue::state.eq(schema::MailState::Queued))
+                .select(diesel::dsl::min(schema::outbound_queue::next_attempt))
+                .first::<Option<chrono::DateTime<chrono::Utc>>>(&connection)
+        }) {
+            Ok(Some(next_attempt)) => std::cmp::min((next_attempt - now).to_std().unwrap_or_default(), fallback_poll),
+            Ok(None) => fallback_poll,
+            Err(e) => {
+                error!("Error finding next retry: {}", e);
+                fallback_poll
+            }
+        };
+        let wait = std::cmp::max(wait, std::time::Duration::from_secs(1));
+        tokio::select! {
+            _ = notify.notified() => {},
+            _ = tokio::time::delay_for(wait) => {}
         }
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use crate::{schema, models};
 
-//        let data = schema::outbound_message::table.inner_join(schema::outbound_queue::table).select((schema::outbound_message::data,)).load(&connection);
+    #[test]
+    #[ignore]
+    fn message_to_several_recipients_is_decrypted_once() {
+        let conn = crate::testing::connection();
+        let keys = crate::crypto::KeyRing::ephemeral();
+        let data = b"Subject: Hello\r\n\r\nHello world\r\n";
 
-        println!("{:?} {:?}", messages, message_forwards);
+        let message_id = super::queue_mail("a@example.com", &["b@example.net", "c@example.org"], data, &keys, &conn).unwrap();
+        let items = schema::outbound_queue::table
+            .filter(schema::outbound_queue::message_id.eq(message_id))
+            .load::<models::OutboundQueueItem>(&conn)
+            .unwrap();
+        assert_eq!(items.len(), 2);
 
-        tokio::time::delay_for(std::time::Duration::new(5, 0)).await;
+        let (messages, failed) = super::load_messages(items, &keys, &conn);
+        assert!(failed.is_empty());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0.data, &data[..]);
+        assert_eq!(messages[0].1.len(), 2);
     }
-}
\ No newline at end of file
+}
@@ -8,6 +8,91 @@ async fn send_response<T: AsyncWrite + std::marker::Unpin>(socket: &mut T, resp:
     Ok(())
 }
 
+/// The message as it was received, that the parsed MIME tree points into, and the key the
+/// message's headers are encrypted under
+struct RawMessage<'a> {
+    id: &'a uuid::Uuid,
+    data: &'a [u8],
+    data_key: &'a crate::crypto::DataKey,
+}
+
+impl RawMessage<'_> {
+    /// Works out where a part's headers start, and where its body starts and ends, in the raw
+    /// message. The headers of a part in a multipart body start on the line after the last
+    /// delimiter before its body.
+    fn offsets(&self, part: &mailparse::ParsedMail<'_>, boundary: Option<&str>) -> Option<(i32, i32, i32)> {
+        let body = raw_body(part);
+        let body_start = (body.as_ptr() as usize).checked_sub(self.data.as_ptr() as usize)?;
+        let mut body_end = body_start + body.len();
+        if body_end > self.data.len() {
+            return None;
+        }
+        // The parsed body of a multipart part is only its preamble, where the part itself runs on
+        // to its parent's next delimiter, or the end of the message
+        if !part.subparts.is_empty() {
+            body_end = match boundary {
+                Some(boundary) => {
+                    let delimiter = format!("--{}", boundary);
+                    self.data[body_start..].windows(delimiter.len())
+                        .position(|w| w == delimiter.as_bytes())
+                        .map(|i| body_start + i)
+                        .unwrap_or_else(|| self.data.len())
+                },
+                None => self.data.len()
+            };
+        }
+
+        let header_start = match boundary {
+            Some(boundary) => {
+                let delimiter = format!("--{}", boundary);
+                let before = &self.data[..body_start];
+                let delimiter_start = before.windows(delimiter.len())
+                    .rposition(|w| w == delimiter.as_bytes())?;
+                match before[delimiter_start..].iter().position(|c| *c == b'\n') {
+                    Some(i) => delimiter_start + i + 1,
+                    None => body_start
+                }
+            },
+            None => 0
+        };
+
+        Some((header_start as i32, body_start as i32, body_end as i32))
+    }
+}
+
+fn raw_body<'a>(part: &'a mailparse::ParsedMail<'a>) -> &'a [u8] {
+    match part.get_body_encoded() {
+        mailparse::body::Body::Base64(b) | mailparse::body::Body::QuotedPrintable(b) => b.get_raw(),
+        mailparse::body::Body::SevenBit(b) | mailparse::body::Body::EightBit(b) => b.get_raw(),
+        mailparse::body::Body::Binary(b) => b.get_raw(),
+    }
+}
+
+/// The value of a header without the CR of its CRLF line ending, which mailparse leaves on
+pub fn header_value(header: &mailparse::MailHeader<'_>) -> String {
+    header.get_value().trim_end_matches(|c| c == '\r' || c == '\n').to_string()
+}
+
+/// Undoes a part's transfer encoding. This can't be left to mailparse, as with the CR left on
+/// the end of the header it doesn't recognise the encoding of a message with CRLF line endings.
+pub fn decode_body(part: &mailparse::ParsedMail<'_>) -> Result<Vec<u8>, mailparse::MailParseError> {
+    let transfer_encoding = part.headers.iter()
+        .find(|h| h.get_key().eq_ignore_ascii_case("Content-Transfer-Encoding"))
+        .map(|h| header_value(h).trim().to_lowercase());
+    match mailparse::body::Body::new(raw_body(part), &part.ctype, &transfer_encoding) {
+        mailparse::body::Body::Base64(b) | mailparse::body::Body::QuotedPrintable(b) => b.get_decoded(),
+        mailparse::body::Body::SevenBit(b) | mailparse::body::Body::EightBit(b) => Ok(b.get_raw().to_vec()),
+        mailparse::body::Body::Binary(b) => Ok(b.get_raw().to_vec()),
+    }
+}
+
+impl From<diesel::result::Error> for SMTPResponse {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("Error storing message: {}", e);
+        SMTPResponse::new(451, "Internal server error")
+    }
+}
+
 struct SessionState {
     config: crate::Config,
     client_identity: Option<String>,
@@ -126,6 +211,18 @@ impl SessionState {
 
             let addr = e.addr.rsplit(":").next().unwrap().to_string();
 
+            match tokio::task::block_in_place(|| {
+                let conn = self.config.connection.get().map_err(|e| e.to_string())?;
+                recipient_refusal(&addr, &conn).map_err(|e| e.to_string())
+            }) {
+                Ok(Some(r)) => return send_response(socket, &r).await,
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error looking up registered address: {}", e);
+                    return send_response(socket, &SMTPResponse::new(451, "Internal server error")).await;
+                }
+            }
+
             println!("Forward path is {}", e);
             self.forward_paths.push(addr);
             send_response(socket, &SMTPResponse::new(250, "UwU emails!")).await
@@ -174,7 +271,8 @@ impl SessionState {
         }
 
         println!("Mail data is:\r\n{}", data);
-        match self.process_email(data.as_bytes()) {
+        let auth = self.check_auth(data.as_bytes()).await;
+        match self.process_email(data.as_bytes(), &auth) {
             Ok(_) => {},
             Err(e) => return send_response(socket, &e).await
         }
@@ -219,7 +317,8 @@ impl SessionState {
 
         if is_last {
             println!("Mail data is:\r\n{:?}", self.binary_data);
-            match self.process_email(&self.binary_data) {
+            let auth = self.check_auth(&self.binary_data).await;
+            match self.process_email(&self.binary_data, &auth) {
                 Ok(_) => send_response(socket, &SMTPResponse::new(250, "Nom nom nom that was delicious")).await?,
                 Err(e) => send_response(socket, &e).await?
             }
@@ -233,126 +332,232 @@ impl SessionState {
         }
     }
 
-    fn process_email(&self, data: &[u8]) -> Result<(), SMTPResponse> {
-        for (recipient, received_header) in self.forward_paths.iter().zip(self.received_headers().iter()) {
-            let header_data = format!("{}{}", self.return_path_header(), received_header);
-            let mut idv_data = header_data.as_bytes().to_vec();
-            idv_data.extend(data);
-            let parsed_imf = match crate::proto::parse_and_validate_parsed_mail(&idv_data) {
-                Ok(p) => p,
-                Err(e) => {
-                    let mut resp = SMTPResponse::new(550, "Ew! Non RFC5322 compliant mail!");
-                    resp.add_line(&e);
-                    return Err(resp);
-                }
-            };
+    async fn check_auth(&self, data: &[u8]) -> crate::auth::AuthResults {
+        crate::auth::check_message(
+            self.peer_addr,
+            self.client_identity.as_deref().unwrap_or_default(),
+            self.reverse_path.as_deref().unwrap_or_default(),
+            data,
+            &self.config
+        ).await
+    }
 
-            let conn = match tokio::task::block_in_place(|| {
-                self.config.connection.get()
-            }) {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("Error getting database connection: {}", e);
-                    return Err(SMTPResponse::new(451, "Internal server error"));
-                }
-            };
+    /// Stores the message once, with a queue entry for each recipient carrying its own trace
+    /// headers, all in one transaction. Each recipient's copy is then held (challenging the sender
+    /// with a CAPTCHA or not), forwarded or dropped, according to the address's policy and allow
+    /// and block lists.
+    fn process_email(&self, data: &[u8], auth: &crate::auth::AuthResults) -> Result<(), SMTPResponse> {
+        let received_at = chrono::Utc::now();
+        let peer_ip = self.peer_addr.to_string();
+        let reverse_dns = self.reverse_dns.as_ref().map(|n| n.to_ascii());
+
+        let parsed_imf = match crate::proto::parse_and_validate_parsed_mail(data) {
+            Ok(p) => p,
+            Err(e) => {
+                let mut resp = SMTPResponse::new(550, "Ew! Non RFC5322 compliant mail!");
+                resp.add_line(&e);
+                return Err(resp);
+            }
+        };
 
-            let queue_id = uuid::Uuid::new_v4();
-            let contents_id = self.process_email_part(&parsed_imf.data, &conn)?;
+        let headers = crate::held::QueuedHeaders {
+            message_id: parsed_imf.message_id.clone(),
+            from: parsed_imf.mail_from_as_vec().iter().map(|f| f.to_string()).collect(),
+            sender: parsed_imf.sender.as_ref().map(|s| s.to_string()),
+            reply_to: parsed_imf.mail_reply_to_as_vec().map(|r| r.iter().map(|f| f.to_string()).collect()),
+        };
+        let headers = match serde_json::to_vec(&headers) {
+            Ok(h) => h,
+            Err(e) => {
+                error!("Error serializing headers: {}", e);
+                return Err(SMTPResponse::new(451, "Internal server error"));
+            }
+        };
 
-            let mail_from = parsed_imf.mail_from_as_vec().iter().map(|f| f.to_string()).collect::<Vec<_>>();
-            let mail_sender = match &parsed_imf.sender {
-                Some(s) => Some(s.to_string()),
-                None => None
-            };
-            let mail_reply_to = match parsed_imf.mail_reply_to_as_vec() {
-                Some(r) => Some(r.iter().map(|f| f.to_string()).collect::<Vec<_>>()),
-                None => None
-            };
-            let mut mail_reply_to_deref = vec![];
-            let mail_reply_to_2 = match &mail_reply_to {
-                Some(reply_to) => {
-                    for r in reply_to {
-                        mail_reply_to_deref.push(r.as_str())
-                    }
-                    Some(mail_reply_to_deref)
-                },
-                None => None
-            };
+        let mut senders = parsed_imf.mail_from_as_vec().into_iter().map(|f| f.addr).collect::<Vec<_>>();
+        if let Some(reverse_path) = self.reverse_path.as_ref().filter(|p| !p.is_empty()) {
+            senders.push(reverse_path.to_string());
+        }
 
-            let new_item = crate::models::NewInboundQueueItem {
-                id: &queue_id,
-                rcpt_to: recipient,
-                message_id: parsed_imf.message_id.as_deref(),
-                mail_from: &mail_from.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
-                mail_sender: mail_sender.as_deref(),
-                mail_reply_to: match &mail_reply_to_2 {
-                    Some(x) => Some(x.as_ref()),
-                    None => None
-                },
-                subject: parsed_imf.subject.as_deref(),
-                contents: &contents_id
-            };
+        let conn = match tokio::task::block_in_place(|| {
+            self.config.connection.get()
+        }) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting database connection: {}", e);
+                return Err(SMTPResponse::new(451, "Internal server error"));
+            }
+        };
 
-            match tokio::task::block_in_place(|| {
-                diesel::insert_into(crate::schema::inbound_queue::table)
+        let keys = &self.config.keys;
+        tokio::task::block_in_place(|| conn.transaction::<_, SMTPResponse, _>(|| {
+            let mut dispositions = vec![];
+            for recipient in &self.forward_paths {
+                let disposition = match crate::alias::lookup(recipient, &conn)? {
+                    Some(a) => crate::alias::disposition(&a, &senders, auth, &conn)?,
+                    None => crate::alias::Disposition::Challenge
+                };
+                dispositions.push(disposition);
+            }
+            // Nothing is stored when every copy is dropped, as nothing would be left to clean it up
+            if dispositions.iter().all(|d| *d == crate::alias::Disposition::Drop) {
+                info!("Dropping message to {} from {:?}", self.forward_paths.join(", "), senders);
+                return Ok(());
+            }
+
+            let stored = store_message(data, &parsed_imf.data, keys, &conn)?;
+            let encrypted_subject = parsed_imf.subject.as_ref().map(|s| stored.data_key.encrypt(s.as_bytes()));
+            let encrypted_headers = stored.data_key.encrypt(&headers);
+
+            for ((recipient, received_header), disposition) in self.forward_paths.iter()
+                .zip(self.received_headers().iter())
+                .zip(dispositions) {
+                if disposition == crate::alias::Disposition::Drop {
+                    info!("Dropping message to {} from {:?}", recipient, senders);
+                    continue;
+                }
+
+                let header_data = format!("{}{}", self.return_path_header(), received_header);
+                let new_item = crate::models::NewInboundQueueItem {
+                    id: &uuid::Uuid::new_v4(),
+                    // Lowercased like WHOIS addresses are, so held mail can be found by address
+                    rcpt_to: &recipient.to_lowercase(),
+                    message_id: None,
+                    mail_from: &[],
+                    mail_sender: None,
+                    mail_reply_to: None,
+                    subject: None,
+                    contents: &stored.contents_id,
+                    envelope_from: self.reverse_path.as_deref(),
+                    helo: self.client_identity.as_deref(),
+                    peer_ip: Some(&peer_ip),
+                    reverse_dns: reverse_dns.as_deref(),
+                    protocol: self.protocol.as_deref(),
+                    received_at: &received_at,
+                    raw_message: Some(&stored.raw_id),
+                    trace_headers: Some(&header_data),
+                    data_key: Some(&stored.data_key.id),
+                    encrypted_subject: encrypted_subject.as_deref(),
+                    spf_result: Some(auth.spf.as_str()),
+                    spf_domain: Some(&auth.spf_domain),
+                    dkim_result: Some(auth.dkim.as_str()),
+                    dkim_domains: Some(&auth.dkim_domains),
+                    dmarc_result: Some(auth.dmarc.as_str()),
+                    dmarc_domain: auth.dmarc_domain.as_deref(),
+                    encrypted_headers: Some(&encrypted_headers),
+                };
+                let item = diesel::insert_into(crate::schema::inbound_queue::table)
                     .values(&new_item)
-                    .execute(&conn)
-            }) {
-                Ok(_) => {},
-                Err(e) => {
-                    error!("Error inserting into queue: {}", e);
-                    return Err(SMTPResponse::new(451, "Internal server error"));
+                    .returning(crate::models::INBOUND_QUEUE_ITEM_COLUMNS)
+                    .get_result::<crate::models::InboundQueueItem>(&conn)?;
+
+                match disposition {
+                    crate::alias::Disposition::Forward => {
+                        if let Err(e) = crate::held::release(&item, &self.config, &conn) {
+                            error!("Error forwarding message to {}: {}", recipient, e);
+                            return Err(SMTPResponse::new(451, "Internal server error"));
+                        }
+                    }
+                    crate::alias::Disposition::Challenge => crate::sender::queue_confirmation_mail(&recipient, &parsed_imf, keys, &conn)?,
+                    crate::alias::Disposition::Hold | crate::alias::Disposition::Drop => {}
                 }
             }
 
-            tokio::task::block_in_place(|| {
-                crate::sender::queue_confirmation_mail(&recipient, &parsed_imf, &conn)
-            })?;
-        }
-
-        Ok(())
+            Ok(())
+        }))
     }
+}
 
-    fn process_email_part(&self, part: &mailparse::ParsedMail<'_>, conn: &crate::DbConn) -> Result<uuid::Uuid, SMTPResponse> {
-        let contents_id = uuid::Uuid::new_v4();
+/// A received message once it's been stored
+pub struct StoredMessage {
+    pub raw_id: uuid::Uuid,
+    pub data_key: crate::crypto::DataKey,
+    /// The top of its MIME tree
+    pub contents_id: uuid::Uuid,
+}
 
-        let body = match part.get_body_raw() {
-            Ok(b) => b,
-            Err(_) => return Err(SMTPResponse::new(550, "Error decoding content transfer encoding"))
-        };
+/// Stores a message as it was received, along with the MIME tree parsed from it
+pub fn store_message(
+    data: &[u8], parsed: &mailparse::ParsedMail<'_>, keys: &crate::crypto::KeyRing, conn: &crate::DbConn,
+) -> Result<StoredMessage, SMTPResponse> {
+    let raw_id = uuid::Uuid::new_v4();
+    let data_key = keys.new_data_key(conn)?;
+    diesel::insert_into(crate::schema::raw_message::table)
+        .values(&crate::models::NewRawMessage {
+            id: &raw_id,
+            data_hash: &crate::blob::store(data, keys, conn)?,
+        })
+        .execute(conn)?;
+
+    let raw = RawMessage {
+        id: &raw_id,
+        data,
+        data_key: &data_key,
+    };
+    let contents_id = store_part(parsed, &raw, None, keys, conn)?;
+    Ok(StoredMessage {
+        raw_id,
+        data_key,
+        contents_id,
+    })
+}
 
-        let subparts: Vec<_> = part.subparts.iter().map(|s| {
-            self.process_email_part(s, conn)
-        }).collect::<Result<Vec<_>, _>>()?;
+fn store_part(
+    part: &mailparse::ParsedMail<'_>, raw: &RawMessage<'_>, boundary: Option<&str>, keys: &crate::crypto::KeyRing, conn: &crate::DbConn,
+) -> Result<uuid::Uuid, SMTPResponse> {
+    let contents_id = uuid::Uuid::new_v4();
 
-        let mut headers = vec![];
-        let headers_1 = part.headers.iter().map(|h| (h.get_key(), h.get_value())).collect::<Vec<_>>();
-        for h in &headers_1 {
-            headers.push(crate::schema::MailHeader(&h.0, &h.1));
+    let body = match decode_body(part) {
+        Ok(b) => b,
+        Err(_) => return Err(SMTPResponse::new(550, "Error decoding content transfer encoding"))
+    };
+
+    let subparts: Vec<_> = part.subparts.iter().map(|s| {
+        store_part(s, raw, part.ctype.params.get("boundary").map(|b| b.as_str()), keys, conn)
+    }).collect::<Result<Vec<_>, _>>()?;
+    let offsets = raw.offsets(part, boundary);
+
+    let headers = part.headers.iter().map(|h| (h.get_key(), header_value(h))).collect::<Vec<_>>();
+    let encrypted_headers = match serde_json::to_vec(&headers) {
+        Ok(h) => raw.data_key.encrypt(&h),
+        Err(e) => {
+            error!("Error serializing headers: {}", e);
+            return Err(SMTPResponse::new(451, "Internal server error"));
         }
+    };
 
-        let new_subpart = crate::models::NewMailSubpart {
-            id: &contents_id,
-            headers: &headers.iter().map(|x| x).collect::<Vec<_>>(),
-            body: body.as_ref(),
-            subparts: &subparts.iter().map(|x| x).collect::<Vec<_>>()
-        };
+    let new_subpart = crate::models::NewMailSubpart {
+        id: &contents_id,
+        headers: &[],
+        subparts: &subparts.iter().map(|x| x).collect::<Vec<_>>(),
+        raw_message: Some(raw.id),
+        header_offset: offsets.map(|o| o.0),
+        body_offset: offsets.map(|o| o.1),
+        end_offset: offsets.map(|o| o.2),
+        body_hash: &crate::blob::store(&body, keys, conn)?,
+        data_key: Some(&raw.data_key.id),
+        encrypted_headers: Some(&encrypted_headers),
+    };
 
-        match tokio::task::block_in_place(|| {
-            diesel::insert_into(crate::schema::mail_subpart::table)
-                .values(&new_subpart)
-                .execute(conn)
-        }) {
-            Ok(_) => {},
-            Err(e) => {
-                error!("Error inserting into queue: {}", e);
-                return Err(SMTPResponse::new(451, "Internal server error"));
-            }
-        }
+    diesel::insert_into(crate::schema::mail_subpart::table)
+        .values(&new_subpart)
+        .execute(conn)?;
 
-        Ok(contents_id)
-    }
+    Ok(contents_id)
+}
+
+/// Finds whether a recipient should be turned away at RCPT, rather than accepting mail only to
+/// drop it
+fn recipient_refusal(address: &str, conn: &crate::DbConn) -> diesel::QueryResult<Option<SMTPResponse>> {
+    let address = match crate::alias::lookup_any(address, conn)? {
+        Some(a) => a,
+        None => return Ok(None)
+    };
+    Ok(crate::alias::refusal(&address).map(|r| match r {
+        crate::alias::Refusal::Rejected => SMTPResponse::new(550, "This address doesn't accept mail"),
+        crate::alias::Refusal::Retired => SMTPResponse::new(550, "This address has been retired"),
+        crate::alias::Refusal::Moved(new) => SMTPResponse::new(551, &format!("This address has moved, please try <{}>", new)),
+    }))
 }
 
 pub async fn process_socket(s: tokio::net::TcpStream, config: crate::Config) -> std::io::Result<()> {
@@ -441,4 +646,34 @@ pub async fn process_socket(s: tokio::net::TcpStream, config: crate::Config) ->
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+
+    #[test]
+    #[ignore]
+    fn rotated_address_is_refused_after_janitor_revokes_it() {
+        let conn = crate::testing::connection();
+        let old = crate::alias::create(
+            "old@whois.as207960.net", "registrant@example.com", crate::schema::AliasPolicy::Challenge, "test", &conn,
+        ).unwrap().unwrap();
+
+        let new = crate::alias::rotate(&old.id, crate::schema::GraceMode::Reject, chrono::Duration::days(1), "test", &conn)
+            .unwrap().unwrap();
+        let refusal = super::recipient_refusal(&old.id, &conn).unwrap().unwrap();
+        assert_eq!(refusal.code, 551);
+        assert!(refusal.lines[0].contains(&new.id));
+
+        diesel::update(crate::schema::registered_addresses::table.find(&old.id))
+            .set(crate::schema::registered_addresses::grace_until.eq(chrono::Utc::now() - chrono::Duration::days(1)))
+            .execute(&conn)
+            .unwrap();
+        assert_eq!(super::recipient_refusal(&old.id, &conn).unwrap().unwrap().code, 550);
+
+        assert_eq!(crate::alias::revoke_retired(&conn).unwrap(), 1);
+        assert_eq!(super::recipient_refusal(&old.id, &conn).unwrap().unwrap().code, 550);
+        assert!(crate::alias::lookup(&old.id, &conn).unwrap().is_none());
+        assert!(super::recipient_refusal(&new.id, &conn).unwrap().is_none());
+    }
+}
@@ -0,0 +1,73 @@
+//! Helpers for tests. Tests that need a database run against the one in `TEST_DATABASE_URL`,
+//! each inside a transaction that's rolled back when the test ends, and are marked `#[ignore]` so
+//! a plain `cargo test` doesn't need one; run them with `cargo test -- --ignored`.
+
+use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectAlternativeName};
+
+static MIGRATIONS: std::sync::Once = std::sync::Once::new();
+
+fn pool() -> diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::pg::PgConnection>> {
+    let database_url = std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must be set to run database tests");
+    diesel::r2d2::Pool::builder()
+        .max_size(1)
+        .build(diesel::r2d2::ConnectionManager::new(&database_url))
+        .expect(&format!("Error connecting to {}", database_url))
+}
+
+/// A connection to the test database, with everything done on it rolled back once it's dropped
+pub fn connection() -> crate::DbConn {
+    MIGRATIONS.call_once(|| {
+        crate::embedded_migrations::run(&pool().get().unwrap()).unwrap();
+    });
+
+    let conn = pool().get().unwrap();
+    diesel::Connection::begin_test_transaction(&*conn).unwrap();
+    conn
+}
+
+/// A certificate along with its private key
+pub struct Issued {
+    pub cert: openssl::x509::X509,
+    pub key: openssl::pkey::PKey<openssl::pkey::Private>,
+}
+
+/// Makes a certificate, signed by `issuer` or self-signed, valid from `from_days` to `to_days`
+/// days from now
+pub fn issue(name: &str, ca: bool, issuer: Option<&Issued>, from_days: i64, to_days: i64) -> Issued {
+    let key = openssl::pkey::PKey::from_ec_key(openssl::ec::EcKey::generate(
+        &openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap()
+    ).unwrap()).unwrap();
+    let mut subject = openssl::x509::X509NameBuilder::new().unwrap();
+    subject.append_entry_by_nid(openssl::nid::Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+
+    let mut serial = openssl::bn::BigNum::new().unwrap();
+    serial.rand(64, openssl::bn::MsbOption::MAYBE_ZERO, false).unwrap();
+    let now = chrono::Utc::now().timestamp();
+
+    let mut builder = openssl::x509::X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_issuer_name(issuer.map_or(&subject, |i| i.cert.subject_name())).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&openssl::asn1::Asn1Time::from_unix(now + from_days * 86400).unwrap()).unwrap();
+    builder.set_not_after(&openssl::asn1::Asn1Time::from_unix(now + to_days * 86400).unwrap()).unwrap();
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build().unwrap()).unwrap();
+    } else {
+        builder.append_extension(BasicConstraints::new().critical().build().unwrap()).unwrap();
+        builder.append_extension(KeyUsage::new().critical().digital_signature().build().unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns(name)
+            .build(&builder.x509v3_context(issuer.map(|i| i.cert.as_ref()), None)).unwrap();
+        builder.append_extension(san).unwrap();
+    }
+    builder.sign(issuer.map_or(&key, |i| &i.key), openssl::hash::MessageDigest::sha256()).unwrap();
+
+    Issued {
+        cert: builder.build(),
+        key,
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How hard we're willing to push a destination
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Most connections open at once to a single recipient domain
+    pub max_concurrent_per_domain: u32,
+    /// Most connections open at once to a single MX host
+    pub max_concurrent_per_mx: u32,
+    /// Most transactions per minute to a destination when it isn't pushing back
+    pub max_rate_per_minute: f64,
+    /// The rate a destination is allowed never drops below this
+    pub min_rate_per_minute: f64,
+    /// How long to stop sending to a destination after it throttles us
+    pub backoff: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_per_domain: 10,
+            max_concurrent_per_mx: 5,
+            max_rate_per_minute: 120.0,
+            min_rate_per_minute: 1.0,
+            backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DestinationState {
+    active: u32,
+    max_concurrent: u32,
+    /// Transactions per minute currently allowed, adjusted up and down based on the replies we get
+    rate: f64,
+    recent: std::collections::VecDeque<Instant>,
+    backoff_until: Option<Instant>,
+    throttled_count: u64,
+}
+
+impl DestinationState {
+    fn new(max_concurrent: u32, rate: f64) -> Self {
+        Self {
+            active: 0,
+            max_concurrent,
+            rate,
+            recent: std::collections::VecDeque::new(),
+            backoff_until: None,
+            throttled_count: 0,
+        }
+    }
+
+    fn can_send(&mut self, now: Instant) -> Result<(), String> {
+        if let Some(until) = self.backoff_until {
+            if until > now {
+                return Err(format!("backing off for {}s", (until - now).as_secs()));
+            }
+            self.backoff_until = None;
+        }
+        if self.active >= self.max_concurrent {
+            return Err(format!("{} connections already open", self.active));
+        }
+        while let Some(t) = self.recent.front() {
+            if now.duration_since(*t) >= Duration::from_secs(60) {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.recent.len() as f64 >= self.rate.floor() {
+            return Err(format!("rate limited to {} per minute", self.rate.floor()));
+        }
+        Ok(())
+    }
+
+    fn is_idle(&self, max_rate: f64) -> bool {
+        self.active == 0 && self.rate >= max_rate && self.backoff_until.is_none()
+    }
+}
+
+#[derive(Default, Debug)]
+struct ThrottleState {
+    domains: HashMap<String, DestinationState>,
+    mxs: HashMap<String, DestinationState>,
+}
+
+/// Limits how hard we push each destination domain and MX. The rate allowed to a destination
+/// increases additively as transactions succeed, and is halved whenever it throttles us.
+#[derive(Default)]
+pub struct Throttle {
+    limits: Limits,
+    state: std::sync::Mutex<ThrottleState>,
+}
+
+/// A reservation of a connection slot to a destination, released when dropped
+pub struct Permit<'a> {
+    throttle: &'a Throttle,
+    domains: Vec<String>,
+    mx: String,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.throttle.update(self, |d| d.active -= 1);
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    /// Reserves a connection to an MX on behalf of the recipient domains, if none of them are at
+    /// their limits
+    pub fn acquire(&self, domains: &[String], mx: &str) -> Result<Permit<'_>, String> {
+        self.acquire_at(domains, mx, Instant::now())
+    }
+
+    fn acquire_at(&self, domains: &[String], mx: &str, now: Instant) -> Result<Permit<'_>, String> {
+        let mut state = self.state.lock().unwrap();
+
+        for domain in domains {
+            state.domains.entry(domain.clone())
+                .or_insert_with(|| DestinationState::new(self.limits.max_concurrent_per_domain, self.limits.max_rate_per_minute))
+                .can_send(now)
+                .map_err(|e| format!("{} {}", domain, e))?;
+        }
+        state.mxs.entry(mx.to_string())
+            .or_insert_with(|| DestinationState::new(self.limits.max_concurrent_per_mx, self.limits.max_rate_per_minute))
+            .can_send(now)
+            .map_err(|e| format!("{} {}", mx, e))?;
+
+        for domain in domains {
+            let d = state.domains.get_mut(domain).unwrap();
+            d.active += 1;
+            d.recent.push_back(now);
+        }
+        let m = state.mxs.get_mut(mx).unwrap();
+        m.active += 1;
+        m.recent.push_back(now);
+
+        Ok(Permit {
+            throttle: self,
+            domains: domains.to_vec(),
+            mx: mx.to_string(),
+        })
+    }
+
+    fn update<F: Fn(&mut DestinationState)>(&self, permit: &Permit<'_>, f: F) {
+        let mut state = self.state.lock().unwrap();
+        for domain in &permit.domains {
+            if let Some(d) = state.domains.get_mut(domain) {
+                f(d);
+            }
+        }
+        if let Some(m) = state.mxs.get_mut(&permit.mx) {
+            f(m);
+        }
+    }
+
+    /// Records that a transaction went through without being throttled
+    pub fn succeeded(&self, permit: &Permit<'_>) {
+        let max_rate = self.limits.max_rate_per_minute;
+        self.update(permit, |d| d.rate = (d.rate + 1.0).min(max_rate));
+    }
+
+    /// Records that a destination pushed back, so sending to it slows down
+    pub fn throttled(&self, permit: &Permit<'_>) {
+        self.throttled_at(permit, Instant::now())
+    }
+
+    fn throttled_at(&self, permit: &Permit<'_>, now: Instant) {
+        let backoff_until = now + self.limits.backoff;
+        let min_rate = self.limits.min_rate_per_minute;
+        self.update(permit, |d| {
+            d.rate = (d.rate / 2.0).max(min_rate);
+            d.backoff_until = Some(backoff_until);
+            d.throttled_count += 1;
+        });
+        warn!("Throttled by {} for {}, backing off", permit.mx, permit.domains.join(", "));
+    }
+
+    /// Logs the state of every destination that's currently being limited, and forgets about
+    /// destinations that are back to normal
+    fn log_state(&self) {
+        let mut state = self.state.lock().unwrap();
+        let ThrottleState { domains, mxs } = &mut *state;
+        for (kind, destinations) in &mut [("domain", domains), ("MX", mxs)] {
+            destinations.retain(|_, d| !d.is_idle(self.limits.max_rate_per_minute));
+            for (name, d) in destinations.iter() {
+                info!(
+                    "Throttle state for {} {}: {} active/{} max, {:.1} per minute, {} sent in the last minute, {} times throttled{}",
+                    kind, name, d.active, d.max_concurrent, d.rate, d.recent.len(), d.throttled_count,
+                    match d.backoff_until {
+                        Some(until) => format!(", backing off for {}s", until.saturating_duration_since(Instant::now()).as_secs()),
+                        None => String::new()
+                    }
+                );
+            }
+        }
+    }
+}
+
+pub async fn logging_task(config: crate::Config) {
+    loop {
+        tokio::time::delay_for(Duration::from_secs(60)).await;
+        config.throttle.log_state();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(Limits {
+            max_concurrent_per_domain: 3,
+            max_concurrent_per_mx: 2,
+            max_rate_per_minute: 10.0,
+            min_rate_per_minute: 2.0,
+            backoff: Duration::from_secs(60),
+        })
+    }
+
+    fn domains() -> Vec<String> {
+        vec!["example.com".to_string()]
+    }
+
+    fn rate(throttle: &Throttle) -> (f64, f64) {
+        let state = throttle.state.lock().unwrap();
+        (state.domains["example.com"].rate, state.mxs["mx.example.com"].rate)
+    }
+
+    #[test]
+    fn throttling_halves_the_rate_down_to_the_minimum() {
+        let throttle = throttle();
+        let mut now = Instant::now();
+        for expected in &[5.0, 2.5, 2.0, 2.0] {
+            let permit = throttle.acquire_at(&domains(), "mx.example.com", now).unwrap();
+            throttle.throttled_at(&permit, now);
+            assert_eq!(rate(&throttle), (*expected, *expected));
+            now += Duration::from_secs(60);
+        }
+    }
+
+    #[test]
+    fn sending_waits_out_the_backoff() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let permit = throttle.acquire_at(&domains(), "mx.example.com", now).unwrap();
+        throttle.throttled_at(&permit, now);
+        drop(permit);
+
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(59)).is_err());
+        // Another MX for the domain is held back too
+        assert!(throttle.acquire_at(&domains(), "mx2.example.com", now + Duration::from_secs(59)).is_err());
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn successes_recover_the_rate_one_at_a_time() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let permit = throttle.acquire_at(&domains(), "mx.example.com", now).unwrap();
+        throttle.throttled_at(&permit, now);
+        assert_eq!(rate(&throttle), (5.0, 5.0));
+
+        throttle.succeeded(&permit);
+        assert_eq!(rate(&throttle), (6.0, 6.0));
+        for _ in 0..10 {
+            throttle.succeeded(&permit);
+        }
+        assert_eq!(rate(&throttle), (10.0, 10.0));
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let first = throttle.acquire_at(&domains(), "mx.example.com", now).unwrap();
+        let _second = throttle.acquire_at(&domains(), "mx.example.com", now).unwrap();
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now).is_err());
+        // The domain's own cap is higher, so another of its MXs can still be used
+        let _third = throttle.acquire_at(&domains(), "mx2.example.com", now).unwrap();
+        assert!(throttle.acquire_at(&domains(), "mx3.example.com", now).is_err());
+
+        drop(first);
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now).is_ok());
+    }
+
+    #[test]
+    fn transactions_are_limited_per_minute() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for i in 0..10 {
+            throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(i)).unwrap();
+        }
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(59)).is_err());
+        // Only the first transaction has left the window
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(60)).is_ok());
+        assert!(throttle.acquire_at(&domains(), "mx.example.com", now + Duration::from_secs(60)).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Finds the locally configured TLS policy for a destination domain. An exact entry for the domain
+/// takes priority over a wildcard entry (`*.example.com`) for its parent.
+pub async fn get_policy(domain: &str, config: &crate::Config) -> Option<models::OutboundTlsPolicy> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let mut candidates = vec![domain.clone()];
+    if let Some(parent) = domain.splitn(2, '.').nth(1) {
+        candidates.push(format!("*.{}", parent));
+    }
+
+    let policies = match tokio::task::block_in_place(|| {
+        let conn = config.connection.get().map_err(|e| e.to_string())?;
+        schema::outbound_tls_policy::table
+            .filter(schema::outbound_tls_policy::domain.eq_any(&candidates))
+            .load::<models::OutboundTlsPolicy>(&conn)
+            .map_err(|e| e.to_string())
+    }) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Error loading outbound TLS policy for {}: {}", domain, e);
+            return None;
+        }
+    };
+
+    candidates.iter()
+        .filter_map(|c| policies.iter().position(|p| &p.domain == c))
+        .next()
+        .map(|i| policies.into_iter().nth(i).unwrap())
+}
+
+/// Parses a SHA-256 certificate fingerprint, with or without colons between the bytes
+pub fn parse_fingerprint(fingerprint: &str) -> Option<Vec<u8>> {
+    let hex = fingerprint.chars().filter(|c| *c != ':').collect::<String>();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,535 @@
+use std::io::Write;
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Name reports are submitted under, which is also the name we EHLO as
+const SUBMITTER: &str = "relay-mx.as207960.net";
+const ORGANIZATION_NAME: &str = "AS207960 Cyfyngedig";
+const CONTACT_INFO: &str = "noc@as207960.net";
+/// How long session results are kept for when a domain's reports can't be delivered, to go in a
+/// later report
+const MAX_UNREPORTED_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyType {
+    Sts,
+    Tlsa,
+    NoPolicyFound,
+}
+
+impl PolicyType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sts => "sts",
+            Self::Tlsa => "tlsa",
+            Self::NoPolicyFound => "no-policy-found",
+        }
+    }
+}
+
+/// The policy a TLS session was negotiated under, as it's described in a report
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub policy_type: PolicyType,
+    pub policy_string: Vec<String>,
+    pub mx_host: Vec<String>,
+}
+
+impl Policy {
+    pub fn none() -> Self {
+        Self {
+            policy_type: PolicyType::NoPolicyFound,
+            policy_string: vec![],
+            mx_host: vec![],
+        }
+    }
+
+    pub fn sts(policy: &crate::mta_sts::Policy) -> Self {
+        let mode = match policy.mode {
+            schema::MtaStsMode::Enforce => "enforce",
+            schema::MtaStsMode::Testing => "testing",
+            schema::MtaStsMode::None => "none",
+        };
+        let mut policy_string = vec!["version: STSv1".to_string(), format!("mode: {}", mode)];
+        policy_string.extend(policy.mx.iter().map(|m| format!("mx: {}", m)));
+        policy_string.push(format!("max_age: {}", policy.max_age));
+
+        Self {
+            policy_type: PolicyType::Sts,
+            policy_string,
+            mx_host: policy.mx.clone(),
+        }
+    }
+
+    pub fn tlsa(records: &[trust_dns_resolver::proto::rr::rdata::TLSA]) -> Self {
+        Self {
+            policy_type: PolicyType::Tlsa,
+            policy_string: records.iter().map(|r| format!(
+                "{} {} {} {}",
+                u8::from(r.cert_usage()), u8::from(r.selector()), u8::from(r.matching()),
+                r.cert_data().iter().map(|b| format!("{:02X}", b)).collect::<String>()
+            )).collect(),
+            mx_host: vec![],
+        }
+    }
+}
+
+/// Result types from RFC 8460 § 4.3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+}
+
+impl ResultType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::StarttlsNotSupported => "starttls-not-supported",
+            Self::CertificateHostMismatch => "certificate-host-mismatch",
+            Self::CertificateExpired => "certificate-expired",
+            Self::CertificateNotTrusted => "certificate-not-trusted",
+            Self::ValidationFailure => "validation-failure",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub result_type: ResultType,
+    pub reason: String,
+}
+
+impl Failure {
+    pub fn new<R: Into<String>>(result_type: ResultType, reason: R) -> Self {
+        Self {
+            result_type,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Records the outcome of TLS sessions with an MX against the recipient domains it's serving
+pub struct SessionRecorder<'a> {
+    pub policy_domains: &'a [String],
+    pub policy: Policy,
+    pub config: &'a crate::Config,
+}
+
+impl SessionRecorder<'_> {
+    pub fn record(
+        &self, receiving_mx_hostname: &str, receiving_ip: std::net::IpAddr, sending_ip: Option<std::net::IpAddr>,
+        failure: Option<&Failure>,
+    ) {
+        let conn = match tokio::task::block_in_place(|| {
+            self.config.connection.get()
+        }) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting DB connection: {}", e);
+                return;
+            }
+        };
+
+        let policy_string = self.policy.policy_string.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let mx_host = self.policy.mx_host.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let sending_ip = sending_ip.map(|i| i.to_string());
+        let receiving_ip = receiving_ip.to_string();
+        let now = chrono::Utc::now();
+
+        for policy_domain in self.policy_domains {
+            let new_result = models::NewTlsReportResult {
+                id: &uuid::Uuid::new_v4(),
+                policy_domain,
+                policy_type: self.policy.policy_type.as_str(),
+                policy_string: &policy_string,
+                mx_host: &mx_host,
+                sending_ip: sending_ip.as_deref(),
+                receiving_mx_hostname,
+                receiving_ip: &receiving_ip,
+                result_type: failure.map(|f| f.result_type.as_str()),
+                failure_reason: failure.map(|f| f.reason.as_str()),
+                recorded_at: &now,
+            };
+
+            match tokio::task::block_in_place(|| {
+                diesel::insert_into(schema::tls_report_result::table)
+                    .values(&new_result)
+                    .execute(&conn)
+            }) {
+                Ok(_) => {},
+                Err(e) => error!("Error recording TLS session result for {}: {}", policy_domain, e)
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Report {
+    organization_name: &'static str,
+    date_range: DateRange,
+    contact_info: &'static str,
+    report_id: String,
+    policies: Vec<PolicyReport>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct DateRange {
+    start_datetime: String,
+    end_datetime: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PolicyReport {
+    policy: ReportPolicy,
+    summary: Summary,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failure_details: Vec<FailureDetails>,
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct ReportPolicy {
+    policy_type: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    policy_string: Vec<String>,
+    policy_domain: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mx_host: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Summary {
+    total_successful_session_count: u64,
+    total_failure_session_count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct FailureDetails {
+    result_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sending_mta_ip: Option<String>,
+    receiving_mx_hostname: String,
+    receiving_ip: String,
+    failed_session_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_reason_code: Option<String>,
+}
+
+fn build_report(
+    domain: &str, start: chrono::DateTime<chrono::Utc>, end: chrono::DateTime<chrono::Utc>, results: &[models::TlsReportResult]
+) -> Report {
+    let mut policies: Vec<PolicyReport> = vec![];
+
+    for result in results {
+        let policy = ReportPolicy {
+            policy_type: result.policy_type.clone(),
+            policy_string: result.policy_string.clone(),
+            policy_domain: domain.to_string(),
+            mx_host: result.mx_host.clone(),
+        };
+        let policy_report = match policies.iter().position(|p| p.policy == policy) {
+            Some(i) => &mut policies[i],
+            None => {
+                policies.push(PolicyReport {
+                    policy,
+                    summary: Summary {
+                        total_successful_session_count: 0,
+                        total_failure_session_count: 0,
+                    },
+                    failure_details: vec![],
+                });
+                policies.last_mut().unwrap()
+            }
+        };
+
+        let result_type = match &result.result_type {
+            Some(r) => r,
+            None => {
+                policy_report.summary.total_successful_session_count += 1;
+                continue;
+            }
+        };
+        policy_report.summary.total_failure_session_count += 1;
+
+        match policy_report.failure_details.iter_mut().find(|d| {
+            &d.result_type == result_type && d.sending_mta_ip == result.sending_ip &&
+                d.receiving_mx_hostname == result.receiving_mx_hostname && d.receiving_ip == result.receiving_ip &&
+                d.failure_reason_code == result.failure_reason
+        }) {
+            Some(d) => d.failed_session_count += 1,
+            None => policy_report.failure_details.push(FailureDetails {
+                result_type: result_type.clone(),
+                sending_mta_ip: result.sending_ip.clone(),
+                receiving_mx_hostname: result.receiving_mx_hostname.clone(),
+                receiving_ip: result.receiving_ip.clone(),
+                failed_session_count: 1,
+                failure_reason_code: result.failure_reason.clone(),
+            })
+        }
+    }
+
+    Report {
+        organization_name: ORGANIZATION_NAME,
+        date_range: DateRange {
+            start_datetime: start.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            end_datetime: end.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        },
+        contact_info: CONTACT_INFO,
+        report_id: format!("{}@{}", uuid::Uuid::new_v4(), SUBMITTER),
+        policies,
+    }
+}
+
+/// Looks up the reporting URIs from a domain's `_smtp._tls` TXT record, as per RFC 8460 § 3
+async fn lookup_rua(domain: &str, config: &crate::Config) -> Result<Vec<String>, String> {
+    let records = match config.resolver.txt_lookup(format!("_smtp._tls.{}.", domain)).await {
+        Ok(r) => r,
+        Err(e) if crate::client::is_no_records(&e) => return Ok(vec![]),
+        Err(e) => return Err(e.to_string())
+    };
+
+    let records = records.iter().map(|r| {
+        r.txt_data().iter().map(|d| String::from_utf8_lossy(d).into_owned()).collect::<String>()
+    }).filter(|r| r.starts_with("v=TLSRPTv1")).collect::<Vec<_>>();
+
+    // More than one record is to be treated as no record
+    let record = match &records[..] {
+        [r] => r,
+        _ => return Ok(vec![])
+    };
+
+    for field in record.split(';') {
+        let mut parts = field.trim().splitn(2, '=');
+        if let (Some("rua"), Some(rua)) = (parts.next(), parts.next()) {
+            return Ok(rua.split(',').map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect());
+        }
+    }
+    Ok(vec![])
+}
+
+/// Gives the address a `mailto:` reporting URI points to, as long as it's a plain address that's
+/// safe to put in a header
+fn mailto_address(uri: &str) -> Option<String> {
+    let to = uri.strip_prefix("mailto:")?.splitn(2, '?').next().unwrap();
+    if to.contains(|c: char| c == '\r' || c == '\n') {
+        return None;
+    }
+    crate::alias::normalise_address(to)
+}
+
+fn report_mail(domain: &str, to: &str, report: &Report, filename: &str, data: &[u8]) -> Vec<u8> {
+    let boundary = format!("tlsrpt-{}", uuid::Uuid::new_v4().simple());
+    let mut mail = format!(
+        "From: <{}>\r\n\
+        To: <{}>\r\n\
+        Subject: Report Domain: {} Submitter: {} Report-ID: <{}>\r\n\
+        Date: {}\r\n\
+        Message-ID: <{}>\r\n\
+        TLS-Report-Domain: {}\r\n\
+        TLS-Report-Submitter: {}\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/report; report-type=\"tlsrpt\"; boundary=\"{}\"\r\n\
+        \r\n\
+        --{}\r\n\
+        Content-Type: text/plain; charset=\"utf-8\"\r\n\
+        \r\n\
+        This is an aggregate TLS report from {}\r\n\
+        \r\n\
+        --{}\r\n\
+        Content-Type: application/tlsrpt+gzip\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        Content-Disposition: attachment; filename=\"{}\"\r\n\
+        \r\n",
+        crate::sender::NOTIFICATION_FROM, to, domain, SUBMITTER, report.report_id, chrono::Utc::now().to_rfc2822(), report.report_id,
+        domain, SUBMITTER, boundary, boundary, SUBMITTER, boundary, filename
+    );
+
+    let encoded = base64::encode(data);
+    for line in encoded.as_bytes().chunks(76) {
+        mail.push_str(&String::from_utf8_lossy(line));
+        mail.push_str("\r\n");
+    }
+    mail.push_str(&format!("--{}--\r\n", boundary));
+
+    mail.into_bytes()
+}
+
+async fn post_report(uri: &str, data: &[u8]) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(60))
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let resp = client.post(uri)
+        .header(reqwest::header::CONTENT_TYPE, "application/tlsrpt+gzip")
+        .body(data.to_vec())
+        .send().await
+        .map_err(|e| e.to_string())?;
+
+    if !resp.status().is_success() {
+        return Err(format!("Report upload returned {}", resp.status()));
+    }
+    Ok(())
+}
+
+/// Sends a report to each of a domain's reporting URIs, giving whether it got to any of them
+async fn send_report(domain: &str, report: &Report, rua: &[String], start: chrono::DateTime<chrono::Utc>,
+                     end: chrono::DateTime<chrono::Utc>, config: &crate::Config) -> bool {
+    let json = match serde_json::to_vec(report) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Error serializing TLS report for {}: {}", domain, e);
+            return false;
+        }
+    };
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    let data = match encoder.write_all(&json).and_then(|_| encoder.finish()) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Error compressing TLS report for {}: {}", domain, e);
+            return false;
+        }
+    };
+    // RFC 8460 § 5.3
+    let filename = format!("{}!{}!{}!{}.json.gz", SUBMITTER, domain, start.timestamp(), end.timestamp());
+
+    let mut sent = false;
+    for uri in rua {
+        if uri.starts_with("mailto:") {
+            let to = match mailto_address(uri) {
+                Some(t) => t,
+                None => {
+                    warn!("Invalid TLS report address for {}: {:?}", domain, uri);
+                    continue;
+                }
+            };
+            let mail = report_mail(domain, &to, report, &filename, &data);
+            match tokio::task::block_in_place(|| {
+                let conn = config.connection.get().map_err(|e| e.to_string())?;
+                crate::sender::queue_mail(crate::sender::NOTIFICATION_FROM, &[&to], &mail, &config.keys, &conn).map_err(|e| e.to_string())
+            }) {
+                Ok(_) => {
+                    info!("Queued TLS report for {} to {}", domain, to);
+                    sent = true;
+                },
+                Err(e) => error!("Error queueing TLS report for {}: {}", domain, e)
+            }
+        } else if uri.starts_with("https:") {
+            match post_report(uri, &data).await {
+                Ok(_) => {
+                    info!("Uploaded TLS report for {} to {}", domain, uri);
+                    sent = true;
+                },
+                Err(e) => warn!("Unable to upload TLS report for {} to {}: {}", domain, uri, e)
+            }
+        } else {
+            warn!("Unsupported TLS report URI for {}: {:?}", domain, uri);
+        }
+    }
+    sent
+}
+
+/// Reports on all sessions recorded before the start of the current day, per policy domain
+async fn send_reports(config: &crate::Config) {
+    let conn = match tokio::task::block_in_place(|| {
+        config.connection.get()
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error getting DB connection: {}", e);
+            return;
+        }
+    };
+
+    let end = chrono::Utc::today().and_hms(0, 0, 0);
+    let results = match tokio::task::block_in_place(|| {
+        schema::tls_report_result::table
+            .filter(schema::tls_report_result::recorded_at.lt(end))
+            .order_by(schema::tls_report_result::recorded_at.asc())
+            .select((
+                schema::tls_report_result::policy_domain, schema::tls_report_result::policy_type,
+                schema::tls_report_result::policy_string, schema::tls_report_result::mx_host,
+                schema::tls_report_result::sending_ip, schema::tls_report_result::receiving_mx_hostname,
+                schema::tls_report_result::receiving_ip, schema::tls_report_result::result_type,
+                schema::tls_report_result::failure_reason, schema::tls_report_result::recorded_at,
+            ))
+            .load::<models::TlsReportResult>(&conn)
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Error loading TLS session results: {}", e);
+            return;
+        }
+    };
+
+    let mut domain_results: std::collections::BTreeMap<String, Vec<models::TlsReportResult>> = std::collections::BTreeMap::new();
+    for result in results {
+        domain_results.entry(result.policy_domain.clone()).or_default().push(result);
+    }
+
+    for (domain, results) in domain_results {
+        let sent = match lookup_rua(&domain, config).await {
+            Ok(rua) => if !rua.is_empty() {
+                let start = results[0].recorded_at.date().and_hms(0, 0, 0);
+                let report = build_report(&domain, start, end, &results);
+                send_report(&domain, &report, &rua, start, end, config).await
+            } else {
+                // No one to report to, so there's nothing to keep the results for
+                true
+            },
+            Err(e) => {
+                warn!("Unable to lookup TLS reporting record for {}: {}", domain, e);
+                false
+            }
+        };
+
+        // Results that couldn't be reported are kept around to go in tomorrow's report, for a while
+        let delete_before = if sent {
+            end
+        } else {
+            end - chrono::Duration::days(MAX_UNREPORTED_DAYS)
+        };
+        match tokio::task::block_in_place(|| {
+            diesel::delete(schema::tls_report_result::table)
+                .filter(schema::tls_report_result::policy_domain.eq(&domain))
+                .filter(schema::tls_report_result::recorded_at.lt(delete_before))
+                .execute(&conn)
+        }) {
+            Ok(_) => {},
+            Err(e) => error!("Error deleting TLS session results for {}: {}", domain, e)
+        }
+    }
+}
+
+pub async fn reporting_task(config: crate::Config) {
+    loop {
+        let now = chrono::Utc::now();
+        let next_run = (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0);
+        tokio::time::delay_for((next_run - now).to_std().unwrap_or_default()).await;
+
+        send_reports(&config).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn mailto_addresses() {
+        assert_eq!(super::mailto_address("mailto:tlsrpt@example.com").as_deref(), Some("tlsrpt@example.com"));
+        assert_eq!(super::mailto_address("mailto:tlsrpt@Example.COM?subject=x").as_deref(), Some("tlsrpt@example.com"));
+        assert_eq!(super::mailto_address("mailto:tlsrpt@example.com\r\nBcc: victim@example.net"), None);
+        assert_eq!(super::mailto_address("mailto:tlsrpt@example.com\n"), None);
+        assert_eq!(super::mailto_address("mailto:<tlsrpt@example.com>"), None);
+        assert_eq!(super::mailto_address("mailto:a@b@example.com"), None);
+        assert_eq!(super::mailto_address("mailto:"), None);
+        assert_eq!(super::mailto_address("https://example.com/report"), None);
+    }
+}
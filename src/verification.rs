@@ -0,0 +1,251 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// How long a confirmed forwarding address stays verified before it has to be confirmed again
+const REVERIFY_DAYS: i64 = 365;
+/// How long before verification lapses we start asking for it to be confirmed again
+const REVERIFY_NOTICE_DAYS: i64 = 14;
+/// How long to wait between verification requests to an address that hasn't confirmed
+const RESEND_DAYS: i64 = 7;
+/// How long a verification link works for after it's sent
+const TOKEN_VALID_DAYS: i64 = 7;
+/// Most verification requests sent in a row, so a mistyped address belonging to someone else
+/// isn't pestered forever. Past this the registrant can log in to the portal, which verifies the
+/// address too, or the registrar can ask for requests to start again with
+/// `POST /api/aliases/{address}/verify`.
+const MAX_REQUESTS: i32 = 3;
+/// Hard bounces in a row after which a forwarding address has to be verified again
+const MAX_HARD_BOUNCES: i32 = 3;
+/// How often the verification task looks for addresses to ask to confirm
+const VERIFICATION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Whether mail may be forwarded to an address, having had its owner confirm it recently enough
+pub fn is_verified(forward_email: &str, conn: &crate::DbConn) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(schema::forward_email_verification::table
+        .find(forward_email.to_lowercase())
+        .filter(schema::forward_email_verification::verified_until.gt(chrono::Utc::now()))
+    )).get_result(conn)
+}
+
+fn ensure_exists(forward_email: &str, conn: &crate::DbConn) -> QueryResult<()> {
+    diesel::insert_into(schema::forward_email_verification::table)
+        .values(&models::NewForwardEmailVerification {
+            forward_email,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Records that the owner of a forwarding address has shown they receive mail sent to it
+pub fn mark_verified(forward_email: &str, conn: &crate::DbConn) -> QueryResult<()> {
+    let forward_email = forward_email.to_lowercase();
+    let now = chrono::Utc::now();
+    conn.transaction(|| {
+        ensure_exists(&forward_email, conn)?;
+        diesel::update(schema::forward_email_verification::table.find(&forward_email))
+            .set((
+                schema::forward_email_verification::token_hash.eq(None::<Vec<u8>>),
+                schema::forward_email_verification::requests_sent.eq(0),
+                schema::forward_email_verification::verified_at.eq(now),
+                schema::forward_email_verification::verified_until.eq(now + chrono::Duration::days(REVERIFY_DAYS)),
+                schema::forward_email_verification::hard_bounces.eq(0),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Finds which forwarding address a verification link was sent to, if it hasn't expired
+pub fn lookup_token(token: &str, conn: &crate::DbConn) -> QueryResult<Option<String>> {
+    schema::forward_email_verification::table
+        .filter(schema::forward_email_verification::token_hash.eq(crate::crypto::token_hash(token)))
+        .filter(schema::forward_email_verification::last_sent_at.gt(chrono::Utc::now() - chrono::Duration::days(TOKEN_VALID_DAYS)))
+        .select(schema::forward_email_verification::forward_email)
+        .get_result::<String>(conn)
+        .optional()
+}
+
+/// Verifies the forwarding address a verification link was sent to, returning the address
+pub fn confirm(token: &str, conn: &crate::DbConn) -> QueryResult<Option<String>> {
+    conn.transaction(|| {
+        let forward_email = lookup_token(token, conn)?;
+        if let Some(forward_email) = &forward_email {
+            mark_verified(forward_email, conn)?;
+        }
+        Ok(forward_email)
+    })
+}
+
+/// Starts sending verification requests to an unconfirmed address again, even if it's had as
+/// many as it gets on its own. Gives whether the address is already verified, in which case
+/// nothing is changed.
+pub fn request_again(forward_email: &str, conn: &crate::DbConn) -> QueryResult<bool> {
+    let forward_email = forward_email.to_lowercase();
+    conn.transaction(|| {
+        if is_verified(&forward_email, conn)? {
+            return Ok(true);
+        }
+        ensure_exists(&forward_email, conn)?;
+        diesel::update(schema::forward_email_verification::table.find(&forward_email))
+            .set((
+                schema::forward_email_verification::requests_sent.eq(0),
+                schema::forward_email_verification::last_sent_at.eq(None::<chrono::DateTime<chrono::Utc>>),
+            ))
+            .execute(conn)?;
+        Ok(false)
+    })
+}
+
+/// Counts hard bounces of mail to an address, so forwarding to one that's stopped working is
+/// suspended until it's confirmed again. A successful delivery resets the count.
+pub fn record_delivery(forward_path: &str, hard_bounce: bool, conn: &crate::DbConn) -> QueryResult<()> {
+    let forward_email = forward_path.to_lowercase();
+    if !hard_bounce {
+        diesel::update(schema::forward_email_verification::table
+            .find(&forward_email)
+            .filter(schema::forward_email_verification::hard_bounces.gt(0))
+        )
+            .set(schema::forward_email_verification::hard_bounces.eq(0))
+            .execute(conn)?;
+        return Ok(());
+    }
+
+    conn.transaction(|| {
+        diesel::update(schema::forward_email_verification::table.find(&forward_email))
+            .set(schema::forward_email_verification::hard_bounces.eq(schema::forward_email_verification::hard_bounces + 1))
+            .execute(conn)?;
+        // Only addresses currently verified are suspended, or bounced verification requests would
+        // keep restarting the cycle of asking
+        let suspended = diesel::update(schema::forward_email_verification::table
+            .find(&forward_email)
+            .filter(schema::forward_email_verification::hard_bounces.ge(MAX_HARD_BOUNCES))
+            .filter(schema::forward_email_verification::verified_until.gt(chrono::Utc::now()))
+        )
+            .set((
+                schema::forward_email_verification::requests_sent.eq(0),
+                schema::forward_email_verification::verified_until.eq(chrono::Utc::now()),
+                schema::forward_email_verification::hard_bounces.eq(0),
+            ))
+            .execute(conn)?;
+        if suspended > 0 {
+            info!("Suspended forwarding to {} after {} hard bounces", forward_email, MAX_HARD_BOUNCES);
+        }
+        Ok(())
+    })
+}
+
+fn send_request(verification: &models::ForwardEmailVerification, config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    let aliases = crate::alias::for_forward_email(&verification.forward_email, conn)
+        .map_err(|e| format!("Unable to load registered addresses: {}", e))?;
+    // Sent as the address was given to us, in case its local part is case sensitive
+    let to = match aliases.first() {
+        Some(a) => a.forward_email.clone(),
+        None => return Ok(())
+    };
+    let token = crate::crypto::random_token();
+
+    let mut context = tera::Context::new();
+    context.insert("forward_email", &to);
+    context.insert("aliases", &aliases.into_iter().map(|a| a.id).collect::<Vec<_>>());
+    context.insert("reverify", &verification.verified_at.is_some());
+    context.insert("verify_link", &format!("{}/verify/{}", crate::web::BASE_URL, token));
+    let data = crate::sender::build_notification(
+        &to, "Confirm your WHOIS forwarding address", "verify_email", &context,
+    )?;
+
+    conn.transaction(|| {
+        crate::sender::queue_mail(crate::sender::NOTIFICATION_FROM, &[&to], &data, &config.keys, conn)?;
+        diesel::update(schema::forward_email_verification::table.find(&verification.forward_email))
+            .set((
+                schema::forward_email_verification::token_hash.eq(crate::crypto::token_hash(&token)),
+                schema::forward_email_verification::requests_sent.eq(verification.requests_sent + 1),
+                schema::forward_email_verification::last_sent_at.eq(chrono::Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }).map_err(|e: diesel::result::Error| format!("Unable to queue verification request: {}", e))
+}
+
+/// Asks the owners of forwarding addresses that haven't been confirmed, or soon need confirming
+/// again, to do so
+fn send_requests(config: &crate::Config, conn: &crate::DbConn) -> Result<(), String> {
+    let now = chrono::Utc::now();
+    let forward_emails = schema::registered_addresses::table
+        .filter(schema::registered_addresses::revoked_at.is_null())
+        .select(crate::alias::lower(schema::registered_addresses::forward_email))
+        .distinct()
+        .load::<String>(conn)
+        .map_err(|e| format!("Unable to load forwarding addresses: {}", e))?;
+    for forward_email in &forward_emails {
+        ensure_exists(forward_email, conn)
+            .map_err(|e| format!("Unable to record forwarding address: {}", e))?;
+    }
+
+    let due = schema::forward_email_verification::table
+        .filter(schema::forward_email_verification::forward_email.eq_any(&forward_emails))
+        .filter(schema::forward_email_verification::verified_until.is_null()
+            .or(schema::forward_email_verification::verified_until.lt(now + chrono::Duration::days(REVERIFY_NOTICE_DAYS))))
+        .filter(schema::forward_email_verification::requests_sent.lt(MAX_REQUESTS))
+        .filter(schema::forward_email_verification::last_sent_at.is_null()
+            .or(schema::forward_email_verification::last_sent_at.lt(now - chrono::Duration::days(RESEND_DAYS))))
+        .select((
+            schema::forward_email_verification::forward_email, schema::forward_email_verification::requests_sent,
+            schema::forward_email_verification::verified_at,
+        ))
+        .load::<models::ForwardEmailVerification>(conn)
+        .map_err(|e| format!("Unable to load forwarding addresses to verify: {}", e))?;
+
+    for verification in due {
+        match send_request(&verification, config, conn) {
+            Ok(_) => info!("Sent verification request to {}", verification.forward_email),
+            Err(e) => error!("Error sending verification request to {}: {}", verification.forward_email, e)
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn verification_task(config: crate::Config) {
+    loop {
+        if let Err(e) = tokio::task::block_in_place(|| {
+            let conn = config.connection.get().map_err(|e| e.to_string())?;
+            send_requests(&config, &conn)
+        }) {
+            error!("Error sending verification requests: {}", e);
+        }
+
+        tokio::time::delay_for(VERIFICATION_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use crate::schema;
+
+    #[test]
+    #[ignore]
+    fn verification_links_expire() {
+        let conn = crate::testing::connection();
+        let token = crate::crypto::random_token();
+        super::ensure_exists("someone@example.com", &conn).unwrap();
+        let set_sent_at = |sent_at: chrono::DateTime<chrono::Utc>| {
+            diesel::update(schema::forward_email_verification::table.find("someone@example.com"))
+                .set((
+                    schema::forward_email_verification::token_hash.eq(crate::crypto::token_hash(&token)),
+                    schema::forward_email_verification::last_sent_at.eq(sent_at),
+                ))
+                .execute(&conn)
+                .unwrap();
+        };
+
+        set_sent_at(chrono::Utc::now() - chrono::Duration::days(super::TOKEN_VALID_DAYS + 1));
+        assert_eq!(super::confirm(&token, &conn).unwrap(), None);
+        assert!(!super::is_verified("someone@example.com", &conn).unwrap());
+
+        set_sent_at(chrono::Utc::now() - chrono::Duration::hours(1));
+        assert_eq!(super::confirm(&token, &conn).unwrap().as_deref(), Some("someone@example.com"));
+        assert!(super::is_verified("someone@example.com", &conn).unwrap());
+    }
+}
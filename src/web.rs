@@ -0,0 +1,213 @@
+use diesel::prelude::*;
+use crate::{schema, models};
+
+/// Where the web service is reachable from the outside world, for links in emails
+pub const BASE_URL: &str = "https://relay.as207961.net";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HeldAction {
+    Release,
+    Delete,
+}
+
+impl HeldAction {
+    fn from_str(action: &str) -> Option<Self> {
+        match action {
+            "release" => Some(Self::Release),
+            "delete" => Some(Self::Delete),
+            _ => None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Release => "release",
+            Self::Delete => "delete",
+        }
+    }
+}
+
+pub fn form_value(query: &[u8], key: &str) -> Option<String> {
+    url::form_urlencoded::parse(query)
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+pub fn render(template: &str, context: &tera::Context, status: hyper::StatusCode) -> hyper::Response<hyper::Body> {
+    match crate::TEMPLATES.render(template, context) {
+        Ok(body) => hyper::Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(hyper::Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            error!("Error rendering {}: {}", template, e);
+            status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn status_response(status: hyper::StatusCode) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::from(status.canonical_reason().unwrap_or_default()))
+        .unwrap()
+}
+
+pub fn redirect(location: &str) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::SEE_OTHER)
+        .header(hyper::header::LOCATION, location)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+/// Shows a held message to confirm releasing or deleting it, and does so once confirmed. Links in
+/// digests only ever lead to the confirmation page, so mail scanners following them can't act on
+/// the registrant's behalf.
+async fn held_action(
+    id: &str, action: HeldAction, req: hyper::Request<hyper::Body>, config: &crate::Config,
+) -> hyper::Response<hyper::Body> {
+    let id = match uuid::Uuid::parse_str(id) {
+        Ok(i) => i,
+        Err(_) => return status_response(hyper::StatusCode::NOT_FOUND)
+    };
+    let method = req.method().to_owned();
+    let token = match method {
+        hyper::Method::GET => req.uri().query().and_then(|q| form_value(q.as_bytes(), "token")),
+        hyper::Method::POST => match hyper::body::to_bytes(req.into_body()).await {
+            Ok(b) => form_value(&b, "token"),
+            Err(_) => return status_response(hyper::StatusCode::BAD_REQUEST)
+        },
+        _ => return status_response(hyper::StatusCode::METHOD_NOT_ALLOWED)
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("action", action.as_str());
+
+    tokio::task::block_in_place(|| {
+        let conn = match config.connection.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting DB connection: {}", e);
+                return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        let item = match schema::inbound_queue::table
+            .find(&id)
+            .select(models::INBOUND_QUEUE_ITEM_COLUMNS)
+            .get_result::<models::InboundQueueItem>(&conn)
+            .optional() {
+            Ok(i) => i,
+            Err(e) => {
+                error!("Error loading held mail: {}", e);
+                return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let item = match (item, &token) {
+            (Some(i), Some(t)) if crate::held::verify_token(&i, t) => i,
+            _ => {
+                context.insert("error", "This link has expired, or the email has already been released or deleted.");
+                return render("web/held_action.html", &context, hyper::StatusCode::NOT_FOUND);
+            }
+        };
+
+        if method == hyper::Method::GET {
+            context.insert("item", &crate::held::summarise(&item, config, &conn));
+            context.insert("token", &token);
+            return render("web/held_action.html", &context, hyper::StatusCode::OK);
+        }
+
+        let res = match action {
+            HeldAction::Release => crate::held::release(&item, config, &conn),
+            HeldAction::Delete => crate::held::delete(&item, &conn),
+        };
+        match res {
+            Ok(_) => {
+                context.insert("done", &true);
+                render("web/held_action.html", &context, hyper::StatusCode::OK)
+            }
+            Err(e) => {
+                error!("Error acting on held mail {}: {}", item.id, e);
+                context.insert("error", "Something went wrong, please try again later.");
+                render("web/held_action.html", &context, hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    })
+}
+
+/// Shows a forwarding address verification link's address to confirm, and verifies it once
+/// confirmed, for the same reason as held mail actions
+async fn verify_forward_email(token: &str, req: hyper::Request<hyper::Body>, config: &crate::Config) -> hyper::Response<hyper::Body> {
+    let method = req.method().to_owned();
+    if method != hyper::Method::GET && method != hyper::Method::POST {
+        return status_response(hyper::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let mut context = tera::Context::new();
+
+    tokio::task::block_in_place(|| {
+        let conn = match config.connection.get() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error getting DB connection: {}", e);
+                return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        let res = if method == hyper::Method::GET {
+            crate::verification::lookup_token(token, &conn)
+        } else {
+            crate::verification::confirm(token, &conn)
+        };
+        match res {
+            Ok(Some(forward_email)) => {
+                context.insert("forward_email", &forward_email);
+                context.insert("done", &(method == hyper::Method::POST));
+                render("web/verify_email.html", &context, hyper::StatusCode::OK)
+            }
+            Ok(None) => {
+                context.insert("error", "This link has expired, or the address has already been confirmed.");
+                render("web/verify_email.html", &context, hyper::StatusCode::NOT_FOUND)
+            }
+            Err(e) => {
+                error!("Error verifying forwarding address: {}", e);
+                context.insert("error", "Something went wrong, please try again later.");
+                render("web/verify_email.html", &context, hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    })
+}
+
+async fn handle_request(req: hyper::Request<hyper::Body>, config: crate::Config) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+    let path = req.uri().path().split('/').skip(1).map(|s| s.to_string()).collect::<Vec<_>>();
+    let path = path.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+
+    Ok(match path.as_slice() {
+        ["held", id, action] => match HeldAction::from_str(action) {
+            Some(a) => held_action(id, a, req, &config).await,
+            None => status_response(hyper::StatusCode::NOT_FOUND)
+        },
+        ["verify", token] => verify_forward_email(token, req, &config).await,
+        ["portal", rest @ ..] => crate::portal::handle_request(rest, req, &config).await,
+        ["api", rest @ ..] => crate::api::handle_request(rest, req, &config).await,
+        _ => status_response(hyper::StatusCode::NOT_FOUND)
+    })
+}
+
+pub async fn web_task(config: crate::Config) {
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let config = config.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                handle_request(req, config.clone())
+            }))
+        }
+    });
+
+    let addr = "[::]:8080".parse().unwrap();
+    if let Err(e) = hyper::Server::bind(&addr).serve(make_service).await {
+        error!("Web server error: {}", e);
+    }
+}